@@ -0,0 +1,5 @@
+-- Migration script for tagging each stored file with the cipher that protects it
+
+-- Existing rows were written with AES-256-CBC; new uploads use AES-256-GCM
+ALTER TABLE files ADD COLUMN cipher VARCHAR(32) NOT NULL DEFAULT 'aes-256-cbc';
+ALTER TABLE files ALTER COLUMN cipher SET DEFAULT 'aes-256-gcm';
@@ -0,0 +1,7 @@
+-- Migration script for recording how each file's AES key is wrapped
+
+-- Existing keys were wrapped with RSA PKCS#1 v1.5; new uploads use RSA-OAEP with SHA-256
+ALTER TABLE files ADD COLUMN key_wrap VARCHAR(32) NOT NULL DEFAULT 'rsa-pkcs1v15';
+ALTER TABLE files ALTER COLUMN key_wrap SET DEFAULT 'rsa-oaep-sha256';
+
+CREATE INDEX idx_files_key_wrap ON files(key_wrap) WHERE key_wrap = 'rsa-pkcs1v15';
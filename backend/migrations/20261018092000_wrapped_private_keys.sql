@@ -0,0 +1,8 @@
+-- Migration script for storing private keys encrypted under a password-derived key
+
+-- AES-256-GCM ciphertext of the PKCS#1 DER private key, the Argon2id salt and the GCM nonce
+ALTER TABLE users ADD COLUMN encrypted_private_key BYTEA;
+ALTER TABLE users ADD COLUMN private_key_salt BYTEA;
+ALTER TABLE users ADD COLUMN private_key_nonce BYTEA;
+
+-- users.private_key now only holds legacy plaintext keys until their owner next logs in
@@ -0,0 +1,4 @@
+-- Migration script for files encrypted on the client before upload
+
+-- The server never holds the key for these rows and only ever serves their ciphertext
+ALTER TABLE files ADD COLUMN client_encrypted BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,13 @@
+-- Migration script for storing streamed uploads as fixed-size encrypted segments
+
+-- Segmented files keep no single blob; their ciphertext lives in file_chunks instead
+ALTER TABLE files ALTER COLUMN encrypted_file DROP NOT NULL;
+ALTER TABLE files ADD COLUMN chunk_count INTEGER;                -- Number of segments, NULL for single-blob files
+
+-- File chunks table - One AES-256-GCM segment per row, in upload order
+CREATE TABLE file_chunks (
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE, -- File this segment belongs to
+    chunk_index INTEGER NOT NULL,                    -- Position of the segment, starting at 0
+    data BYTEA NOT NULL,                             -- Segment ciphertext followed by its GCM tag
+    PRIMARY KEY (file_id, chunk_index)
+);
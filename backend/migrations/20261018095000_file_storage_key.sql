@@ -0,0 +1,5 @@
+-- Migration script for moving file content out of Postgres into the blob store
+
+-- Key of the file's ciphertext in the configured blob store (filesystem or S3)
+-- Rows still NULL here keep their content in encrypted_file / file_chunks until `migrate-blobs` moves it
+ALTER TABLE files ADD COLUMN storage_key TEXT UNIQUE;
@@ -0,0 +1,28 @@
+-- Migration script for resumable (tus) uploads
+
+-- Tus uploads table - One row per upload that has been created but not yet completed
+CREATE TABLE tus_uploads (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, -- Uploader
+    file_name VARCHAR(255) NOT NULL,
+    upload_length BIGINT NOT NULL,                   -- Total size announced in Upload-Length
+    upload_offset BIGINT NOT NULL DEFAULT 0,         -- Bytes received so far
+    recipient_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    password TEXT NOT NULL,                          -- Share password, already hashed
+    expiration_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    staging_key BYTEA NOT NULL,                      -- AES-256 key the received parts are staged under
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX tus_uploads_updated_at_idx ON tus_uploads (updated_at);
+
+-- Tus upload parts table - One staged blob per PATCH request, in offset order
+CREATE TABLE tus_upload_parts (
+    upload_id UUID NOT NULL REFERENCES tus_uploads(id) ON DELETE CASCADE,
+    part_offset BIGINT NOT NULL,                     -- Upload-Offset the part starts at
+    part_size BIGINT NOT NULL,
+    storage_key TEXT NOT NULL UNIQUE,                -- Staged ciphertext in the blob store
+    iv BYTEA NOT NULL,                               -- Segment nonce prefix of the staged part
+    PRIMARY KEY (upload_id, part_offset)
+);
@@ -0,0 +1,28 @@
+-- Migration script for sharing one stored file with several recipients
+
+-- File keys table - The file's AES key, wrapped once for each recipient's public key
+CREATE TABLE file_keys (
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE, -- File the key decrypts
+    recipient_user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE, -- Recipient whose public key wrapped it
+    encrypted_aes_key BYTEA NOT NULL,                -- AES key encrypted with the recipient's public key
+    key_wrap VARCHAR(32) NOT NULL DEFAULT 'rsa-oaep-sha256', -- How the AES key is wrapped
+    PRIMARY KEY (file_id, recipient_user_id)
+);
+
+CREATE INDEX idx_file_keys_key_wrap ON file_keys(key_wrap) WHERE key_wrap = 'rsa-pkcs1v15';
+
+-- Every existing file was shared with a single recipient, whose key was kept on the file itself
+INSERT INTO file_keys (file_id, recipient_user_id, encrypted_aes_key, key_wrap)
+SELECT DISTINCT f.id, sl.recipient_user_id, f.encrypted_aes_key, f.key_wrap
+FROM files f
+JOIN shared_links sl ON sl.file_id = f.id
+WHERE sl.recipient_user_id IS NOT NULL;
+
+ALTER TABLE files DROP COLUMN encrypted_aes_key;
+ALTER TABLE files DROP COLUMN key_wrap;
+
+-- Resumable uploads are shared with every recipient once they complete
+ALTER TABLE tus_uploads ADD COLUMN recipient_user_ids UUID[] NOT NULL DEFAULT '{}';
+UPDATE tus_uploads SET recipient_user_ids = ARRAY[recipient_user_id];
+ALTER TABLE tus_uploads ALTER COLUMN recipient_user_ids DROP DEFAULT;
+ALTER TABLE tus_uploads DROP COLUMN recipient_user_id;
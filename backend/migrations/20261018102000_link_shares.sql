@@ -0,0 +1,8 @@
+-- Migration script for link shares to recipients without an account
+
+-- Link shares have no recipient_user_id and are opened with the share password alone
+ALTER TABLE shared_links ADD COLUMN link_key VARCHAR(16);          -- 'fragment' or 'password' for link shares, NULL otherwise
+ALTER TABLE shared_links ADD COLUMN key_check BYTEA;              -- fragment links: SHA-256 of the file key carried in the URL
+ALTER TABLE shared_links ADD COLUMN encrypted_aes_key BYTEA;      -- password links: file key wrapped under the share password
+ALTER TABLE shared_links ADD COLUMN key_salt BYTEA;               -- password links: Argon2id salt
+ALTER TABLE shared_links ADD COLUMN key_nonce BYTEA;              -- password links: AES-256-GCM nonce
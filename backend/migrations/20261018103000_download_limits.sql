@@ -0,0 +1,6 @@
+-- Migration script for download limits and burn-after-reading shares
+
+-- Every served download decrements the counter; the share and its key are deleted once it reaches zero
+ALTER TABLE shared_links ADD COLUMN downloads_remaining INTEGER;   -- NULL means unlimited
+
+ALTER TABLE tus_uploads ADD COLUMN max_downloads INTEGER;          -- Download limit for the shares created on completion
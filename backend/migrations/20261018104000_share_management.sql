@@ -0,0 +1,5 @@
+-- Migration script for sender-side share management
+
+-- Revoked shares are kept, without their wrapped keys, until they expire
+ALTER TABLE shared_links ADD COLUMN revoked_at TIMESTAMP WITH TIME ZONE;   -- When the sender revoked the share
+ALTER TABLE shared_links ADD COLUMN updated_at TIMESTAMP WITH TIME ZONE;   -- Last expiry or password change by the sender
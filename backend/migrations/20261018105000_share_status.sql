@@ -0,0 +1,17 @@
+-- Migration script for the share state machine
+
+-- pending -> accepted -> downloaded, or pending -> declined; any open share can be revoked or expire
+ALTER TABLE shared_links ADD COLUMN status VARCHAR(16) NOT NULL DEFAULT 'pending'
+    CHECK (status IN ('pending', 'accepted', 'declined', 'revoked', 'expired', 'downloaded'));
+ALTER TABLE shared_links ADD COLUMN accepted_at TIMESTAMP WITH TIME ZONE;     -- When the recipient accepted
+ALTER TABLE shared_links ADD COLUMN declined_at TIMESTAMP WITH TIME ZONE;     -- When the recipient declined
+ALTER TABLE shared_links ADD COLUMN downloaded_at TIMESTAMP WITH TIME ZONE;   -- First download
+
+-- Existing shares keep what is known about them; acceptance times were never recorded
+UPDATE shared_links SET status = 'accepted' WHERE is_retrieved = true;
+UPDATE shared_links SET status = 'revoked' WHERE revoked_at IS NOT NULL;
+UPDATE shared_links SET status = 'expired' WHERE expiration_date < NOW() AND status IN ('pending', 'accepted');
+
+ALTER TABLE shared_links DROP COLUMN is_retrieved;
+
+CREATE INDEX shared_links_recipient_status_idx ON shared_links (recipient_user_id, status);
@@ -0,0 +1,13 @@
+-- Migration script for brute-force protection on share passwords
+
+-- Every attempt is counted before the password is checked; a correct password resets the counter
+ALTER TABLE shared_links ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;   -- Attempts since the last correct password
+ALTER TABLE shared_links ADD COLUMN last_attempt_at TIMESTAMP WITH TIME ZONE;      -- Start of the current backoff window
+ALTER TABLE shared_links ADD COLUMN locked_at TIMESTAMP WITH TIME ZONE;            -- Set on lockout, cleared only by the sender
+
+-- The same counter per recipient, across all shares, so guesses cannot be spread over many of them
+CREATE TABLE user_password_attempts (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    last_attempt_at TIMESTAMP WITH TIME ZONE
+);
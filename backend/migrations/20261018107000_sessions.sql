@@ -0,0 +1,24 @@
+-- Migration script for refresh-token sessions and access token revocation
+
+-- One row per login; the refresh token rotates on every use and only its SHA-256 hash is kept
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),          -- Carried in access tokens as `sid`
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    refresh_token_hash BYTEA NOT NULL UNIQUE,                -- Hash of the current refresh token
+    previous_token_hash BYTEA,                               -- Hash of the token it replaced, to spot reuse
+    user_agent TEXT,                                         -- Device the session was last used from
+    ip_address VARCHAR(64),                                  -- Address the session was last used from
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),     -- Last login or refresh
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,            -- Refresh tokens stop working after this
+    revoked_at TIMESTAMP WITH TIME ZONE                      -- Set on logout; every token of the session is rejected
+);
+
+CREATE INDEX idx_sessions_user_id ON sessions(user_id);
+CREATE INDEX idx_sessions_previous_token_hash ON sessions(previous_token_hash);
+
+-- Access tokens revoked before they expire, by their `jti` claim
+CREATE TABLE revoked_tokens (
+    jti UUID PRIMARY KEY,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL   -- The token's own expiry; the row is removed after it
+);
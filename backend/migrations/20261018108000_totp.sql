@@ -0,0 +1,23 @@
+-- Migration script for TOTP two-factor authentication
+
+-- One authenticator per user; the row exists unconfirmed until a first code is checked
+CREATE TABLE user_totp (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    secret BYTEA NOT NULL,                              -- RFC 6238 shared secret
+    enabled_at TIMESTAMP WITH TIME ZONE,                -- NULL while enrolment awaits confirmation
+    last_used_step BIGINT,                              -- Time step of the last accepted code, so codes are single-use
+    failed_attempts INTEGER NOT NULL DEFAULT 0,         -- Wrong codes since the last correct one
+    last_attempt_at TIMESTAMP WITH TIME ZONE,           -- Start of the current backoff window
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+-- Single-use recovery codes, hashed like passwords
+CREATE TABLE recovery_codes (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(255) NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+
+CREATE INDEX idx_recovery_codes_user_id ON recovery_codes(user_id);
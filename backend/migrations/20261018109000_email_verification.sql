@@ -0,0 +1,7 @@
+-- Migration script for email verification
+
+-- NULL until the user follows the link sent to their address
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMP WITH TIME ZONE;
+
+-- Accounts created before verification existed are trusted as they are
+UPDATE users SET email_verified_at = created_at;
@@ -0,0 +1,17 @@
+-- Migration script for personal access tokens
+
+-- Named, revocable API tokens for scripts and CLI clients; only a SHA-256 hash of each is kept
+CREATE TABLE personal_access_tokens (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,                         -- Label chosen by the user
+    token_hash BYTEA NOT NULL UNIQUE,                   -- Hash of the full token
+    token_prefix VARCHAR(32) NOT NULL,                  -- Start of the token, to tell tokens apart in lists
+    scopes TEXT[] NOT NULL,                             -- e.g. files:send, files:receive, users:read
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    expires_at TIMESTAMP WITH TIME ZONE,                -- NULL for tokens that do not expire
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_personal_access_tokens_user_id ON personal_access_tokens(user_id);
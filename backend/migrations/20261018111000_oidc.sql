@@ -0,0 +1,29 @@
+-- Migration script for OpenID Connect sign-in
+
+-- Accounts created through single sign-on have no password
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+
+-- External identities users sign in with, at most one per issuer and user
+CREATE TABLE user_identities (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    provider VARCHAR(50) NOT NULL,                      -- Name of the configured provider
+    issuer TEXT NOT NULL,                               -- `iss` of the provider's ID tokens
+    subject VARCHAR(255) NOT NULL,                      -- `sub` of the user at that issuer
+    email VARCHAR(255),                                 -- Email the provider reported when linking
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    last_login_at TIMESTAMP WITH TIME ZONE,
+    UNIQUE (issuer, subject),
+    UNIQUE (user_id, issuer)
+);
+
+-- Authorization requests waiting for the provider to redirect back; each is used once
+CREATE TABLE oidc_auth_requests (
+    state_hash BYTEA PRIMARY KEY,                       -- Hash of the `state` parameter
+    provider VARCHAR(50) NOT NULL,
+    nonce VARCHAR(64) NOT NULL,                         -- Expected in the ID token
+    code_verifier VARCHAR(128) NOT NULL,                -- PKCE verifier sent with the code
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE, -- Set when linking to a signed-in account
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,30 @@
+-- Migration script for WebAuthn passkeys
+
+-- Public key credentials users sign in with
+CREATE TABLE webauthn_credentials (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    credential_id BYTEA NOT NULL UNIQUE,                -- Raw credential ID chosen by the authenticator
+    public_key BYTEA NOT NULL,                          -- COSE_Key from the attested credential data
+    algorithm INTEGER NOT NULL,                         -- COSE algorithm: -7 ES256, -8 EdDSA, -257 RS256
+    sign_count BIGINT NOT NULL DEFAULT 0,               -- Last signature counter, to spot cloned authenticators
+    transports TEXT[] NOT NULL DEFAULT '{}',            -- Hints for the browser, e.g. internal, usb, hybrid
+    backup_eligible BOOLEAN NOT NULL DEFAULT FALSE,     -- Synced passkey that can be backed up
+    backed_up BOOLEAN NOT NULL DEFAULT FALSE,
+    name VARCHAR(100) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_webauthn_credentials_user_id ON webauthn_credentials(user_id);
+
+-- Challenges of ceremonies waiting for the authenticator's response; each is used once
+CREATE TABLE webauthn_challenges (
+    challenge_hash BYTEA PRIMARY KEY,                   -- Hash of the challenge sent to the browser
+    purpose VARCHAR(20) NOT NULL,                       -- 'signup', 'register' or 'login'
+    user_id UUID,                                       -- Account the credential is for; for a signup, the id it will get
+    name VARCHAR(100),                                  -- Name and email of the account a signup creates
+    email VARCHAR(255),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Migration script for roles and disabled accounts
+
+-- 'user' owns files only; 'auditor' can also see usage across accounts; 'admin' can act on it
+ALTER TABLE users ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'user'
+    CHECK (role IN ('user', 'auditor', 'admin'));
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMP WITH TIME ZONE;   -- When an admin disabled the account
@@ -0,0 +1,30 @@
+-- Migration script for the audit log
+
+-- Security-relevant events, each chained to the one before it by a SHA-256 hash
+CREATE TABLE audit_events (
+    id BIGSERIAL PRIMARY KEY,                           -- Order of the hash chain
+    user_id UUID,                                       -- Who acted, if known; kept after the account is deleted
+    action VARCHAR(64) NOT NULL,                        -- e.g. auth.login, file.uploaded, share.downloaded
+    succeeded BOOLEAN NOT NULL,
+    target_id UUID,                                     -- The file, share or account acted on
+    ip_address VARCHAR(64),
+    user_agent VARCHAR(512),
+    details JSONB NOT NULL DEFAULT '{}',
+    occurred_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    prev_hash BYTEA NOT NULL,                           -- Hash of the previous event; zeroes for the first
+    hash BYTEA NOT NULL UNIQUE                          -- SHA-256 over prev_hash and this event's fields
+);
+
+CREATE INDEX idx_audit_events_user_id ON audit_events(user_id, id);
+CREATE INDEX idx_audit_events_action ON audit_events(action, id);
+
+-- Events are only ever appended; changing or removing one has to bypass this trigger first
+CREATE FUNCTION audit_events_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_events
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
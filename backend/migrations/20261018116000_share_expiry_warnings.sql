@@ -0,0 +1,6 @@
+-- Migration script for share expiry warnings
+
+-- Set once the recipient has been warned, so only one instance sends the warning
+ALTER TABLE shared_links ADD COLUMN expiry_warned_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX shared_links_expiry_warning_idx ON shared_links (expiration_date) WHERE expiry_warned_at IS NULL;
@@ -0,0 +1,15 @@
+-- Migration script for organization invitations
+
+-- Owners invite accounts by email; they only become members once they accept
+CREATE TABLE organization_invitations (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    organization_id UUID NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,   -- Invitee
+    invited_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    role VARCHAR(16) NOT NULL DEFAULT 'member' CHECK (role IN ('owner', 'member')),
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    UNIQUE (organization_id, user_id)                   -- Inviting again renews the invitation
+);
+
+CREATE INDEX idx_organization_invitations_user_id ON organization_invitations(user_id);
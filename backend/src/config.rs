@@ -1,25 +1,303 @@
+use std::net::IpAddr;
+
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Filesystem {
+        root: String,
+    },
+    S3 {
+        bucket: String,
+        region: String,
+        endpoint: Option<String>,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SmtpTls {
+    None,
+    StartTls,
+    Tls,
+}
+
+#[derive(Debug, Clone)]
+pub enum MailConfig {
+    Smtp {
+        host: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+        tls: SmtpTls,
+    },
+    File {
+        root: String,
+    },
+    Stdout,
+}
+
+// An OpenID Connect provider users can sign in with, configured as OIDC_<NAME>_*
+#[derive(Debug, Clone)]
+pub struct OidcProviderConfig {
+    pub name: String,
+    pub display_name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
+}
+
+// A proxy whose X-Forwarded-For is believed, as a single address or a CIDR range
+#[derive(Debug, Clone, Copy)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub database_url: String,
-    pub jwt_secret: String,
+    // Legacy HS256 secret, only kept to verify tokens issued before the switch to JWT_KEYS_DIR
+    pub jwt_secret: Option<String>,
+    pub jwt_keys_dir: String,
+    pub jwt_signing_kid: Option<String>,
+    // Lets an empty JWT_KEYS_DIR get a key of its own, for local development only
+    pub jwt_generate_key: bool,
     pub jwt_maxage: i64,
+    pub refresh_token_maxage_days: i64,
     pub port: u16,
     pub client_url: String,
+    pub public_url: String,
+    pub max_upload_bytes: usize,
+    pub tus_upload_expiry_hours: i64,
+    // How long before a share expires its recipient is warned
+    pub expiry_warning_hours: i64,
+    pub share_password_max_attempts: i32,
+    pub password_backoff_seconds: i64,
+    pub require_email_verification: bool,
+    pub mail_from: String,
+    pub mail: MailConfig,
+    pub storage: StorageConfig,
+    pub oidc_providers: Vec<OidcProviderConfig>,
+    pub key_wrap_secret: Option<String>,
+    pub webauthn_rp_id: String,
+    pub webauthn_rp_name: String,
+    pub webauthn_origins: Vec<String>,
+    pub trusted_proxies: Vec<TrustedProxy>,
 }
 
 impl Config {
     pub fn init() -> Config {
         let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let jwt_secret = std::env::var("JWT_SECRET_KEY").expect("JWT_SECRET_KEY must be set");
+        let jwt_secret = std::env::var("JWT_SECRET_KEY").ok().filter(|secret| !secret.is_empty());
+        let jwt_keys_dir = std::env::var("JWT_KEYS_DIR").unwrap_or_else(|_| "./keys".to_string());
+        let jwt_signing_kid = std::env::var("JWT_SIGNING_KID").ok();
+        let jwt_generate_key = std::env::var("JWT_GENERATE_KEY")
+            .ok()
+            .and_then(|value| value.parse::<bool>().ok())
+            .unwrap_or(false);
         let jwt_maxage = std::env::var("JWT_MAXAGE").expect("JWT_MAXAGE must be set");
+        let refresh_token_maxage_days = std::env::var("REFRESH_TOKEN_MAXAGE_DAYS")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(30);
         let client_url = std::env::var("CLIENT_URL").expect("CLIENT_URL must be set");
+        let max_upload_mb = std::env::var("MAX_UPLOAD_SIZE_MB")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(2048);
+        let tus_upload_expiry_hours = std::env::var("TUS_UPLOAD_EXPIRY_HOURS")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(24);
+        let expiry_warning_hours = std::env::var("EXPIRY_WARNING_HOURS")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(24);
+        let share_password_max_attempts = std::env::var("SHARE_PASSWORD_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse::<i32>().ok())
+            .unwrap_or(5);
+        let password_backoff_seconds = std::env::var("PASSWORD_BACKOFF_SECONDS")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(2);
+        let require_email_verification = std::env::var("REQUIRE_EMAIL_VERIFICATION")
+            .ok()
+            .and_then(|value| value.parse::<bool>().ok())
+            .unwrap_or(true);
+        let mail_from = std::env::var("MAIL_FROM")
+            .unwrap_or_else(|_| "Aerofy <no-reply@localhost>".to_string());
+        let public_url = std::env::var("PUBLIC_URL")
+            .unwrap_or_else(|_| "http://localhost:8080".to_string());
+        let oidc_providers = OidcProviderConfig::init_all();
+        let key_wrap_secret = std::env::var("KEY_WRAP_SECRET").ok();
+
+        // Passkeys are scoped to a domain: by default the frontend's, which is also the origin they are used from
+        let webauthn_rp_id = std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| {
+            url::Url::parse(&client_url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .expect("CLIENT_URL must be a URL with a host, or set WEBAUTHN_RP_ID")
+        });
+        let webauthn_rp_name = std::env::var("WEBAUTHN_RP_NAME")
+            .unwrap_or_else(|_| "Aerofy".to_string());
+        let webauthn_origins = std::env::var("WEBAUTHN_ORIGINS")
+            .unwrap_or_else(|_| client_url.clone())
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        // Without any, X-Forwarded-For is ignored and the peer address is the client's
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| TrustedProxy::parse(proxy)
+                .unwrap_or_else(|| panic!("TRUSTED_PROXIES must list addresses or CIDR ranges, got {}", proxy)))
+            .collect();
+
+        if !oidc_providers.is_empty() && key_wrap_secret.is_none() {
+            panic!("KEY_WRAP_SECRET must be set when OIDC providers are configured");
+        }
         
         Config {
             database_url,
             jwt_secret,
+            jwt_keys_dir,
+            jwt_signing_kid,
+            jwt_generate_key,
             jwt_maxage: jwt_maxage.parse::<i64>().unwrap(),
+            refresh_token_maxage_days,
             port: 8080,
             client_url,
+            public_url: public_url.trim_end_matches('/').to_string(),
+            max_upload_bytes: max_upload_mb * 1024 * 1024,
+            tus_upload_expiry_hours,
+            expiry_warning_hours,
+            share_password_max_attempts,
+            password_backoff_seconds,
+            require_email_verification,
+            mail_from,
+            mail: MailConfig::init(),
+            storage: StorageConfig::init(),
+            oidc_providers,
+            key_wrap_secret,
+            webauthn_rp_id,
+            webauthn_rp_name,
+            webauthn_origins,
+            trusted_proxies,
         }
     }
-}
\ No newline at end of file
+}
+
+impl TrustedProxy {
+    fn parse(value: &str) -> Option<TrustedProxy> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len.parse::<u32>().ok()?)),
+            None => (value, None),
+        };
+
+        let network = address.parse::<IpAddr>().ok()?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+
+        (prefix_len <= max_len).then_some(TrustedProxy { network, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl StorageConfig {
+    fn init() -> StorageConfig {
+        let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "fs".to_string());
+
+        match backend.as_str() {
+            "s3" => StorageConfig::S3 {
+                bucket: std::env::var("S3_BUCKET").expect("S3_BUCKET must be set"),
+                region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                endpoint: std::env::var("S3_ENDPOINT").ok(),
+                access_key: std::env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY must be set"),
+                secret_key: std::env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY must be set"),
+            },
+            "fs" => StorageConfig::Filesystem {
+                root: std::env::var("STORAGE_PATH").unwrap_or_else(|_| "./storage".to_string()),
+            },
+            other => panic!("STORAGE_BACKEND must be either fs or s3, got {}", other),
+        }
+    }
+}
+
+impl OidcProviderConfig {
+    // OIDC_PROVIDERS lists the provider names, e.g. "corp" reads OIDC_CORP_ISSUER and so on
+    fn init_all() -> Vec<OidcProviderConfig> {
+        std::env::var("OIDC_PROVIDERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(OidcProviderConfig::init)
+            .collect()
+    }
+
+    fn init(name: &str) -> OidcProviderConfig {
+        let prefix = format!("OIDC_{}", name.to_uppercase().replace('-', "_"));
+        let var = |suffix: &str| std::env::var(format!("{}_{}", prefix, suffix));
+
+        OidcProviderConfig {
+            name: name.to_lowercase(),
+            display_name: var("NAME").unwrap_or_else(|_| name.to_string()),
+            issuer: var("ISSUER").unwrap_or_else(|_| panic!("{}_ISSUER must be set", prefix)),
+            client_id: var("CLIENT_ID").unwrap_or_else(|_| panic!("{}_CLIENT_ID must be set", prefix)),
+            client_secret: var("CLIENT_SECRET").ok(),
+            scopes: var("SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+        }
+    }
+}
+
+impl MailConfig {
+    fn init() -> MailConfig {
+        let transport = std::env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "stdout".to_string());
+
+        match transport.as_str() {
+            "smtp" => {
+                let tls = match std::env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string()).as_str() {
+                    "none" => SmtpTls::None,
+                    "starttls" => SmtpTls::StartTls,
+                    "tls" => SmtpTls::Tls,
+                    other => panic!("SMTP_TLS must be none, starttls or tls, got {}", other),
+                };
+
+                MailConfig::Smtp {
+                    host: std::env::var("SMTP_HOST").expect("SMTP_HOST must be set"),
+                    port: std::env::var("SMTP_PORT")
+                        .ok()
+                        .and_then(|value| value.parse::<u16>().ok())
+                        .unwrap_or(587),
+                    username: std::env::var("SMTP_USERNAME").ok(),
+                    password: std::env::var("SMTP_PASSWORD").ok(),
+                    tls,
+                }
+            }
+            "file" => MailConfig::File {
+                root: std::env::var("MAIL_PATH").unwrap_or_else(|_| "./mail".to_string()),
+            },
+            "stdout" => MailConfig::Stdout,
+            other => panic!("MAIL_TRANSPORT must be smtp, file or stdout, got {}", other),
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SubsecRound, Utc};
 use sqlx::{Pool, Postgres};
 use uuid::Uuid;
 
-use crate::models::{File, ReceiveFileDetails, SentFileDetails, SharedLink, User};
+use crate::{models::{AuditEvent, AuditFilter, CeremonyPurpose, File, FileKey, Group, GroupMember, LegacyWrappedKey, LinkShare, NewAuditEvent, NewFile, NewFileKey, NewLinkShare, OidcAuthRequest, OrgRole, Organization, OrganizationInvitation, OrganizationMember, OwnedShare, PasswordAttempts, PersonalAccessToken, ReceiveFileDetails, RecoveryCode, Role, SentFileDetails, Session, ShareNotice, ShareOverview, SharedLink, StorageStats, TokenGrant, TusUpload, TusUploadPart, User, UserIdentity, UserOverview, UserTotp, WebauthnChallenge, WebauthnCredential}, utils::{audit::{chain_hash, GENESIS_HASH}, cipher::KeyWrap, keys::PasswordWrappedKey, webauthn::VerifiedCredential}};
 
 #[derive(Debug, Clone)]
 pub struct DBClient {
@@ -32,6 +32,9 @@ pub trait UserExt {
         password: T,
     ) -> Result<User, sqlx::Error>;
 
+    // An account without a password, such as one provisioned through single sign-on
+    async fn save_passwordless_user(&self, user_id: Uuid, name: &str, email: &str, email_verified: bool) -> Result<User, sqlx::Error>;
+
     async fn update_user_name<T: Into<String> + Send>(
         &self,
         user_id: Uuid,
@@ -42,25 +45,319 @@ pub trait UserExt {
         &self,
         user_id: Uuid,
         password: String,
+        wrapped_private_key: Option<PasswordWrappedKey>,
     ) -> Result<User, sqlx::Error>;
 
-    async fn save_user_keys(&self, user_id: Uuid, public_key: String, private_key: String) -> Result<(), sqlx::Error>;
+    async fn save_generated_keys(&self, user_id: Uuid, public_key: String, wrapped_private_key: PasswordWrappedKey) -> Result<(), sqlx::Error>;
+
+    // Registers a client-held key pair; any server-held private key is discarded
+    async fn save_user_keys(&self, user_id: Uuid, public_key: String) -> Result<(), sqlx::Error>;
+
+    async fn save_wrapped_private_key(&self, user_id: Uuid, wrapped_private_key: PasswordWrappedKey) -> Result<(), sqlx::Error>;
+
+    // False if the address was already verified
+    async fn verify_user_email(&self, user_id: Uuid) -> Result<bool, sqlx::Error>;
 
     async fn search_by_email(&self, user_id: Uuid, query: String)
         -> Result<Vec<User>, sqlx::Error>;
 
-    async fn save_encrypted_file(
+    async fn create_session(
         &self,
         user_id: Uuid,
-        file_name: String,
-        file_size: i64,
-        recipient_user_id: Uuid,
+        refresh_token_hash: Vec<u8>,
+        user_agent: Option<String>,
+        ip_address: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Session, sqlx::Error>;
+
+    // Swaps the refresh token for a new one; None if it is not the session's current token
+    async fn rotate_refresh_token(
+        &self,
+        refresh_token_hash: Vec<u8>,
+        new_refresh_token_hash: Vec<u8>,
+        user_agent: Option<String>,
+        ip_address: Option<String>,
+    ) -> Result<Option<Session>, sqlx::Error>;
+
+    // Revokes the session a replaced refresh token belonged to, as it is being replayed
+    async fn revoke_reused_session(&self, refresh_token_hash: Vec<u8>) -> Result<bool, sqlx::Error>;
+
+    // Revokes the session a refresh token belongs to, returning its user
+    async fn revoke_refresh_token(&self, refresh_token_hash: Vec<u8>) -> Result<Option<Uuid>, sqlx::Error>;
+
+    async fn get_active_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, sqlx::Error>;
+
+    async fn revoke_session(&self, session_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn revoke_user_sessions(&self, user_id: Uuid) -> Result<u64, sqlx::Error>;
+
+    async fn revoke_token(&self, jti: Uuid, expires_at: DateTime<Utc>) -> Result<(), sqlx::Error>;
+
+    // False once the token's jti or its session has been revoked
+    async fn is_token_active(&self, jti: Uuid, session_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    // Removes expired and revoked sessions, revocations of tokens that have expired anyway and
+    // abandoned single sign-on attempts
+    async fn delete_expired_sessions(&self) -> Result<u64, sqlx::Error>;
+
+    async fn create_personal_access_token(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token_hash: Vec<u8>,
+        token_prefix: &str,
+        scopes: &[String],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<PersonalAccessToken, sqlx::Error>;
+
+    // Tokens that are neither revoked nor expired, newest first
+    async fn get_personal_access_tokens(&self, user_id: Uuid) -> Result<Vec<PersonalAccessToken>, sqlx::Error>;
+
+    async fn revoke_personal_access_token(&self, token_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    // Looks up a usable token by hash and records that it was used
+    async fn use_personal_access_token(&self, token_hash: &[u8]) -> Result<Option<TokenGrant>, sqlx::Error>;
+
+    async fn save_oidc_auth_request(
+        &self,
+        state_hash: Vec<u8>,
+        provider: &str,
+        nonce: &str,
+        code_verifier: &str,
+        user_id: Option<Uuid>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+
+    // Removes and returns an unexpired authorization request, so each state is used once
+    async fn take_oidc_auth_request(&self, state_hash: Vec<u8>) -> Result<Option<OidcAuthRequest>, sqlx::Error>;
+
+    // Looks up a linked identity and records the sign-in
+    async fn record_identity_login(&self, issuer: &str, subject: &str) -> Result<Option<UserIdentity>, sqlx::Error>;
+
+    async fn link_user_identity(
+        &self,
+        user_id: Uuid,
+        provider: &str,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> Result<UserIdentity, sqlx::Error>;
+
+    async fn get_user_identities(&self, user_id: Uuid) -> Result<Vec<UserIdentity>, sqlx::Error>;
+
+    async fn unlink_user_identity(&self, identity_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn save_webauthn_challenge(
+        &self,
+        challenge_hash: Vec<u8>,
+        purpose: CeremonyPurpose,
+        user_id: Option<Uuid>,
+        name: Option<&str>,
+        email: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+
+    // Removes and returns an unexpired challenge of the given ceremony, so each is answered once
+    async fn take_webauthn_challenge(&self, challenge_hash: Vec<u8>, purpose: CeremonyPurpose) -> Result<Option<WebauthnChallenge>, sqlx::Error>;
+
+    async fn save_webauthn_credential(
+        &self,
+        user_id: Uuid,
+        credential: &VerifiedCredential,
+        transports: &[String],
+        name: &str,
+    ) -> Result<WebauthnCredential, sqlx::Error>;
+
+    // Creates a passkey-only account with its key pair and first passkey, all or nothing
+    #[allow(clippy::too_many_arguments)]
+    async fn save_passkey_user(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        email: &str,
+        public_key: String,
+        wrapped_private_key: PasswordWrappedKey,
+        credential: &VerifiedCredential,
+        transports: &[String],
+        passkey_name: &str,
+    ) -> Result<User, sqlx::Error>;
+
+    async fn get_webauthn_credential(&self, credential_id: &[u8]) -> Result<Option<WebauthnCredential>, sqlx::Error>;
+
+    async fn get_webauthn_credentials(&self, user_id: Uuid) -> Result<Vec<WebauthnCredential>, sqlx::Error>;
+
+    // Stores the new signature counter, unless another sign-in with the credential got there first
+    async fn record_webauthn_credential_use(
+        &self,
+        credential_id: Uuid,
+        previous_sign_count: i64,
+        sign_count: i64,
+        backed_up: bool,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn delete_webauthn_credential(&self, credential_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    // Accounts whose name or email contains `search`, newest first, with the total count
+    async fn get_user_overviews(
+        &self,
+        search: Option<&str>,
+        page: u32,
+        limit: usize,
+    ) -> Result<(Vec<UserOverview>, i64), sqlx::Error>;
+
+    async fn update_user_role(&self, user_id: Uuid, role: Role) -> Result<bool, sqlx::Error>;
+
+    async fn set_user_disabled(&self, user_id: Uuid, disabled: bool) -> Result<bool, sqlx::Error>;
+
+    // Shares of files the user sent, newest first, with the total count
+    async fn get_share_overviews(
+        &self,
+        sender_id: Uuid,
+        page: u32,
+        limit: usize,
+    ) -> Result<(Vec<ShareOverview>, i64), sqlx::Error>;
+
+    // Expires one open share, or every open share of files `sender_id` sent, dropping the keys
+    // they could be opened with; the cleanup job deletes them with their files
+    async fn force_expire_shares(&self, shared_id: Option<Uuid>, sender_id: Option<Uuid>) -> Result<u64, sqlx::Error>;
+
+    async fn get_storage_stats(&self) -> Result<StorageStats, sqlx::Error>;
+
+    // The organization the user belongs to, if any, with their role in it
+    async fn get_user_organization(&self, user_id: Uuid) -> Result<Option<Organization>, sqlx::Error>;
+
+    // Creates an organization owned by `user_id`; fails with a unique violation if they already belong to one
+    async fn create_organization(&self, user_id: Uuid, name: &str) -> Result<Organization, sqlx::Error>;
+
+    async fn delete_organization(&self, organization_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn update_organization_policy(
+        &self,
+        organization_id: Uuid,
+        allowed_domains: &[String],
+        max_expiry_days: Option<i32>,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn get_organization_members(&self, organization_id: Uuid) -> Result<Vec<OrganizationMember>, sqlx::Error>;
+
+    // Invites `user_id`, or renews their invitation with the new role and expiry
+    async fn save_organization_invitation(
+        &self,
+        organization_id: Uuid,
+        user_id: Uuid,
+        invited_by: Uuid,
+        role: OrgRole,
+        expires_at: DateTime<Utc>,
+    ) -> Result<OrganizationInvitation, sqlx::Error>;
+
+    async fn get_organization_invitation(&self, invitation_id: Uuid) -> Result<Option<OrganizationInvitation>, sqlx::Error>;
+
+    // Unexpired invitations an organization has sent
+    async fn get_organization_invitations(&self, organization_id: Uuid) -> Result<Vec<OrganizationInvitation>, sqlx::Error>;
+
+    // Unexpired invitations a user has received
+    async fn get_user_invitations(&self, user_id: Uuid) -> Result<Vec<OrganizationInvitation>, sqlx::Error>;
+
+    // Turns the invitation into a membership; None if it is not the user's or has expired.
+    // Fails with a unique violation if the user already belongs to an organization
+    async fn accept_organization_invitation(&self, invitation_id: Uuid, user_id: Uuid) -> Result<Option<Uuid>, sqlx::Error>;
+
+    async fn delete_organization_invitation(&self, invitation_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn update_organization_member_role(&self, organization_id: Uuid, user_id: Uuid, role: OrgRole) -> Result<bool, sqlx::Error>;
+
+    // Also removes the user from the organization's groups
+    async fn remove_organization_member(&self, organization_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn count_organization_owners(&self, organization_id: Uuid) -> Result<i64, sqlx::Error>;
+
+    async fn get_groups(&self, organization_id: Uuid) -> Result<Vec<Group>, sqlx::Error>;
+
+    async fn get_group(&self, organization_id: Uuid, group_id: Uuid) -> Result<Option<Group>, sqlx::Error>;
+
+    // Fails with a unique violation if the organization already has a group with that name
+    async fn create_group(&self, organization_id: Uuid, name: &str) -> Result<Group, sqlx::Error>;
+
+    async fn delete_group(&self, organization_id: Uuid, group_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn get_group_members(&self, group_id: Uuid) -> Result<Vec<GroupMember>, sqlx::Error>;
+
+    // The user must already be a member of the organization; fails with a unique violation if they are in the group
+    async fn add_group_member(&self, organization_id: Uuid, group_id: Uuid, user_id: Uuid) -> Result<(), sqlx::Error>;
+
+    async fn remove_group_member(&self, group_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    // The current members of the given groups, each once, in the order they joined
+    async fn get_group_recipients(&self, group_ids: &[Uuid]) -> Result<Vec<User>, sqlx::Error>;
+
+    // Chains the event onto the last one; writers take turns so the chain never forks
+    async fn append_audit_event(&self, event: NewAuditEvent) -> Result<(), sqlx::Error>;
+
+    // Matching events, newest first, with the total count
+    async fn get_audit_events(
+        &self,
+        filter: &AuditFilter,
+        page: u32,
+        limit: usize,
+    ) -> Result<(Vec<AuditEvent>, i64), sqlx::Error>;
+
+    // Events in chain order after `after_id`, for checking the chain in batches
+    async fn get_audit_chain(&self, after_id: i64, limit: i64) -> Result<Vec<AuditEvent>, sqlx::Error>;
+
+    // Delivered to every instance's listener once the surrounding transaction, if any, commits
+    async fn notify_event(&self, channel: &str, payload: &str) -> Result<(), sqlx::Error>;
+
+    // The recipient shares of a file, as its recipients are told about them
+    async fn get_file_share_notices(&self, file_id: Uuid) -> Result<Vec<ShareNotice>, sqlx::Error>;
+
+    async fn get_share_notice(&self, shared_id: Uuid) -> Result<Option<ShareNotice>, sqlx::Error>;
+
+    // Marks open recipient shares expiring within the next hours as warned and returns them,
+    // so that each warning is only sent once however many instances run the job
+    async fn take_expiring_share_notices(&self, within_hours: i64) -> Result<Vec<ShareNotice>, sqlx::Error>;
+
+    async fn get_user_totp(&self, user_id: Uuid) -> Result<Option<UserTotp>, sqlx::Error>;
+
+    // Starts or restarts enrolment; false if two-factor authentication is already enabled
+    async fn save_totp_secret(&self, user_id: Uuid, secret: Vec<u8>) -> Result<bool, sqlx::Error>;
+
+    // Confirms enrolment and stores the first set of recovery codes
+    async fn enable_totp(&self, user_id: Uuid, step: i64, recovery_code_hashes: Vec<String>) -> Result<bool, sqlx::Error>;
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<(), sqlx::Error>;
+
+    // Counts an attempt, unless another one was counted since `seen_attempts` was read
+    async fn claim_totp_attempt(&self, user_id: Uuid, seen_attempts: i32) -> Result<bool, sqlx::Error>;
+
+    // Clears the counter after a correct code and, for a TOTP code, burns its time step.
+    // False if the step was already used
+    async fn record_totp_success(&self, user_id: Uuid, step: Option<i64>) -> Result<bool, sqlx::Error>;
+
+    async fn get_unused_recovery_codes(&self, user_id: Uuid) -> Result<Vec<RecoveryCode>, sqlx::Error>;
+
+    // False if the code was already used
+    async fn use_recovery_code(&self, code_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    async fn replace_recovery_codes(&self, user_id: Uuid, recovery_code_hashes: Vec<String>) -> Result<(), sqlx::Error>;
+
+    // One shared link per recipient, each with the file key wrapped for that recipient,
+    // plus the link share if one was requested. Returns the new file's id
+    async fn save_encrypted_file(
+        &self,
+        file: NewFile,
+        file_keys: Vec<NewFileKey>,
+        link_share: Option<NewLinkShare>,
         password: String,
         expiration_date: DateTime<Utc>,
-        encrypted_aes_key: Vec<u8>,
-        encrypted_file: Vec<u8>,
-        iv: Vec<u8>,
-    ) -> Result<(), sqlx::Error>;
+        max_downloads: Option<i32>,
+    ) -> Result<Uuid, sqlx::Error>;
+
+    // Segments of files uploaded before file content moved to the blob store
+    async fn get_file_chunk(
+        &self,
+        file_id: Uuid,
+        chunk_index: i32,
+    ) -> Result<Option<Vec<u8>>, sqlx::Error>;
 
     async fn get_shared(
         &self,
@@ -73,6 +370,15 @@ pub trait UserExt {
         file_id: Uuid,
     ) -> Result<Option<File>, sqlx::Error>;
 
+    // Only unexpired link shares are returned
+    async fn get_link_share(&self, shared_id: Uuid) -> Result<Option<LinkShare>, sqlx::Error>;
+
+    async fn get_file_key(
+        &self,
+        file_id: Uuid,
+        recipient_user_id: Uuid,
+    ) -> Result<Option<FileKey>, sqlx::Error>;
+
     async fn get_sent_files(
         &self,
         user_id: Uuid,
@@ -80,10 +386,49 @@ pub trait UserExt {
         limit: usize
     ) -> Result<(Vec<SentFileDetails>, i64), sqlx::Error>;
 
+    // Returns the storage keys of the deleted files so their blobs can be removed too
     async fn delete_expired_files(
         &self
+    ) -> Result<Vec<String>, sqlx::Error>;
+
+    // Only open shares of files owned by user_id
+    async fn get_owned_share(&self, shared_id: Uuid, user_id: Uuid) -> Result<Option<OwnedShare>, sqlx::Error>;
+
+    // Marks the share revoked and drops the keys it could be opened with
+    async fn revoke_share(&self, shared_id: Uuid) -> Result<(), sqlx::Error>;
+
+    async fn update_share_expiration(&self, shared_id: Uuid, expiration_date: DateTime<Utc>) -> Result<(), sqlx::Error>;
+
+    // Password links pass their file key re-wrapped under the new password
+    async fn update_share_password(
+        &self,
+        shared_id: Uuid,
+        password: String,
+        wrapped_key: Option<PasswordWrappedKey>,
     ) -> Result<(), sqlx::Error>;
 
+    async fn get_user_password_attempts(&self, user_id: Uuid) -> Result<Option<PasswordAttempts>, sqlx::Error>;
+
+    // Counts an attempt, unless another one was counted since `seen_attempts` was read
+    async fn claim_share_attempt(&self, shared_id: Uuid, seen_attempts: i32) -> Result<bool, sqlx::Error>;
+
+    async fn claim_user_attempt(&self, user_id: Uuid, seen_attempts: i32) -> Result<bool, sqlx::Error>;
+
+    // Clears the counters after a correct password
+    async fn reset_password_attempts(&self, shared_id: Uuid, user_id: Option<Uuid>) -> Result<(), sqlx::Error>;
+
+    async fn lock_share(&self, shared_id: Uuid) -> Result<(), sqlx::Error>;
+
+    async fn unlock_share(&self, shared_id: Uuid) -> Result<(), sqlx::Error>;
+
+    // Takes one download off the share's limit. None once the limit is used up,
+    // Some(true) when this was the last download allowed
+    async fn record_download(&self, shared_id: Uuid) -> Result<Option<bool>, sqlx::Error>;
+
+    // Deletes the share and its wrapped key, and the file once nobody else holds a share of it.
+    // Returns the storage key of a deleted file so its blob can be removed too
+    async fn delete_share(&self, shared_id: Uuid) -> Result<Vec<String>, sqlx::Error>;
+
     async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error>;
 
     // Add these new methods
@@ -94,8 +439,58 @@ pub trait UserExt {
     // For pending files only
     async fn get_pending_files(&self, user_id: uuid::Uuid, page: u32, limit: u32) -> Result<(Vec<ReceiveFileDetails>, i64), sqlx::Error>;
 
-    // Add a new method to mark a file as retrieved
-    async fn mark_file_as_retrieved(&self, shared_id: Uuid) -> Result<(), sqlx::Error>;
+    // Moves a pending share to accepted; false if it was no longer pending
+    async fn accept_share(&self, shared_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    // Moves a pending share to declined and drops the recipient's wrapped key
+    async fn decline_share(&self, shared_id: Uuid) -> Result<bool, sqlx::Error>;
+
+    // Files whose AES key is still wrapped with PKCS#1 v1.5, with the recipient's private key,
+    // in primary key order starting after `after`
+    async fn get_legacy_wrapped_keys(&self, after: (Uuid, Uuid), limit: i64) -> Result<Vec<LegacyWrappedKey>, sqlx::Error>;
+
+    async fn get_user_legacy_wrapped_keys(&self, user_id: Uuid) -> Result<Vec<LegacyWrappedKey>, sqlx::Error>;
+
+    async fn update_file_key_wrap(
+        &self,
+        file_id: Uuid,
+        recipient_user_id: Uuid,
+        encrypted_aes_key: Vec<u8>,
+        key_wrap: KeyWrap,
+    ) -> Result<(), sqlx::Error>;
+
+    async fn get_legacy_file_content(&self, file_id: Uuid) -> Result<Option<Vec<u8>>, sqlx::Error>;
+
+    // Files whose content still lives in encrypted_file / file_chunks
+    async fn get_unmigrated_files(&self, limit: i64) -> Result<Vec<File>, sqlx::Error>;
+
+    // Points a file at its blob and drops the copy kept in Postgres
+    async fn set_file_storage_key(&self, file_id: Uuid, storage_key: String) -> Result<(), sqlx::Error>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_tus_upload(
+        &self,
+        user_id: Uuid,
+        file_name: String,
+        upload_length: i64,
+        recipient_user_ids: Vec<Uuid>,
+        password: String,
+        expiration_date: DateTime<Utc>,
+        max_downloads: Option<i32>,
+        staging_key: Vec<u8>,
+    ) -> Result<TusUpload, sqlx::Error>;
+
+    async fn get_tus_upload(&self, upload_id: Uuid, user_id: Uuid) -> Result<Option<TusUpload>, sqlx::Error>;
+
+    // Records a staged part and advances the offset, unless another request already moved it
+    async fn add_tus_upload_part(&self, upload_id: Uuid, part: TusUploadPart) -> Result<Option<i64>, sqlx::Error>;
+
+    async fn get_tus_upload_parts(&self, upload_id: Uuid) -> Result<Vec<TusUploadPart>, sqlx::Error>;
+
+    // Returns the storage keys of the staged parts so their blobs can be removed too
+    async fn delete_tus_upload(&self, upload_id: Uuid) -> Result<Vec<String>, sqlx::Error>;
+
+    async fn delete_stale_tus_uploads(&self, max_age_hours: i64) -> Result<Vec<String>, sqlx::Error>;
 }
 
 #[async_trait]
@@ -111,19 +506,19 @@ impl UserExt for DBClient {
         if let Some(user_id) = user_id {
             user = sqlx::query_as!(
                 User,
-                r#"SELECT id, name, email, password, public_key, private_key, created_at, updated_at FROM users WHERE id = $1"#,
+                r#"SELECT id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at FROM users WHERE id = $1"#,
                 user_id
             ).fetch_optional(&self.pool).await?;
         } else if let Some(name) = name {
             user = sqlx::query_as!(
                 User,
-                r#"SELECT id, name, email, password, public_key, private_key, created_at, updated_at FROM users WHERE name = $1"#,
+                r#"SELECT id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at FROM users WHERE name = $1"#,
                 name
             ).fetch_optional(&self.pool).await?;
         } else if let Some(email) = email {
             user = sqlx::query_as!(
                 User,
-                r#"SELECT id, name, email, password, public_key, private_key, created_at, updated_at FROM users WHERE email = $1"#,
+                r#"SELECT id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at FROM users WHERE email = $1"#,
                 email
             ).fetch_optional(&self.pool).await?;
         }
@@ -142,7 +537,7 @@ impl UserExt for DBClient {
             r#"
             INSERT INTO users (name, email, password) 
             VALUES ($1, $2, $3) 
-            RETURNING id, name, email, password, public_key, private_key, created_at, updated_at
+            RETURNING id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
             "#,
             name.into(),
             email.into(),
@@ -153,6 +548,25 @@ impl UserExt for DBClient {
         Ok(user)
     }
 
+    async fn save_passwordless_user(&self, user_id: Uuid, name: &str, email: &str, email_verified: bool) -> Result<User, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            INSERT INTO users (id, name, email, email_verified_at)
+            VALUES ($1, $2, $3, CASE WHEN $4 THEN NOW() END)
+            RETURNING id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
+            "#,
+            user_id,
+            name,
+            email,
+            email_verified
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
     async fn update_user_name<T: Into<String> + Send>(
         &self,
         user_id: Uuid,
@@ -164,7 +578,7 @@ impl UserExt for DBClient {
             UPDATE users
             SET name = $1, updated_at = Now()
             WHERE id = $2
-            RETURNING id, name, email, password, public_key, private_key, created_at, updated_at
+            RETURNING id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
             "#,
             new_name.into(),
             user_id
@@ -179,17 +593,30 @@ impl UserExt for DBClient {
         &self,
         user_id: Uuid,
         new_password: String,
+        wrapped_private_key: Option<PasswordWrappedKey>,
     ) -> Result<User, sqlx::Error> {
+        let (ciphertext, salt, nonce) = match wrapped_private_key {
+            Some(wrapped) => (Some(wrapped.ciphertext), Some(wrapped.salt), Some(wrapped.nonce)),
+            None => (None, None, None),
+        };
+
         let user = sqlx::query_as!(
             User,
             r#"
             UPDATE users
-            SET password = $1, updated_at = Now()
+            SET password = $1,
+                encrypted_private_key = COALESCE($3, encrypted_private_key),
+                private_key_salt = COALESCE($4, private_key_salt),
+                private_key_nonce = COALESCE($5, private_key_nonce),
+                updated_at = Now()
             WHERE id = $2
-            RETURNING id, name, email, password, public_key, private_key, created_at, updated_at
+            RETURNING id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
             "#,
             new_password,
-            user_id
+            user_id,
+            ciphertext,
+            salt,
+            nonce
         )
         .fetch_one(&self.pool)
         .await?;
@@ -197,15 +624,79 @@ impl UserExt for DBClient {
         Ok(user)
     }
 
-    async fn save_user_keys(&self, user_id: Uuid, public_key: String, private_key: String) -> Result<(), sqlx::Error> {
+    async fn save_generated_keys(&self, user_id: Uuid, public_key: String, wrapped_private_key: PasswordWrappedKey) -> Result<(), sqlx::Error> {
         sqlx::query!(
             r#"
             UPDATE users
-            SET public_key = $1, private_key = $2, updated_at = Now()
-            WHERE id = $3
+            SET public_key = $1,
+                private_key = NULL,
+                encrypted_private_key = $2,
+                private_key_salt = $3,
+                private_key_nonce = $4,
+                updated_at = Now()
+            WHERE id = $5
+            "#,
+            public_key,
+            wrapped_private_key.ciphertext,
+            wrapped_private_key.salt,
+            wrapped_private_key.nonce,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn verify_user_email(&self, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email_verified_at = NOW(), updated_at = NOW()
+            WHERE id = $1
+            AND email_verified_at IS NULL
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_user_keys(&self, user_id: Uuid, public_key: String) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET public_key = $1,
+                private_key = NULL,
+                encrypted_private_key = NULL,
+                private_key_salt = NULL,
+                private_key_nonce = NULL,
+                updated_at = Now()
+            WHERE id = $2
             "#,
             public_key,
-            private_key,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn save_wrapped_private_key(&self, user_id: Uuid, wrapped_private_key: PasswordWrappedKey) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET private_key = NULL,
+                encrypted_private_key = $1,
+                private_key_salt = $2,
+                private_key_nonce = $3,
+                updated_at = Now()
+            WHERE id = $4
+            "#,
+            wrapped_private_key.ciphertext,
+            wrapped_private_key.salt,
+            wrapped_private_key.nonce,
             user_id
         )
         .execute(&self.pool)
@@ -221,7 +712,7 @@ impl UserExt for DBClient {
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT id, name, email, password, public_key, private_key, created_at, updated_at
+            SELECT id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
             FROM users
             WHERE email LIKE $1
             AND public_key IS NOT NULL
@@ -235,93 +726,1918 @@ impl UserExt for DBClient {
 
         Ok(user)
     }
-    async fn save_encrypted_file(
+
+    async fn create_session(
         &self,
         user_id: Uuid,
-        file_name: String,
-        file_size: i64,
-        recipient_user_ud: Uuid,
-        password: String,
-        expiration_date: DateTime<Utc>,
-        encrypted_aes_key: Vec<u8>,
-        encrypted_file: Vec<u8>,
-        iv: Vec<u8>,
-    ) -> Result<(), sqlx::Error> {
-        // Insert into the files table and get the file_id
-        let file_id: Uuid = sqlx::query_scalar!(
+        refresh_token_hash: Vec<u8>,
+        user_agent: Option<String>,
+        ip_address: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Session, sqlx::Error> {
+        let session = sqlx::query_as!(
+            Session,
             r#"
-            INSERT INTO files (user_id, file_name, file_size, encrypted_aes_key, encrypted_file, iv, created_at)
-            VALUES ($1, $2, $3, $4, $5, $6, NOW())
-            RETURNING id
+            INSERT INTO sessions (user_id, refresh_token_hash, user_agent, ip_address, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, user_id, user_agent, ip_address, created_at, last_used_at, expires_at
             "#,
             user_id,
-            file_name,
-            file_size,
-            encrypted_aes_key,
-            encrypted_file,
-            iv
+            refresh_token_hash,
+            user_agent,
+            ip_address,
+            expires_at
         )
         .fetch_one(&self.pool)
         .await?;
 
-        // Insert into the shared_links table using the returned file_id
-        sqlx::query!(
+        Ok(session)
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        refresh_token_hash: Vec<u8>,
+        new_refresh_token_hash: Vec<u8>,
+        user_agent: Option<String>,
+        ip_address: Option<String>,
+    ) -> Result<Option<Session>, sqlx::Error> {
+        let session = sqlx::query_as!(
+            Session,
             r#"
-            INSERT INTO shared_links (file_id, recipient_user_id, password, expiration_date, created_at)
-            VALUES ($1, $2, $3, $4, NOW())
+            UPDATE sessions
+            SET refresh_token_hash = $2,
+                previous_token_hash = $1,
+                user_agent = COALESCE($3, user_agent),
+                ip_address = COALESCE($4, ip_address),
+                last_used_at = NOW()
+            WHERE refresh_token_hash = $1
+            AND revoked_at IS NULL
+            AND expires_at > NOW()
+            RETURNING id, user_id, user_agent, ip_address, created_at, last_used_at, expires_at
             "#,
-            file_id,
-            recipient_user_ud,
-            password,
-            expiration_date
+            refresh_token_hash,
+            new_refresh_token_hash,
+            user_agent,
+            ip_address
         )
-        .execute(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(())
+        Ok(session)
     }
 
-    async fn get_shared(
-        &self,
-        shared_id: Uuid,
-        user_id: Uuid,
-    ) -> Result<Option<SharedLink>, sqlx::Error> {
-        let shared_link = sqlx::query_as!(
-            SharedLink,
+    async fn revoke_reused_session(&self, refresh_token_hash: Vec<u8>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
             r#"
-            SELECT id, file_id, recipient_user_id, password, expiration_date, created_at, is_retrieved
-            FROM shared_links
-            WHERE id = $1
-            AND recipient_user_id = $2
-            AND expiration_date > NOW()
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE previous_token_hash = $1
+            AND revoked_at IS NULL
             "#,
-            shared_id,
-            user_id,
+            refresh_token_hash
         )
-        .fetch_optional(&self.pool)
+        .execute(&self.pool)
         .await?;
 
-        Ok(shared_link)
+        Ok(result.rows_affected() > 0)
     }
 
-    async fn get_file(
-        &self,
-        file_id: Uuid,
-    ) -> Result<Option<File>, sqlx::Error> {
-        let file = sqlx::query_as!(
-            File,
+    async fn revoke_refresh_token(&self, refresh_token_hash: Vec<u8>) -> Result<Option<Uuid>, sqlx::Error> {
+        let user_id = sqlx::query_scalar!(
             r#"
-            SELECT id, user_id, file_name, file_size, encrypted_aes_key, encrypted_file, iv, created_at
-            FROM files
-            WHERE id = $1
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE refresh_token_hash = $1
+            AND revoked_at IS NULL
+            RETURNING user_id
             "#,
-            file_id
+            refresh_token_hash
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        Ok(file)
+        Ok(user_id)
     }
+
+    async fn get_active_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, sqlx::Error> {
+        let sessions = sqlx::query_as!(
+            Session,
+            r#"
+            SELECT id, user_id, user_agent, ip_address, created_at, last_used_at, expires_at
+            FROM sessions
+            WHERE user_id = $1
+            AND revoked_at IS NULL
+            AND expires_at > NOW()
+            ORDER BY last_used_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    async fn revoke_session(&self, session_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE id = $1
+            AND user_id = $2
+            AND revoked_at IS NULL
+            "#,
+            session_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn revoke_user_sessions(&self, user_id: Uuid) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1
+            AND revoked_at IS NULL
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_token(&self, jti: Uuid, expires_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO revoked_tokens (jti, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+            jti,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn is_token_active(&self, jti: Uuid, session_id: Uuid) -> Result<bool, sqlx::Error> {
+        let active = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM sessions
+                WHERE id = $2
+                AND revoked_at IS NULL
+                AND expires_at > NOW()
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM revoked_tokens
+                WHERE jti = $1
+            ) AS "active!"
+            "#,
+            jti,
+            session_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(active)
+    }
+
+    async fn delete_expired_sessions(&self) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM sessions
+            WHERE expires_at < NOW()
+            OR revoked_at IS NOT NULL
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM revoked_tokens
+            WHERE expires_at < NOW()
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM oidc_auth_requests
+            WHERE expires_at < NOW()
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM webauthn_challenges
+            WHERE expires_at < NOW()
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn create_personal_access_token(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        token_hash: Vec<u8>,
+        token_prefix: &str,
+        scopes: &[String],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<PersonalAccessToken, sqlx::Error> {
+        let token = sqlx::query_as!(
+            PersonalAccessToken,
+            r#"
+            INSERT INTO personal_access_tokens (user_id, name, token_hash, token_prefix, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, token_prefix, scopes, created_at, last_used_at, expires_at
+            "#,
+            user_id,
+            name,
+            token_hash,
+            token_prefix,
+            scopes,
+            expires_at
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn get_personal_access_tokens(&self, user_id: Uuid) -> Result<Vec<PersonalAccessToken>, sqlx::Error> {
+        let tokens = sqlx::query_as!(
+            PersonalAccessToken,
+            r#"
+            SELECT id, name, token_prefix, scopes, created_at, last_used_at, expires_at
+            FROM personal_access_tokens
+            WHERE user_id = $1
+            AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    async fn revoke_personal_access_token(&self, token_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE personal_access_tokens
+            SET revoked_at = NOW()
+            WHERE id = $1
+            AND user_id = $2
+            AND revoked_at IS NULL
+            "#,
+            token_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn use_personal_access_token(&self, token_hash: &[u8]) -> Result<Option<TokenGrant>, sqlx::Error> {
+        let grant = sqlx::query_as!(
+            TokenGrant,
+            r#"
+            UPDATE personal_access_tokens
+            SET last_used_at = NOW()
+            WHERE token_hash = $1
+            AND revoked_at IS NULL
+            AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING user_id, scopes
+            "#,
+            token_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(grant)
+    }
+
+    async fn save_oidc_auth_request(
+        &self,
+        state_hash: Vec<u8>,
+        provider: &str,
+        nonce: &str,
+        code_verifier: &str,
+        user_id: Option<Uuid>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO oidc_auth_requests (state_hash, provider, nonce, code_verifier, user_id, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            state_hash,
+            provider,
+            nonce,
+            code_verifier,
+            user_id,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn take_oidc_auth_request(&self, state_hash: Vec<u8>) -> Result<Option<OidcAuthRequest>, sqlx::Error> {
+        let request = sqlx::query_as!(
+            OidcAuthRequest,
+            r#"
+            DELETE FROM oidc_auth_requests
+            WHERE state_hash = $1
+            AND expires_at > NOW()
+            RETURNING provider, nonce, code_verifier, user_id
+            "#,
+            state_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(request)
+    }
+
+    async fn record_identity_login(&self, issuer: &str, subject: &str) -> Result<Option<UserIdentity>, sqlx::Error> {
+        let identity = sqlx::query_as!(
+            UserIdentity,
+            r#"
+            UPDATE user_identities
+            SET last_login_at = NOW()
+            WHERE issuer = $1
+            AND subject = $2
+            RETURNING id, user_id, provider, email, created_at, last_login_at
+            "#,
+            issuer,
+            subject
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(identity)
+    }
+
+    async fn link_user_identity(
+        &self,
+        user_id: Uuid,
+        provider: &str,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> Result<UserIdentity, sqlx::Error> {
+        let identity = sqlx::query_as!(
+            UserIdentity,
+            r#"
+            INSERT INTO user_identities (user_id, provider, issuer, subject, email, last_login_at)
+            VALUES ($1, $2, $3, $4, $5, NOW())
+            RETURNING id, user_id, provider, email, created_at, last_login_at
+            "#,
+            user_id,
+            provider,
+            issuer,
+            subject,
+            email
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(identity)
+    }
+
+    async fn get_user_identities(&self, user_id: Uuid) -> Result<Vec<UserIdentity>, sqlx::Error> {
+        let identities = sqlx::query_as!(
+            UserIdentity,
+            r#"
+            SELECT id, user_id, provider, email, created_at, last_login_at
+            FROM user_identities
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(identities)
+    }
+
+    async fn unlink_user_identity(&self, identity_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM user_identities
+            WHERE id = $1
+            AND user_id = $2
+            "#,
+            identity_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_webauthn_challenge(
+        &self,
+        challenge_hash: Vec<u8>,
+        purpose: CeremonyPurpose,
+        user_id: Option<Uuid>,
+        name: Option<&str>,
+        email: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO webauthn_challenges (challenge_hash, purpose, user_id, name, email, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            challenge_hash,
+            purpose.as_str(),
+            user_id,
+            name,
+            email,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn take_webauthn_challenge(&self, challenge_hash: Vec<u8>, purpose: CeremonyPurpose) -> Result<Option<WebauthnChallenge>, sqlx::Error> {
+        let challenge = sqlx::query_as!(
+            WebauthnChallenge,
+            r#"
+            DELETE FROM webauthn_challenges
+            WHERE challenge_hash = $1
+            AND purpose = $2
+            AND expires_at > NOW()
+            RETURNING user_id, name, email
+            "#,
+            challenge_hash,
+            purpose.as_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(challenge)
+    }
+
+    async fn save_webauthn_credential(
+        &self,
+        user_id: Uuid,
+        credential: &VerifiedCredential,
+        transports: &[String],
+        name: &str,
+    ) -> Result<WebauthnCredential, sqlx::Error> {
+        let credential = sqlx::query_as!(
+            WebauthnCredential,
+            r#"
+            INSERT INTO webauthn_credentials (user_id, credential_id, public_key, algorithm, sign_count, transports, backup_eligible, backed_up, name)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING id, user_id, credential_id, public_key, algorithm, sign_count, transports, backup_eligible, backed_up, name, created_at, last_used_at
+            "#,
+            user_id,
+            credential.credential_id,
+            credential.public_key,
+            credential.algorithm,
+            credential.sign_count,
+            transports,
+            credential.backup_eligible,
+            credential.backed_up,
+            name
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(credential)
+    }
+
+    async fn save_passkey_user(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        email: &str,
+        public_key: String,
+        wrapped_private_key: PasswordWrappedKey,
+        credential: &VerifiedCredential,
+        transports: &[String],
+        passkey_name: &str,
+    ) -> Result<User, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            INSERT INTO users (id, name, email, public_key, encrypted_private_key, private_key_salt, private_key_nonce)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
+            "#,
+            user_id,
+            name,
+            email,
+            public_key,
+            wrapped_private_key.ciphertext,
+            wrapped_private_key.salt,
+            wrapped_private_key.nonce
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO webauthn_credentials (user_id, credential_id, public_key, algorithm, sign_count, transports, backup_eligible, backed_up, name)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+            user_id,
+            credential.credential_id,
+            credential.public_key,
+            credential.algorithm,
+            credential.sign_count,
+            transports,
+            credential.backup_eligible,
+            credential.backed_up,
+            passkey_name
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(user)
+    }
+
+    async fn get_webauthn_credential(&self, credential_id: &[u8]) -> Result<Option<WebauthnCredential>, sqlx::Error> {
+        let credential = sqlx::query_as!(
+            WebauthnCredential,
+            r#"
+            SELECT id, user_id, credential_id, public_key, algorithm, sign_count, transports, backup_eligible, backed_up, name, created_at, last_used_at
+            FROM webauthn_credentials
+            WHERE credential_id = $1
+            "#,
+            credential_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(credential)
+    }
+
+    async fn get_webauthn_credentials(&self, user_id: Uuid) -> Result<Vec<WebauthnCredential>, sqlx::Error> {
+        let credentials = sqlx::query_as!(
+            WebauthnCredential,
+            r#"
+            SELECT id, user_id, credential_id, public_key, algorithm, sign_count, transports, backup_eligible, backed_up, name, created_at, last_used_at
+            FROM webauthn_credentials
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(credentials)
+    }
+
+    async fn record_webauthn_credential_use(
+        &self,
+        credential_id: Uuid,
+        previous_sign_count: i64,
+        sign_count: i64,
+        backed_up: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE webauthn_credentials
+            SET sign_count = $3, backed_up = $4, last_used_at = NOW()
+            WHERE id = $1
+            AND sign_count = $2
+            "#,
+            credential_id,
+            previous_sign_count,
+            sign_count,
+            backed_up
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_webauthn_credential(&self, credential_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM webauthn_credentials
+            WHERE id = $1
+            AND user_id = $2
+            "#,
+            credential_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_user_overviews(
+        &self,
+        search: Option<&str>,
+        page: u32,
+        limit: usize,
+    ) -> Result<(Vec<UserOverview>, i64), sqlx::Error> {
+        let offset = (page - 1) * limit as u32;
+
+        let users = sqlx::query_as!(
+            UserOverview,
+            r#"
+            SELECT
+                u.id,
+                u.name,
+                u.email,
+                u.role,
+                u.email_verified_at,
+                u.disabled_at,
+                u.created_at,
+                usage.file_count AS "file_count!",
+                usage.stored_bytes AS "stored_bytes!"
+            FROM users u
+            CROSS JOIN LATERAL (
+                SELECT COUNT(*) AS file_count, COALESCE(SUM(f.file_size), 0)::BIGINT AS stored_bytes
+                FROM files f
+                WHERE f.user_id = u.id
+            ) usage
+            WHERE $1::TEXT IS NULL
+            OR u.email ILIKE '%' || $1 || '%'
+            OR u.name ILIKE '%' || $1 || '%'
+            ORDER BY u.created_at DESC
+            LIMIT $2
+            OFFSET $3
+            "#,
+            search,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total_count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*)
+            FROM users u
+            WHERE $1::TEXT IS NULL
+            OR u.email ILIKE '%' || $1 || '%'
+            OR u.name ILIKE '%' || $1 || '%'
+            "#,
+            search
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((users, total_count.unwrap_or(0)))
+    }
+
+    async fn update_user_role(&self, user_id: Uuid, role: Role) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET role = $1, updated_at = NOW()
+            WHERE id = $2
+            "#,
+            role.as_str(),
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_user_disabled(&self, user_id: Uuid, disabled: bool) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET disabled_at = CASE WHEN $1 THEN COALESCE(disabled_at, NOW()) END, updated_at = NOW()
+            WHERE id = $2
+            "#,
+            disabled,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_share_overviews(
+        &self,
+        sender_id: Uuid,
+        page: u32,
+        limit: usize,
+    ) -> Result<(Vec<ShareOverview>, i64), sqlx::Error> {
+        let offset = (page - 1) * limit as u32;
+
+        let shares = sqlx::query_as!(
+            ShareOverview,
+            r#"
+            SELECT
+                sl.id,
+                f.id AS file_id,
+                f.file_size,
+                u.email AS "recipient_email?",
+                sl.link_key,
+                CASE
+                    WHEN sl.status IN ('pending', 'accepted', 'downloaded') AND sl.expiration_date < NOW() THEN 'expired'
+                    WHEN sl.status IN ('pending', 'accepted', 'downloaded') AND sl.locked_at IS NOT NULL THEN 'locked'
+                    ELSE sl.status
+                END AS "status!",
+                sl.expiration_date,
+                sl.created_at
+            FROM shared_links sl
+            JOIN files f ON sl.file_id = f.id
+            LEFT JOIN users u ON sl.recipient_user_id = u.id
+            WHERE f.user_id = $1
+            ORDER BY sl.created_at DESC
+            LIMIT $2
+            OFFSET $3
+            "#,
+            sender_id,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total_count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*)
+            FROM shared_links sl
+            JOIN files f ON sl.file_id = f.id
+            WHERE f.user_id = $1
+            "#,
+            sender_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((shares, total_count.unwrap_or(0)))
+    }
+
+    async fn force_expire_shares(&self, shared_id: Option<Uuid>, sender_id: Option<Uuid>) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let expired = sqlx::query!(
+            r#"
+            UPDATE shared_links sl
+            SET status = 'expired', expiration_date = NOW(), updated_at = NOW(),
+                key_check = NULL, encrypted_aes_key = NULL, key_salt = NULL, key_nonce = NULL
+            FROM files f
+            WHERE sl.file_id = f.id
+            AND sl.status IN ('pending', 'accepted', 'downloaded')
+            AND ($1::UUID IS NULL OR sl.id = $1)
+            AND ($2::UUID IS NULL OR f.user_id = $2)
+            RETURNING sl.file_id, sl.recipient_user_id
+            "#,
+            shared_id,
+            sender_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for share in &expired {
+            if let (Some(file_id), Some(recipient_user_id)) = (share.file_id, share.recipient_user_id) {
+                sqlx::query!(
+                    r#"
+                    DELETE FROM file_keys
+                    WHERE file_id = $1 AND recipient_user_id = $2
+                    "#,
+                    file_id,
+                    recipient_user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(expired.len() as u64)
+    }
+
+    async fn get_storage_stats(&self) -> Result<StorageStats, sqlx::Error> {
+        let stats = sqlx::query_as!(
+            StorageStats,
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM users) AS "users!",
+                (SELECT COUNT(*) FROM users WHERE disabled_at IS NOT NULL) AS "disabled_users!",
+                (SELECT COUNT(*) FROM files) AS "files!",
+                (SELECT COALESCE(SUM(file_size), 0)::BIGINT FROM files) AS "stored_bytes!",
+                (
+                    SELECT COUNT(*) FROM shared_links
+                    WHERE status IN ('pending', 'accepted', 'downloaded') AND expiration_date >= NOW()
+                ) AS "open_shares!",
+                (
+                    SELECT COUNT(*) FROM shared_links
+                    WHERE link_key IS NOT NULL AND status IN ('pending', 'accepted', 'downloaded') AND expiration_date >= NOW()
+                ) AS "link_shares!",
+                (SELECT COUNT(*) FROM tus_uploads) AS "pending_uploads!",
+                (SELECT COALESCE(SUM(upload_offset), 0)::BIGINT FROM tus_uploads) AS "pending_upload_bytes!"
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(stats)
+    }
+
+    async fn get_user_organization(&self, user_id: Uuid) -> Result<Option<Organization>, sqlx::Error> {
+        let organization = sqlx::query_as!(
+            Organization,
+            r#"
+            SELECT o.id, o.name, o.allowed_domains, o.max_expiry_days, m.role, o.created_at
+            FROM organizations o
+            JOIN organization_members m ON m.organization_id = o.id
+            WHERE m.user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(organization)
+    }
+
+    async fn create_organization(&self, user_id: Uuid, name: &str) -> Result<Organization, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let organization = sqlx::query_as!(
+            Organization,
+            r#"
+            INSERT INTO organizations (name)
+            VALUES ($1)
+            RETURNING id, name, allowed_domains, max_expiry_days, 'owner' AS "role!", created_at
+            "#,
+            name
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role)
+            VALUES ($1, $2, 'owner')
+            "#,
+            organization.id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(organization)
+    }
+
+    async fn delete_organization(&self, organization_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM organizations
+            WHERE id = $1
+            "#,
+            organization_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn update_organization_policy(
+        &self,
+        organization_id: Uuid,
+        allowed_domains: &[String],
+        max_expiry_days: Option<i32>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE organizations
+            SET allowed_domains = $1, max_expiry_days = $2, updated_at = NOW()
+            WHERE id = $3
+            "#,
+            allowed_domains,
+            max_expiry_days,
+            organization_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_organization_members(&self, organization_id: Uuid) -> Result<Vec<OrganizationMember>, sqlx::Error> {
+        let members = sqlx::query_as!(
+            OrganizationMember,
+            r#"
+            SELECT u.id AS user_id, u.name, u.email, m.role, m.created_at
+            FROM organization_members m
+            JOIN users u ON m.user_id = u.id
+            WHERE m.organization_id = $1
+            ORDER BY m.created_at
+            "#,
+            organization_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    async fn save_organization_invitation(
+        &self,
+        organization_id: Uuid,
+        user_id: Uuid,
+        invited_by: Uuid,
+        role: OrgRole,
+        expires_at: DateTime<Utc>,
+    ) -> Result<OrganizationInvitation, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let invitation_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO organization_invitations (organization_id, user_id, invited_by, role, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (organization_id, user_id) DO UPDATE
+            SET invited_by = EXCLUDED.invited_by,
+                role = EXCLUDED.role,
+                created_at = NOW(),
+                expires_at = EXCLUDED.expires_at
+            RETURNING id
+            "#,
+            organization_id,
+            user_id,
+            invited_by,
+            role.as_str(),
+            expires_at
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let invitation = sqlx::query_as!(
+            OrganizationInvitation,
+            r#"
+            SELECT i.id, i.organization_id, o.name AS organization_name, i.user_id, u.name, u.email, i.role,
+                   b.email AS "invited_by_email?", i.created_at, i.expires_at
+            FROM organization_invitations i
+            JOIN organizations o ON i.organization_id = o.id
+            JOIN users u ON i.user_id = u.id
+            LEFT JOIN users b ON i.invited_by = b.id
+            WHERE i.id = $1
+            "#,
+            invitation_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(invitation)
+    }
+
+    async fn get_organization_invitation(&self, invitation_id: Uuid) -> Result<Option<OrganizationInvitation>, sqlx::Error> {
+        let invitation = sqlx::query_as!(
+            OrganizationInvitation,
+            r#"
+            SELECT i.id, i.organization_id, o.name AS organization_name, i.user_id, u.name, u.email, i.role,
+                   b.email AS "invited_by_email?", i.created_at, i.expires_at
+            FROM organization_invitations i
+            JOIN organizations o ON i.organization_id = o.id
+            JOIN users u ON i.user_id = u.id
+            LEFT JOIN users b ON i.invited_by = b.id
+            WHERE i.id = $1
+            "#,
+            invitation_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(invitation)
+    }
+
+    async fn get_organization_invitations(&self, organization_id: Uuid) -> Result<Vec<OrganizationInvitation>, sqlx::Error> {
+        let invitations = sqlx::query_as!(
+            OrganizationInvitation,
+            r#"
+            SELECT i.id, i.organization_id, o.name AS organization_name, i.user_id, u.name, u.email, i.role,
+                   b.email AS "invited_by_email?", i.created_at, i.expires_at
+            FROM organization_invitations i
+            JOIN organizations o ON i.organization_id = o.id
+            JOIN users u ON i.user_id = u.id
+            LEFT JOIN users b ON i.invited_by = b.id
+            WHERE i.organization_id = $1
+            AND i.expires_at > NOW()
+            ORDER BY i.created_at
+            "#,
+            organization_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invitations)
+    }
+
+    async fn get_user_invitations(&self, user_id: Uuid) -> Result<Vec<OrganizationInvitation>, sqlx::Error> {
+        let invitations = sqlx::query_as!(
+            OrganizationInvitation,
+            r#"
+            SELECT i.id, i.organization_id, o.name AS organization_name, i.user_id, u.name, u.email, i.role,
+                   b.email AS "invited_by_email?", i.created_at, i.expires_at
+            FROM organization_invitations i
+            JOIN organizations o ON i.organization_id = o.id
+            JOIN users u ON i.user_id = u.id
+            LEFT JOIN users b ON i.invited_by = b.id
+            WHERE i.user_id = $1
+            AND i.expires_at > NOW()
+            ORDER BY i.created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invitations)
+    }
+
+    async fn accept_organization_invitation(&self, invitation_id: Uuid, user_id: Uuid) -> Result<Option<Uuid>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let invitation = sqlx::query!(
+            r#"
+            DELETE FROM organization_invitations
+            WHERE id = $1 AND user_id = $2
+            AND expires_at > NOW()
+            RETURNING organization_id, role
+            "#,
+            invitation_id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(invitation) = invitation else {
+            return Ok(None);
+        };
+
+        sqlx::query!(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role)
+            VALUES ($1, $2, $3)
+            "#,
+            invitation.organization_id,
+            user_id,
+            invitation.role
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(invitation.organization_id))
+    }
+
+    async fn delete_organization_invitation(&self, invitation_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM organization_invitations
+            WHERE id = $1
+            "#,
+            invitation_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn update_organization_member_role(&self, organization_id: Uuid, user_id: Uuid, role: OrgRole) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE organization_members
+            SET role = $1
+            WHERE organization_id = $2 AND user_id = $3
+            "#,
+            role.as_str(),
+            organization_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn remove_organization_member(&self, organization_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM organization_members
+            WHERE organization_id = $1 AND user_id = $2
+            "#,
+            organization_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn count_organization_owners(&self, organization_id: Uuid) -> Result<i64, sqlx::Error> {
+        let count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*)
+            FROM organization_members
+            WHERE organization_id = $1 AND role = 'owner'
+            "#,
+            organization_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn get_groups(&self, organization_id: Uuid) -> Result<Vec<Group>, sqlx::Error> {
+        let groups = sqlx::query_as!(
+            Group,
+            r#"
+            SELECT g.id, g.name, COUNT(gm.user_id) AS "member_count!", g.created_at
+            FROM groups g
+            LEFT JOIN group_members gm ON gm.group_id = g.id
+            WHERE g.organization_id = $1
+            GROUP BY g.id
+            ORDER BY g.name
+            "#,
+            organization_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(groups)
+    }
+
+    async fn get_group(&self, organization_id: Uuid, group_id: Uuid) -> Result<Option<Group>, sqlx::Error> {
+        let group = sqlx::query_as!(
+            Group,
+            r#"
+            SELECT g.id, g.name, COUNT(gm.user_id) AS "member_count!", g.created_at
+            FROM groups g
+            LEFT JOIN group_members gm ON gm.group_id = g.id
+            WHERE g.organization_id = $1 AND g.id = $2
+            GROUP BY g.id
+            "#,
+            organization_id,
+            group_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(group)
+    }
+
+    async fn create_group(&self, organization_id: Uuid, name: &str) -> Result<Group, sqlx::Error> {
+        let group = sqlx::query_as!(
+            Group,
+            r#"
+            INSERT INTO groups (organization_id, name)
+            VALUES ($1, $2)
+            RETURNING id, name, 0::BIGINT AS "member_count!", created_at
+            "#,
+            organization_id,
+            name
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(group)
+    }
+
+    async fn delete_group(&self, organization_id: Uuid, group_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM groups
+            WHERE organization_id = $1 AND id = $2
+            "#,
+            organization_id,
+            group_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_group_members(&self, group_id: Uuid) -> Result<Vec<GroupMember>, sqlx::Error> {
+        let members = sqlx::query_as!(
+            GroupMember,
+            r#"
+            SELECT u.id AS user_id, u.name, u.email, gm.created_at
+            FROM group_members gm
+            JOIN users u ON gm.user_id = u.id
+            WHERE gm.group_id = $1
+            ORDER BY gm.created_at
+            "#,
+            group_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    async fn add_group_member(&self, organization_id: Uuid, group_id: Uuid, user_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO group_members (group_id, organization_id, user_id)
+            VALUES ($1, $2, $3)
+            "#,
+            group_id,
+            organization_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_group_member(&self, group_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM group_members
+            WHERE group_id = $1 AND user_id = $2
+            "#,
+            group_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_group_recipients(&self, group_ids: &[Uuid]) -> Result<Vec<User>, sqlx::Error> {
+        let recipients = sqlx::query_as!(
+            User,
+            r#"
+            SELECT u.id, u.name, u.email, u.password, u.public_key, u.private_key, u.encrypted_private_key, u.private_key_salt, u.private_key_nonce, u.email_verified_at, u.role, u.disabled_at, u.created_at, u.updated_at
+            FROM users u
+            JOIN (
+                SELECT user_id, MIN(created_at) AS joined_at
+                FROM group_members
+                WHERE group_id = ANY($1)
+                GROUP BY user_id
+            ) gm ON gm.user_id = u.id
+            ORDER BY gm.joined_at, u.email
+            "#,
+            group_ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(recipients)
+    }
+
+    async fn append_audit_event(&self, event: NewAuditEvent) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // Readers are not blocked, only other writers
+        sqlx::query!("LOCK TABLE audit_events IN EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+
+        let prev_hash = sqlx::query_scalar!(
+            r#"
+            SELECT hash
+            FROM audit_events
+            ORDER BY id DESC
+            LIMIT 1
+            "#
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .unwrap_or_else(|| GENESIS_HASH.to_vec());
+
+        // Postgres keeps microseconds, so the hash is taken over what will be stored
+        let occurred_at = Utc::now().trunc_subsecs(6);
+        let hash = chain_hash(&prev_hash, occurred_at, &event);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_events (user_id, action, succeeded, target_id, ip_address, user_agent, details, occurred_at, prev_hash, hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            "#,
+            event.user_id,
+            event.action.as_str(),
+            event.succeeded,
+            event.target_id,
+            event.ip_address,
+            event.user_agent,
+            event.details,
+            occurred_at,
+            prev_hash,
+            hash
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_audit_events(
+        &self,
+        filter: &AuditFilter,
+        page: u32,
+        limit: usize,
+    ) -> Result<(Vec<AuditEvent>, i64), sqlx::Error> {
+        let offset = (page - 1) * limit as u32;
+        let action = filter.action.map(|action| action.as_str());
+
+        let events = sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT id, user_id, action, succeeded, target_id, ip_address, user_agent, details, occurred_at, prev_hash, hash
+            FROM audit_events
+            WHERE ($1::UUID IS NULL OR user_id = $1)
+            AND ($2::TEXT IS NULL OR action = $2)
+            AND ($3::BOOLEAN IS NULL OR succeeded = $3)
+            AND ($4::TIMESTAMPTZ IS NULL OR occurred_at >= $4)
+            AND ($5::TIMESTAMPTZ IS NULL OR occurred_at < $5)
+            ORDER BY id DESC
+            LIMIT $6
+            OFFSET $7
+            "#,
+            filter.user_id,
+            action,
+            filter.succeeded,
+            filter.since,
+            filter.until,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total_count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*)
+            FROM audit_events
+            WHERE ($1::UUID IS NULL OR user_id = $1)
+            AND ($2::TEXT IS NULL OR action = $2)
+            AND ($3::BOOLEAN IS NULL OR succeeded = $3)
+            AND ($4::TIMESTAMPTZ IS NULL OR occurred_at >= $4)
+            AND ($5::TIMESTAMPTZ IS NULL OR occurred_at < $5)
+            "#,
+            filter.user_id,
+            action,
+            filter.succeeded,
+            filter.since,
+            filter.until
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((events, total_count.unwrap_or(0)))
+    }
+
+    async fn get_audit_chain(&self, after_id: i64, limit: i64) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        let events = sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT id, user_id, action, succeeded, target_id, ip_address, user_agent, details, occurred_at, prev_hash, hash
+            FROM audit_events
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            after_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(events)
+    }
+
+    async fn notify_event(&self, channel: &str, payload: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"SELECT pg_notify($1, $2)::TEXT"#,
+            channel,
+            payload
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_file_share_notices(&self, file_id: Uuid) -> Result<Vec<ShareNotice>, sqlx::Error> {
+        let notices = sqlx::query_as!(
+            ShareNotice,
+            r#"
+            SELECT sl.id AS shared_id, f.id AS file_id, f.file_name, u.id AS sender_id, u.email AS sender_email,
+                sl.recipient_user_id, sl.expiration_date
+            FROM shared_links sl
+            JOIN files f ON sl.file_id = f.id
+            JOIN users u ON f.user_id = u.id
+            WHERE sl.file_id = $1
+            AND sl.recipient_user_id IS NOT NULL
+            "#,
+            file_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(notices)
+    }
+
+    async fn get_share_notice(&self, shared_id: Uuid) -> Result<Option<ShareNotice>, sqlx::Error> {
+        let notice = sqlx::query_as!(
+            ShareNotice,
+            r#"
+            SELECT sl.id AS shared_id, f.id AS file_id, f.file_name, u.id AS sender_id, u.email AS sender_email,
+                sl.recipient_user_id, sl.expiration_date
+            FROM shared_links sl
+            JOIN files f ON sl.file_id = f.id
+            JOIN users u ON f.user_id = u.id
+            WHERE sl.id = $1
+            "#,
+            shared_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(notice)
+    }
+
+    async fn take_expiring_share_notices(&self, within_hours: i64) -> Result<Vec<ShareNotice>, sqlx::Error> {
+        let notices = sqlx::query_as!(
+            ShareNotice,
+            r#"
+            UPDATE shared_links sl
+            SET expiry_warned_at = NOW()
+            FROM files f
+            JOIN users u ON f.user_id = u.id
+            WHERE sl.file_id = f.id
+            AND sl.recipient_user_id IS NOT NULL
+            AND sl.status IN ('pending', 'accepted')
+            AND sl.expiry_warned_at IS NULL
+            AND sl.expiration_date > NOW()
+            AND sl.expiration_date <= NOW() + make_interval(hours => $1::INT)
+            RETURNING sl.id AS shared_id, f.id AS file_id, f.file_name, u.id AS sender_id, u.email AS sender_email,
+                sl.recipient_user_id, sl.expiration_date
+            "#,
+            within_hours as i32
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(notices)
+    }
+
+    async fn get_user_totp(&self, user_id: Uuid) -> Result<Option<UserTotp>, sqlx::Error> {
+        let totp = sqlx::query_as!(
+            UserTotp,
+            r#"
+            SELECT secret, enabled_at, last_used_step, failed_attempts, last_attempt_at
+            FROM user_totp
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(totp)
+    }
+
+    async fn save_totp_secret(&self, user_id: Uuid, secret: Vec<u8>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO user_totp (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret,
+                last_used_step = NULL,
+                failed_attempts = 0,
+                last_attempt_at = NULL,
+                created_at = NOW()
+            WHERE user_totp.enabled_at IS NULL
+            "#,
+            user_id,
+            secret
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn enable_totp(&self, user_id: Uuid, step: i64, recovery_code_hashes: Vec<String>) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET enabled_at = NOW(), last_used_step = $2
+            WHERE user_id = $1
+            AND enabled_at IS NULL
+            "#,
+            user_id,
+            step
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO recovery_codes (user_id, code_hash)
+            SELECT $1, UNNEST($2::VARCHAR[])
+            "#,
+            user_id,
+            &recovery_code_hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn disable_totp(&self, user_id: Uuid) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM user_totp
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn claim_totp_attempt(&self, user_id: Uuid, seen_attempts: i32) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET failed_attempts = failed_attempts + 1, last_attempt_at = NOW()
+            WHERE user_id = $1
+            AND failed_attempts = $2
+            "#,
+            user_id,
+            seen_attempts
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_totp_success(&self, user_id: Uuid, step: Option<i64>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET failed_attempts = 0,
+                last_attempt_at = NULL,
+                last_used_step = COALESCE($2, last_used_step)
+            WHERE user_id = $1
+            AND ($2::BIGINT IS NULL OR last_used_step IS NULL OR last_used_step < $2)
+            "#,
+            user_id,
+            step
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_unused_recovery_codes(&self, user_id: Uuid) -> Result<Vec<RecoveryCode>, sqlx::Error> {
+        let codes = sqlx::query_as!(
+            RecoveryCode,
+            r#"
+            SELECT id, code_hash
+            FROM recovery_codes
+            WHERE user_id = $1
+            AND used_at IS NULL
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(codes)
+    }
+
+    async fn use_recovery_code(&self, code_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE recovery_codes
+            SET used_at = NOW()
+            WHERE id = $1
+            AND used_at IS NULL
+            "#,
+            code_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn replace_recovery_codes(&self, user_id: Uuid, recovery_code_hashes: Vec<String>) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO recovery_codes (user_id, code_hash)
+            SELECT $1, UNNEST($2::VARCHAR[])
+            "#,
+            user_id,
+            &recovery_code_hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+    async fn save_encrypted_file(
+        &self,
+        file: NewFile,
+        file_keys: Vec<NewFileKey>,
+        link_share: Option<NewLinkShare>,
+        password: String,
+        expiration_date: DateTime<Utc>,
+        max_downloads: Option<i32>,
+    ) -> Result<Uuid, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // Insert into the files table and get the file_id
+        let file_id: Uuid = sqlx::query_scalar!(
+            r#"
+            INSERT INTO files (user_id, file_name, file_size, storage_key, iv, cipher, client_encrypted, chunk_count, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW())
+            RETURNING id
+            "#,
+            file.user_id,
+            file.file_name,
+            file.file_size,
+            file.storage_key,
+            file.iv,
+            file.cipher.as_str(),
+            file.client_encrypted,
+            file.chunk_count
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for file_key in file_keys {
+            sqlx::query!(
+                r#"
+                INSERT INTO file_keys (file_id, recipient_user_id, encrypted_aes_key, key_wrap)
+                VALUES ($1, $2, $3, $4)
+                "#,
+                file_id,
+                file_key.recipient_user_id,
+                file_key.encrypted_aes_key,
+                file_key.key_wrap.as_str()
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            // Each recipient gets a shared link of their own, so they accept independently
+            sqlx::query!(
+                r#"
+                INSERT INTO shared_links (file_id, recipient_user_id, password, expiration_date, downloads_remaining, created_at)
+                VALUES ($1, $2, $3, $4, $5, NOW())
+                "#,
+                file_id,
+                file_key.recipient_user_id,
+                password,
+                expiration_date,
+                max_downloads
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if let Some(link_share) = link_share {
+            let (encrypted_aes_key, key_salt, key_nonce) = match link_share.wrapped_key {
+                Some(wrapped) => (Some(wrapped.ciphertext), Some(wrapped.salt), Some(wrapped.nonce)),
+                None => (None, None, None),
+            };
+
+            sqlx::query!(
+                r#"
+                INSERT INTO shared_links (id, file_id, password, expiration_date, downloads_remaining, link_key, key_check, encrypted_aes_key, key_salt, key_nonce, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, NOW())
+                "#,
+                link_share.id,
+                file_id,
+                password,
+                expiration_date,
+                max_downloads,
+                link_share.link_key.as_str(),
+                link_share.key_check,
+                encrypted_aes_key,
+                key_salt,
+                key_nonce
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(file_id)
+    }
+
+    async fn get_file_chunk(
+        &self,
+        file_id: Uuid,
+        chunk_index: i32,
+    ) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        let data = sqlx::query_scalar!(
+            r#"
+            SELECT data
+            FROM file_chunks
+            WHERE file_id = $1 AND chunk_index = $2
+            "#,
+            file_id,
+            chunk_index
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(data)
+    }
+
+    async fn get_shared(
+        &self,
+        shared_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<SharedLink>, sqlx::Error> {
+        let shared_link = sqlx::query_as!(
+            SharedLink,
+            r#"
+            SELECT id, file_id, recipient_user_id, password, expiration_date, created_at, status, failed_attempts, last_attempt_at, locked_at
+            FROM shared_links
+            WHERE id = $1
+            AND recipient_user_id = $2
+            AND expiration_date > NOW()
+            AND status IN ('pending', 'accepted', 'downloaded')
+            "#,
+            shared_id,
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(shared_link)
+    }
+
+    async fn get_file(
+        &self,
+        file_id: Uuid,
+    ) -> Result<Option<File>, sqlx::Error> {
+        let file = sqlx::query_as!(
+            File,
+            r#"
+            SELECT id, user_id, file_name, file_size, iv, cipher, client_encrypted, chunk_count, storage_key, created_at
+            FROM files
+            WHERE id = $1
+            "#,
+            file_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(file)
+    }
+
+    async fn get_link_share(&self, shared_id: Uuid) -> Result<Option<LinkShare>, sqlx::Error> {
+        let link_share = sqlx::query_as!(
+            LinkShare,
+            r#"
+            SELECT id, file_id, password, failed_attempts, last_attempt_at, locked_at, link_key AS "link_key!", key_check, encrypted_aes_key, key_salt, key_nonce
+            FROM shared_links
+            WHERE id = $1
+            AND recipient_user_id IS NULL
+            AND link_key IS NOT NULL
+            AND expiration_date > NOW()
+            AND status IN ('pending', 'accepted', 'downloaded')
+            "#,
+            shared_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(link_share)
+    }
+
+    async fn get_file_key(
+        &self,
+        file_id: Uuid,
+        recipient_user_id: Uuid,
+    ) -> Result<Option<FileKey>, sqlx::Error> {
+        let file_key = sqlx::query_as!(
+            FileKey,
+            r#"
+            SELECT encrypted_aes_key, key_wrap
+            FROM file_keys
+            WHERE file_id = $1 AND recipient_user_id = $2
+            "#,
+            file_id,
+            recipient_user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(file_key)
+    }
+
     async fn get_sent_files(
         &self,
         user_id: Uuid,
@@ -335,15 +2651,27 @@ impl UserExt for DBClient {
             r#"
                 SELECT
                     f.id AS file_id,
+                    sl.id AS shared_id,
                     f.file_name,
-                    u.email AS recipient_email,
+                    u.email AS "recipient_email?",
                     sl.expiration_date,
-                    sl.created_at
+                    sl.created_at,
+                    sl.updated_at,
+                    CASE
+                        WHEN sl.status IN ('pending', 'accepted', 'downloaded') AND sl.expiration_date < NOW() THEN 'expired'
+                        WHEN sl.status IN ('pending', 'accepted', 'downloaded') AND sl.locked_at IS NOT NULL THEN 'locked'
+                        ELSE sl.status
+                    END AS "status!",
+                    sl.accepted_at,
+                    sl.declined_at,
+                    sl.downloaded_at,
+                    sl.revoked_at,
+                    sl.locked_at
                 FROM 
                     shared_links sl
                 JOIN 
                     files f ON sl.file_id = f.id
-                JOIN 
+                LEFT JOIN 
                     users u ON sl.recipient_user_id = u.id
                 WHERE 
                     f.user_id = $1
@@ -359,88 +2687,390 @@ impl UserExt for DBClient {
         .fetch_all(&self.pool)
         .await?;
 
-        let count_row = sqlx::query_scalar!(
+        let count_row = sqlx::query_scalar!(
+            r#"
+                SELECT COUNT(*)
+                FROM shared_links sl
+                JOIN files f ON sl.file_id = f.id
+                WHERE f.user_id = $1
+            "#,
+            user_id,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let total_count = count_row.unwrap_or(0);
+
+        Ok((files, total_count))
+    }
+
+    async fn delete_expired_files(
+        &self
+    ) -> Result<Vec<String>, sqlx::Error> {
+        
+        let expired_shared_links: Vec<Uuid> = sqlx::query_scalar!(
+            r#"
+            SELECT sl.id
+            FROM shared_links sl
+            WHERE sl.expiration_date < NOW()
+            "#,
+        ).
+        fetch_all(&self.pool)
+        .await?;
+
+        if expired_shared_links.is_empty() {
+            println!("No expired files or shared links to delete.");
+            return Ok(Vec::new());
+        }
+
+        // Shares of one file can expire at different times, so a file only goes with its last share
+        let expired_file_ids: Vec<Uuid> = sqlx::query_scalar!(
+            r#"
+            SELECT f.id
+            FROM files f
+            WHERE f.id IN (
+                SELECT sl.file_id
+                FROM shared_links sl
+                WHERE sl.expiration_date < NOW()
+            )
+            AND NOT EXISTS (
+                SELECT 1
+                FROM shared_links sl
+                WHERE sl.file_id = f.id AND sl.expiration_date >= NOW()
+            )
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM file_keys fk
+            USING shared_links sl
+            WHERE sl.id = ANY($1)
+            AND fk.file_id = sl.file_id
+            AND fk.recipient_user_id = sl.recipient_user_id
+            "#,
+            &expired_shared_links[..]
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM shared_links
+            WHERE id = ANY($1)
+            "#,
+            &expired_shared_links[..] // Pass the list of expired shared link IDs
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Delete the expired files, keeping their storage keys so the blobs can go too
+        let storage_keys: Vec<Option<String>> = sqlx::query_scalar!(
+            r#"
+            DELETE FROM files
+            WHERE id = ANY($1)
+            RETURNING storage_key
+            "#,
+            &expired_file_ids[..] // Pass the list of expired file IDs
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        println!("Successfully deleted expired files and their shared links.");
+
+        Ok(storage_keys.into_iter().flatten().collect())
+
+    }
+
+    async fn get_owned_share(&self, shared_id: Uuid, user_id: Uuid) -> Result<Option<OwnedShare>, sqlx::Error> {
+        let share = sqlx::query_as!(
+            OwnedShare,
+            r#"
+            SELECT sl.id, sl.password, sl.link_key, sl.encrypted_aes_key, sl.key_salt, sl.key_nonce
+            FROM shared_links sl
+            JOIN files f ON sl.file_id = f.id
+            WHERE sl.id = $1
+            AND f.user_id = $2
+            AND sl.status IN ('pending', 'accepted', 'downloaded')
+            "#,
+            shared_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(share)
+    }
+
+    async fn revoke_share(&self, shared_id: Uuid) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE shared_links
+            SET status = 'revoked', revoked_at = NOW(), key_check = NULL, encrypted_aes_key = NULL, key_salt = NULL, key_nonce = NULL
+            WHERE id = $1 AND status IN ('pending', 'accepted', 'downloaded')
+            RETURNING file_id, recipient_user_id
+            "#,
+            shared_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(revoked) = revoked
+            && let (Some(file_id), Some(recipient_user_id)) = (revoked.file_id, revoked.recipient_user_id)
+        {
+            sqlx::query!(
+                r#"
+                DELETE FROM file_keys
+                WHERE file_id = $1 AND recipient_user_id = $2
+                "#,
+                file_id,
+                recipient_user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn update_share_expiration(&self, shared_id: Uuid, expiration_date: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE shared_links
+            SET expiration_date = $1, updated_at = NOW(), expiry_warned_at = NULL
+            WHERE id = $2 AND status IN ('pending', 'accepted', 'downloaded')
+            "#,
+            expiration_date,
+            shared_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_share_password(
+        &self,
+        shared_id: Uuid,
+        password: String,
+        wrapped_key: Option<PasswordWrappedKey>,
+    ) -> Result<(), sqlx::Error> {
+        let (encrypted_aes_key, key_salt, key_nonce) = match wrapped_key {
+            Some(wrapped) => (Some(wrapped.ciphertext), Some(wrapped.salt), Some(wrapped.nonce)),
+            None => (None, None, None),
+        };
+
+        // Only password links hold a wrapped key, so the other shares keep theirs as they are
+        sqlx::query!(
+            r#"
+            UPDATE shared_links
+            SET password = $1,
+                encrypted_aes_key = COALESCE($2, encrypted_aes_key),
+                key_salt = COALESCE($3, key_salt),
+                key_nonce = COALESCE($4, key_nonce),
+                updated_at = NOW()
+            WHERE id = $5 AND status IN ('pending', 'accepted', 'downloaded')
+            "#,
+            password,
+            encrypted_aes_key,
+            key_salt,
+            key_nonce,
+            shared_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_user_password_attempts(&self, user_id: Uuid) -> Result<Option<PasswordAttempts>, sqlx::Error> {
+        let attempts = sqlx::query_as!(
+            PasswordAttempts,
+            r#"
+            SELECT failed_attempts, last_attempt_at
+            FROM user_password_attempts
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(attempts)
+    }
+
+    async fn claim_share_attempt(&self, shared_id: Uuid, seen_attempts: i32) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE shared_links
+            SET failed_attempts = failed_attempts + 1, last_attempt_at = NOW()
+            WHERE id = $1 AND failed_attempts = $2 AND locked_at IS NULL
+            "#,
+            shared_id,
+            seen_attempts
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn claim_user_attempt(&self, user_id: Uuid, seen_attempts: i32) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO user_password_attempts (user_id, failed_attempts, last_attempt_at)
+            VALUES ($1, 1, NOW())
+            ON CONFLICT (user_id) DO UPDATE
+            SET failed_attempts = user_password_attempts.failed_attempts + 1, last_attempt_at = NOW()
+            WHERE user_password_attempts.failed_attempts = $2
+            "#,
+            user_id,
+            seen_attempts
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn reset_password_attempts(&self, shared_id: Uuid, user_id: Option<Uuid>) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE shared_links
+            SET failed_attempts = 0, last_attempt_at = NULL
+            WHERE id = $1
+            "#,
+            shared_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if let Some(user_id) = user_id {
+            sqlx::query!(
+                r#"
+                DELETE FROM user_password_attempts
+                WHERE user_id = $1
+                "#,
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn lock_share(&self, shared_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
             r#"
-                SELECT COUNT(*)
-                FROM shared_links sl
-                JOIN files f ON sl.file_id = f.id
-                WHERE f.user_id = $1
+            UPDATE shared_links
+            SET locked_at = NOW()
+            WHERE id = $1 AND locked_at IS NULL
             "#,
-            user_id,
+            shared_id
         )
-        .fetch_one(&self.pool)
+        .execute(&self.pool)
         .await?;
 
-        let total_count = count_row.unwrap_or(0);
-
-        Ok((files, total_count))
+        Ok(())
     }
 
-    async fn delete_expired_files(
-        &self
-    ) -> Result<(), sqlx::Error> {
-        
-        let expired_shared_links: Vec<Uuid> = sqlx::query_scalar!(
+    async fn unlock_share(&self, shared_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
             r#"
-            SELECT sl.id
-            FROM shared_links sl
-            WHERE sl.expiration_date < NOW()
+            UPDATE shared_links
+            SET failed_attempts = 0, last_attempt_at = NULL, locked_at = NULL, updated_at = NOW()
+            WHERE id = $1 AND status IN ('pending', 'accepted', 'downloaded')
             "#,
-        ).
-        fetch_all(&self.pool)
+            shared_id
+        )
+        .execute(&self.pool)
         .await?;
 
-        if expired_shared_links.is_empty() {
-            println!("No expired files or shared links to delete.");
-            return Ok(());
-        }
+        Ok(())
+    }
 
-        let expired_file_ids: Vec<Uuid> = sqlx::query_scalar!(
+    async fn record_download(&self, shared_id: Uuid) -> Result<Option<bool>, sqlx::Error> {
+        // A single conditional update, so concurrent downloads cannot both take the last one
+        let last_download = sqlx::query_scalar!(
             r#"
-            SELECT f.id
-            FROM files f
-            WHERE f.id IN (
-                SELECT sl.file_id
-                FROM shared_links sl
-                WHERE sl.expiration_date < NOW()
-            )
+            UPDATE shared_links
+            SET downloads_remaining = downloads_remaining - 1,
+                status = 'downloaded',
+                downloaded_at = COALESCE(downloaded_at, NOW())
+            WHERE id = $1
+            AND status IN ('pending', 'accepted', 'downloaded')
+            AND (downloads_remaining IS NULL OR downloads_remaining > 0)
+            RETURNING COALESCE(downloads_remaining = 0, FALSE) AS "last_download!"
             "#,
+            shared_id
         )
-        .fetch_all(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        sqlx::query!(
+        Ok(last_download)
+    }
+
+    async fn delete_share(&self, shared_id: Uuid) -> Result<Vec<String>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let deleted = sqlx::query!(
             r#"
             DELETE FROM shared_links
-            WHERE id = ANY($1)
+            WHERE id = $1
+            RETURNING file_id, recipient_user_id
             "#,
-            &expired_shared_links[..] // Pass the list of expired shared link IDs
+            shared_id
         )
-        .execute(&self.pool)
+        .fetch_optional(&mut *tx)
         .await?;
 
-        // Delete the expired files
-        sqlx::query!(
+        let Some(file_id) = deleted.as_ref().and_then(|deleted| deleted.file_id) else {
+            tx.commit().await?;
+            return Ok(Vec::new());
+        };
+
+        if let Some(recipient_user_id) = deleted.and_then(|deleted| deleted.recipient_user_id) {
+            sqlx::query!(
+                r#"
+                DELETE FROM file_keys
+                WHERE file_id = $1 AND recipient_user_id = $2
+                "#,
+                file_id,
+                recipient_user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let storage_keys: Vec<Option<String>> = sqlx::query_scalar!(
             r#"
             DELETE FROM files
-            WHERE id = ANY($1)
+            WHERE id = $1
+            AND NOT EXISTS (SELECT 1 FROM shared_links WHERE file_id = $1)
+            RETURNING storage_key
             "#,
-            &expired_file_ids[..] // Pass the list of expired file IDs
+            file_id
         )
-        .execute(&self.pool)
+        .fetch_all(&mut *tx)
         .await?;
 
-        println!("Successfully deleted expired files and their shared links.");
-
-        Ok(())
+        tx.commit().await?;
 
+        Ok(storage_keys.into_iter().flatten().collect())
     }
 
     async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error> {
         sqlx::query_as!(
             User,
             r#"
-            SELECT id, name, email, password, public_key, private_key, created_at, updated_at
+            SELECT id, name, email, password, public_key, private_key, encrypted_private_key, private_key_salt, private_key_nonce, email_verified_at, role, disabled_at, created_at, updated_at
             FROM users
             WHERE id = $1
             "#,
@@ -462,13 +3092,15 @@ impl UserExt for DBClient {
                 f.file_name,
                 u.email AS sender_email,
                 sl.expiration_date,
-                sl.created_at
+                sl.created_at,
+                sl.updated_at,
+                sl.status
             FROM 
                 files f
                 JOIN shared_links sl ON f.id = sl.file_id
                 JOIN users u ON f.user_id = u.id
             WHERE 
-                sl.recipient_user_id = $1 AND sl.is_retrieved = true
+                sl.recipient_user_id = $1 AND sl.status IN ('accepted', 'downloaded')
             ORDER BY 
                 sl.created_at DESC
             LIMIT $2 OFFSET $3
@@ -484,7 +3116,7 @@ impl UserExt for DBClient {
             r#"
             SELECT COUNT(*) 
             FROM shared_links sl
-            WHERE sl.recipient_user_id = $1 AND sl.is_retrieved = true
+            WHERE sl.recipient_user_id = $1 AND sl.status IN ('accepted', 'downloaded')
             "#,
             user_id
         )
@@ -507,13 +3139,15 @@ impl UserExt for DBClient {
                 f.file_name,
                 u.email AS sender_email,
                 sl.expiration_date,
-                sl.created_at
+                sl.created_at,
+                sl.updated_at,
+                sl.status
             FROM 
                 files f
                 JOIN shared_links sl ON f.id = sl.file_id
                 JOIN users u ON f.user_id = u.id
             WHERE 
-                sl.recipient_user_id = $1 AND (sl.is_retrieved = false OR sl.is_retrieved IS NULL)
+                sl.recipient_user_id = $1 AND sl.status = 'pending'
             ORDER BY 
                 sl.created_at DESC
             LIMIT $2 OFFSET $3
@@ -529,7 +3163,7 @@ impl UserExt for DBClient {
             r#"
             SELECT COUNT(*) 
             FROM shared_links sl
-            WHERE sl.recipient_user_id = $1 AND (sl.is_retrieved = false OR sl.is_retrieved IS NULL)
+            WHERE sl.recipient_user_id = $1 AND sl.status = 'pending'
             "#,
             user_id
         )
@@ -540,19 +3174,368 @@ impl UserExt for DBClient {
         Ok((files, total_count))
     }
     
-    // Add a new method to mark a file as retrieved
-    async fn mark_file_as_retrieved(&self, shared_id: Uuid) -> Result<(), sqlx::Error> {
-        sqlx::query!(
+    async fn accept_share(&self, shared_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
             r#"
             UPDATE shared_links
-            SET is_retrieved = true
-            WHERE id = $1
+            SET status = 'accepted', accepted_at = NOW()
+            WHERE id = $1 AND status = 'pending'
+            "#,
+            shared_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn decline_share(&self, shared_id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let declined = sqlx::query!(
+            r#"
+            UPDATE shared_links
+            SET status = 'declined', declined_at = NOW()
+            WHERE id = $1 AND status = 'pending'
+            RETURNING file_id, recipient_user_id
             "#,
             shared_id
         )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(declined) = declined else {
+            return Ok(false);
+        };
+
+        if let (Some(file_id), Some(recipient_user_id)) = (declined.file_id, declined.recipient_user_id) {
+            sqlx::query!(
+                r#"
+                DELETE FROM file_keys
+                WHERE file_id = $1 AND recipient_user_id = $2
+                "#,
+                file_id,
+                recipient_user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+    async fn get_legacy_wrapped_keys(&self, after: (Uuid, Uuid), limit: i64) -> Result<Vec<LegacyWrappedKey>, sqlx::Error> {
+        let keys = sqlx::query_as!(
+            LegacyWrappedKey,
+            r#"
+            SELECT
+                fk.file_id,
+                fk.recipient_user_id,
+                fk.encrypted_aes_key,
+                u.private_key
+            FROM
+                file_keys fk
+                JOIN users u ON fk.recipient_user_id = u.id
+            WHERE
+                fk.key_wrap = 'rsa-pkcs1v15' AND u.private_key IS NOT NULL
+                AND (fk.file_id, fk.recipient_user_id) > ($1, $2)
+            ORDER BY fk.file_id, fk.recipient_user_id
+            LIMIT $3
+            "#,
+            after.0,
+            after.1,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    async fn get_user_legacy_wrapped_keys(&self, user_id: Uuid) -> Result<Vec<LegacyWrappedKey>, sqlx::Error> {
+        let keys = sqlx::query_as!(
+            LegacyWrappedKey,
+            r#"
+            SELECT
+                file_id,
+                recipient_user_id,
+                encrypted_aes_key,
+                NULL::TEXT AS private_key
+            FROM
+                file_keys
+            WHERE
+                key_wrap = 'rsa-pkcs1v15' AND recipient_user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(keys)
+    }
+
+    async fn update_file_key_wrap(
+        &self,
+        file_id: Uuid,
+        recipient_user_id: Uuid,
+        encrypted_aes_key: Vec<u8>,
+        key_wrap: KeyWrap,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE file_keys
+            SET encrypted_aes_key = $1, key_wrap = $2
+            WHERE file_id = $3 AND recipient_user_id = $4
+            "#,
+            encrypted_aes_key,
+            key_wrap.as_str(),
+            file_id,
+            recipient_user_id
+        )
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    async fn get_legacy_file_content(&self, file_id: Uuid) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        let content = sqlx::query_scalar!(
+            r#"
+            SELECT encrypted_file
+            FROM files
+            WHERE id = $1
+            "#,
+            file_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(content.flatten())
+    }
+
+    async fn get_unmigrated_files(&self, limit: i64) -> Result<Vec<File>, sqlx::Error> {
+        let files = sqlx::query_as!(
+            File,
+            r#"
+            SELECT id, user_id, file_name, file_size, iv, cipher, client_encrypted, chunk_count, storage_key, created_at
+            FROM files
+            WHERE storage_key IS NULL
+            ORDER BY created_at
+            LIMIT $1
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(files)
+    }
+
+    async fn set_file_storage_key(&self, file_id: Uuid, storage_key: String) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE files
+            SET storage_key = $1, encrypted_file = NULL
+            WHERE id = $2
+            "#,
+            storage_key,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM file_chunks
+            WHERE file_id = $1
+            "#,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_tus_upload(
+        &self,
+        user_id: Uuid,
+        file_name: String,
+        upload_length: i64,
+        recipient_user_ids: Vec<Uuid>,
+        password: String,
+        expiration_date: DateTime<Utc>,
+        max_downloads: Option<i32>,
+        staging_key: Vec<u8>,
+    ) -> Result<TusUpload, sqlx::Error> {
+        let upload = sqlx::query_as!(
+            TusUpload,
+            r#"
+            INSERT INTO tus_uploads (user_id, file_name, upload_length, recipient_user_ids, password, expiration_date, max_downloads, staging_key)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, user_id, file_name, upload_length, upload_offset, recipient_user_ids, password, expiration_date, max_downloads, staging_key, created_at, updated_at
+            "#,
+            user_id,
+            file_name,
+            upload_length,
+            &recipient_user_ids,
+            password,
+            expiration_date,
+            max_downloads,
+            staging_key
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(upload)
+    }
+
+    async fn get_tus_upload(&self, upload_id: Uuid, user_id: Uuid) -> Result<Option<TusUpload>, sqlx::Error> {
+        let upload = sqlx::query_as!(
+            TusUpload,
+            r#"
+            SELECT id, user_id, file_name, upload_length, upload_offset, recipient_user_ids, password, expiration_date, max_downloads, staging_key, created_at, updated_at
+            FROM tus_uploads
+            WHERE id = $1 AND user_id = $2
+            "#,
+            upload_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(upload)
+    }
+
+    async fn add_tus_upload_part(&self, upload_id: Uuid, part: TusUploadPart) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let new_offset = sqlx::query_scalar!(
+            r#"
+            UPDATE tus_uploads
+            SET upload_offset = upload_offset + $1, updated_at = NOW()
+            WHERE id = $2 AND upload_offset = $3
+            RETURNING upload_offset
+            "#,
+            part.part_size,
+            upload_id,
+            part.part_offset
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if new_offset.is_none() {
+            return Ok(None);
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO tus_upload_parts (upload_id, part_offset, part_size, storage_key, iv)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            upload_id,
+            part.part_offset,
+            part.part_size,
+            part.storage_key,
+            part.iv
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(new_offset)
+    }
+
+    async fn get_tus_upload_parts(&self, upload_id: Uuid) -> Result<Vec<TusUploadPart>, sqlx::Error> {
+        let parts = sqlx::query_as!(
+            TusUploadPart,
+            r#"
+            SELECT part_offset, part_size, storage_key, iv
+            FROM tus_upload_parts
+            WHERE upload_id = $1
+            ORDER BY part_offset
+            "#,
+            upload_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(parts)
+    }
+
+    async fn delete_tus_upload(&self, upload_id: Uuid) -> Result<Vec<String>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let storage_keys = sqlx::query_scalar!(
+            r#"
+            DELETE FROM tus_upload_parts
+            WHERE upload_id = $1
+            RETURNING storage_key
+            "#,
+            upload_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM tus_uploads
+            WHERE id = $1
+            "#,
+            upload_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(storage_keys)
+    }
+
+    async fn delete_stale_tus_uploads(&self, max_age_hours: i64) -> Result<Vec<String>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let stale_upload_ids: Vec<Uuid> = sqlx::query_scalar!(
+            r#"
+            SELECT id
+            FROM tus_uploads
+            WHERE updated_at < NOW() - make_interval(hours => $1)
+            FOR UPDATE
+            "#,
+            max_age_hours as i32
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let storage_keys = sqlx::query_scalar!(
+            r#"
+            DELETE FROM tus_upload_parts
+            WHERE upload_id = ANY($1)
+            RETURNING storage_key
+            "#,
+            &stale_upload_ids[..]
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM tus_uploads
+            WHERE id = ANY($1)
+            "#,
+            &stale_upload_ids[..]
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(storage_keys)
+    }
+}
@@ -1,9 +1,9 @@
 use core::str;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use validator::{Validate, ValidationError};
 
-use crate::models::{ReceiveFileDetails, SentFileDetails, User};
+use crate::models::{AuditEvent, Group, GroupMember, OrgRole, Organization, OrganizationInvitation, OrganizationMember, PersonalAccessToken, ReceiveFileDetails, Role, Scope, SentFileDetails, Session, ShareOverview, SharePolicy, StorageStats, User, UserIdentity, UserOverview, WebauthnCredential};
 
 #[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
 pub struct RegisterUserDto {
@@ -53,6 +53,8 @@ pub struct FilterUserDto {
     pub name: String,
     pub email: String,
     pub public_key: Option<String>,
+    pub email_verified: bool,
+    pub role: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -64,6 +66,8 @@ impl FilterUserDto {
             name: user.name.to_owned(),
             email: user.email.to_owned(),
             public_key: user.public_key.to_owned(),
+            email_verified: user.email_verified_at.is_some(),
+            role: user.role.to_owned(),
             created_at: user.created_at.unwrap(),
             updated_at: user.updated_at.unwrap(),
         }
@@ -84,20 +88,38 @@ pub struct UserResponseDto {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserSendFileDto {
     pub file_id: String,
+    pub shared_id: String,
     pub file_name: String,
-    pub recipient_email: String,
+    // None for link shares, which have no recipient account
+    pub recipient_email: Option<String>,
     pub expiration_date: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+    // pending, accepted, declined, revoked, expired, downloaded or locked
+    pub status: String,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub declined_at: Option<DateTime<Utc>>,
+    pub downloaded_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub locked_at: Option<DateTime<Utc>>,
 }
 
 impl UserSendFileDto {
     pub fn filter_send_user_file(file_data: &SentFileDetails) -> Self {
         UserSendFileDto {
             file_id: file_data.file_id.to_string(),
+            shared_id: file_data.shared_id.to_string(),
             file_name: file_data.file_name.to_owned(),
             recipient_email: file_data.recipient_email.to_owned(),
             expiration_date: file_data.expiration_date.unwrap(),
             created_at: file_data.created_at.unwrap(),
+            updated_at: file_data.updated_at,
+            status: file_data.status.to_owned(),
+            accepted_at: file_data.accepted_at,
+            declined_at: file_data.declined_at,
+            downloaded_at: file_data.downloaded_at,
+            revoked_at: file_data.revoked_at,
+            locked_at: file_data.locked_at,
         }
     }
 
@@ -122,6 +144,9 @@ pub struct UserReceiveFileDto {
     pub sender_email: String,
     pub expiration_date: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
+    // Last time the sender changed the share's expiry or password
+    pub updated_at: Option<DateTime<Utc>>,
+    pub status: String,
 }
 
 impl UserReceiveFileDto {
@@ -133,6 +158,8 @@ impl UserReceiveFileDto {
             sender_email: file_data.sender_email.to_owned(),
             expiration_date: file_data.expiration_date.unwrap(),
             created_at: file_data.created_at.unwrap(),
+            updated_at: file_data.updated_at,
+            status: file_data.status.to_owned(),
         }
     }
 
@@ -153,6 +180,779 @@ pub struct UserReceiveFileListResponseDto {
 pub struct UserLoginResponseDto {
     pub status: String,
     pub token: String,
+    pub refresh_token: String,
+}
+
+// Returned by login instead of tokens when the account has two-factor authentication
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaRequiredResponseDto {
+    pub status: String,
+    pub mfa_token: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MfaLoginDto {
+    #[validate(length(min = 1, message = "MFA token is required"))]
+    pub mfa_token: String,
+    #[validate(length(min = 1, max = 64, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TotpCodeDto {
+    #[validate(length(min = 1, max = 64, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TotpDisableDto {
+    #[validate(length(min = 1, message = "Password is required"))]
+    pub password: String,
+    #[validate(length(min = 1, max = 64, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpStatusResponseDto {
+    pub status: String,
+    pub enabled: bool,
+    pub recovery_codes_remaining: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpSetupResponseDto {
+    pub status: String,
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+// Recovery codes are shown once, when they are generated
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodesResponseDto {
+    pub status: String,
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VerifyEmailDto {
+    #[validate(length(min = 1, message = "Token is required"))]
+    pub token: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ForgotPasswordDto {
+    #[validate(
+        length(min = 1, message = "Email is required"),
+        email(message = "Email is invalid")
+    )]
+    pub email: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ResetPasswordDto {
+    #[validate(length(min = 1, message = "Token is required"))]
+    pub token: String,
+
+    #[validate(
+        length(min = 1, message = "New password is required."),
+        length(min = 6, message = "new password must be at least 6 characters")
+    )]
+    pub new_password: String,
+
+    #[validate(
+        length(min = 1, message = "New password confirm is required."),
+        length(min = 6, message = "new password confirm must be at least 6 characters"),
+        must_match(other = "new_password", message="new passwords do not match")
+    )]
+    pub new_password_confirm: String,
+}
+
+// Browsers send the refresh token as a cookie; other clients may send it in the body
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenDto {
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionDto {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+    pub current: bool,
+}
+
+impl SessionDto {
+    pub fn filter_session(session: &Session, current_session_id: Option<uuid::Uuid>) -> Self {
+        SessionDto {
+            id: session.id.to_string(),
+            user_agent: session.user_agent.to_owned(),
+            ip_address: session.ip_address.to_owned(),
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+            current: current_session_id == Some(session.id),
+        }
+    }
+
+    pub fn filter_sessions(sessions: &[Session], current_session_id: Option<uuid::Uuid>) -> Vec<SessionDto> {
+        sessions.iter().map(|session| SessionDto::filter_session(session, current_session_id)).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionListResponseDto {
+    pub status: String,
+    pub sessions: Vec<SessionDto>,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CreateAccessTokenDto {
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+
+    #[validate(
+        length(min = 1, message = "At least one scope is required"),
+        custom = "validate_scopes"
+    )]
+    pub scopes: Vec<String>,
+
+    // Tokens without an expiry stay valid until they are revoked
+    #[validate(range(min = 1, max = 365, message = "Expiry must be between 1 and 365 days"))]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessTokenDto {
+    pub id: String,
+    pub name: String,
+    pub token_prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AccessTokenDto {
+    pub fn filter_token(token: &PersonalAccessToken) -> Self {
+        AccessTokenDto {
+            id: token.id.to_string(),
+            name: token.name.to_owned(),
+            token_prefix: token.token_prefix.to_owned(),
+            scopes: token.scopes.to_owned(),
+            created_at: token.created_at,
+            last_used_at: token.last_used_at,
+            expires_at: token.expires_at,
+        }
+    }
+
+    pub fn filter_tokens(tokens: &[PersonalAccessToken]) -> Vec<AccessTokenDto> {
+        tokens.iter().map(AccessTokenDto::filter_token).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessTokenListResponseDto {
+    pub status: String,
+    pub tokens: Vec<AccessTokenDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcProviderDto {
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcProviderListResponseDto {
+    pub status: String,
+    pub providers: Vec<OidcProviderDto>,
+}
+
+// What the identity provider sends back to the callback, on success or failure
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OidcCallbackQueryDto {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LinkIdentityDto {
+    #[validate(length(min = 1, message = "Provider is required"))]
+    pub provider: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcAuthorizationResponseDto {
+    pub status: String,
+    pub authorization_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityDto {
+    pub id: String,
+    pub provider: String,
+    pub email: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+impl IdentityDto {
+    pub fn filter_identities(identities: &[UserIdentity]) -> Vec<IdentityDto> {
+        identities
+            .iter()
+            .map(|identity| IdentityDto {
+                id: identity.id.to_string(),
+                provider: identity.provider.to_owned(),
+                email: identity.email.to_owned(),
+                created_at: identity.created_at,
+                last_login_at: identity.last_login_at,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityListResponseDto {
+    pub status: String,
+    pub identities: Vec<IdentityDto>,
+}
+
+// Name and email of a new account that signs in with a passkey only
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PasskeySignupOptionsDto {
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+    #[validate(
+        length(min = 1, message = "Email is required"),
+        email(message = "Email is invalid")
+    )]
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnOptionsResponseDto {
+    pub status: String,
+    pub options: serde_json::Value,
+}
+
+// `PublicKeyCredential.toJSON()` of a new credential; binary fields are base64url
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RegistrationCredentialDto {
+    pub id: String,
+    pub response: AttestationResponseDto,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AttestationResponseDto {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+// `PublicKeyCredential.toJSON()` of an assertion; binary fields are base64url
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AuthenticationCredentialDto {
+    pub id: String,
+    pub response: AssertionResponseDto,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AssertionResponseDto {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PasskeyRegistrationDto {
+    pub credential: RegistrationCredentialDto,
+    #[validate(length(min = 1, max = 100, message = "Passkey name must be between 1 and 100 characters"))]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PasskeyLoginDto {
+    pub credential: AuthenticationCredentialDto,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasskeyDto {
+    pub id: String,
+    pub name: String,
+    pub transports: Vec<String>,
+    pub backup_eligible: bool,
+    pub backed_up: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl PasskeyDto {
+    pub fn filter_passkey(credential: &WebauthnCredential) -> Self {
+        PasskeyDto {
+            id: credential.id.to_string(),
+            name: credential.name.to_owned(),
+            transports: credential.transports.to_owned(),
+            backup_eligible: credential.backup_eligible,
+            backed_up: credential.backed_up,
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+        }
+    }
+
+    pub fn filter_passkeys(credentials: &[WebauthnCredential]) -> Vec<PasskeyDto> {
+        credentials.iter().map(PasskeyDto::filter_passkey).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasskeyResponseDto {
+    pub status: String,
+    pub passkey: PasskeyDto,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasskeyListResponseDto {
+    pub status: String,
+    pub passkeys: Vec<PasskeyDto>,
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct AdminUserQueryDto {
+    #[validate(range(min = 1))]
+    pub page: Option<usize>,
+    #[validate(range(min = 1, max = 100))]
+    pub limit: Option<usize>,
+    #[validate(length(max = 255, message = "Search must not be more than 255 characters"))]
+    pub search: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUserDto {
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub email_verified: bool,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub file_count: i64,
+    pub stored_bytes: i64,
+}
+
+impl AdminUserDto {
+    pub fn filter_users(users: &[UserOverview]) -> Vec<AdminUserDto> {
+        users
+            .iter()
+            .map(|user| AdminUserDto {
+                id: user.id.to_string(),
+                name: user.name.to_owned(),
+                email: user.email.to_owned(),
+                role: user.role.to_owned(),
+                email_verified: user.email_verified_at.is_some(),
+                disabled_at: user.disabled_at,
+                created_at: user.created_at,
+                file_count: user.file_count,
+                stored_bytes: user.stored_bytes,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUserListResponseDto {
+    pub status: String,
+    pub users: Vec<AdminUserDto>,
+    pub results: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateRoleDto {
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminShareDto {
+    pub id: String,
+    pub file_id: String,
+    pub file_size: i64,
+    // None for link shares, which have no recipient account
+    pub recipient_email: Option<String>,
+    pub link_key: Option<String>,
+    pub status: String,
+    pub expiration_date: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl AdminShareDto {
+    pub fn filter_shares(shares: &[ShareOverview]) -> Vec<AdminShareDto> {
+        shares
+            .iter()
+            .map(|share| AdminShareDto {
+                id: share.id.to_string(),
+                file_id: share.file_id.to_string(),
+                file_size: share.file_size,
+                recipient_email: share.recipient_email.to_owned(),
+                link_key: share.link_key.to_owned(),
+                status: share.status.to_owned(),
+                expiration_date: share.expiration_date,
+                created_at: share.created_at,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminShareListResponseDto {
+    pub status: String,
+    pub shares: Vec<AdminShareDto>,
+    pub results: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageStatsDto {
+    pub users: i64,
+    pub disabled_users: i64,
+    pub files: i64,
+    pub stored_bytes: i64,
+    pub open_shares: i64,
+    pub link_shares: i64,
+    pub pending_uploads: i64,
+    pub pending_upload_bytes: i64,
+}
+
+impl StorageStatsDto {
+    pub fn filter_stats(stats: &StorageStats) -> Self {
+        StorageStatsDto {
+            users: stats.users,
+            disabled_users: stats.disabled_users,
+            files: stats.files,
+            stored_bytes: stats.stored_bytes,
+            open_shares: stats.open_shares,
+            link_shares: stats.link_shares,
+            pending_uploads: stats.pending_uploads,
+            pending_upload_bytes: stats.pending_upload_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageStatsResponseDto {
+    pub status: String,
+    pub stats: StorageStatsDto,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CreateOrganizationDto {
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OrganizationPolicyDto {
+    #[validate(
+        length(max = 50, message = "At most 50 domains can be allowed."),
+        custom = "validate_domains"
+    )]
+    pub allowed_domains: Vec<String>,
+
+    #[validate(range(min = 1, max = 3650, message = "Max expiry must be between 1 and 3650 days."))]
+    pub max_expiry_days: Option<i32>,
+}
+
+#[derive(Validate, Debug, Clone, Serialize, Deserialize)]
+pub struct InviteOrganizationMemberDto {
+    #[validate(
+        length(min = 1, message = "Email is required"),
+        email(message = "Email is invalid")
+    )]
+    pub email: String,
+    pub role: Option<OrgRole>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateOrganizationMemberDto {
+    pub role: OrgRole,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CreateGroupDto {
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AddGroupMemberDto {
+    #[validate(
+        length(min = 1, message = "Email is required"),
+        email(message = "Email is invalid")
+    )]
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationDto {
+    pub id: String,
+    pub name: String,
+    pub role: String,
+    pub allowed_domains: Vec<String>,
+    pub max_expiry_days: Option<i32>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl OrganizationDto {
+    pub fn filter_organization(organization: &Organization) -> Self {
+        OrganizationDto {
+            id: organization.id.to_string(),
+            name: organization.name.to_owned(),
+            role: organization.role.to_owned(),
+            allowed_domains: organization.allowed_domains.to_owned(),
+            max_expiry_days: organization.max_expiry_days,
+            created_at: organization.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationResponseDto {
+    pub status: String,
+    pub organization: OrganizationDto,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationMemberDto {
+    pub user_id: String,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl OrganizationMemberDto {
+    pub fn filter_member(member: &OrganizationMember) -> Self {
+        OrganizationMemberDto {
+            user_id: member.user_id.to_string(),
+            name: member.name.to_owned(),
+            email: member.email.to_owned(),
+            role: member.role.to_owned(),
+            created_at: member.created_at,
+        }
+    }
+
+    pub fn filter_members(members: &[OrganizationMember]) -> Vec<OrganizationMemberDto> {
+        members.iter().map(OrganizationMemberDto::filter_member).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationMemberListResponseDto {
+    pub status: String,
+    pub members: Vec<OrganizationMemberDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationInvitationDto {
+    pub id: String,
+    pub organization_id: String,
+    pub organization_name: String,
+    pub user_id: String,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub invited_by: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OrganizationInvitationDto {
+    pub fn filter_invitation(invitation: &OrganizationInvitation) -> Self {
+        OrganizationInvitationDto {
+            id: invitation.id.to_string(),
+            organization_id: invitation.organization_id.to_string(),
+            organization_name: invitation.organization_name.to_owned(),
+            user_id: invitation.user_id.to_string(),
+            name: invitation.name.to_owned(),
+            email: invitation.email.to_owned(),
+            role: invitation.role.to_owned(),
+            invited_by: invitation.invited_by_email.to_owned(),
+            created_at: invitation.created_at,
+            expires_at: invitation.expires_at,
+        }
+    }
+
+    pub fn filter_invitations(invitations: &[OrganizationInvitation]) -> Vec<OrganizationInvitationDto> {
+        invitations.iter().map(OrganizationInvitationDto::filter_invitation).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationInvitationResponseDto {
+    pub status: String,
+    pub invitation: OrganizationInvitationDto,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationInvitationListResponseDto {
+    pub status: String,
+    pub invitations: Vec<OrganizationInvitationDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupDto {
+    pub id: String,
+    pub name: String,
+    pub member_count: i64,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl GroupDto {
+    pub fn filter_group(group: &Group) -> Self {
+        GroupDto {
+            id: group.id.to_string(),
+            name: group.name.to_owned(),
+            member_count: group.member_count,
+            created_at: group.created_at,
+        }
+    }
+
+    pub fn filter_groups(groups: &[Group]) -> Vec<GroupDto> {
+        groups.iter().map(GroupDto::filter_group).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupResponseDto {
+    pub status: String,
+    pub group: GroupDto,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupListResponseDto {
+    pub status: String,
+    pub groups: Vec<GroupDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupMemberDto {
+    pub user_id: String,
+    pub name: String,
+    pub email: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl GroupMemberDto {
+    pub fn filter_member(member: &GroupMember) -> Self {
+        GroupMemberDto {
+            user_id: member.user_id.to_string(),
+            name: member.name.to_owned(),
+            email: member.email.to_owned(),
+            created_at: member.created_at,
+        }
+    }
+
+    pub fn filter_members(members: &[GroupMember]) -> Vec<GroupMemberDto> {
+        members.iter().map(GroupMemberDto::filter_member).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupMemberListResponseDto {
+    pub status: String,
+    pub members: Vec<GroupMemberDto>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+// Users only ever see their own events; auditors can look at anyone's
+#[derive(Serialize, Deserialize, Validate)]
+pub struct AuditQueryDto {
+    #[validate(range(min = 1))]
+    pub page: Option<usize>,
+    #[validate(range(min = 1, max = 100))]
+    pub limit: Option<usize>,
+    #[validate(length(max = 64, message = "Action must not be more than 64 characters"))]
+    pub action: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    pub user_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEventDto {
+    pub id: i64,
+    pub user_id: Option<String>,
+    pub action: String,
+    pub outcome: AuditOutcome,
+    pub target_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: serde_json::Value,
+    pub occurred_at: DateTime<Utc>,
+    pub hash: String,
+}
+
+impl AuditEventDto {
+    pub fn filter_events(events: &[AuditEvent]) -> Vec<AuditEventDto> {
+        events
+            .iter()
+            .map(|event| AuditEventDto {
+                id: event.id,
+                user_id: event.user_id.map(|id| id.to_string()),
+                action: event.action.to_owned(),
+                outcome: match event.succeeded {
+                    true => AuditOutcome::Success,
+                    false => AuditOutcome::Failure,
+                },
+                target_id: event.target_id.map(|id| id.to_string()),
+                ip_address: event.ip_address.to_owned(),
+                user_agent: event.user_agent.to_owned(),
+                details: event.details.clone(),
+                occurred_at: event.occurred_at,
+                hash: event.hash.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEventListResponseDto {
+    pub status: String,
+    pub events: Vec<AuditEventDto>,
+    pub results: i64,
+}
+
+// The first event whose hash or link to the previous one no longer matches, if any
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditVerifyResponseDto {
+    pub status: String,
+    pub verified: bool,
+    pub events: i64,
+    pub first_invalid_id: Option<i64>,
+}
+
+// The token itself is shown once, when it is created
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateAccessTokenResponseDto {
+    pub status: String,
+    pub token: String,
+    pub details: AccessTokenDto,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -219,9 +1019,43 @@ pub struct EmailListResponseDto {
 }
 
 #[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UserKeysDto {
+    #[validate(length(min = 1, message = "Public key is required"))]
+    pub public_key: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PublicKeyQueryDto {
+    #[validate(
+        length(min = 1, message = "Email is required"),
+        email(message = "Email is invalid")
+    )]
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeyResponseDto {
+    pub status: String,
+    pub email: String,
+    pub public_key: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+#[validate(schema(function = "validate_share_targets", skip_on_field_errors = false))]
 pub struct FileUploadDtos {
-    #[validate(email(message = "Invalid email format"))]
-    pub recipient_email: String,
+    #[validate(
+        length(max = 50, message = "A file can be shared with at most 50 recipients."),
+        custom = "validate_recipient_emails"
+    )]
+    pub recipient_emails: Vec<String>,
+
+    // Groups of the sender's organization, sent to whoever is in them at upload time
+    #[validate(length(max = 10, message = "A file can be shared with at most 10 groups."))]
+    pub recipient_groups: Vec<String>,
+
+    // "fragment" or "password" to also create a link share for people without an account
+    #[validate(custom = "validate_share_link")]
+    pub share_link: Option<String>,
 
     #[validate(
         length(min = 1, message = "New password is required."),
@@ -231,6 +1065,145 @@ pub struct FileUploadDtos {
 
     #[validate(custom = "validate_expiration_date")]
     pub expiration_date: String,
+
+    // Downloads allowed per share before it is deleted; unlimited when absent
+    #[validate(range(min = 1, max = 10000, message = "Max downloads must be between 1 and 10000."))]
+    pub max_downloads: Option<i32>,
+
+    // Shorthand for a single download
+    pub burn_after_reading: bool,
+
+    // The sender's organization policy, filled in by the server before validating
+    #[serde(skip)]
+    pub policy: Option<SharePolicy>,
+}
+
+impl FileUploadDtos {
+    pub fn download_limit(&self) -> Option<i32> {
+        match self.burn_after_reading {
+            true => Some(1),
+            false => self.max_downloads,
+        }
+    }
+}
+
+fn validate_share_targets(form_data: &FileUploadDtos) -> Result<(), ValidationError> {
+    if form_data.recipient_emails.is_empty() && form_data.recipient_groups.is_empty() && form_data.share_link.is_none() {
+        let mut error = ValidationError::new("recipient_required");
+        error.message = Some("At least one recipient or a share link is required.".into());
+        return Err(error);
+    }
+
+    validate_share_policy(form_data)
+}
+
+fn validate_share_policy(form_data: &FileUploadDtos) -> Result<(), ValidationError> {
+    let Some(policy) = &form_data.policy else {
+        return Ok(());
+    };
+
+    if !policy.allowed_domains.is_empty() {
+        // Anyone can open a link, whatever their domain
+        if form_data.share_link.is_some() {
+            let mut error = ValidationError::new("share_link_not_allowed");
+            error.message = Some("Your organization only allows sharing with its allowed domains, so share links are disabled.".into());
+            return Err(error);
+        }
+
+        for email in &form_data.recipient_emails {
+            let domain = email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase()).unwrap_or_default();
+
+            if !policy.allowed_domains.contains(&domain) {
+                let mut error = ValidationError::new("recipient_domain_not_allowed");
+                error.message = Some(format!("Your organization does not allow sharing with {}.", email).into());
+                return Err(error);
+            }
+        }
+    }
+
+    validate_max_expiry(policy, &form_data.expiration_date)
+}
+
+fn validate_expiration_policy(body: &ShareExpirationUpdateDto) -> Result<(), ValidationError> {
+    match &body.policy {
+        Some(policy) => validate_max_expiry(policy, &body.expiration_date),
+        None => Ok(()),
+    }
+}
+
+fn validate_max_expiry(policy: &SharePolicy, expiration_date: &str) -> Result<(), ValidationError> {
+    // An unparsable date is reported by `validate_expiration_date`
+    let (Some(max_expiry_days), Ok(parsed_date)) = (policy.max_expiry_days, DateTime::parse_from_rfc3339(expiration_date)) else {
+        return Ok(());
+    };
+
+    if parsed_date > Utc::now() + Duration::days(max_expiry_days as i64) {
+        let mut error = ValidationError::new("expiration_date_policy");
+        error.message = Some(format!("Your organization allows shares to stay open for at most {} days.", max_expiry_days).into());
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn validate_domains(domains: &[String]) -> Result<(), ValidationError> {
+    for domain in domains {
+        let valid = !domain.is_empty()
+            && domain.len() <= 253
+            && domain.contains('.')
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+
+        if !valid {
+            let mut error = ValidationError::new("invalid_domain");
+            error.message = Some(format!("Invalid domain: {}", domain).into());
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_scopes(scopes: &[String]) -> Result<(), ValidationError> {
+    if scopes.iter().any(|scope| Scope::try_from(scope.as_str()).is_err()) {
+        let mut error = ValidationError::new("invalid_scope");
+        error.message = Some("Scopes must be files:send, files:receive or users:read.".into());
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn validate_share_link(share_link: &str) -> Result<(), ValidationError> {
+    if share_link != "fragment" && share_link != "password" {
+        let mut error = ValidationError::new("invalid_share_link");
+        error.message = Some("Share link must be either fragment or password.".into());
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn validate_recipient_emails(recipient_emails: &[String]) -> Result<(), ValidationError> {
+    for (index, email) in recipient_emails.iter().enumerate() {
+        if !validator::validate_email(email) {
+            let mut error = ValidationError::new("invalid_email");
+            error.message = Some(format!("Invalid email format: {}", email).into());
+            return Err(error);
+        }
+
+        if recipient_emails[..index].contains(email) {
+            let mut error = ValidationError::new("duplicate_recipient");
+            error.message = Some(format!("{} is listed more than once.", email).into());
+            return Err(error);
+        }
+    }
+
+    Ok(())
 }
 
 fn validate_expiration_date(expiration_date: &str) -> Result<(), ValidationError> {
@@ -271,8 +1244,54 @@ pub struct RetrieveFileDto {
 }
 
 
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+#[validate(schema(function = "validate_expiration_policy", skip_on_field_errors = false))]
+pub struct ShareExpirationUpdateDto {
+    #[validate(custom = "validate_expiration_date")]
+    pub expiration_date: String,
+
+    // The sender's organization policy, filled in by the server before validating
+    #[serde(skip)]
+    pub policy: Option<SharePolicy>,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SharePasswordUpdateDto {
+    #[validate(
+        length(min = 1, message = "New password is required."),
+        length(min = 6, message = "New password must be at least 6 characters")
+    )]
+    pub password: String,
+
+    // Needed for password links, whose file key is wrapped under the current password
+    pub current_password: Option<String>,
+}
+
 #[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
 pub struct DownloadFileDto {
     #[validate(length(min = 1, message = "Shared id is required"))]
     pub shared_id: String,
+}
+
+#[derive(Validate, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PublicDownloadDto {
+    #[validate(length(min = 1, message = "Password is required."))]
+    pub password: String,
+
+    // File key from the link's URL fragment, base64url encoded; not used by password links
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareLinkDto {
+    pub shared_id: String,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadFileResponseDto {
+    pub status: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_link: Option<ShareLinkDto>,
 }
\ No newline at end of file
@@ -28,11 +28,18 @@ pub enum ErrorMessage {
     EmailExist,
     UserNoLongerExist,
     TokenNotProvided,
+    KeysLocked,
+    InvalidRefreshToken,
+    EmailNotVerified,
+    MissingScope(&'static str),
+    SessionRequired,
+    AccountDisabled,
+    PermissionDenied,
 }
 
-impl ToString for ErrorMessage {
-    fn to_string(&self) -> String {
-        self.to_str().to_owned()
+impl fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_str())
     }
 }
 
@@ -48,6 +55,13 @@ impl ErrorMessage {
             ErrorMessage::ExceededMaxPasswordLength(max_length) => format!("Password must not be more than {} characters", max_length),
             ErrorMessage::InvalidToken => "Authentication token is invalid or expired".to_string(),
             ErrorMessage::TokenNotProvided => "You are not logged in, please provide a token".to_string(),
+            ErrorMessage::KeysLocked => "Your encryption keys are not unlocked on this server, please log in again".to_string(),
+            ErrorMessage::EmailNotVerified => "Please verify your email address first".to_string(),
+            ErrorMessage::MissingScope(scope) => format!("This access token is missing the {} scope", scope),
+            ErrorMessage::SessionRequired => "This action requires logging in, access tokens cannot be used".to_string(),
+            ErrorMessage::InvalidRefreshToken => "Your session has expired or was revoked, please log in again".to_string(),
+            ErrorMessage::AccountDisabled => "Your account has been disabled, please contact an administrator".to_string(),
+            ErrorMessage::PermissionDenied => "You do not have permission to perform this action".to_string(),
         }
     }
 }
@@ -59,7 +73,6 @@ pub struct HttpError {
 }
 
 impl HttpError {
-    #[allow(dead_code)]
     pub fn new(message: impl Into<String>, status: StatusCode) -> Self {
         HttpError {
             message: message.into(),
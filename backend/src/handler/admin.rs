@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, Query}, response::IntoResponse, routing::{get, post, put}, Extension, Json, Router};
+use validator::Validate;
+
+use crate::{db::UserExt, dtos::{AdminShareDto, AdminShareListResponseDto, AdminUserDto, AdminUserListResponseDto, AdminUserQueryDto, RequestQueryDto, Response, StorageStatsDto, StorageStatsResponseDto, UpdateRoleDto}, error::HttpError, middleware::JWTAuthMiddeware, models::AuditAction, utils::{audit::{self, AuditEntry}, session::ClientInfo}, AppState};
+
+/// What auditors may see: usage across accounts, never file names or content.
+pub fn admin_read_handler() -> Router {
+    Router::new()
+        .route("/users", get(list_users))
+        .route("/users/:user_id/shares", get(list_user_shares))
+        .route("/stats", get(storage_stats))
+}
+
+/// What only admins may do.
+pub fn admin_handler() -> Router {
+    Router::new()
+        .route("/users/:user_id/role", put(update_role))
+        .route("/users/:user_id/disable", post(disable_user))
+        .route("/users/:user_id/enable", post(enable_user))
+        .route("/users/:user_id/shares/expire", post(expire_user_shares))
+        .route("/shares/:share_id/expire", post(expire_share))
+}
+
+fn parse_id(id: &str, what: &str) -> Result<uuid::Uuid, HttpError> {
+    uuid::Uuid::parse_str(id).map_err(|e| HttpError::bad_request(format!("Invalid {} ID: {}", what, e)))
+}
+
+pub async fn list_users(
+    Query(query_params): Query<AdminUserQueryDto>,
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Result<impl IntoResponse, HttpError> {
+    query_params.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let page = query_params.page.unwrap_or(1);
+    let limit = query_params.limit.unwrap_or(20);
+    let search = query_params.search.as_deref().map(str::trim).filter(|search| !search.is_empty());
+
+    let (users, total_count) = app_state.db_client
+        .get_user_overviews(search, page as u32, limit)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = AdminUserListResponseDto {
+        status: "success".to_string(),
+        users: AdminUserDto::filter_users(&users),
+        results: total_count,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn list_user_shares(
+    Query(query_params): Query<RequestQueryDto>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    query_params.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let user_id = parse_id(&user_id, "user")?;
+    let page = query_params.page.unwrap_or(1);
+    let limit = query_params.limit.unwrap_or(10);
+
+    let (shares, total_count) = app_state.db_client
+        .get_share_overviews(user_id, page as u32, limit)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = AdminShareListResponseDto {
+        status: "success".to_string(),
+        shares: AdminShareDto::filter_shares(&shares),
+        results: total_count,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn storage_stats(
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let stats = app_state.db_client
+        .get_storage_stats()
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = StorageStatsResponseDto {
+        status: "success".to_string(),
+        stats: StorageStatsDto::filter_stats(&stats),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn update_role(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(admin): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Path(user_id): Path<String>,
+    Json(body): Json<UpdateRoleDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    let user_id = parse_id(&user_id, "user")?;
+
+    // Otherwise the last admin could lock everyone out of the admin API
+    if user_id == admin.user.id {
+        return Err(HttpError::bad_request("You cannot change your own role".to_string()));
+    }
+
+    let updated = app_state.db_client
+        .update_user_role(user_id, body.role)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !updated {
+        return Err(HttpError::not_found("User not found".to_string()));
+    }
+
+    tracing::info!("Admin {} changed the role of user {} to {}", admin.user.id, user_id, body.role.as_str());
+
+    let entry = AuditEntry::succeeded(AuditAction::RoleChanged, Some(admin.user.id))
+        .target(user_id)
+        .details(serde_json::json!({ "role": body.role.as_str() }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response {
+        status: "success",
+        message: format!("Role changed to {}", body.role.as_str()),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn disable_user(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(admin): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let user_id = parse_id(&user_id, "user")?;
+
+    if user_id == admin.user.id {
+        return Err(HttpError::bad_request("You cannot disable your own account".to_string()));
+    }
+
+    let disabled = app_state.db_client
+        .set_user_disabled(user_id, true)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !disabled {
+        return Err(HttpError::not_found("User not found".to_string()));
+    }
+
+    // Signed-in devices are logged out; the account's shares stay as they are
+    app_state.db_client
+        .revoke_user_sessions(user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    app_state.key_cache.remove(user_id);
+
+    tracing::info!("Admin {} disabled user {}", admin.user.id, user_id);
+
+    let entry = AuditEntry::succeeded(AuditAction::UserDisabled, Some(admin.user.id)).target(user_id);
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response {
+        status: "success",
+        message: "Account disabled".to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn enable_user(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(admin): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let user_id = parse_id(&user_id, "user")?;
+
+    let enabled = app_state.db_client
+        .set_user_disabled(user_id, false)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !enabled {
+        return Err(HttpError::not_found("User not found".to_string()));
+    }
+
+    tracing::info!("Admin {} enabled user {}", admin.user.id, user_id);
+
+    let entry = AuditEntry::succeeded(AuditAction::UserEnabled, Some(admin.user.id)).target(user_id);
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response {
+        status: "success",
+        message: "Account enabled".to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn expire_user_shares(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(admin): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let user_id = parse_id(&user_id, "user")?;
+
+    let expired = app_state.db_client
+        .force_expire_shares(None, Some(user_id))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    tracing::info!("Admin {} expired {} shares sent by user {}", admin.user.id, expired, user_id);
+
+    let entry = AuditEntry::succeeded(AuditAction::SharesExpired, Some(admin.user.id))
+        .target(user_id)
+        .details(serde_json::json!({ "expired": expired }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response {
+        status: "success",
+        message: format!("Expired {} shares", expired),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn expire_share(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(admin): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Path(share_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let share_id = parse_id(&share_id, "share")?;
+
+    let expired = app_state.db_client
+        .force_expire_shares(Some(share_id), None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if expired == 0 {
+        return Err(HttpError::not_found("Share not found or no longer open".to_string()));
+    }
+
+    tracing::info!("Admin {} expired share {}", admin.user.id, share_id);
+
+    let entry = AuditEntry::succeeded(AuditAction::SharesExpired, Some(admin.user.id))
+        .target(share_id)
+        .details(serde_json::json!({ "expired": 1 }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response {
+        status: "success",
+        message: "Share expired".to_string(),
+    };
+
+    Ok(Json(response))
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use axum::{extract::Query, response::IntoResponse, routing::get, Extension, Json, Router};
+use validator::Validate;
+
+use crate::{db::UserExt, dtos::{AuditEventDto, AuditEventListResponseDto, AuditOutcome, AuditQueryDto, AuditVerifyResponseDto}, error::HttpError, middleware::JWTAuthMiddeware, models::{AuditAction, AuditFilter, Role}, utils::audit::{event_hash_matches, GENESIS_HASH}, AppState};
+
+/// Events read back from the chain at a time while verifying it.
+const VERIFY_BATCH_SIZE: i64 = 1000;
+
+/// Open to every signed-in account, scoped to its own events below the auditor role.
+pub fn audit_handler() -> Router {
+    Router::new()
+        .route("/", get(list_events))
+}
+
+/// Walks the whole log, so it is kept to auditors.
+pub fn audit_verify_handler() -> Router {
+    Router::new()
+        .route("/verify", get(verify_chain))
+}
+
+pub async fn list_events(
+    Query(query_params): Query<AuditQueryDto>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    query_params.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let page = query_params.page.unwrap_or(1);
+    let limit = query_params.limit.unwrap_or(20);
+
+    let requested_user_id = query_params.user_id
+        .as_deref()
+        .map(|user_id| uuid::Uuid::parse_str(user_id)
+            .map_err(|e| HttpError::bad_request(format!("Invalid user ID: {}", e))))
+        .transpose()?;
+
+    let user_id = match user.role >= Role::Auditor {
+        true => requested_user_id,
+        false => Some(user.user.id),
+    };
+
+    let filter = AuditFilter {
+        user_id,
+        action: query_params.action.as_deref().map(AuditAction::try_from).transpose()?,
+        succeeded: query_params.outcome.map(|outcome| outcome == AuditOutcome::Success),
+        since: query_params.since,
+        until: query_params.until,
+    };
+
+    let (events, total_count) = app_state.db_client
+        .get_audit_events(&filter, page as u32, limit)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = AuditEventListResponseDto {
+        status: "success".to_string(),
+        events: AuditEventDto::filter_events(&events),
+        results: total_count,
+    };
+
+    Ok(Json(response))
+}
+
+/// Recomputes every hash in order. An edit shows up as a hash that no longer matches its
+/// event, a removed event as a `prev_hash` that no longer matches the event before it.
+pub async fn verify_chain(
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let mut prev_hash = GENESIS_HASH.to_vec();
+    let mut after_id = 0;
+    let mut checked = 0;
+    let mut first_invalid_id = None;
+
+    'chain: loop {
+        let events = app_state.db_client
+            .get_audit_chain(after_id, VERIFY_BATCH_SIZE)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if events.is_empty() {
+            break;
+        }
+
+        for event in events {
+            if event.prev_hash != prev_hash || !event_hash_matches(&event) {
+                first_invalid_id = Some(event.id);
+                break 'chain;
+            }
+
+            checked += 1;
+            after_id = event.id;
+            prev_hash = event.hash;
+        }
+    }
+
+    let response = AuditVerifyResponseDto {
+        status: "success".to_string(),
+        verified: first_invalid_id.is_none(),
+        events: checked,
+        first_invalid_id,
+    };
+
+    Ok(Json(response))
+}
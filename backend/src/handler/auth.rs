@@ -2,17 +2,32 @@ use std::sync::Arc;
 
 use axum::{extract::{Request}, http::{header, HeaderMap}, response::IntoResponse, routing::{post, get}, Extension, Json, Router};
 use axum_extra::extract::cookie::{Cookie, CookieJar};
+use chrono::{DateTime, Utc};
 use validator::Validate;
 use serde::Serialize;
 
-use crate::{db::UserExt, dtos::{LoginUserDto, RegisterUserDto, UserLoginResponseDto}, error::{ErrorMessage, HttpError}, utils::{keys::generate_key, password, token}, AppState};
+use crate::{db::UserExt, dtos::{ForgotPasswordDto, LoginUserDto, MfaLoginDto, MfaRequiredResponseDto, RefreshTokenDto, RegisterUserDto, ResetPasswordDto, Response, UserLoginResponseDto, VerifyEmailDto}, error::{ErrorMessage, HttpError}, handler::{oidc::oidc_handler, webauthn::webauthn_handler}, middleware::{request_token, verify_access_token}, mail::{password_reset_email, send_in_background, verification_email}, models::{AuditAction, User}, utils::{audit::{self, AuditEntry}, keys::{generate_key, reset_private_key, rewrap_user_legacy_keys, unlock_passwordless_key, unlock_private_key, KeyReset}, password, session::{refresh_session, start_session, ClientInfo, SessionTokens}, token::{self, TokenPurpose}, totp::verify_second_factor}, AppState};
+
+/// How long the password step of a login stays valid while the second factor is entered.
+const MFA_TOKEN_MAXAGE_MINUTES: i64 = 5;
+
+const EMAIL_VERIFICATION_MAXAGE_MINUTES: i64 = 24 * 60;
+
+const PASSWORD_RESET_MAXAGE_MINUTES: i64 = 30;
 
 pub fn auth_handler() -> Router {
     Router::new()
         .route("/register", post(register))
         .route("/login", post(login))
+        .route("/login/mfa", post(login_mfa))
+        .route("/refresh", post(refresh))
         .route("/logout", post(logout))
         .route("/verify", get(verify_token))
+        .route("/verify-email", post(verify_email))
+        .route("/forgot-password", post(forgot_password))
+        .route("/reset-password", post(reset_password))
+        .nest("/oidc", oidc_handler())
+        .nest("/webauthn", webauthn_handler())
 }
 
 fn create_auth_cookie(token: &str, maxage_minutes: Option<i64>) -> Cookie<'static> {
@@ -30,11 +45,29 @@ fn create_auth_cookie(token: &str, maxage_minutes: Option<i64>) -> Cookie<'stati
         .build()
 }
 
-fn create_auth_response<T: Serialize>(data: T, cookie: Cookie<'static>) -> axum::response::Response {
+// The refresh token is only ever sent back to the auth routes
+fn create_refresh_cookie(refresh_token: &str, maxage_days: Option<i64>) -> Cookie<'static> {
+    let duration = match maxage_days {
+        Some(days) => time::Duration::days(days),
+        None => time::Duration::seconds(0)
+    };
+
+    Cookie::build(("refresh_token", refresh_token.to_string()))
+        .path("/api/auth")
+        .max_age(duration)
+        .secure(true)
+        .same_site(axum_extra::extract::cookie::SameSite::None)
+        .http_only(true)
+        .build()
+}
+
+pub fn create_auth_response<T: Serialize>(data: T, cookies: Vec<Cookie<'static>>) -> axum::response::Response {
     let json_response = Json(data);
     
     let mut headers = HeaderMap::new();
-    headers.append(header::SET_COOKIE, cookie.to_string().parse().unwrap());
+    for cookie in cookies {
+        headers.append(header::SET_COOKIE, cookie.to_string().parse().unwrap());
+    }
 
     
     let mut response = json_response.into_response();
@@ -43,8 +76,34 @@ fn create_auth_response<T: Serialize>(data: T, cookie: Cookie<'static>) -> axum:
     response
 }
 
+/// Cookies that end the session in the browser.
+pub fn clear_auth_cookies() -> Vec<Cookie<'static>> {
+    vec![create_auth_cookie("", None), create_refresh_cookie("", None)]
+}
+
+/// Cookies that carry a new session to the browser.
+pub fn session_cookies(app_state: &Arc<AppState>, tokens: &SessionTokens) -> Vec<Cookie<'static>> {
+    vec![
+        create_auth_cookie(&tokens.access_token, Some(app_state.env.jwt_maxage)),
+        create_refresh_cookie(&tokens.refresh_token, Some(app_state.env.refresh_token_maxage_days)),
+    ]
+}
+
+pub fn create_session_response(app_state: &Arc<AppState>, tokens: SessionTokens) -> axum::response::Response {
+    let cookies = session_cookies(app_state, &tokens);
+
+    let response_data = UserLoginResponseDto {
+        status: "success".to_string(),
+        token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+    };
+
+    create_auth_response(response_data, cookies)
+}
+
 pub async fn register(
     Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
     Json(body): Json<RegisterUserDto>
 ) -> Result<impl IntoResponse, HttpError> {
     body.validate()
@@ -59,22 +118,13 @@ pub async fn register(
 
     match result {
         Ok(user) => {
-            let _key_result = generate_key(&app_state, &user).await?;
+            let private_key = generate_key(&app_state, &user, &body.password).await?;
+            send_verification_email(&app_state, &user)?;
+            app_state.key_cache.insert(user.id, private_key, app_state.env.jwt_maxage);
             
-            let token = token::create_token(
-                &user.id.to_string(), 
-                &app_state.env.jwt_secret.as_bytes(), 
-                app_state.env.jwt_maxage
-            ).map_err(|e| HttpError::server_error(e.to_string()))?;
+            let tokens = start_session(&app_state, user.id, &client).await?;
             
-            let cookie = create_auth_cookie(&token, Some(app_state.env.jwt_maxage * 60));
-            
-            let response_data = UserLoginResponseDto {
-                status: "success".to_string(),
-                token: token.clone(),
-            };
-            
-            Ok(create_auth_response(response_data, cookie))
+            Ok(create_session_response(&app_state, tokens))
         },
         Err(sqlx::Error::Database(db_err)) => {
             if db_err.is_unique_violation() {
@@ -91,6 +141,7 @@ pub async fn register(
 
 pub async fn login(
     Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
     Json(body): Json<LoginUserDto>
 ) -> Result<impl IntoResponse, HttpError> {
     body.validate()
@@ -101,41 +152,212 @@ pub async fn login(
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
-    let user = result.ok_or(HttpError::bad_request(ErrorMessage::WrongCredentials.to_string()))?;
+    let Some(user) = result else {
+        let entry = AuditEntry::failed(AuditAction::Login, None)
+            .details(serde_json::json!({ "method": "password", "email": body.email }));
+        audit::record(&app_state, Some(&client), entry).await;
 
-    let password_matched = password::compare(&body.password, &user.password)
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
+        return Err(HttpError::bad_request(ErrorMessage::WrongCredentials.to_string()));
+    };
+
+    // Accounts created through single sign-on have no password to log in with
+    let password_matched = match &user.password {
+        Some(hashed_password) => password::compare(&body.password, hashed_password)
+            .map_err(|e| HttpError::server_error(e.to_string()))?,
+        None => false,
+    };
 
     if password_matched {
-        let token = token::create_token(
-            &user.id.to_string(), 
-            &app_state.env.jwt_secret.as_bytes(), 
-            app_state.env.jwt_maxage
-        )
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
+        let private_key = unlock_private_key(&app_state.db_client, &user, &body.password).await?;
+
+        if let Some(private_key) = &private_key {
+            // Upgrade any PKCS#1 v1.5 key wraps while the private key is unlocked
+            let db_client = app_state.db_client.clone();
+            let rewrap_key = private_key.clone();
+            let user_id = user.id;
+            tokio::spawn(async move {
+                if let Err(e) = rewrap_user_legacy_keys(&db_client, user_id, &rewrap_key).await {
+                    tracing::error!("Failed to re-wrap legacy keys for user {}: {}", user_id, e);
+                }
+            });
+        }
+
+        // The password alone is not enough; the client continues at /login/mfa, and the key
+        // stays out of the cache until the second factor is checked there
+        if let Some(mfa_token) = create_mfa_token(&app_state, user.id).await? {
+            if let Some(private_key) = private_key {
+                app_state.key_cache.hold(&mfa_token, user.id, private_key, MFA_TOKEN_MAXAGE_MINUTES);
+            }
+
+            let response_data = MfaRequiredResponseDto {
+                status: "mfa_required".to_string(),
+                mfa_token,
+            };
+
+            return Ok(Json(response_data).into_response());
+        }
+
+        // No second factor to wait for, so the key lives as long as the session
+        if let Some(private_key) = private_key {
+            app_state.key_cache.insert(user.id, private_key, app_state.env.jwt_maxage);
+        }
+
+        let tokens = start_session(&app_state, user.id, &client).await?;
 
-        let cookie = create_auth_cookie(&token, Some(app_state.env.jwt_maxage * 60));
-        
-        let response_data = UserLoginResponseDto {
-            status: "success".to_string(),
-            token: token.clone(),
-        };
-        
-        Ok(create_auth_response(response_data, cookie))
+        let entry = AuditEntry::succeeded(AuditAction::Login, Some(user.id))
+            .details(serde_json::json!({ "method": "password" }));
+        audit::record(&app_state, Some(&client), entry).await;
+
+        Ok(create_session_response(&app_state, tokens))
     } else {
+        let entry = AuditEntry::failed(AuditAction::Login, Some(user.id))
+            .details(serde_json::json!({ "method": "password" }));
+        audit::record(&app_state, Some(&client), entry).await;
+
         Err(HttpError::bad_request(ErrorMessage::WrongCredentials.to_string()))
     }
 }
 
-pub async fn logout() -> Result<impl IntoResponse, HttpError> {
-    let cookie = create_auth_cookie("", None); // Empty token with no duration (immediate expiry)
-    
+/// A token for the second step of a login, or `None` when the account has no two-factor
+/// authentication set up and the first step was enough.
+pub async fn create_mfa_token(app_state: &Arc<AppState>, user_id: uuid::Uuid) -> Result<Option<String>, HttpError> {
+    let totp = app_state.db_client
+        .get_user_totp(user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if totp.is_none_or(|totp| totp.enabled_at.is_none()) {
+        return Ok(None);
+    }
+
+    let mfa_token = token::create_purpose_token(
+        &user_id.to_string(),
+        TokenPurpose::Mfa,
+        None,
+        &app_state.jwt_keys,
+        MFA_TOKEN_MAXAGE_MINUTES,
+    )
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(Some(mfa_token))
+}
+
+pub async fn login_mfa(
+    Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
+    Json(body): Json<MfaLoginDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let claims = token::decode_purpose_token(&body.mfa_token, TokenPurpose::Mfa, &app_state.jwt_keys)?;
+
+    let user_id = uuid::Uuid::parse_str(&claims.sub)
+        .map_err(|_| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+    if let Err(e) = verify_second_factor(&app_state, user_id, &body.code).await {
+        let entry = AuditEntry::failed(AuditAction::Login, Some(user_id))
+            .details(serde_json::json!({ "method": "totp" }));
+        audit::record(&app_state, Some(&client), entry).await;
+
+        return Err(e);
+    }
+
+    // A password login left its key with the token; an account without a password, signing
+    // in through single sign-on, unlocks its key only now
+    app_state.key_cache.release(&body.mfa_token, user_id, app_state.env.jwt_maxage);
+
+    let user = app_state.db_client
+        .get_user(Some(user_id), None, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::unauthorized(ErrorMessage::UserNoLongerExist.to_string()))?;
+
+    unlock_passwordless_key(&app_state, &user).await?;
+
+    let tokens = start_session(&app_state, user_id, &client).await?;
+
+    let entry = AuditEntry::succeeded(AuditAction::Login, Some(user_id))
+        .details(serde_json::json!({ "method": "totp" }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    Ok(create_session_response(&app_state, tokens))
+}
+
+pub async fn refresh(
+    cookie_jar: CookieJar,
+    Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
+    body: Option<Json<RefreshTokenDto>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let refresh_token = body
+        .and_then(|Json(body)| body.refresh_token)
+        .or_else(|| cookie_jar.get("refresh_token").map(|cookie| cookie.value().to_string()))
+        .ok_or_else(|| HttpError::unauthorized(ErrorMessage::TokenNotProvided.to_string()))?;
+
+    let tokens = refresh_session(&app_state, &refresh_token, &client).await?;
+
+    // An unlocked private key stays available for as long as the session is kept alive
+    app_state.key_cache.extend(tokens.user_id, app_state.env.jwt_maxage);
+
+    Ok(create_session_response(&app_state, tokens))
+}
+
+pub async fn logout(
+    cookie_jar: CookieJar,
+    Extension(app_state): Extension<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, HttpError> {
+    let mut user_id = None;
+
+    // Revoke the access token itself, so it stops working before it expires
+    if let Some(token) = request_token(&cookie_jar, &headers)
+        && let Ok(claims) = token::decode_token(token, &app_state.jwt_keys)
+        && let Ok(token_user_id) = uuid::Uuid::parse_str(&claims.sub)
+    {
+        let expires_at = DateTime::from_timestamp(claims.exp as i64, 0).unwrap_or_else(Utc::now);
+
+        app_state.db_client
+            .revoke_token(claims.jti, expires_at)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        app_state.db_client
+            .revoke_session(claims.sid, token_user_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        user_id = Some(token_user_id);
+    }
+
+    // The refresh token still identifies the session once the access token has expired
+    if let Some(refresh_token) = cookie_jar.get("refresh_token").map(|cookie| cookie.value().to_string()) {
+        let refresh_user_id = app_state.db_client
+            .revoke_refresh_token(token::hash_refresh_token(&refresh_token))
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        user_id = user_id.or(refresh_user_id);
+    }
+
+    // Lock the user's private key again once their last session ends
+    if let Some(user_id) = user_id {
+        let sessions = app_state.db_client
+            .get_active_sessions(user_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if sessions.is_empty() {
+            app_state.key_cache.remove(user_id);
+        }
+    }
+
     let response_data = serde_json::json!({
         "status": "success",
         "message": "Logged out successfully"
     });
     
-    Ok(create_auth_response(response_data, cookie))
+    Ok(create_auth_response(response_data, clear_auth_cookies()))
 }
 
 pub async fn verify_token(
@@ -143,26 +365,12 @@ pub async fn verify_token(
     Extension(app_state): Extension<Arc<AppState>>,
     request: Request,
 ) -> Result<impl IntoResponse, HttpError> {
-    let token = cookie_jar
-        .get("token")
-        .map(|cookie| cookie.value().to_string())
-        .or_else(|| {
-            request.headers()
-                .get(header::AUTHORIZATION)
-                .and_then(|auth_header| auth_header.to_str().ok())
-                .and_then(|auth_value| {
-                    if auth_value.starts_with("Bearer ") {
-                        Some(auth_value[7..].to_owned())
-                    } else {
-                        None
-                    }
-                })  
-        })
+    let token = request_token(&cookie_jar, request.headers())
         .ok_or_else(|| {
             HttpError::unauthorized(ErrorMessage::TokenNotProvided.to_string())
         })?;
 
-    let _token_details = token::decode_token(&token, app_state.env.jwt_secret.as_bytes())?;
+    let _token_details = verify_access_token(&app_state, token).await?;
     let response_data = serde_json::json!({
         "status": "success",
         "verified": true,
@@ -170,4 +378,152 @@ pub async fn verify_token(
     });
     
     Ok(Json(response_data))
-}
\ No newline at end of file
+}
+
+/// Emails the user a link that proves they own their address. The token is bound to the
+/// address, so it stops working if the address changes.
+pub fn send_verification_email(app_state: &Arc<AppState>, user: &User) -> Result<(), HttpError> {
+    let token = token::create_purpose_token(
+        &user.id.to_string(),
+        TokenPurpose::EmailVerification,
+        Some(&user.email),
+        &app_state.jwt_keys,
+        EMAIL_VERIFICATION_MAXAGE_MINUTES,
+    )
+    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let link = format!("{}/auth/verify-email?token={}", app_state.env.client_url, token);
+    send_in_background(&app_state.mailer, verification_email(&user.email, &user.name, &link));
+
+    Ok(())
+}
+
+pub async fn verify_email(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(body): Json<VerifyEmailDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let claims = token::decode_purpose_token(&body.token, TokenPurpose::EmailVerification, &app_state.jwt_keys)?;
+
+    let user_id = uuid::Uuid::parse_str(&claims.sub)
+        .map_err(|_| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+    let user = app_state.db_client
+        .get_user(Some(user_id), None, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .filter(|user| claims.is_bound_to(&user.email))
+        .ok_or_else(|| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+    app_state.db_client
+        .verify_user_email(user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = Response {
+        message: "Email verified successfully".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn forgot_password(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(body): Json<ForgotPasswordDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let user = app_state.db_client
+        .get_user(None, None, Some(&body.email))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // The token is bound to the current password hash, so it works once and dies with any change.
+    // Accounts without a password can set one this way.
+    if let Some(user) = user {
+        let token = token::create_purpose_token(
+            &user.id.to_string(),
+            TokenPurpose::PasswordReset,
+            Some(user.password.as_deref().unwrap_or_default()),
+            &app_state.jwt_keys,
+            PASSWORD_RESET_MAXAGE_MINUTES,
+        )
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        let link = format!("{}/auth/reset-password?token={}", app_state.env.client_url, token);
+        send_in_background(&app_state.mailer, password_reset_email(&user.email, &user.name, &link));
+    }
+
+    // The same answer either way, so the endpoint does not reveal who has an account
+    let response = Response {
+        message: "If an account exists for this email, a password reset link has been sent".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn reset_password(
+    Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
+    Json(body): Json<ResetPasswordDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let claims = token::decode_purpose_token(&body.token, TokenPurpose::PasswordReset, &app_state.jwt_keys)?;
+
+    let user_id = uuid::Uuid::parse_str(&claims.sub)
+        .map_err(|_| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+    let user = app_state.db_client
+        .get_user(Some(user_id), None, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .filter(|user| claims.is_bound_to(user.password.as_deref().unwrap_or_default()))
+        .ok_or_else(|| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+    let hashed_password = password::hash(&body.new_password)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let key_reset = reset_private_key(&app_state, &user, &body.new_password).await?;
+
+    app_state.db_client
+        .update_user_password(user.id, hashed_password, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // Following the emailed link proved the address, and every old session ends
+    app_state.db_client
+        .verify_user_email(user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    app_state.db_client
+        .revoke_user_sessions(user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    app_state.key_cache.remove(user.id);
+
+    let entry = AuditEntry::succeeded(AuditAction::PasswordReset, Some(user.id))
+        .details(serde_json::json!({ "keys_regenerated": key_reset == KeyReset::Regenerated }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let message = match key_reset {
+        KeyReset::Regenerated => "Password reset successfully. Your encryption keys could not be recovered and were replaced, so files sent to you before the reset can no longer be opened",
+        KeyReset::Rewrapped | KeyReset::Unchanged => "Password reset successfully",
+    };
+
+    let response_data = serde_json::json!({
+        "status": "success",
+        "message": message,
+        "keys_regenerated": key_reset == KeyReset::Regenerated,
+    });
+
+    Ok(create_auth_response(response_data, clear_auth_cookies()))
+}
@@ -0,0 +1,98 @@
+use std::{convert::Infallible, pin::pin, sync::Arc, time::Duration};
+
+use axum::{extract::{ws::{CloseFrame, Message, WebSocket}, WebSocketUpgrade}, http::{header, HeaderMap, StatusCode}, response::{sse::{Event, KeepAlive}, IntoResponse, Sse}, routing::get, Extension, Router};
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{error::HttpError, middleware::JWTAuthMiddeware, utils::events::UserEvent, AppState};
+
+/// Close code sent when the access token that opened the socket has expired.
+const TOKEN_EXPIRED_CLOSE_CODE: u16 = 4001;
+
+pub fn events_handler() -> Router {
+    Router::new()
+        .route("/", get(stream_events))
+        .route("/ws", get(websocket_events))
+}
+
+/// The events for one account, ending once the access token that asked for them expires.
+/// Clients then reconnect with a fresh token, so a logged out or disabled account stops
+/// hearing about shares within `JWT_MAXAGE` minutes.
+fn user_events(app_state: &Arc<AppState>, user_id: uuid::Uuid) -> impl Stream<Item = UserEvent> + use<> {
+    let receiver = app_state.events.subscribe();
+    let token_expired = tokio::time::sleep(Duration::from_secs(app_state.env.jwt_maxage as u64 * 60));
+
+    stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if event.user_id == user_id => return Some((event, receiver)),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => return Some((UserEvent::events_missed(user_id), receiver)),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .take_until(token_expired)
+}
+
+pub async fn stream_events(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> impl IntoResponse {
+    let events = user_events(&app_state, user.user.id)
+        .map(|event| Ok::<_, Infallible>(Event::default().event(event.kind.as_str()).data(event.data.to_string())));
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+pub async fn websocket_events(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, HttpError> {
+    // Browsers send cookies along with cross-site WebSocket handshakes, and CORS does not apply
+    let origin = headers.get(header::ORIGIN).and_then(|origin| origin.to_str().ok());
+
+    if origin.is_some_and(|origin| origin.trim_end_matches('/') != app_state.env.client_url.trim_end_matches('/')) {
+        return Err(HttpError::new("WebSocket connections are only accepted from the Aerofy client", StatusCode::FORBIDDEN));
+    }
+
+    let events = user_events(&app_state, user.user.id);
+
+    Ok(ws.on_upgrade(move |socket| forward_events(socket, events)))
+}
+
+async fn forward_events(mut socket: WebSocket, events: impl Stream<Item = UserEvent>) {
+    let mut events = pin!(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+
+                let message = serde_json::json!({ "type": event.kind, "data": event.data });
+
+                if socket.send(Message::Text(message.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                // Nothing is expected from the client; pings are answered for us
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    let close = CloseFrame {
+        code: TOKEN_EXPIRED_CLOSE_CODE,
+        reason: "Access token expired, reconnect with a fresh one".into(),
+    };
+
+    if let Err(e) = socket.send(Message::Close(Some(close))).await {
+        tracing::debug!("Failed to close event socket: {}", e);
+    }
+}
@@ -1,138 +1,491 @@
-use std::sync::Arc;
+use std::{pin::Pin, sync::Arc};
 
-use axum::{body::Body, extract::Multipart, http::{Response, StatusCode}, response::IntoResponse, routing::post, Extension, Json, Router};
+use axum::{body::{Body, Bytes}, extract::{multipart::Field, Multipart, Path}, http::{header, HeaderMap, Method, Response, StatusCode}, response::IntoResponse, routing::{delete, get, post, put}, Extension, Json, Router};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
 use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt, TryStreamExt};
 use rsa::{pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey}, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
 use validator::Validate;
 
-use crate::{db::UserExt, dtos::{FileUploadDtos, Response as ResponseDto, DownloadFileDto, RetrieveFileDto}, error::HttpError, middleware::JWTAuthMiddeware, utils::{decrypt::decrypt_file, encrypt::encrypt_file, password}, AppState};
+use crate::{db::UserExt, handler::tus::tus_handler, dtos::{FileUploadDtos, Response as ResponseDto, DownloadFileDto, RetrieveFileDto, ShareExpirationUpdateDto, ShareLinkDto, SharePasswordUpdateDto, UploadFileResponseDto}, error::{ErrorMessage, HttpError}, middleware::JWTAuthMiddeware, models::{AuditAction, File, FileKey, NewFile, NewFileKey, NewLinkShare, Organization, OwnedShare, PasswordAttempts, ShareStatus, User}, storage::{collect_blob, delete_blobs, new_blob_key, open_file_content, open_file_range, BlobWriter}, utils::{attempts::{verify_share_password, ShareAttempt}, audit::{self, AuditEntry}, cipher::{FileCipher, KeyWrap, LinkKey}, decrypt::{decrypt_file, decrypt_file_stream, StreamDecryptor}, encrypt::{generate_file_key, segment_count, StreamEncryptor, ENCRYPTED_SEGMENT_SIZE, NONCE_PREFIX_SIZE, SEGMENT_SIZE}, events::{publish_share_accepted, publish_share_created, publish_share_declined, publish_share_revoked}, keys::{unwrap_link_key, wrap_aes_key, wrap_link_key}, password, range::{http_date, if_range_matches, none_match, parse_range, slice_stream, ByteRange, RangeRequest}, session::ClientInfo}, AppState};
 
-pub fn file_handle() -> Router {
+// Uploading and managing your own shares, granted to access tokens by `files:send`
+pub fn file_send_handler() -> Router {
     Router::new()
     .route("/upload", post(upload_file))
+    .route("/shares/:shared_id", delete(revoke_share))
+    .route("/shares/:shared_id/expiration", put(update_share_expiration))
+    .route("/shares/:shared_id/password", put(update_share_password))
+    .route("/shares/:shared_id/unlock", post(unlock_share))
+    .route("/e2e/upload", post(upload_encrypted_file))
+    .nest("/tus", tus_handler())
+}
+
+// Accepting and downloading files shared with you, granted to access tokens by `files:receive`
+pub fn file_receive_handler() -> Router {
+    Router::new()
     .route("/retrieve", post(retrieve_file))
+    .route("/retrieve/:shared_id", get(download_file))
     .route("/accept", post(accept_file))
+    .route("/decline", post(decline_file))
+    .route("/e2e/retrieve", post(retrieve_encrypted_file))
 }
 
 pub async fn upload_file(
     Extension(app_state): Extension<Arc<AppState>>,
     Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
     mut multipart: Multipart
 ) -> Result<impl IntoResponse, HttpError> {
 
-    let mut file_data = Vec::new();
-    let mut file_name = String::new();
-    let mut file_size: i64 = 0;
-    let mut form_data = FileUploadDtos {
-        recipient_email: String::new(),
-        password: String::new(),
-        expiration_date: String::new(),
+    // The blob is written before the row exists, so a failed upload must remove it again
+    let mut storage_key = None;
+
+    let share_link = match store_streamed_upload(&app_state, user.user.id, &mut multipart, &mut storage_key).await {
+        Ok((share_link, entry)) => {
+            audit::record(&app_state, Some(&client), entry).await;
+            share_link
+        }
+        Err(e) => {
+            if let Some(storage_key) = storage_key
+                && let Err(delete_err) = app_state.blob_store.delete(&storage_key).await
+            {
+                tracing::error!("Failed to remove incomplete upload {}: {}", storage_key, delete_err);
+            }
+
+            return Err(e);
+        }
     };
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
+    let response = UploadFileResponseDto {
+        message: "File uploaded and encrypted successfully".to_string(),
+        status: "success",
+        share_link,
+    };
+
+    Ok(Json(response))
+}
+
+/// Encrypts the `fileUpload` field segment by segment straight into the blob store, then wraps
+/// the file key for the recipients once the remaining form fields have been read.
+async fn store_streamed_upload(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    multipart: &mut Multipart,
+    storage_key: &mut Option<String>,
+) -> Result<(Option<ShareLinkDto>, AuditEntry), HttpError> {
+
+    let (aes_key, nonce_prefix) = generate_file_key();
+    let mut file_name = String::new();
+    let mut stored = None;
+    let mut form_data = FileUploadDtos::default();
+
+    while let Some(mut field) = multipart.next_field().await
+        .map_err(|e| HttpError::bad_request(e.to_string()))? {
+        let name = field.name().unwrap_or_default().to_string();
 
         match name.as_str() {
             "fileUpload" => {
+                if storage_key.is_some() {
+                    return Err(HttpError::bad_request("Only one file can be uploaded at a time".to_string()));
+                }
+
                 file_name = field.file_name().unwrap_or("unknow_file").to_string();
-                file_data = field.bytes().await.unwrap().to_vec();
-                file_size = file_data.len() as i64;
+
+                let encryptor = StreamEncryptor::new(&aes_key, nonce_prefix);
+                let content = field_stream(&mut field);
+                let blob = write_stream_to_blob(app_state, content, Some(encryptor)).await?;
+
+                *storage_key = Some(blob.storage_key.clone());
+                stored = Some(blob);
             },
-            "recipient_email" => {
-                form_data.recipient_email = field.text().await.unwrap();
+            "recipient_email" | "recipient_emails" => {
+                add_recipients(&mut form_data.recipient_emails, &multipart_text(field).await?);
+            },
+            "recipient_group" | "recipient_groups" => {
+                add_recipients(&mut form_data.recipient_groups, &multipart_text(field).await?);
+            },
+            "share_link" => {
+                form_data.share_link = Some(multipart_text(field).await?);
             },
             "password" => {
-                form_data.password = field.text().await.unwrap();
+                form_data.password = multipart_text(field).await?;
             },
             "expiration_date" => {
-                form_data.expiration_date = field.text().await.unwrap();
+                form_data.expiration_date = multipart_text(field).await?;
+            },
+            "max_downloads" => {
+                form_data.max_downloads = Some(parse_max_downloads(&multipart_text(field).await?)?);
+            },
+            "burn_after_reading" => {
+                form_data.burn_after_reading = parse_flag(&multipart_text(field).await?);
             },
             _ => {}
         }
     }
 
+    let stored = stored
+        .ok_or_else(|| HttpError::bad_request("File is required".to_string()))?;
+
+    let share = resolve_share_settings(app_state, user_id, form_data).await?;
+    let entry = upload_audit_entry(user_id, &share, "upload");
+
+    let (file_id, share_link) = save_server_encrypted_file(app_state, user_id, file_name, stored, &aes_key, nonce_prefix, share).await?;
+
+    Ok((share_link, entry.target(file_id)))
+}
+
+/// Who a new upload is shared with and on what terms, checked before the file row is written.
+pub struct ShareSettings {
+    pub recipients: Vec<User>,
+    pub hash_password: String,
+    pub expiration_date: DateTime<Utc>,
+    pub max_downloads: Option<i32>,
+    pub link: Option<ShareLinkSettings>,
+}
+
+/// A link share to create alongside the recipient shares. The plaintext password is only
+/// kept to derive the key that wraps the file key of password links.
+pub struct ShareLinkSettings {
+    pub link_key: LinkKey,
+    pub password: String,
+}
+
+pub fn parse_max_downloads(value: &str) -> Result<i32, HttpError> {
+    value.trim().parse::<i32>()
+        .map_err(|_| HttpError::bad_request("Max downloads must be a whole number".to_string()))
+}
+
+/// Form and metadata flags are sent as text; anything but "true", "1" or "on" is false.
+pub fn parse_flag(value: &str) -> bool {
+    matches!(value.trim(), "true" | "1" | "on")
+}
+
+/// Recipient emails and group IDs may be sent as repeated fields or as one comma separated list.
+pub fn add_recipients(recipients: &mut Vec<String>, value: &str) {
+    recipients.extend(
+        value.split(',')
+            .map(str::trim)
+            .filter(|email| !email.is_empty())
+            .map(str::to_string)
+    );
+}
+
+/// Expands recipient groups into their current members and checks the upload against the
+/// sender's organization policy before looking the recipients up.
+pub async fn resolve_share_settings(
+    app_state: &Arc<AppState>,
+    sender_id: uuid::Uuid,
+    mut form_data: FileUploadDtos,
+) -> Result<ShareSettings, HttpError> {
+    let organization = app_state.db_client
+        .get_user_organization(sender_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    form_data.policy = organization.as_ref().map(Organization::share_policy);
+
+    if !form_data.recipient_groups.is_empty() {
+        let organization = organization.as_ref()
+            .ok_or_else(|| HttpError::bad_request("Only members of an organization can share with groups".to_string()))?;
+
+        add_group_recipients(app_state, organization.id, sender_id, &mut form_data).await?;
+    }
+
     form_data.validate()
         .map_err(|e| HttpError::bad_request(e.to_string()))?;
 
-    let recipient_result = app_state.db_client
-        .get_user(None, None, Some(&form_data.recipient_email))
+    // Recipients keep the order they were given in, which end-to-end uploads rely on
+    let mut recipients = Vec::with_capacity(form_data.recipient_emails.len());
+
+    for recipient_email in &form_data.recipient_emails {
+        let recipient = app_state.db_client
+            .get_user(None, None, Some(recipient_email))
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .ok_or_else(|| HttpError::not_found(format!("Recipient {} not found", recipient_email)))?;
+
+        if recipient.public_key.is_none() {
+            return Err(HttpError::bad_request(format!("Recipient {} has no public key", recipient_email)));
+        }
+
+        recipients.push(recipient);
+    }
+
+    let hash_password = password::hash(&form_data.password)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let expiration_date = DateTime::parse_from_rfc3339(&form_data.expiration_date)
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .with_timezone(&Utc);
+
+    let link = form_data.share_link.as_deref()
+        .map(|share_link| {
+            LinkKey::try_from(share_link)
+                .map(|link_key| ShareLinkSettings { link_key, password: form_data.password.clone() })
+                .map_err(|e| HttpError::bad_request(e.message))
+        })
+        .transpose()?;
+
+    Ok(ShareSettings {
+        recipients,
+        hash_password,
+        expiration_date,
+        max_downloads: form_data.download_limit(),
+        link,
+    })
+}
+
+/// Adds the members of the requested groups to the recipient emails, skipping the sender and
+/// anyone already listed. Members who join later do not get access to the file.
+async fn add_group_recipients(
+    app_state: &Arc<AppState>,
+    organization_id: uuid::Uuid,
+    sender_id: uuid::Uuid,
+    form_data: &mut FileUploadDtos,
+) -> Result<(), HttpError> {
+    let mut group_ids = Vec::with_capacity(form_data.recipient_groups.len());
+
+    for group in &form_data.recipient_groups {
+        let group_id = uuid::Uuid::parse_str(group)
+            .map_err(|_| HttpError::bad_request(format!("Invalid group ID: {}", group)))?;
+
+        app_state.db_client
+            .get_group(organization_id, group_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .ok_or_else(|| HttpError::not_found(format!("Group {} not found", group)))?;
+
+        group_ids.push(group_id);
+    }
+
+    let members = app_state.db_client
+        .get_group_recipients(&group_ids)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
-    let recipient = match recipient_result {
-        Some(user) => user,
-        None => {
-            return Err(HttpError::not_found("Recipient not found".to_string()));
+    for member in members {
+        if member.id != sender_id && !form_data.recipient_emails.contains(&member.email) {
+            form_data.recipient_emails.push(member.email);
         }
+    }
+
+    if form_data.recipient_emails.is_empty() && form_data.share_link.is_none() {
+        return Err(HttpError::bad_request("The selected groups have no one else to share with".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Describes an upload for the audit log, before its settings are consumed by saving it.
+pub fn upload_audit_entry(user_id: uuid::Uuid, share: &ShareSettings, via: &str) -> AuditEntry {
+    let recipients: Vec<&str> = share.recipients.iter()
+        .map(|recipient| recipient.email.as_str())
+        .collect();
+
+    AuditEntry::succeeded(AuditAction::FileUploaded, Some(user_id))
+        .details(serde_json::json!({
+            "via": via,
+            "recipients": recipients,
+            "share_link": share.link.is_some(),
+            "expires_at": share.expiration_date,
+        }))
+}
+
+/// Wraps the file key for every recipient and records a blob written by `StreamEncryptor`.
+/// Returns the new file's id and the link to hand out when a link share was requested.
+pub async fn save_server_encrypted_file(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    file_name: String,
+    stored: StoredBlob,
+    aes_key: &[u8; 32],
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    share: ShareSettings,
+) -> Result<(uuid::Uuid, Option<ShareLinkDto>), HttpError> {
+    let mut file_keys = Vec::with_capacity(share.recipients.len());
+
+    for recipient in &share.recipients {
+        let public_key_str = recipient.public_key.as_deref()
+            .ok_or_else(|| HttpError::bad_request(format!("Recipient {} has no public key", recipient.email)))?;
+
+        let public_key = match RsaPublicKey::from_pkcs1_pem(public_key_str) {
+            Ok(key) => key,
+            Err(e) => {
+                tracing::error!("Failed to parse PKCS1 PEM key: {}", e);
+                return Err(HttpError::server_error(format!("Key parsing error: {}", e)));
+            }
+        };
+
+        file_keys.push(NewFileKey {
+            recipient_user_id: recipient.id,
+            encrypted_aes_key: wrap_aes_key(aes_key, &public_key, KeyWrap::RsaOaepSha256)?,
+            key_wrap: KeyWrap::RsaOaepSha256,
+        });
+    }
+
+    let new_file = NewFile {
+        user_id,
+        file_name,
+        file_size: stored.size,
+        storage_key: stored.storage_key,
+        iv: nonce_prefix.to_vec(),
+        cipher: FileCipher::Aes256GcmStream,
+        client_encrypted: false,
+        chunk_count: Some(stored.segments),
     };
 
-    let public_key_str = match &recipient.public_key {
-        Some(key) => key,
-        None => {
-            return Err(HttpError::bad_request("Recipient has no public key".to_string()));
+    let (link_share, share_link) = match share.link {
+        Some(link) => {
+            let (link_share, share_link) = new_link_share(app_state, aes_key, link)?;
+            (Some(link_share), Some(share_link))
         }
+        None => (None, None),
     };
 
-    let public_key = match RsaPublicKey::from_pkcs1_pem(public_key_str) {
-        Ok(key) => key,
-        Err(e) => {
-            tracing::error!("Failed to parse PKCS1 PEM key: {}", e);
-            return Err(HttpError::server_error(format!("Key parsing error: {}", e)));
+    let file_id = app_state.db_client
+        .save_encrypted_file(new_file, file_keys, link_share, share.hash_password, share.expiration_date, share.max_downloads)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    publish_share_created(&app_state.db_client, file_id).await;
+
+    Ok((file_id, share_link))
+}
+
+/// Fragment links put the file key in the URL, which browsers never send to the server, and
+/// only leave its hash behind; password links store the key wrapped under the share password.
+fn new_link_share(
+    app_state: &Arc<AppState>,
+    aes_key: &[u8; 32],
+    link: ShareLinkSettings,
+) -> Result<(NewLinkShare, ShareLinkDto), HttpError> {
+    let link_id = uuid::Uuid::new_v4();
+    let share_url = format!("{}/share/{}", app_state.env.client_url, link_id);
+
+    let (link_share, url) = match link.link_key {
+        LinkKey::Fragment => {
+            let link_share = NewLinkShare {
+                id: link_id,
+                link_key: LinkKey::Fragment,
+                key_check: Some(Sha256::digest(aes_key).to_vec()),
+                wrapped_key: None,
+            };
+
+            (link_share, format!("{}#{}", share_url, URL_SAFE_NO_PAD.encode(aes_key)))
+        }
+        LinkKey::Password => {
+            let link_share = NewLinkShare {
+                id: link_id,
+                link_key: LinkKey::Password,
+                key_check: None,
+                wrapped_key: Some(wrap_link_key(link_id, aes_key, &link.password)?),
+            };
+
+            (link_share, share_url)
         }
     };
 
-    let (
-        encrypted_aes_key,
-        encrypted_data,
-        iv
-    ) = encrypt_file(file_data, &public_key).await?;
+    Ok((link_share, ShareLinkDto { shared_id: link_id.to_string(), url }))
+}
 
-    let user_id = uuid::Uuid::parse_str(&user.user.id.to_string()).unwrap();
+/// Plaintext or ciphertext on its way into the blob store.
+pub type ContentStream<'a> = Pin<Box<dyn Stream<Item = Result<Bytes, HttpError>> + Send + 'a>>;
 
-    let hash_password = password::hash(&form_data.password)
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
-
-    let expiration_date = DateTime::parse_from_rfc3339(&form_data.expiration_date)
-        .map_err(|e| HttpError::server_error(e.to_string()))?
-        .with_timezone(&Utc);
+pub struct StoredBlob {
+    pub storage_key: String,
+    pub size: i64,
+    pub segments: i32,
+}
 
-    let recipient_user_id = uuid::Uuid::parse_str(&recipient.id.to_string()).unwrap();
+/// Streams content into a new blob, encrypting it on the way when an encryptor is given.
+/// The blob is aborted if anything fails before it is complete.
+pub async fn write_stream_to_blob(
+    app_state: &Arc<AppState>,
+    content: ContentStream<'_>,
+    encryptor: Option<StreamEncryptor>,
+) -> Result<StoredBlob, HttpError> {
+    let storage_key = new_blob_key();
 
-    app_state.db_client
-        .save_encrypted_file(
-            user_id.clone(),
-            file_name, 
-            file_size, 
-            recipient_user_id, 
-            hash_password, 
-            expiration_date, 
-            encrypted_aes_key, 
-            encrypted_data, 
-            iv
-        )
+    let mut writer = app_state.blob_store
+        .writer(&storage_key)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
-    let response = ResponseDto {
-        message: "File uploaded and encrypted successfully".to_string(),
-        status: "success"
-    };
+    match copy_stream(content, encryptor, writer.as_mut()).await {
+        Ok((size, segments)) => {
+            if let Err(e) = writer.finish().await {
+                // The store may have kept the blob even though finishing reported an error
+                if let Err(delete_err) = app_state.blob_store.delete(&storage_key).await {
+                    tracing::error!("Failed to remove incomplete upload {}: {}", storage_key, delete_err);
+                }
 
-    Ok(Json(response))
+                return Err(HttpError::server_error(e.to_string()));
+            }
+
+            Ok(StoredBlob { storage_key, size, segments })
+        }
+        Err(e) => {
+            if let Err(abort_err) = writer.abort().await {
+                tracing::error!("Failed to abort upload {}: {}", storage_key, abort_err);
+            }
+
+            Err(e)
+        }
+    }
 }
 
-pub async fn retrieve_file(
-    Extension(app_state): Extension<Arc<AppState>>,
-    Extension(user): Extension<JWTAuthMiddeware>,
-    Json(body): Json<DownloadFileDto>
-) -> Result<impl IntoResponse, HttpError> {
+async fn copy_stream(
+    mut content: ContentStream<'_>,
+    mut encryptor: Option<StreamEncryptor>,
+    writer: &mut dyn BlobWriter,
+) -> Result<(i64, i32), HttpError> {
+    let mut size: i64 = 0;
+    let mut segments: i32 = 0;
+
+    while let Some(bytes) = content.next().await {
+        let bytes = bytes?;
+        size += bytes.len() as i64;
+
+        match &mut encryptor {
+            Some(encryptor) => {
+                for segment in encryptor.update(&bytes)? {
+                    writer.write(Bytes::from(segment)).await
+                        .map_err(|e| HttpError::server_error(e.to_string()))?;
+                    segments += 1;
+                }
+            }
+            None => {
+                writer.write(bytes).await
+                    .map_err(|e| HttpError::server_error(e.to_string()))?;
+            }
+        }
+    }
+
+    if let Some(encryptor) = encryptor {
+        writer.write(Bytes::from(encryptor.finish()?)).await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+        segments += 1;
+    }
+
+    Ok((size, segments))
+}
+
+fn field_stream<'a>(field: &'a mut Field<'_>) -> ContentStream<'a> {
+    Box::pin(field.map_err(|e| HttpError::bad_request(e.to_string())))
+}
+
+/// Returns the parsed shared id along with the file, so downloads can be counted against it.
+async fn get_accepted_file(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    shared_id: &str,
+) -> Result<(uuid::Uuid, File), HttpError> {
     // Parse the shared_id from string to UUID
-    let shared_id = uuid::Uuid::parse_str(&body.shared_id)
+    let shared_id = uuid::Uuid::parse_str(shared_id)
         .map_err(|e| HttpError::bad_request(format!("Invalid shared ID: {}", e)))?;
     
-    // Get the user's UUID
-    let user_id = user.user.id;
-    
     // Get the shared link from the database
     let shared_link = app_state.db_client
         .get_shared(shared_id, user_id)
@@ -141,7 +494,7 @@ pub async fn retrieve_file(
         .ok_or_else(|| HttpError::bad_request("Shared link not found or has expired".to_string()))?;
     
     // Check if the file has been accepted/retrieved
-    if !shared_link.is_retrieved.unwrap_or(false) {
+    if ShareStatus::try_from(shared_link.status.as_str())? == ShareStatus::Pending {
         return Err(HttpError::bad_request("You must accept this file before downloading it".to_string()));
     }
     
@@ -159,43 +512,331 @@ pub async fn retrieve_file(
         HttpError::bad_request("The requested file either does not exist or has expired.".to_string())
     })?;
 
-    // Get the user with their private key from the database
+    Ok((shared_id, file_data))
+}
+
+/// The file's AES key as wrapped for this recipient.
+async fn get_file_key(
+    app_state: &Arc<AppState>,
+    file_data: &File,
+    user_id: uuid::Uuid,
+) -> Result<FileKey, HttpError> {
+    app_state.db_client
+        .get_file_key(file_data.id, user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::server_error("No key found for this recipient".to_string()))
+}
+
+pub async fn retrieve_file(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    method: Method,
+    headers: HeaderMap,
+    Json(body): Json<DownloadFileDto>
+) -> Result<impl IntoResponse, HttpError> {
+    let (shared_id, file_data) = get_accepted_file(&app_state, user.user.id, &body.shared_id).await?;
+
+    send_file(&app_state, &client, shared_id, file_data, FileAccess::Recipient(user.user.id), method, &headers).await
+}
+
+/// Same as `retrieve_file`, for clients such as browsers and download managers that can
+/// only resume or seek with a plain GET.
+pub async fn download_file(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(shared_id): Path<String>,
+    client: ClientInfo,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, HttpError> {
+    let (shared_id, file_data) = get_accepted_file(&app_state, user.user.id, &shared_id).await?;
+
+    send_file(&app_state, &client, shared_id, file_data, FileAccess::Recipient(user.user.id), method, &headers).await
+}
+
+/// How the file key is obtained when serving a file.
+pub enum FileAccess {
+    /// Unwrapped with the recipient's private key.
+    Recipient(uuid::Uuid),
+    /// Already known, as for link shares.
+    Key(Vec<u8>),
+}
+
+/// Serves a file, honouring `Range`, `If-Range` and `If-None-Match`. Only the segments
+/// covering the requested range are fetched and decrypted.
+pub async fn send_file(
+    app_state: &Arc<AppState>,
+    client: &ClientInfo,
+    shared_id: uuid::Uuid,
+    file_data: File,
+    access: FileAccess,
+    method: Method,
+    headers: &HeaderMap,
+) -> Result<Response<Body>, HttpError> {
+    if file_data.client_encrypted {
+        return Err(HttpError::bad_request("This file is end-to-end encrypted, download it from /file/e2e/retrieve".to_string()));
+    }
+
+    // Files are immutable, so the id is a strong validator
+    let etag = format!("\"{}\"", file_data.id);
+    let file_size = file_data.file_size as u64;
+
+    let mut response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "private")
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if let Some(created_at) = file_data.created_at {
+        response = response.header(header::LAST_MODIFIED, http_date(created_at));
+    }
+
+    if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH)
+        && none_match(if_none_match, &etag)
+    {
+        let status = match method == Method::GET || method == Method::HEAD {
+            true => StatusCode::NOT_MODIFIED,
+            false => StatusCode::PRECONDITION_FAILED,
+        };
+
+        return response
+            .status(status)
+            .body(Body::empty())
+            .map_err(|e| HttpError::server_error(e.to_string()));
+    }
+
+    // A stale If-Range means the client's partial copy is useless, so it gets the whole file
+    let range_valid = header_str(headers, header::IF_RANGE)
+        .is_none_or(|if_range| if_range_matches(if_range, &etag, file_data.created_at));
+
+    let range = match header_str(headers, header::RANGE) {
+        Some(range) if range_valid => parse_range(range, file_size),
+        _ => RangeRequest::Full,
+    };
+
+    let range = match range {
+        RangeRequest::Full => None,
+        RangeRequest::Partial(range) => Some(range),
+        RangeRequest::Unsatisfiable => {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", file_size))
+                .body(Body::empty())
+                .map_err(|e| HttpError::server_error(e.to_string()));
+        }
+    };
+
+    response = response
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_data.file_name));
+
+    response = match range {
+        Some(range) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end, file_size))
+            .header(header::CONTENT_LENGTH, range.len()),
+        None => response
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, file_size),
+    };
+
+    if method == Method::HEAD {
+        return response
+            .body(Body::empty())
+            .map_err(|e| HttpError::server_error(e.to_string()));
+    }
+
+    let user_id = match access {
+        FileAccess::Recipient(user_id) => Some(user_id),
+        FileAccess::Key(_) => None,
+    };
+
+    // Nothing is counted until there is a body to send, so a locked key or a broken blob
+    // cannot use up a download or burn the share
+    let body = decrypt_file_body(app_state, &file_data, access, range).await?;
+
+    // Every response with a body counts, ranges included: otherwise a used up share would
+    // keep serving everything past the first byte
+    let burn = count_download(app_state, shared_id).await?;
+
+    let requested_range = range.as_ref().map(|range| format!("bytes={}-{}", range.start, range.end));
+    let entry = AuditEntry::succeeded(AuditAction::ShareDownloaded, user_id)
+        .target(shared_id)
+        .details(serde_json::json!({ "file_id": file_data.id, "range": requested_range }));
+    audit::record(app_state, Some(client), entry).await;
+
+    response
+        .body(burn_after_body(body, burn))
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+/// Takes a download off the share's limit. Returns a guard when this was the last one, which
+/// deletes the share once the response body is dropped.
+async fn count_download(app_state: &Arc<AppState>, shared_id: uuid::Uuid) -> Result<Option<BurnOnDrop>, HttpError> {
+    let last_download = app_state.db_client
+        .record_download(shared_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::new("This share has reached its download limit", StatusCode::GONE))?;
+
+    Ok(last_download.then(|| BurnOnDrop { app_state: app_state.clone(), shared_id }))
+}
+
+/// Deletes a share whose last download was handed out. Dropping it with the response body means
+/// the blob is only removed after it has been streamed, or once the client goes away.
+struct BurnOnDrop {
+    app_state: Arc<AppState>,
+    shared_id: uuid::Uuid,
+}
+
+impl Drop for BurnOnDrop {
+    fn drop(&mut self) {
+        let app_state = self.app_state.clone();
+        let shared_id = self.shared_id;
+
+        tokio::spawn(async move {
+            match app_state.db_client.delete_share(shared_id).await {
+                Ok(storage_keys) => delete_blobs(&app_state.blob_store, storage_keys).await,
+                Err(e) => tracing::error!("Failed to delete used up share {}: {}", shared_id, e),
+            }
+        });
+    }
+}
+
+fn burn_after_body(body: Body, burn: Option<BurnOnDrop>) -> Body {
+    match burn {
+        Some(burn) => Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _ = &burn;
+            chunk
+        })),
+        None => body,
+    }
+}
+
+async fn decrypt_file_body(
+    app_state: &Arc<AppState>,
+    file_data: &File,
+    access: FileAccess,
+    range: Option<ByteRange>,
+) -> Result<Body, HttpError> {
+    let cipher = FileCipher::try_from(file_data.cipher.as_str())?;
+
+    match cipher {
+        FileCipher::Aes256GcmStream => {
+            let decryptor = match access {
+                FileAccess::Recipient(user_id) => {
+                    let private_key = recipient_private_key(app_state, user_id).await?;
+                    let file_key = get_file_key(app_state, file_data, user_id).await?;
+
+                    StreamDecryptor::new(
+                        &file_key.encrypted_aes_key,
+                        &file_data.iv,
+                        KeyWrap::try_from(file_key.key_wrap.as_str())?,
+                        &private_key
+                    )?
+                }
+                FileAccess::Key(aes_key) => StreamDecryptor::from_key(&aes_key, &file_data.iv)?,
+            };
+
+            let file_size = file_data.file_size as u64;
+            let segment_size = SEGMENT_SIZE as u64;
+            let segments = file_data.chunk_count
+                .map(|count| count as u64)
+                .unwrap_or_else(|| segment_count(file_size));
+
+            let (first, last) = match range {
+                Some(range) => (range.start / segment_size, range.end / segment_size),
+                None => (0, segments - 1),
+            };
+
+            // Every segment carries a 16 byte tag, so the ciphertext is slightly longer than the file
+            let offset = first * ENCRYPTED_SEGMENT_SIZE as u64;
+            let end = ((last + 1) * ENCRYPTED_SEGMENT_SIZE as u64).min(file_size + 16 * segments);
+
+            let content = open_file_range(&app_state.blob_store, &app_state.db_client, file_data, offset, end - offset)
+                .await
+                .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+            let plaintext = decrypt_file_stream(content, decryptor, first as u32..=last as u32, segments as u32 - 1);
+
+            Ok(match range {
+                Some(range) => Body::from_stream(slice_stream(plaintext, range.start - first * segment_size, range.len())),
+                None => Body::from_stream(plaintext),
+            })
+        }
+        _ => {
+            // Link shares only exist for segmented files
+            let FileAccess::Recipient(user_id) = access else {
+                return Err(HttpError::server_error("Unsupported file cipher for this share".to_string()));
+            };
+
+            let private_key = recipient_private_key(app_state, user_id).await?;
+            let file_key = get_file_key(app_state, file_data, user_id).await?;
+            let key_wrap = KeyWrap::try_from(file_key.key_wrap.as_str())?;
+
+            let content = open_file_content(&app_state.blob_store, &app_state.db_client, file_data)
+                .await
+                .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+            let encrypted_file = collect_blob(content)
+                .await
+                .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+            let decrypted_file = decrypt_file(
+                file_key.encrypted_aes_key,
+                encrypted_file,
+                file_data.iv.clone(),
+                cipher,
+                key_wrap,
+                &private_key
+            ).await?;
+
+            // Whole-file ciphers cannot be decrypted in parts, so ranges are cut from the plaintext
+            let decrypted_file = match range {
+                Some(range) => decrypted_file
+                    .get(range.start as usize..=range.end as usize)
+                    .ok_or_else(|| HttpError::server_error("File size does not match its content".to_string()))?
+                    .to_vec(),
+                None => decrypted_file,
+            };
+
+            Ok(Body::from(decrypted_file))
+        }
+    }
+}
+
+/// Uses the private key unlocked at login; legacy plaintext keys still work until the next login.
+/// Keys are only unlocked in the memory of the instance that handled the login, so a request
+/// routed elsewhere, or made after a restart, has to log in again. Callers look the key up
+/// before anything is counted or changed.
+async fn recipient_private_key(app_state: &Arc<AppState>, user_id: uuid::Uuid) -> Result<RsaPrivateKey, HttpError> {
+    if let Some(private_key) = app_state.key_cache.get(user_id) {
+        return Ok(private_key);
+    }
+
     let user_data = app_state.db_client
-        .get_user_by_id(user.user.id)
+        .get_user_by_id(user_id)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?
         .ok_or_else(|| HttpError::unauthorized("User not found".to_string()))?;
-    
-    // Get private key from user data
+
     let private_key = user_data.private_key
-        .ok_or_else(|| HttpError::server_error("Private key not found for user".to_string()))?;
-    
-    // Parse the PEM string into a RsaPrivateKey object
-    let private_key_pem = RsaPrivateKey::from_pkcs1_pem(&private_key)
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
+        .ok_or_else(|| HttpError::unauthorized(ErrorMessage::KeysLocked.to_string()))?;
 
-    let decrypted_file = decrypt_file(
-        file_data.encrypted_aes_key, 
-        file_data.encrypted_file,
-        file_data.iv,
-        &private_key_pem
-    ).await?;
+    RsaPrivateKey::from_pkcs1_pem(&private_key)
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
 
-    // Create the download response
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/octet-stream")
-        .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_data.file_name))
-        .body(Body::from(decrypted_file))
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
-    
-    Ok(response)
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
 }
 
 
 pub async fn accept_file(
     Extension(app_state): Extension<Arc<AppState>>,
     Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
     Json(body): Json<RetrieveFileDto>
 ) -> Result<impl IntoResponse, HttpError> {
     // Validate the request body
@@ -215,23 +856,44 @@ pub async fn accept_file(
         .map_err(|e| HttpError::server_error(e.to_string()))?
         .ok_or_else(|| HttpError::bad_request("Shared link not found or has expired".to_string()))?;
     
-    if shared_link.is_retrieved.unwrap_or(false) {
-        return Err(HttpError::bad_request("This file has already been accepted".to_string()));
+    let status = ShareStatus::try_from(shared_link.status.as_str())?;
+
+    if status != ShareStatus::Pending {
+        return Err(HttpError::bad_request(format!("This file has already been {}", status.as_str())));
     }
     
-    // Verify the password
-    let is_valid = password::compare(&body.password, &shared_link.password)
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
-    
-    if !is_valid {
-        return Err(HttpError::unauthorized("Invalid password".to_string()));
+    // Verify the password, counting the attempt against the share and the recipient
+    let share = ShareAttempt {
+        shared_id,
+        password_hash: &shared_link.password,
+        attempts: PasswordAttempts {
+            failed_attempts: shared_link.failed_attempts,
+            last_attempt_at: shared_link.last_attempt_at,
+        },
+        locked_at: shared_link.locked_at,
+    };
+
+    if let Err(e) = verify_share_password(&app_state, share, Some(user_id), &body.password).await {
+        let entry = AuditEntry::failed(AuditAction::ShareAccepted, Some(user_id)).target(shared_id);
+        audit::record(&app_state, Some(&client), entry).await;
+
+        return Err(e);
     }
     
-    // Mark the file as retrieved without downloading
-    app_state.db_client
-        .mark_file_as_retrieved(shared_id)
+    // Mark the file as accepted without downloading
+    let accepted = app_state.db_client
+        .accept_share(shared_id)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !accepted {
+        return Err(HttpError::bad_request("This file has already been accepted".to_string()));
+    }
+
+    let entry = AuditEntry::succeeded(AuditAction::ShareAccepted, Some(user_id)).target(shared_id);
+    audit::record(&app_state, Some(&client), entry).await;
+
+    publish_share_accepted(&app_state.db_client, shared_id, &user.user.email).await;
     
     // Create a success response
     let response = ResponseDto {
@@ -240,4 +902,406 @@ pub async fn accept_file(
     };
     
     Ok(Json(response))
-}
\ No newline at end of file
+}
+
+/// Declines a pending share. The recipient's wrapped key is dropped, and the sender sees the
+/// share as declined.
+pub async fn decline_file(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Json(body): Json<DownloadFileDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate().map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let shared_id = uuid::Uuid::parse_str(&body.shared_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid shared ID: {}", e)))?;
+
+    let shared_link = app_state.db_client
+        .get_shared(shared_id, user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::bad_request("Shared link not found or has expired".to_string()))?;
+
+    let declined = app_state.db_client
+        .decline_share(shared_link.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !declined {
+        return Err(HttpError::bad_request("Only pending files can be declined".to_string()));
+    }
+
+    let entry = AuditEntry::succeeded(AuditAction::ShareDeclined, Some(user.user.id)).target(shared_link.id);
+    audit::record(&app_state, Some(&client), entry).await;
+
+    publish_share_declined(&app_state.db_client, shared_link.id, &user.user.email).await;
+
+    let response = ResponseDto {
+        message: "File declined successfully".to_string(),
+        status: "success"
+    };
+
+    Ok(Json(response))
+}
+
+/// Revokes a share of one of the caller's files. The recipient's wrapped key is dropped at once,
+/// while the share itself is kept as revoked until it expires.
+pub async fn revoke_share(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(shared_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let share = get_owned_share(&app_state, user.user.id, &shared_id).await?;
+
+    app_state.db_client
+        .revoke_share(share.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    publish_share_revoked(&app_state.db_client, share.id).await;
+
+    let response = ResponseDto {
+        message: "Share revoked successfully".to_string(),
+        status: "success"
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn update_share_expiration(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(shared_id): Path<String>,
+    Json(body): Json<ShareExpirationUpdateDto>
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = app_state.db_client
+        .get_user_organization(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let body = ShareExpirationUpdateDto {
+        policy: organization.as_ref().map(Organization::share_policy),
+        ..body
+    };
+
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let share = get_owned_share(&app_state, user.user.id, &shared_id).await?;
+
+    let expiration_date = DateTime::parse_from_rfc3339(&body.expiration_date)
+        .map_err(|e| HttpError::bad_request(e.to_string()))?
+        .with_timezone(&Utc);
+
+    app_state.db_client
+        .update_share_expiration(share.id, expiration_date)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = ResponseDto {
+        message: "Share expiration updated successfully".to_string(),
+        status: "success"
+    };
+
+    Ok(Json(response))
+}
+
+/// Rotates a share's password. Password links also need the current password, since their
+/// file key has to be re-wrapped under the new one.
+pub async fn update_share_password(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(shared_id): Path<String>,
+    Json(body): Json<SharePasswordUpdateDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let share = get_owned_share(&app_state, user.user.id, &shared_id).await?;
+
+    let wrapped_key = match share.link_key.as_deref() {
+        Some(link_key) if LinkKey::try_from(link_key)? == LinkKey::Password => {
+            let wrapped = share.wrapped_key()
+                .ok_or_else(|| HttpError::server_error("Link share has no stored key".to_string()))?;
+
+            let current_password = body.current_password.as_deref()
+                .ok_or_else(|| HttpError::bad_request("Current password is required for password links".to_string()))?;
+
+            let is_valid = password::compare(current_password, &share.password)
+                .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+            if !is_valid {
+                return Err(HttpError::unauthorized("Current password is wrong".to_string()));
+            }
+
+            let aes_key = unwrap_link_key(share.id, &wrapped, current_password)?;
+
+            Some(wrap_link_key(share.id, &aes_key, &body.password)?)
+        }
+        _ => None,
+    };
+
+    let hash_password = password::hash(&body.password)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    app_state.db_client
+        .update_share_password(share.id, hash_password, wrapped_key)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = ResponseDto {
+        message: "Share password updated successfully".to_string(),
+        status: "success"
+    };
+
+    Ok(Json(response))
+}
+
+/// Clears a share locked by too many wrong passwords, along with its attempt counter.
+pub async fn unlock_share(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(shared_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let share = get_owned_share(&app_state, user.user.id, &shared_id).await?;
+
+    app_state.db_client
+        .unlock_share(share.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = ResponseDto {
+        message: "Share unlocked successfully".to_string(),
+        status: "success"
+    };
+
+    Ok(Json(response))
+}
+
+/// Loads a share for its sender; shares of other users' files are reported as missing.
+async fn get_owned_share(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    shared_id: &str,
+) -> Result<OwnedShare, HttpError> {
+    let shared_id = uuid::Uuid::parse_str(shared_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid shared ID: {}", e)))?;
+
+    app_state.db_client
+        .get_owned_share(shared_id, user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found("Share not found or already revoked".to_string()))
+}
+
+/// Stores a blob the client already encrypted. The server never sees the plaintext or the
+/// AES key; it only records the wrapped key and IV alongside the ciphertext.
+pub async fn upload_encrypted_file(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    mut multipart: Multipart
+) -> Result<impl IntoResponse, HttpError> {
+
+    let mut storage_key = None;
+
+    match store_encrypted_upload(&app_state, user.user.id, &mut multipart, &mut storage_key).await {
+        Ok(entry) => audit::record(&app_state, Some(&client), entry).await,
+        Err(e) => {
+            if let Some(storage_key) = storage_key
+                && let Err(delete_err) = app_state.blob_store.delete(&storage_key).await
+            {
+                tracing::error!("Failed to remove incomplete upload {}: {}", storage_key, delete_err);
+            }
+
+            return Err(e);
+        }
+    }
+
+    let response = ResponseDto {
+        message: "Encrypted file uploaded successfully".to_string(),
+        status: "success"
+    };
+
+    Ok(Json(response))
+}
+
+async fn store_encrypted_upload(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    multipart: &mut Multipart,
+    storage_key: &mut Option<String>,
+) -> Result<AuditEntry, HttpError> {
+
+    let mut file_name = String::new();
+    let mut encrypted_size: i64 = 0;
+    let mut file_size: Option<i64> = None;
+    let mut encrypted_aes_keys = Vec::new();
+    let mut iv = Vec::new();
+    let mut cipher = FileCipher::Aes256Gcm;
+    let mut key_wrap = KeyWrap::RsaOaepSha256;
+    let mut form_data = FileUploadDtos::default();
+
+    while let Some(mut field) = multipart.next_field().await
+        .map_err(|e| HttpError::bad_request(e.to_string()))? {
+        let name = field.name().unwrap_or_default().to_string();
+
+        match name.as_str() {
+            "encryptedFile" => {
+                if storage_key.is_some() {
+                    return Err(HttpError::bad_request("Only one file can be uploaded at a time".to_string()));
+                }
+
+                file_name = field.file_name().unwrap_or("unknow_file").to_string();
+
+                let stored = write_stream_to_blob(app_state, field_stream(&mut field), None).await?;
+
+                encrypted_size = stored.size;
+                *storage_key = Some(stored.storage_key);
+            },
+            "file_size" => {
+                let value = multipart_text(field).await?;
+                file_size = Some(value.parse::<i64>()
+                    .map_err(|_| HttpError::bad_request("Invalid file size".to_string()))?);
+            },
+            "encrypted_aes_key" => {
+                encrypted_aes_keys.push(decode_base64_field(&multipart_text(field).await?, "encrypted_aes_key")?);
+            },
+            "iv" => {
+                iv = decode_base64_field(&multipart_text(field).await?, "iv")?;
+            },
+            "cipher" => {
+                cipher = FileCipher::try_from(multipart_text(field).await?.as_str())
+                    .map_err(|e| HttpError::bad_request(e.message))?;
+            },
+            "key_wrap" => {
+                key_wrap = KeyWrap::try_from(multipart_text(field).await?.as_str())
+                    .map_err(|e| HttpError::bad_request(e.message))?;
+            },
+            "recipient_email" | "recipient_emails" => {
+                add_recipients(&mut form_data.recipient_emails, &multipart_text(field).await?);
+            },
+            // The client wraps the key itself, so it has to know every recipient up front
+            "recipient_group" | "recipient_groups" => {
+                return Err(HttpError::bad_request("Group recipients are not available for end-to-end encrypted uploads, send to the group's member emails instead".to_string()));
+            },
+            "share_link" => {
+                return Err(HttpError::bad_request("Link shares are not available for end-to-end encrypted uploads".to_string()));
+            },
+            "password" => {
+                form_data.password = multipart_text(field).await?;
+            },
+            "expiration_date" => {
+                form_data.expiration_date = multipart_text(field).await?;
+            },
+            "max_downloads" => {
+                form_data.max_downloads = Some(parse_max_downloads(&multipart_text(field).await?)?);
+            },
+            "burn_after_reading" => {
+                form_data.burn_after_reading = parse_flag(&multipart_text(field).await?);
+            },
+            _ => {}
+        }
+    }
+
+    // Legacy algorithms are only kept around for decrypting old rows
+    if cipher != FileCipher::Aes256Gcm || key_wrap != KeyWrap::RsaOaepSha256 {
+        return Err(HttpError::bad_request("End-to-end uploads must use aes-256-gcm with rsa-oaep-sha256".to_string()));
+    }
+
+    let stored_key = match storage_key {
+        Some(stored_key) if encrypted_size > 0 => stored_key.clone(),
+        _ => return Err(HttpError::bad_request("Encrypted file, wrapped key and a 12 byte IV are required".to_string())),
+    };
+
+    if encrypted_aes_keys.iter().any(Vec::is_empty) || iv.len() != 12 {
+        return Err(HttpError::bad_request("Encrypted file, wrapped key and a 12 byte IV are required".to_string()));
+    }
+
+    // The n-th wrapped key belongs to the n-th recipient
+    if encrypted_aes_keys.len() != form_data.recipient_emails.len() {
+        return Err(HttpError::bad_request("One encrypted_aes_key is required per recipient, in the same order".to_string()));
+    }
+
+    let share = resolve_share_settings(app_state, user_id, form_data).await?;
+    let entry = upload_audit_entry(user_id, &share, "e2e");
+
+    let file_keys = share.recipients.iter()
+        .zip(encrypted_aes_keys)
+        .map(|(recipient, encrypted_aes_key)| NewFileKey {
+            recipient_user_id: recipient.id,
+            encrypted_aes_key,
+            key_wrap,
+        })
+        .collect();
+
+    // Without a plaintext size from the client, fall back to the ciphertext length
+    let new_file = NewFile {
+        user_id,
+        file_name,
+        file_size: file_size.unwrap_or(encrypted_size),
+        storage_key: stored_key,
+        iv,
+        cipher,
+        client_encrypted: true,
+        chunk_count: None,
+    };
+
+    let file_id = app_state.db_client
+        .save_encrypted_file(new_file, file_keys, None, share.hash_password, share.expiration_date, share.max_downloads)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    publish_share_created(&app_state.db_client, file_id).await;
+
+    Ok(entry.target(file_id))
+}
+
+/// Serves the stored ciphertext as-is, with the wrapped key and IV in response headers,
+/// so the recipient can unwrap and decrypt it with their own private key.
+pub async fn retrieve_encrypted_file(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Json(body): Json<DownloadFileDto>
+) -> Result<impl IntoResponse, HttpError> {
+    let (shared_id, file_data) = get_accepted_file(&app_state, user.user.id, &body.shared_id).await?;
+    let file_key = get_file_key(&app_state, &file_data, user.user.id).await?;
+
+    // Segmented files are served as their concatenated segments, each followed by its tag
+    let content = open_file_content(&app_state.blob_store, &app_state.db_client, &file_data)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let burn = count_download(&app_state, shared_id).await?;
+
+    let entry = AuditEntry::succeeded(AuditAction::ShareDownloaded, Some(user.user.id))
+        .target(shared_id)
+        .details(serde_json::json!({ "file_id": file_data.id }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/octet-stream")
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_data.file_name))
+        .header("X-Encrypted-Aes-Key", STANDARD.encode(&file_key.encrypted_aes_key))
+        .header("X-Iv", STANDARD.encode(&file_data.iv))
+        .header("X-Cipher", file_data.cipher)
+        .header("X-Key-Wrap", file_key.key_wrap)
+        .header("X-Segment-Size", SEGMENT_SIZE)
+        .body(burn_after_body(Body::from_stream(content), burn))
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(response)
+}
+
+async fn multipart_text(field: Field<'_>) -> Result<String, HttpError> {
+    field.text().await
+        .map_err(|e| HttpError::bad_request(e.to_string()))
+}
+
+fn decode_base64_field(value: &str, field_name: &str) -> Result<Vec<u8>, HttpError> {
+    STANDARD.decode(value.trim())
+        .map_err(|_| HttpError::bad_request(format!("{} must be base64 encoded", field_name)))
+}
@@ -5,9 +5,13 @@ use validator::Validate;
 
 use crate::{db::UserExt, dtos::{RequestQueryDto, UserReceiveFileDto, UserReceiveFileListResponseDto, UserSendFileDto, UserSendFileListResponseDto}, error::HttpError, middleware::JWTAuthMiddeware, AppState};
 
-pub fn get_file_list_handler() -> Router {
+pub fn get_sent_list_handler() -> Router {
     Router::new()
        .route("/send", get(get_user_shared_files))
+}
+
+pub fn get_received_list_handler() -> Router {
+    Router::new()
        .route("/receive", get(get_receive_shared_files))
        .route("/pendingreceive", get(get_pending_receive_files))
 }
@@ -1,4 +1,13 @@
 pub mod auth;
+pub mod oidc;
 pub mod user;
 pub mod file_query;
-pub mod file;
\ No newline at end of file
+pub mod file;
+pub mod share;
+pub mod tus;
+pub mod well_known;
+pub mod webauthn;
+pub mod admin;
+pub mod organization;
+pub mod audit;
+pub mod events;
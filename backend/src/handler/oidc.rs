@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, Query}, response::{IntoResponse, Redirect}, routing::get, Extension, Json, Router};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use chrono::{Duration, Utc};
+
+use crate::{config::OidcProviderConfig, db::UserExt, dtos::{OidcCallbackQueryDto, OidcProviderDto, OidcProviderListResponseDto}, error::{ErrorMessage, HttpError}, handler::auth::{create_mfa_token, send_verification_email, session_cookies}, models::{AuditAction, User}, utils::{audit::{self, AuditEntry}, keys::{generate_key, server_key_passphrase, unlock_passwordless_key}, oidc::{self, IdTokenClaims}, session::{start_session, ClientInfo}}, AppState};
+
+/// How long the user has to finish signing in at the identity provider.
+const AUTH_REQUEST_MAXAGE_MINUTES: i64 = 10;
+
+const STATE_COOKIE: &str = "oidc_state";
+
+const STATE_COOKIE_PATH: &str = "/api/auth/oidc";
+
+pub fn oidc_handler() -> Router {
+    Router::new()
+        .route("/providers", get(list_providers))
+        .route("/:provider/login", get(login))
+        .route("/:provider/callback", get(callback))
+}
+
+fn find_provider<'a>(app_state: &'a Arc<AppState>, name: &str) -> Result<&'a OidcProviderConfig, HttpError> {
+    app_state.env.oidc_providers
+        .iter()
+        .find(|provider| provider.name == name)
+        .ok_or_else(|| HttpError::not_found("Unknown identity provider".to_string()))
+}
+
+fn redirect_uri(app_state: &Arc<AppState>, provider: &OidcProviderConfig) -> String {
+    format!("{}/api/auth/oidc/{}/callback", app_state.env.public_url, provider.name)
+}
+
+// Ties the callback to the browser that started the flow. Lax, so it survives the
+// top-level redirect back from the provider.
+fn create_state_cookie(state: &str, maxage_minutes: Option<i64>) -> Cookie<'static> {
+    let duration = match maxage_minutes {
+        Some(minutes) => time::Duration::minutes(minutes),
+        None => time::Duration::seconds(0)
+    };
+
+    Cookie::build((STATE_COOKIE, state.to_string()))
+        .path(STATE_COOKIE_PATH)
+        .max_age(duration)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .http_only(true)
+        .build()
+}
+
+/// Starts an authorization-code flow with PKCE at `provider_name`. With a `user_id` the identity
+/// is linked to that account when the provider redirects back, instead of signing in.
+pub async fn begin_authorization(
+    app_state: &Arc<AppState>,
+    provider_name: &str,
+    user_id: Option<uuid::Uuid>,
+) -> Result<(String, Cookie<'static>), HttpError> {
+    let provider = find_provider(app_state, provider_name)?;
+
+    let state = oidc::random_token();
+    let nonce = oidc::random_token();
+    let code_verifier = oidc::random_token();
+
+    let authorization_url = app_state.oidc
+        .authorization_url(provider, &redirect_uri(app_state, provider), &state, &nonce, &code_verifier)
+        .await?;
+
+    app_state.db_client
+        .save_oidc_auth_request(
+            oidc::hash_state(&state),
+            &provider.name,
+            &nonce,
+            &code_verifier,
+            user_id,
+            Utc::now() + Duration::minutes(AUTH_REQUEST_MAXAGE_MINUTES),
+        )
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok((authorization_url, create_state_cookie(&state, Some(AUTH_REQUEST_MAXAGE_MINUTES))))
+}
+
+pub async fn list_providers(
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let providers = app_state.env.oidc_providers
+        .iter()
+        .map(|provider| OidcProviderDto {
+            name: provider.name.to_owned(),
+            display_name: provider.display_name.to_owned(),
+        })
+        .collect();
+
+    let response = OidcProviderListResponseDto {
+        status: "success".to_string(),
+        providers,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn login(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Path(provider): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let (authorization_url, state_cookie) = begin_authorization(&app_state, &provider, None).await?;
+
+    Ok((CookieJar::new().add(state_cookie), Redirect::to(&authorization_url)))
+}
+
+pub async fn callback(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Path(provider_name): Path<String>,
+    Query(query): Query<OidcCallbackQueryDto>,
+    cookie_jar: CookieJar,
+    client: ClientInfo,
+) -> Result<impl IntoResponse, HttpError> {
+    if let Some(error) = query.error {
+        let reason = query.error_description.unwrap_or(error);
+        return Err(HttpError::bad_request(format!("Sign-in was not completed: {}", reason)));
+    }
+
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return Err(HttpError::bad_request("Missing code or state".to_string()));
+    };
+
+    if cookie_jar.get(STATE_COOKIE).map(|cookie| cookie.value()) != Some(state.as_str()) {
+        return Err(HttpError::bad_request("Sign-in was started in another browser, please try again".to_string()));
+    }
+
+    let request = app_state.db_client
+        .take_oidc_auth_request(oidc::hash_state(&state))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .filter(|request| request.provider == provider_name)
+        .ok_or_else(|| HttpError::bad_request("Sign-in request expired, please try again".to_string()))?;
+
+    let provider = find_provider(&app_state, &provider_name)?;
+
+    let claims = app_state.oidc
+        .exchange_code(provider, &redirect_uri(&app_state, provider), &code, &request.code_verifier, &request.nonce)
+        .await?;
+
+    let cookie_jar = cookie_jar.add(create_state_cookie("", None));
+
+    if let Some(user_id) = request.user_id {
+        link_identity(&app_state, user_id, provider, &claims).await?;
+
+        let redirect = Redirect::to(&format!("{}/profile", app_state.env.client_url));
+        return Ok((cookie_jar, redirect).into_response());
+    }
+
+    let user = sign_in_user(&app_state, provider, &claims).await?;
+
+    // Two-factor authentication set up on the account applies to single sign-on as well. The
+    // token goes in the fragment, so it is not sent on to the frontend's server or its logs
+    if let Some(mfa_token) = create_mfa_token(&app_state, user.id).await? {
+        let redirect = Redirect::to(&format!("{}/login/mfa#mfa_token={}", app_state.env.client_url, mfa_token));
+        return Ok((cookie_jar, redirect).into_response());
+    }
+
+    // Only now that no second factor is outstanding, otherwise /login/mfa unlocks it. A newly
+    // provisioned account already has its fresh key cached
+    if app_state.key_cache.get(user.id).is_none() {
+        unlock_passwordless_key(&app_state, &user).await?;
+    }
+
+    let tokens = start_session(&app_state, user.id, &client).await?;
+
+    let entry = AuditEntry::succeeded(AuditAction::Login, Some(user.id))
+        .details(serde_json::json!({ "method": "oidc", "provider": provider.name }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let cookie_jar = session_cookies(&app_state, &tokens)
+        .into_iter()
+        .fold(cookie_jar, |cookie_jar, cookie| cookie_jar.add(cookie));
+
+    Ok((cookie_jar, Redirect::to(&app_state.env.client_url)).into_response())
+}
+
+async fn link_identity(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    provider: &OidcProviderConfig,
+    claims: &IdTokenClaims,
+) -> Result<(), HttpError> {
+    let result = app_state.db_client
+        .link_user_identity(user_id, &provider.name, &claims.iss, &claims.sub, claims.email.as_deref())
+        .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            Err(HttpError::unique_constraint_violation(format!(
+                "This {} account is already linked, or your account already has one linked",
+                provider.display_name
+            )))
+        }
+        Err(e) => Err(HttpError::server_error(e.to_string())),
+    }
+}
+
+/// The account a verified identity signs in as. The first sign-in of an unknown identity
+/// provisions a passwordless account for it, unless its email already belongs to an account,
+/// which has to link the identity itself.
+async fn sign_in_user(
+    app_state: &Arc<AppState>,
+    provider: &OidcProviderConfig,
+    claims: &IdTokenClaims,
+) -> Result<User, HttpError> {
+    let identity = app_state.db_client
+        .record_identity_login(&claims.iss, &claims.sub)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if let Some(identity) = identity {
+        let user = app_state.db_client
+            .get_user(Some(identity.user_id), None, None)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .ok_or_else(|| HttpError::unauthorized(ErrorMessage::UserNoLongerExist.to_string()))?;
+
+        return Ok(user);
+    }
+
+    let email = claims.email
+        .as_deref()
+        .ok_or_else(|| HttpError::bad_request("The identity provider did not share an email address".to_string()))?;
+
+    let email_taken = app_state.db_client
+        .get_user(None, None, Some(email))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .is_some();
+
+    if email_taken {
+        return Err(HttpError::unique_constraint_violation(format!(
+            "An account with this email already exists. Log in and link your {} account from your profile",
+            provider.display_name
+        )));
+    }
+
+    let name: String = claims.name
+        .as_deref()
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or(email)
+        .chars()
+        .take(100)
+        .collect();
+
+    let user = app_state.db_client
+        .save_passwordless_user(uuid::Uuid::new_v4(), &name, email, claims.email_verified())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let passphrase = server_key_passphrase(app_state, user.id)?;
+    let private_key = generate_key(app_state, &user, &passphrase).await?;
+
+    link_identity(app_state, user.id, provider, claims).await?;
+
+    if user.email_verified_at.is_none() {
+        send_verification_email(app_state, &user)?;
+    }
+
+    app_state.key_cache.insert(user.id, private_key, app_state.env.jwt_maxage);
+
+    Ok(user)
+}
@@ -0,0 +1,575 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::{delete, get, post, put}, Extension, Json, Router};
+use chrono::{Duration, Utc};
+use validator::Validate;
+
+use crate::{db::UserExt, dtos::{AddGroupMemberDto, CreateGroupDto, CreateOrganizationDto, GroupDto, GroupListResponseDto, GroupMemberDto, GroupMemberListResponseDto, GroupResponseDto, InviteOrganizationMemberDto, OrganizationDto, OrganizationInvitationDto, OrganizationInvitationListResponseDto, OrganizationInvitationResponseDto, OrganizationMemberDto, OrganizationMemberListResponseDto, OrganizationPolicyDto, OrganizationResponseDto, Response, UpdateOrganizationMemberDto}, error::{ErrorMessage, HttpError}, mail::{organization_invitation_email, send_in_background}, middleware::JWTAuthMiddeware, models::{Group, OrgRole, Organization, User}, AppState};
+
+/// How long an invitation to join an organization can be accepted.
+const INVITATION_MAXAGE_DAYS: i64 = 7;
+
+// Every account belongs to at most one organization, so routes act on the caller's own
+pub fn organization_handler() -> Router {
+    Router::new()
+        .route("/", get(get_organization).post(create_organization).delete(delete_organization))
+        .route("/policy", put(update_policy))
+        .route("/members", get(get_members))
+        .route("/members/:user_id", put(update_member).delete(remove_member))
+        .route("/invitations", get(get_invitations).post(invite_member))
+        .route("/invitations/received", get(get_received_invitations))
+        .route("/invitations/:invitation_id", delete(delete_invitation))
+        .route("/invitations/:invitation_id/accept", post(accept_invitation))
+        .route("/groups", get(get_groups).post(create_group))
+        .route("/groups/:group_id", delete(delete_group))
+        .route("/groups/:group_id/members", get(get_group_members).post(add_group_member))
+        .route("/groups/:group_id/members/:user_id", delete(remove_group_member))
+}
+
+async fn current_organization(app_state: &Arc<AppState>, user_id: uuid::Uuid) -> Result<Organization, HttpError> {
+    app_state.db_client
+        .get_user_organization(user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found("You are not a member of an organization".to_string()))
+}
+
+// Members, groups and the policy are managed by owners only
+async fn owned_organization(app_state: &Arc<AppState>, user_id: uuid::Uuid) -> Result<Organization, HttpError> {
+    let organization = current_organization(app_state, user_id).await?;
+
+    if organization.role()? != OrgRole::Owner {
+        return Err(HttpError::new(ErrorMessage::PermissionDenied.to_string(), StatusCode::FORBIDDEN));
+    }
+
+    Ok(organization)
+}
+
+async fn get_group_in(app_state: &Arc<AppState>, organization_id: uuid::Uuid, group_id: &str) -> Result<Group, HttpError> {
+    let group_id = uuid::Uuid::parse_str(group_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid group ID: {}", e)))?;
+
+    app_state.db_client
+        .get_group(organization_id, group_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found("Group not found".to_string()))
+}
+
+async fn get_user_by_email(app_state: &Arc<AppState>, email: &str) -> Result<User, HttpError> {
+    app_state.db_client
+        .get_user(None, None, Some(email))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found(format!("User {} not found", email)))
+}
+
+fn parse_invitation_id(invitation_id: &str) -> Result<uuid::Uuid, HttpError> {
+    uuid::Uuid::parse_str(invitation_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid invitation ID: {}", e)))
+}
+
+fn parse_user_id(user_id: &str) -> Result<uuid::Uuid, HttpError> {
+    uuid::Uuid::parse_str(user_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid user ID: {}", e)))
+}
+
+pub async fn get_organization(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = current_organization(&app_state, user.user.id).await?;
+
+    let response = OrganizationResponseDto {
+        status: "success".to_string(),
+        organization: OrganizationDto::filter_organization(&organization),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn create_organization(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<CreateOrganizationDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let organization = match app_state.db_client.create_organization(user.user.id, body.name.trim()).await {
+        Ok(organization) => organization,
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            return Err(HttpError::unique_constraint_violation("You already belong to an organization".to_string()));
+        }
+        Err(e) => return Err(HttpError::server_error(e.to_string())),
+    };
+
+    let response = OrganizationResponseDto {
+        status: "success".to_string(),
+        organization: OrganizationDto::filter_organization(&organization),
+    };
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+/// Deletes the organization with its groups. Files already shared are left as they are.
+pub async fn delete_organization(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = owned_organization(&app_state, user.user.id).await?;
+
+    app_state.db_client
+        .delete_organization(organization.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = Response {
+        status: "success",
+        message: "Organization deleted".to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn update_policy(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<OrganizationPolicyDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = owned_organization(&app_state, user.user.id).await?;
+
+    // Domains are compared case-insensitively against recipient emails
+    let mut allowed_domains: Vec<String> = body.allowed_domains.iter()
+        .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+        .collect();
+    allowed_domains.sort();
+    allowed_domains.dedup();
+
+    let body = OrganizationPolicyDto { allowed_domains, ..body };
+
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    app_state.db_client
+        .update_organization_policy(organization.id, &body.allowed_domains, body.max_expiry_days)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let organization = current_organization(&app_state, user.user.id).await?;
+
+    let response = OrganizationResponseDto {
+        status: "success".to_string(),
+        organization: OrganizationDto::filter_organization(&organization),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_members(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = current_organization(&app_state, user.user.id).await?;
+
+    let members = app_state.db_client
+        .get_organization_members(organization.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = OrganizationMemberListResponseDto {
+        status: "success".to_string(),
+        members: OrganizationMemberDto::filter_members(&members),
+    };
+
+    Ok(Json(response))
+}
+
+/// Nobody is added to an organization without their consent: owners invite an existing
+/// account, which joins once it accepts.
+pub async fn invite_member(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<InviteOrganizationMemberDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let organization = owned_organization(&app_state, user.user.id).await?;
+    let invitee = get_user_by_email(&app_state, &body.email).await?;
+    let role = body.role.unwrap_or(OrgRole::Member);
+
+    let already_member = app_state.db_client
+        .get_user_organization(invitee.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .is_some_and(|invitee_organization| invitee_organization.id == organization.id);
+
+    if already_member {
+        return Err(HttpError::unique_constraint_violation(format!("{} is already a member", body.email)));
+    }
+
+    let expires_at = Utc::now() + Duration::days(INVITATION_MAXAGE_DAYS);
+
+    let invitation = app_state.db_client
+        .save_organization_invitation(organization.id, invitee.id, user.user.id, role, expires_at)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let link = format!("{}/organization/invitations", app_state.env.client_url);
+    send_in_background(
+        &app_state.mailer,
+        organization_invitation_email(&invitee.email, &invitee.name, &organization.name, &user.user.name, &link),
+    );
+
+    let response = OrganizationInvitationResponseDto {
+        status: "success".to_string(),
+        invitation: OrganizationInvitationDto::filter_invitation(&invitation),
+    };
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+pub async fn get_invitations(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = owned_organization(&app_state, user.user.id).await?;
+
+    let invitations = app_state.db_client
+        .get_organization_invitations(organization.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = OrganizationInvitationListResponseDto {
+        status: "success".to_string(),
+        invitations: OrganizationInvitationDto::filter_invitations(&invitations),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_received_invitations(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let invitations = app_state.db_client
+        .get_user_invitations(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = OrganizationInvitationListResponseDto {
+        status: "success".to_string(),
+        invitations: OrganizationInvitationDto::filter_invitations(&invitations),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn accept_invitation(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(invitation_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let invitation_id = parse_invitation_id(&invitation_id)?;
+
+    match app_state.db_client.accept_organization_invitation(invitation_id, user.user.id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(HttpError::not_found("Invitation not found or has expired".to_string())),
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            return Err(HttpError::unique_constraint_violation("You already belong to an organization; leave it first".to_string()));
+        }
+        Err(e) => return Err(HttpError::server_error(e.to_string())),
+    }
+
+    let organization = current_organization(&app_state, user.user.id).await?;
+
+    let response = OrganizationResponseDto {
+        status: "success".to_string(),
+        organization: OrganizationDto::filter_organization(&organization),
+    };
+
+    Ok(Json(response))
+}
+
+/// The invitee declines an invitation; the organization's owners withdraw it.
+pub async fn delete_invitation(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(invitation_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let invitation_id = parse_invitation_id(&invitation_id)?;
+
+    let invitation = app_state.db_client
+        .get_organization_invitation(invitation_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found("Invitation not found".to_string()))?;
+
+    if invitation.user_id != user.user.id {
+        let organization = owned_organization(&app_state, user.user.id).await?;
+
+        // Invitations of other organizations are not found, rather than forbidden
+        if organization.id != invitation.organization_id {
+            return Err(HttpError::not_found("Invitation not found".to_string()));
+        }
+    }
+
+    app_state.db_client
+        .delete_organization_invitation(invitation.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let message = match invitation.user_id == user.user.id {
+        true => "Invitation declined",
+        false => "Invitation withdrawn",
+    };
+
+    let response = Response {
+        status: "success",
+        message: message.to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn update_member(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(user_id): Path<String>,
+    Json(body): Json<UpdateOrganizationMemberDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = owned_organization(&app_state, user.user.id).await?;
+    let user_id = parse_user_id(&user_id)?;
+
+    if body.role != OrgRole::Owner {
+        ensure_other_owner(&app_state, &organization, user_id).await?;
+    }
+
+    let updated = app_state.db_client
+        .update_organization_member_role(organization.id, user_id, body.role)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !updated {
+        return Err(HttpError::not_found("Member not found".to_string()));
+    }
+
+    let response = Response {
+        status: "success",
+        message: format!("Role changed to {}", body.role.as_str()),
+    };
+
+    Ok(Json(response))
+}
+
+/// Owners remove members; anyone can leave. Removed members leave the organization's groups
+/// too, but keep the files already shared with them.
+pub async fn remove_member(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    let organization = match user_id == user.user.id {
+        true => current_organization(&app_state, user.user.id).await?,
+        false => owned_organization(&app_state, user.user.id).await?,
+    };
+
+    ensure_other_owner(&app_state, &organization, user_id).await?;
+
+    let removed = app_state.db_client
+        .remove_organization_member(organization.id, user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !removed {
+        return Err(HttpError::not_found("Member not found".to_string()));
+    }
+
+    let response = Response {
+        status: "success",
+        message: "Member removed".to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+// Refuses to leave an organization without an owner when `user_id` is its last one
+async fn ensure_other_owner(app_state: &Arc<AppState>, organization: &Organization, user_id: uuid::Uuid) -> Result<(), HttpError> {
+    let members = app_state.db_client
+        .get_organization_members(organization.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let is_owner = members.iter()
+        .any(|member| member.user_id == user_id && member.role == OrgRole::Owner.as_str());
+
+    if !is_owner {
+        return Ok(());
+    }
+
+    let owners = app_state.db_client
+        .count_organization_owners(organization.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if owners <= 1 {
+        return Err(HttpError::bad_request("An organization needs an owner; make someone else an owner or delete the organization".to_string()));
+    }
+
+    Ok(())
+}
+
+pub async fn get_groups(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = current_organization(&app_state, user.user.id).await?;
+
+    let groups = app_state.db_client
+        .get_groups(organization.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = GroupListResponseDto {
+        status: "success".to_string(),
+        groups: GroupDto::filter_groups(&groups),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn create_group(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<CreateGroupDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let organization = owned_organization(&app_state, user.user.id).await?;
+
+    let group = match app_state.db_client.create_group(organization.id, body.name.trim()).await {
+        Ok(group) => group,
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            return Err(HttpError::unique_constraint_violation("A group with this name already exists".to_string()));
+        }
+        Err(e) => return Err(HttpError::server_error(e.to_string())),
+    };
+
+    let response = GroupResponseDto {
+        status: "success".to_string(),
+        group: GroupDto::filter_group(&group),
+    };
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+pub async fn delete_group(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(group_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = owned_organization(&app_state, user.user.id).await?;
+    let group = get_group_in(&app_state, organization.id, &group_id).await?;
+
+    app_state.db_client
+        .delete_group(organization.id, group.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = Response {
+        status: "success",
+        message: "Group deleted".to_string(),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_group_members(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(group_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = current_organization(&app_state, user.user.id).await?;
+    let group = get_group_in(&app_state, organization.id, &group_id).await?;
+
+    let members = app_state.db_client
+        .get_group_members(group.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = GroupMemberListResponseDto {
+        status: "success".to_string(),
+        members: GroupMemberDto::filter_members(&members),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn add_group_member(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(group_id): Path<String>,
+    Json(body): Json<AddGroupMemberDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let organization = owned_organization(&app_state, user.user.id).await?;
+    let group = get_group_in(&app_state, organization.id, &group_id).await?;
+    let member = get_user_by_email(&app_state, &body.email).await?;
+
+    let in_organization = app_state.db_client
+        .get_user_organization(member.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .is_some_and(|member_organization| member_organization.id == organization.id);
+
+    if !in_organization {
+        return Err(HttpError::bad_request(format!("{} is not a member of the organization", body.email)));
+    }
+
+    match app_state.db_client.add_group_member(organization.id, group.id, member.id).await {
+        Ok(()) => {}
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            return Err(HttpError::unique_constraint_violation(format!("{} is already in the group", body.email)));
+        }
+        Err(e) => return Err(HttpError::server_error(e.to_string())),
+    }
+
+    let response = Response {
+        status: "success",
+        message: format!("{} added to {}", body.email, group.name),
+    };
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+pub async fn remove_group_member(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path((group_id, user_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, HttpError> {
+    let organization = owned_organization(&app_state, user.user.id).await?;
+    let group = get_group_in(&app_state, organization.id, &group_id).await?;
+    let user_id = parse_user_id(&user_id)?;
+
+    let removed = app_state.db_client
+        .remove_group_member(group.id, user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !removed {
+        return Err(HttpError::not_found("Member not found in the group".to_string()));
+    }
+
+    let response = Response {
+        status: "success",
+        message: "Member removed from the group".to_string(),
+    };
+
+    Ok(Json(response))
+}
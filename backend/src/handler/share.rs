@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::{HeaderMap, Method}, response::IntoResponse, routing::post, Extension, Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+use validator::Validate;
+
+use crate::{db::UserExt, dtos::PublicDownloadDto, error::HttpError, handler::file::{send_file, FileAccess}, models::{AuditAction, PasswordAttempts}, utils::{attempts::{verify_share_password, ShareAttempt}, audit::{self, AuditEntry}, cipher::LinkKey, keys::unwrap_link_key, session::ClientInfo}, AppState};
+
+/// Link shares are opened without an account, so these routes sit outside the auth layer.
+pub fn share_handler() -> Router {
+    Router::new()
+        .route("/:shared_id/download", post(download_shared_link))
+}
+
+pub async fn download_shared_link(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Path(shared_id): Path<String>,
+    client: ClientInfo,
+    method: Method,
+    headers: HeaderMap,
+    Json(body): Json<PublicDownloadDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let shared_id = uuid::Uuid::parse_str(&shared_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid shared ID: {}", e)))?;
+
+    let link_share = app_state.db_client
+        .get_link_share(shared_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::bad_request("Shared link not found or has expired".to_string()))?;
+
+    let share = ShareAttempt {
+        shared_id: link_share.id,
+        password_hash: &link_share.password,
+        attempts: PasswordAttempts {
+            failed_attempts: link_share.failed_attempts,
+            last_attempt_at: link_share.last_attempt_at,
+        },
+        locked_at: link_share.locked_at,
+    };
+
+    // Link shares have no recipient account, so only the share's own counter applies
+    if let Err(e) = verify_share_password(&app_state, share, None, &body.password).await {
+        let entry = AuditEntry::failed(AuditAction::ShareDownloaded, None).target(link_share.id);
+        audit::record(&app_state, Some(&client), entry).await;
+
+        return Err(e);
+    }
+
+    let aes_key = match LinkKey::try_from(link_share.link_key.as_str())? {
+        LinkKey::Fragment => {
+            // The key never reached the server in the clear; only its hash was kept to check it
+            let aes_key = body.key
+                .as_deref()
+                .and_then(|key| URL_SAFE_NO_PAD.decode(key).ok())
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| HttpError::bad_request("A valid link key is required".to_string()))?;
+
+            if link_share.key_check.as_deref() != Some(Sha256::digest(&aes_key).as_slice()) {
+                return Err(HttpError::unauthorized("Invalid link key".to_string()));
+            }
+
+            aes_key
+        }
+        LinkKey::Password => {
+            let wrapped = link_share.wrapped_key()
+                .ok_or_else(|| HttpError::server_error("Link share has no stored key".to_string()))?;
+
+            unwrap_link_key(link_share.id, &wrapped, &body.password)?
+        }
+    };
+
+    let file_id = link_share.file_id
+        .ok_or_else(|| HttpError::server_error("File ID not found in shared link".to_string()))?;
+
+    let file_data = app_state.db_client
+        .get_file(file_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::bad_request("The requested file either does not exist or has expired.".to_string()))?;
+
+    send_file(&app_state, &client, link_share.id, file_data, FileAccess::Key(aes_key), method, &headers).await
+}
@@ -0,0 +1,411 @@
+use std::{collections::HashMap, future::ready, sync::Arc};
+
+use axum::{body::{Body, Bytes}, extract::Path, http::{HeaderMap, HeaderValue, Response, StatusCode}, middleware, response::IntoResponse, routing::{head, post}, Extension, Router};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use futures_util::{stream, StreamExt, TryStreamExt};
+
+use crate::{db::UserExt, dtos::FileUploadDtos, error::HttpError, handler::file::{add_recipients, parse_flag, parse_max_downloads, resolve_share_settings, save_server_encrypted_file, upload_audit_entry, write_stream_to_blob, ShareSettings}, middleware::JWTAuthMiddeware, models::{TusUpload, TusUploadPart}, storage::delete_blobs, utils::{audit::{self, AuditEntry}, decrypt::{decrypt_file_stream, StreamDecryptor}, encrypt::{generate_file_key, segment_count, StreamEncryptor}, range::http_date, session::ClientInfo}, AppState};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+pub fn tus_handler() -> Router {
+    Router::new()
+    .route("/", post(create_upload))
+    .route("/:upload_id", head(get_upload_offset).patch(append_upload).delete(terminate_upload))
+    .layer(middleware::map_response(add_tus_headers))
+}
+
+// Discovery headers go on every response: the CORS layer answers OPTIONS requests itself
+async fn add_tus_headers(
+    Extension(app_state): Extension<Arc<AppState>>,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    let headers = response.headers_mut();
+    headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("Tus-Extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    headers.insert("Tus-Max-Size", HeaderValue::from(app_state.env.max_upload_bytes));
+    response
+}
+
+/// Creates an upload. The share settings travel in `Upload-Metadata` and are checked here,
+/// so a bad recipient or password fails before any content is sent.
+pub async fn create_upload(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, HttpError> {
+    check_tus_resumable(&headers)?;
+
+    let upload_length = header_i64(&headers, "Upload-Length")?
+        .ok_or_else(|| HttpError::bad_request("Upload-Length is required".to_string()))?;
+
+    if upload_length < 0 {
+        return Err(HttpError::bad_request("Upload-Length must not be negative".to_string()));
+    }
+
+    if upload_length as u64 > app_state.env.max_upload_bytes as u64 {
+        return Err(HttpError::new("Upload exceeds the maximum allowed size", StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    let metadata = parse_metadata(&headers)?;
+
+    let file_name = metadata.get("filename")
+        .or_else(|| metadata.get("name"))
+        .cloned()
+        .unwrap_or_else(|| "unknow_file".to_string());
+
+    let mut form_data = FileUploadDtos {
+        recipient_emails: Vec::new(),
+        recipient_groups: Vec::new(),
+        password: metadata.get("password").cloned().unwrap_or_default(),
+        expiration_date: metadata.get("expiration_date").cloned().unwrap_or_default(),
+        // The link URL has nowhere to go in a tus response, so links are only made by /upload
+        share_link: None,
+        max_downloads: metadata.get("max_downloads").map(|value| parse_max_downloads(value)).transpose()?,
+        burn_after_reading: metadata.get("burn_after_reading").is_some_and(|value| parse_flag(value)),
+        policy: None,
+    };
+
+    for key in ["recipient_email", "recipient_emails"] {
+        if let Some(value) = metadata.get(key) {
+            add_recipients(&mut form_data.recipient_emails, value);
+        }
+    }
+
+    for key in ["recipient_group", "recipient_groups"] {
+        if let Some(value) = metadata.get(key) {
+            add_recipients(&mut form_data.recipient_groups, value);
+        }
+    }
+
+    let share = resolve_share_settings(&app_state, user.user.id, form_data).await?;
+
+    // Received parts are staged encrypted under a key of their own until the upload completes
+    let (staging_key, _) = generate_file_key();
+
+    let upload = app_state.db_client
+        .create_tus_upload(
+            user.user.id,
+            file_name,
+            upload_length,
+            share.recipients.iter().map(|recipient| recipient.id).collect(),
+            share.hash_password,
+            share.expiration_date,
+            share.max_downloads,
+            staging_key.to_vec()
+        )
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // An empty file is complete as soon as it exists
+    if upload_length == 0 {
+        finish_upload(&app_state, &client, &upload).await?;
+    }
+
+    Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Location", format!("/api/file/tus/{}", upload.id))
+        .header("Upload-Expires", upload_expires(&app_state, &upload))
+        .body(Body::empty())
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+pub async fn get_upload_offset(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(upload_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, HttpError> {
+    check_tus_resumable(&headers)?;
+
+    let upload = get_upload(&app_state, upload_id, user.user.id).await?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Upload-Offset", upload.upload_offset)
+        .header("Upload-Length", upload.upload_length)
+        .header("Upload-Expires", upload_expires(&app_state, &upload))
+        .header("Cache-Control", "no-store")
+        .body(Body::empty())
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+/// Appends the request body at `Upload-Offset`. If the connection drops, whatever arrived is
+/// kept so the client can resume from the new offset.
+pub async fn append_upload(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Path(upload_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, HttpError> {
+    check_tus_resumable(&headers)?;
+
+    let content_type = headers.get("Content-Type").and_then(|value| value.to_str().ok());
+    if content_type != Some(OFFSET_CONTENT_TYPE) {
+        return Err(HttpError::new(format!("Content-Type must be {}", OFFSET_CONTENT_TYPE), StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+
+    let upload_offset = header_i64(&headers, "Upload-Offset")?
+        .ok_or_else(|| HttpError::bad_request("Upload-Offset is required".to_string()))?;
+
+    let upload = get_upload(&app_state, upload_id, user.user.id).await?;
+
+    if upload_offset != upload.upload_offset {
+        return Err(HttpError::new("Upload-Offset does not match the current offset", StatusCode::CONFLICT));
+    }
+
+    let remaining = upload.upload_length - upload.upload_offset;
+    let mut received: i64 = 0;
+
+    // A dropped connection ends the part early instead of failing it
+    let content = body.into_data_stream()
+        .take_while(|chunk| ready(chunk.is_ok()))
+        .filter_map(|chunk| ready(chunk.ok()))
+        .map(move |chunk: Bytes| {
+            received += chunk.len() as i64;
+
+            if received > remaining {
+                return Err(HttpError::new("Upload exceeds its Upload-Length", StatusCode::PAYLOAD_TOO_LARGE));
+            }
+
+            Ok(chunk)
+        });
+
+    let (_, part_prefix) = generate_file_key();
+    let encryptor = StreamEncryptor::new(&staging_key(&upload)?, part_prefix);
+
+    let stored = write_stream_to_blob(&app_state, Box::pin(content), Some(encryptor)).await?;
+
+    if stored.size == 0 {
+        delete_blobs(&app_state.blob_store, vec![stored.storage_key]).await;
+        return offset_response(upload.upload_offset);
+    }
+
+    let part = TusUploadPart {
+        part_offset: upload_offset,
+        part_size: stored.size,
+        storage_key: stored.storage_key.clone(),
+        iv: part_prefix.to_vec(),
+    };
+
+    let new_offset = app_state.db_client
+        .add_tus_upload_part(upload.id, part)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()));
+
+    // Another request appended at the same offset first
+    let new_offset = match new_offset {
+        Ok(Some(new_offset)) => new_offset,
+        Ok(None) => {
+            delete_blobs(&app_state.blob_store, vec![stored.storage_key]).await;
+            return Err(HttpError::new("Upload-Offset does not match the current offset", StatusCode::CONFLICT));
+        }
+        Err(e) => {
+            delete_blobs(&app_state.blob_store, vec![stored.storage_key]).await;
+            return Err(e);
+        }
+    };
+
+    if new_offset == upload.upload_length {
+        finish_upload(&app_state, &client, &upload).await?;
+    }
+
+    offset_response(new_offset)
+}
+
+pub async fn terminate_upload(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(upload_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, HttpError> {
+    check_tus_resumable(&headers)?;
+
+    let upload = get_upload(&app_state, upload_id, user.user.id).await?;
+
+    remove_upload(&app_state, &upload).await?;
+
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+/// Decrypts the staged parts in order and runs them through the regular upload encryption,
+/// sharing the file with the recipients chosen at creation. A failed upload is discarded.
+async fn finish_upload(app_state: &Arc<AppState>, client: &ClientInfo, upload: &TusUpload) -> Result<(), HttpError> {
+    let result = encrypt_staged_upload(app_state, upload).await;
+
+    if let Err(e) = remove_upload(app_state, upload).await {
+        tracing::error!("Failed to remove tus upload {}: {}", upload.id, e.message);
+    }
+
+    audit::record(app_state, Some(client), result?).await;
+
+    Ok(())
+}
+
+async fn encrypt_staged_upload(app_state: &Arc<AppState>, upload: &TusUpload) -> Result<AuditEntry, HttpError> {
+    let parts = app_state.db_client
+        .get_tus_upload_parts(upload.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let mut recipients = Vec::with_capacity(upload.recipient_user_ids.len());
+
+    for recipient_user_id in &upload.recipient_user_ids {
+        let recipient = app_state.db_client
+            .get_user_by_id(*recipient_user_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .ok_or_else(|| HttpError::not_found("Recipient not found".to_string()))?;
+
+        recipients.push(recipient);
+    }
+
+    let staging_key = staging_key(upload)?;
+    let blob_store = app_state.blob_store.clone();
+
+    let content = stream::iter(parts)
+        .then(move |part| {
+            let blob_store = blob_store.clone();
+
+            async move {
+                let decryptor = StreamDecryptor::from_key(&staging_key, &part.iv)?;
+
+                let blob = blob_store
+                    .get(&part.storage_key)
+                    .await
+                    .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+                let final_segment = segment_count(part.part_size as u64) as u32 - 1;
+
+                Ok::<_, HttpError>(decrypt_file_stream(blob, decryptor, 0..=final_segment, final_segment))
+            }
+        })
+        .try_flatten();
+
+    let (aes_key, nonce_prefix) = generate_file_key();
+    let encryptor = StreamEncryptor::new(&aes_key, nonce_prefix);
+
+    let stored = write_stream_to_blob(app_state, Box::pin(content), Some(encryptor)).await?;
+    let storage_key = stored.storage_key.clone();
+
+    let share = ShareSettings {
+        recipients,
+        hash_password: upload.password.clone(),
+        expiration_date: upload.expiration_date,
+        max_downloads: upload.max_downloads,
+        link: None,
+    };
+    let entry = upload_audit_entry(upload.user_id, &share, "tus");
+
+    let saved = save_server_encrypted_file(
+        app_state,
+        upload.user_id,
+        upload.file_name.clone(),
+        stored,
+        &aes_key,
+        nonce_prefix,
+        share
+    ).await;
+
+    if saved.is_err() {
+        delete_blobs(&app_state.blob_store, vec![storage_key]).await;
+    }
+
+    saved.map(|(file_id, _)| entry.target(file_id))
+}
+
+async fn remove_upload(app_state: &Arc<AppState>, upload: &TusUpload) -> Result<(), HttpError> {
+    let storage_keys = app_state.db_client
+        .delete_tus_upload(upload.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    delete_blobs(&app_state.blob_store, storage_keys).await;
+
+    Ok(())
+}
+
+async fn get_upload(
+    app_state: &Arc<AppState>,
+    upload_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+) -> Result<TusUpload, HttpError> {
+    app_state.db_client
+        .get_tus_upload(upload_id, user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found("Upload not found".to_string()))
+}
+
+fn staging_key(upload: &TusUpload) -> Result<[u8; 32], HttpError> {
+    upload.staging_key.as_slice()
+        .try_into()
+        .map_err(|_| HttpError::server_error("Invalid staging key".to_string()))
+}
+
+fn offset_response(upload_offset: i64) -> Result<Response<Body>, HttpError> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Upload-Offset", upload_offset)
+        .body(Body::empty())
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+// Uploads are removed by the scheduler once they have been idle for this long
+fn upload_expires(app_state: &Arc<AppState>, upload: &TusUpload) -> String {
+    let last_activity = upload.updated_at.unwrap_or_else(Utc::now);
+    http_date(last_activity + Duration::hours(app_state.env.tus_upload_expiry_hours))
+}
+
+fn check_tus_resumable(headers: &HeaderMap) -> Result<(), HttpError> {
+    match headers.get("Tus-Resumable").and_then(|value| value.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(HttpError::new(format!("Tus-Resumable must be {}", TUS_VERSION), StatusCode::PRECONDITION_FAILED)),
+    }
+}
+
+fn header_i64(headers: &HeaderMap, name: &str) -> Result<Option<i64>, HttpError> {
+    headers.get(name)
+        .map(|value| {
+            value.to_str()
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .ok_or_else(|| HttpError::bad_request(format!("Invalid {} header", name)))
+        })
+        .transpose()
+}
+
+/// Parses `Upload-Metadata`: comma separated `key base64value` pairs, the value being optional.
+fn parse_metadata(headers: &HeaderMap) -> Result<HashMap<String, String>, HttpError> {
+    let mut metadata = HashMap::new();
+
+    let Some(value) = headers.get("Upload-Metadata") else {
+        return Ok(metadata);
+    };
+
+    let value = value.to_str()
+        .map_err(|_| HttpError::bad_request("Invalid Upload-Metadata header".to_string()))?;
+
+    for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, encoded) = pair.split_once(' ').unwrap_or((pair, ""));
+
+        let decoded = STANDARD.decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| HttpError::bad_request(format!("Invalid Upload-Metadata value for {}", key)))?;
+
+        metadata.insert(key.to_string(), decoded);
+    }
+
+    Ok(metadata)
+}
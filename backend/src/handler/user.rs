@@ -1,20 +1,40 @@
 use std::sync::Arc;
 
-use axum::{extract::Query, response::IntoResponse, routing::{get, put}, Extension, Json, Router};
+use axum::{extract::{Path, Query}, http::StatusCode, response::IntoResponse, routing::{delete, get, post, put}, Extension, Json, Router};
+use rsa::pkcs1::EncodeRsaPublicKey;
 use validator::Validate;
 
-use crate::{db::UserExt, dtos::{EmailListResponseDto, FilterEmailDto, FilterUserDto, NameUpdateDto, Response, SearchQueryByEmailDTO, UserData, UserPasswordUpdateDto, UserResponseDto}, error::{ErrorMessage, HttpError}, middleware::JWTAuthMiddeware, utils::password, AppState};
+use crate::{db::UserExt, dtos::{AccessTokenDto, AccessTokenListResponseDto, CreateAccessTokenDto, CreateAccessTokenResponseDto, EmailListResponseDto, FilterEmailDto, FilterUserDto, IdentityDto, IdentityListResponseDto, LinkIdentityDto, NameUpdateDto, OidcAuthorizationResponseDto, PublicKeyQueryDto, PublicKeyResponseDto, RecoveryCodesResponseDto, Response, SearchQueryByEmailDTO, SessionDto, SessionListResponseDto, TotpCodeDto, TotpDisableDto, TotpSetupResponseDto, TotpStatusResponseDto, UserData, UserKeysDto, UserPasswordUpdateDto, UserResponseDto}, error::{ErrorMessage, HttpError}, handler::{auth::{clear_auth_cookies, create_auth_response, send_verification_email}, oidc::begin_authorization}, middleware::JWTAuthMiddeware, models::{AuditAction, Scope}, utils::{audit::{self, AuditEntry}, keys::{parse_public_key, unlock_private_key, wrap_private_key}, password, session::ClientInfo, token, totp}, AppState};
 
 
-pub fn users_handler() -> Router {
+// Read-only lookups, granted to access tokens by `users:read`
+pub fn users_read_handler() -> Router {
     Router::new()
         .route(
             "/me", 
             get(get_me)
     )
+    .route("/search-emails", get(search_by_email))
+    .route("/keys", get(get_public_key))
+}
+
+// Account management, which needs a login session
+pub fn users_handler() -> Router {
+    Router::new()
     .route("/name", put(update_user_name))
     .route("/password", put(update_user_password))
-    .route("/search-emails", get(search_by_email))
+    .route("/keys", put(update_user_keys))
+    .route("/tokens", get(get_access_tokens).post(create_access_token))
+    .route("/tokens/:token_id", delete(revoke_access_token))
+    .route("/identities", get(get_identities).post(link_identity))
+    .route("/identities/:identity_id", delete(unlink_identity))
+    .route("/sessions", get(get_sessions).delete(revoke_all_sessions))
+    .route("/sessions/:session_id", delete(revoke_session))
+    .route("/verify-email", post(resend_verification_email))
+    .route("/totp", get(get_totp_status).delete(disable_totp))
+    .route("/totp/setup", post(setup_totp))
+    .route("/totp/confirm", post(confirm_totp))
+    .route("/totp/recovery-codes", post(regenerate_recovery_codes))
 }
 
 
@@ -45,7 +65,7 @@ pub async fn update_user_name(
     let user_id = uuid::Uuid::parse_str(&user.id.to_string()).unwrap();
 
     let result = app_state.db_client
-        .update_user_name(user_id.clone(), &body.name)
+        .update_user_name(user_id, &body.name)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
@@ -62,6 +82,7 @@ pub async fn update_user_name(
 pub async fn update_user_password(
     Extension(app_state): Extension<Arc<AppState>>,
     Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
     Json(body): Json<UserPasswordUpdateDto>
 ) -> Result<impl IntoResponse, HttpError> {
     body.validate()
@@ -72,31 +93,50 @@ pub async fn update_user_password(
     let user_id = uuid::Uuid::parse_str(&user.id.to_string()).unwrap();
 
     let result = app_state.db_client
-        .get_user(Some(user_id.clone()), None, None)
+        .get_user(Some(user_id), None, None)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
     let user = result
         .ok_or(HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
 
+    let Some(current_password) = &user.password else {
+        return Err(HttpError::bad_request("This account has no password yet, use forgot password to set one".to_string()));
+    };
+
     let password_match = password::compare(
         &body.old_password,
-        &user.password 
+        current_password 
     )
     .map_err(|e| HttpError::server_error(e.to_string()))?;
 
     if !password_match {
+        audit::record(&app_state, Some(&client), AuditEntry::failed(AuditAction::PasswordChanged, Some(user_id))).await;
+
         return Err(HttpError::bad_request("Old password is incorrect".to_string()));
     }
 
     let hashed_password = password::hash(&body.new_password)
        .map_err(|e| HttpError::server_error(e.to_string()))?;
 
+    // Re-wrap the private key under the new password so it stays unlockable
+    let private_key = unlock_private_key(&app_state.db_client, &user, &body.old_password).await?;
+    let wrapped_private_key = match &private_key {
+        Some(private_key) => Some(wrap_private_key(user_id, private_key, &body.new_password)?),
+        None => None,
+    };
+
     app_state.db_client
-        .update_user_password(user_id.clone(), hashed_password)
+        .update_user_password(user_id, hashed_password, wrapped_private_key)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
+    if let Some(private_key) = private_key {
+        app_state.key_cache.insert(user_id, private_key, app_state.env.jwt_maxage);
+    }
+
+    audit::record(&app_state, Some(&client), AuditEntry::succeeded(AuditAction::PasswordChanged, Some(user_id))).await;
+
     let response = Response {
         message: "Password updated successfully".to_string(),
         status: "success",
@@ -117,7 +157,7 @@ pub async fn search_by_email(
     let user_id = uuid::Uuid::parse_str(&user.user.id.to_string()).unwrap();
 
     let users = app_state.db_client
-        .search_by_email(user_id.clone(), query_pattern.clone())
+        .search_by_email(user_id, query_pattern.clone())
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
@@ -128,4 +168,452 @@ pub async fn search_by_email(
     };
 
     Ok(Json(response_data))
-}
\ No newline at end of file
+}
+
+pub async fn update_user_keys(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    client: ClientInfo,
+    Json(body): Json<UserKeysDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let public_key = parse_public_key(&body.public_key)?;
+
+    // Store in PKCS#1 form so server-side uploads can keep wrapping keys for this user
+    let public_key_pem = public_key.to_pkcs1_pem(rsa::pkcs1::LineEnding::LF)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    app_state.db_client
+        .save_user_keys(user.user.id, public_key_pem)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // The server no longer holds a private key for this user
+    app_state.key_cache.remove(user.user.id);
+
+    let entry = AuditEntry::succeeded(AuditAction::KeysGenerated, Some(user.user.id))
+        .details(serde_json::json!({ "source": "client" }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    let response = Response {
+        message: "Public key registered successfully".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_public_key(
+    Query(params): Query<PublicKeyQueryDto>,
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(_user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    params.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let recipient = app_state.db_client
+        .get_user(None, None, Some(&params.email))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::not_found("User not found".to_string()))?;
+
+    let public_key = recipient.public_key
+        .ok_or_else(|| HttpError::not_found("User has no public key".to_string()))?;
+
+    let response = PublicKeyResponseDto {
+        status: "success".to_string(),
+        email: recipient.email,
+        public_key,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_sessions(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let sessions = app_state.db_client
+        .get_active_sessions(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = SessionListResponseDto {
+        status: "success".to_string(),
+        sessions: SessionDto::filter_sessions(&sessions, user.session_id),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn revoke_session(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(session_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let session_id = uuid::Uuid::parse_str(&session_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid session ID: {}", e)))?;
+
+    let revoked = app_state.db_client
+        .revoke_session(session_id, user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !revoked {
+        return Err(HttpError::not_found("Session not found".to_string()));
+    }
+
+    let response = Response {
+        message: "Session revoked successfully".to_string(),
+        status: "success",
+    };
+
+    // Revoking the current session logs this browser out too
+    if user.session_id == Some(session_id) {
+        return Ok(create_auth_response(response, clear_auth_cookies()));
+    }
+
+    Ok(Json(response).into_response())
+}
+
+pub async fn revoke_all_sessions(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let revoked = app_state.db_client
+        .revoke_user_sessions(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    app_state.key_cache.remove(user.user.id);
+
+    let response = Response {
+        message: format!("Logged out of {} sessions", revoked),
+        status: "success",
+    };
+
+    Ok(create_auth_response(response, clear_auth_cookies()))
+}
+
+pub async fn get_access_tokens(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let tokens = app_state.db_client
+        .get_personal_access_tokens(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = AccessTokenListResponseDto {
+        status: "success".to_string(),
+        tokens: AccessTokenDto::filter_tokens(&tokens),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn create_access_token(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<CreateAccessTokenDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let mut scopes = Vec::new();
+    for scope in &body.scopes {
+        let scope = Scope::try_from(scope.as_str())?.as_str().to_string();
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let expires_at = body.expires_in_days
+        .map(|days| chrono::Utc::now() + chrono::Duration::days(days));
+
+    let (access_token, token_hash) = token::generate_personal_access_token();
+    let token_prefix = &access_token[..token::PERSONAL_ACCESS_TOKEN_PREFIX.len() + 4];
+
+    let created = app_state.db_client
+        .create_personal_access_token(user.user.id, body.name.trim(), token_hash, token_prefix, &scopes, expires_at)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = CreateAccessTokenResponseDto {
+        status: "success".to_string(),
+        details: AccessTokenDto::filter_token(&created),
+        token: access_token,
+    };
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+pub async fn revoke_access_token(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(token_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let token_id = uuid::Uuid::parse_str(&token_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid token ID: {}", e)))?;
+
+    let revoked = app_state.db_client
+        .revoke_personal_access_token(token_id, user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !revoked {
+        return Err(HttpError::not_found("Access token not found".to_string()));
+    }
+
+    let response = Response {
+        message: "Access token revoked successfully".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_identities(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let identities = app_state.db_client
+        .get_user_identities(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = IdentityListResponseDto {
+        status: "success".to_string(),
+        identities: IdentityDto::filter_identities(&identities),
+    };
+
+    Ok(Json(response))
+}
+
+// The browser follows the returned URL; the identity is linked when the provider redirects back
+pub async fn link_identity(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<LinkIdentityDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let (authorization_url, state_cookie) = begin_authorization(&app_state, &body.provider, Some(user.user.id)).await?;
+
+    let response = OidcAuthorizationResponseDto {
+        status: "success".to_string(),
+        authorization_url,
+    };
+
+    Ok(create_auth_response(response, vec![state_cookie]))
+}
+
+pub async fn unlink_identity(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(identity_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let identity_id = uuid::Uuid::parse_str(&identity_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid identity ID: {}", e)))?;
+
+    let identities = app_state.db_client
+        .get_user_identities(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let passkeys = app_state.db_client
+        .get_webauthn_credentials(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // Without a password or a passkey the last identity is the only way back into the account
+    if user.user.password.is_none() && passkeys.is_empty() && identities.len() <= 1 {
+        return Err(HttpError::bad_request("This is the only way to sign in to your account, set a password first".to_string()));
+    }
+
+    let unlinked = app_state.db_client
+        .unlink_user_identity(identity_id, user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !unlinked {
+        return Err(HttpError::not_found("Identity not found".to_string()));
+    }
+
+    let response = Response {
+        message: "Identity unlinked successfully".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn get_totp_status(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let totp = app_state.db_client
+        .get_user_totp(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let enabled = totp.is_some_and(|totp| totp.enabled_at.is_some());
+
+    let recovery_codes_remaining = if enabled {
+        app_state.db_client
+            .get_unused_recovery_codes(user.user.id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .len()
+    } else {
+        0
+    };
+
+    let response = TotpStatusResponseDto {
+        status: "success".to_string(),
+        enabled,
+        recovery_codes_remaining,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn setup_totp(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let secret = totp::generate_secret();
+
+    let saved = app_state.db_client
+        .save_totp_secret(user.user.id, secret.clone())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !saved {
+        return Err(HttpError::unique_constraint_violation("Two-factor authentication is already enabled".to_string()));
+    }
+
+    let response = TotpSetupResponseDto {
+        status: "success".to_string(),
+        secret: totp::encode_secret(&secret),
+        otpauth_uri: totp::otpauth_uri(&user.user.email, &secret),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn confirm_totp(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<TotpCodeDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let pending = app_state.db_client
+        .get_user_totp(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .filter(|totp| totp.enabled_at.is_none())
+        .ok_or_else(|| HttpError::bad_request("No two-factor enrolment is pending, start with /users/totp/setup".to_string()))?;
+
+    let step = totp::verify_code(&pending.secret, body.code.trim(), None)
+        .ok_or_else(|| HttpError::bad_request("Invalid authentication code".to_string()))?;
+
+    let (recovery_codes, recovery_code_hashes) = totp::generate_recovery_codes()?;
+
+    let enabled = app_state.db_client
+        .enable_totp(user.user.id, step, recovery_code_hashes)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !enabled {
+        return Err(HttpError::unique_constraint_violation("Two-factor authentication is already enabled".to_string()));
+    }
+
+    let response = RecoveryCodesResponseDto {
+        status: "success".to_string(),
+        recovery_codes,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn regenerate_recovery_codes(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<TotpCodeDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    totp::verify_second_factor(&app_state, user.user.id, &body.code).await?;
+
+    let (recovery_codes, recovery_code_hashes) = totp::generate_recovery_codes()?;
+
+    app_state.db_client
+        .replace_recovery_codes(user.user.id, recovery_code_hashes)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = RecoveryCodesResponseDto {
+        status: "success".to_string(),
+        recovery_codes,
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn disable_totp(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<TotpDisableDto>
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    // Accounts without a password confirm with the code alone
+    if let Some(hashed_password) = &user.user.password {
+        let password_match = password::compare(&body.password, hashed_password)
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if !password_match {
+            return Err(HttpError::bad_request("Password is incorrect".to_string()));
+        }
+    }
+
+    totp::verify_second_factor(&app_state, user.user.id, &body.code).await?;
+
+    app_state.db_client
+        .disable_totp(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = Response {
+        message: "Two-factor authentication disabled".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn resend_verification_email(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    if user.user.email_verified_at.is_some() {
+        return Err(HttpError::bad_request("Your email address is already verified".to_string()));
+    }
+
+    send_verification_email(&app_state, &user.user)?;
+
+    let response = Response {
+        message: "Verification email sent".to_string(),
+        status: "success",
+    };
+
+    Ok(Json(response))
+}
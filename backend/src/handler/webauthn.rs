@@ -0,0 +1,406 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::{delete, get, post}, Extension, Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use validator::Validate;
+
+use crate::{db::UserExt, dtos::{PasskeyDto, PasskeyListResponseDto, PasskeyLoginDto, PasskeyRegistrationDto, PasskeyResponseDto, PasskeySignupOptionsDto, RegistrationCredentialDto, Response, WebauthnOptionsResponseDto}, error::{ErrorMessage, HttpError}, handler::auth::{create_session_response, send_verification_email}, middleware::JWTAuthMiddeware, models::{AuditAction, CeremonyPurpose, WebauthnChallenge, WebauthnCredential}, utils::{audit::{self, AuditEntry}, keys::{new_key_pair, server_key_passphrase, unlock_passwordless_key}, session::{start_session, ClientInfo}, webauthn::{self, VerifiedCredential}}, AppState};
+
+const DEFAULT_PASSKEY_NAME: &str = "Passkey";
+
+// Transport hints browsers send, kept so they can be passed back in `allowCredentials`
+const KNOWN_TRANSPORTS: [&str; 6] = ["ble", "hybrid", "internal", "nfc", "smart-card", "usb"];
+
+pub fn webauthn_handler() -> Router {
+    Router::new()
+        .route("/signup/options", post(signup_options))
+        .route("/signup", post(signup))
+        .route("/login/options", post(login_options))
+        .route("/login", post(login))
+}
+
+/// Passkeys of the signed-in account, mounted behind `auth` and `require_session`.
+pub fn webauthn_credentials_handler() -> Router {
+    Router::new()
+        .route("/", get(list_passkeys).post(register_passkey))
+        .route("/options", post(register_options))
+        .route("/:credential_id", delete(delete_passkey))
+}
+
+fn decode_field(value: &str, field: &str) -> Result<Vec<u8>, HttpError> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| HttpError::bad_request(format!("Invalid passkey response: {} is not base64url", field)))
+}
+
+async fn issue_challenge(
+    app_state: &Arc<AppState>,
+    purpose: CeremonyPurpose,
+    user_id: Option<uuid::Uuid>,
+    name: Option<&str>,
+    email: Option<&str>,
+) -> Result<String, HttpError> {
+    let challenge = webauthn::random_challenge();
+
+    app_state.db_client
+        .save_webauthn_challenge(
+            webauthn::hash_challenge(&challenge),
+            purpose,
+            user_id,
+            name,
+            email,
+            Utc::now() + Duration::minutes(webauthn::CHALLENGE_MAXAGE_MINUTES),
+        )
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(challenge)
+}
+
+// The challenge a response answers, taken so it cannot be answered again
+async fn take_challenge(
+    app_state: &Arc<AppState>,
+    client_data_json: &[u8],
+    ceremony: &str,
+    purpose: CeremonyPurpose,
+) -> Result<WebauthnChallenge, HttpError> {
+    let challenge = webauthn::client_data_challenge(&app_state.env, client_data_json, ceremony)?;
+
+    app_state.db_client
+        .take_webauthn_challenge(webauthn::hash_challenge(&challenge), purpose)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::bad_request("Passkey request expired, please try again".to_string()))
+}
+
+// Checks a registration response against a challenge issued for `purpose`
+async fn verify_registration(
+    app_state: &Arc<AppState>,
+    credential: &RegistrationCredentialDto,
+    purpose: CeremonyPurpose,
+) -> Result<(WebauthnChallenge, VerifiedCredential), HttpError> {
+    let client_data_json = decode_field(&credential.response.client_data_json, "clientDataJSON")?;
+    let attestation_object = decode_field(&credential.response.attestation_object, "attestationObject")?;
+
+    let challenge = take_challenge(app_state, &client_data_json, "webauthn.create", purpose).await?;
+    let verified = webauthn::verify_registration(&app_state.env, &attestation_object)?;
+
+    let registered = app_state.db_client
+        .get_webauthn_credential(&verified.credential_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .is_some();
+
+    if registered {
+        return Err(HttpError::unique_constraint_violation("This passkey is already registered".to_string()));
+    }
+
+    Ok((challenge, verified))
+}
+
+async fn save_passkey(
+    app_state: &Arc<AppState>,
+    user_id: uuid::Uuid,
+    verified: &VerifiedCredential,
+    body: &PasskeyRegistrationDto,
+) -> Result<WebauthnCredential, HttpError> {
+    let result = app_state.db_client
+        .save_webauthn_credential(user_id, verified, &passkey_transports(body), passkey_name(body))
+        .await;
+
+    match result {
+        Ok(credential) => Ok(credential),
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            Err(HttpError::unique_constraint_violation("This passkey is already registered".to_string()))
+        }
+        Err(e) => Err(HttpError::server_error(e.to_string())),
+    }
+}
+
+fn passkey_transports(body: &PasskeyRegistrationDto) -> Vec<String> {
+    body.credential.response.transports
+        .iter()
+        .filter(|transport| KNOWN_TRANSPORTS.contains(&transport.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn passkey_name(body: &PasskeyRegistrationDto) -> &str {
+    body.name.as_deref().unwrap_or(DEFAULT_PASSKEY_NAME)
+}
+
+pub async fn signup_options(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Json(body): Json<PasskeySignupOptionsDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    // The private key of an account without a password is wrapped under KEY_WRAP_SECRET
+    if app_state.env.key_wrap_secret.is_none() {
+        return Err(HttpError::new("Passkey-only accounts are not enabled on this server", StatusCode::FORBIDDEN));
+    }
+
+    let email_taken = app_state.db_client
+        .get_user(None, None, Some(&body.email))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .is_some();
+
+    if email_taken {
+        return Err(HttpError::unique_constraint_violation(ErrorMessage::EmailExist.to_string()));
+    }
+
+    // The account does not exist yet, but the passkey is stored with the id it will get
+    let user_id = uuid::Uuid::new_v4();
+    let challenge = issue_challenge(&app_state, CeremonyPurpose::Signup, Some(user_id), Some(&body.name), Some(&body.email)).await?;
+
+    let response = WebauthnOptionsResponseDto {
+        status: "success".to_string(),
+        options: webauthn::creation_options(&app_state.env, &challenge, user_id.as_bytes(), &body.email, &body.name, &[]),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn signup(
+    Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
+    Json(body): Json<PasskeyRegistrationDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let (challenge, verified) = verify_registration(&app_state, &body.credential, CeremonyPurpose::Signup).await?;
+
+    let (Some(user_id), Some(name), Some(email)) = (challenge.user_id, challenge.name, challenge.email) else {
+        return Err(HttpError::server_error("Signup challenge is missing the account".to_string()));
+    };
+
+    let passphrase = server_key_passphrase(&app_state, user_id)?;
+    let (private_key, public_key, wrapped_private_key) = new_key_pair(user_id, &passphrase)?;
+
+    // The account, its keys and its passkey are saved together, so a failure leaves no account without a way in
+    let result = app_state.db_client
+        .save_passkey_user(
+            user_id,
+            &name,
+            &email,
+            public_key,
+            wrapped_private_key,
+            &verified,
+            &passkey_transports(&body),
+            passkey_name(&body),
+        )
+        .await;
+
+    let user = match result {
+        Ok(user) => user,
+        Err(sqlx::Error::Database(db_err)) if db_err.constraint() == Some("webauthn_credentials_credential_id_key") => {
+            return Err(HttpError::unique_constraint_violation("This passkey is already registered".to_string()));
+        }
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            return Err(HttpError::unique_constraint_violation(ErrorMessage::EmailExist.to_string()));
+        }
+        Err(e) => return Err(HttpError::server_error(e.to_string())),
+    };
+
+    let entry = AuditEntry::succeeded(AuditAction::KeysGenerated, Some(user.id))
+        .details(serde_json::json!({ "source": "server" }));
+    audit::record(&app_state, None, entry).await;
+
+    send_verification_email(&app_state, &user)?;
+    app_state.key_cache.insert(user.id, private_key, app_state.env.jwt_maxage);
+
+    let tokens = start_session(&app_state, user.id, &client).await?;
+
+    Ok(create_session_response(&app_state, tokens))
+}
+
+pub async fn login_options(
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> Result<impl IntoResponse, HttpError> {
+    let challenge = issue_challenge(&app_state, CeremonyPurpose::Login, None, None, None).await?;
+
+    let response = WebauthnOptionsResponseDto {
+        status: "success".to_string(),
+        options: webauthn::request_options(&app_state.env, &challenge, &[]),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn login(
+    Extension(app_state): Extension<Arc<AppState>>,
+    client: ClientInfo,
+    Json(body): Json<PasskeyLoginDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    let response = &body.credential.response;
+
+    let credential_id = decode_field(&body.credential.id, "id")?;
+    let client_data_json = decode_field(&response.client_data_json, "clientDataJSON")?;
+    let authenticator_data = decode_field(&response.authenticator_data, "authenticatorData")?;
+    let signature = decode_field(&response.signature, "signature")?;
+
+    take_challenge(&app_state, &client_data_json, "webauthn.get", CeremonyPurpose::Login).await?;
+
+    let credential = app_state.db_client
+        .get_webauthn_credential(&credential_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::unauthorized("This passkey is not registered".to_string()))?;
+
+    if let Some(user_handle) = &response.user_handle
+        && decode_field(user_handle, "userHandle")? != credential.user_id.as_bytes()
+    {
+        return Err(HttpError::unauthorized("This passkey belongs to another account".to_string()));
+    }
+
+    let verified = match webauthn::verify_assertion(&app_state.env, &credential, &authenticator_data, &client_data_json, &signature) {
+        Ok(verified) => verified,
+        Err(e) => {
+            let entry = AuditEntry::failed(AuditAction::Login, Some(credential.user_id))
+                .details(serde_json::json!({ "method": "passkey" }));
+            audit::record(&app_state, Some(&client), entry).await;
+
+            return Err(e);
+        }
+    };
+
+    let recorded = app_state.db_client
+        .record_webauthn_credential_use(credential.id, credential.sign_count, verified.sign_count, verified.backed_up)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !recorded {
+        return Err(HttpError::unauthorized("This passkey was used for another sign-in at the same time, please try again".to_string()));
+    }
+
+    let user = app_state.db_client
+        .get_user(Some(credential.user_id), None, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or_else(|| HttpError::unauthorized(ErrorMessage::UserNoLongerExist.to_string()))?;
+
+    unlock_passwordless_key(&app_state, &user).await?;
+
+    // A passkey that verified its user already counts as two factors, so TOTP is not asked for
+    let tokens = start_session(&app_state, user.id, &client).await?;
+
+    let entry = AuditEntry::succeeded(AuditAction::Login, Some(user.id))
+        .details(serde_json::json!({ "method": "passkey" }));
+    audit::record(&app_state, Some(&client), entry).await;
+
+    Ok(create_session_response(&app_state, tokens))
+}
+
+pub async fn list_passkeys(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let credentials = app_state.db_client
+        .get_webauthn_credentials(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let response = PasskeyListResponseDto {
+        status: "success".to_string(),
+        passkeys: PasskeyDto::filter_passkeys(&credentials),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn register_options(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+) -> Result<impl IntoResponse, HttpError> {
+    let credentials = app_state.db_client
+        .get_webauthn_credentials(user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let challenge = issue_challenge(&app_state, CeremonyPurpose::Register, Some(user.user.id), None, None).await?;
+
+    // Registered passkeys are excluded so the same authenticator is not added twice
+    let response = WebauthnOptionsResponseDto {
+        status: "success".to_string(),
+        options: webauthn::creation_options(
+            &app_state.env,
+            &challenge,
+            user.user.id.as_bytes(),
+            &user.user.email,
+            &user.user.name,
+            &credentials,
+        ),
+    };
+
+    Ok(Json(response))
+}
+
+pub async fn register_passkey(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Json(body): Json<PasskeyRegistrationDto>,
+) -> Result<impl IntoResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let (challenge, verified) = verify_registration(&app_state, &body.credential, CeremonyPurpose::Register).await?;
+
+    if challenge.user_id != Some(user.user.id) {
+        return Err(HttpError::bad_request("Passkey request expired, please try again".to_string()));
+    }
+
+    let credential = save_passkey(&app_state, user.user.id, &verified, &body).await?;
+
+    let response = PasskeyResponseDto {
+        status: "success".to_string(),
+        passkey: PasskeyDto::filter_passkey(&credential),
+    };
+
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+pub async fn delete_passkey(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    Path(credential_id): Path<String>,
+) -> Result<impl IntoResponse, HttpError> {
+    let credential_id = uuid::Uuid::parse_str(&credential_id)
+        .map_err(|e| HttpError::bad_request(format!("Invalid passkey ID: {}", e)))?;
+
+    if user.user.password.is_none() {
+        let identities = app_state.db_client
+            .get_user_identities(user.user.id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        let credentials = app_state.db_client
+            .get_webauthn_credentials(user.user.id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        // Without a password or a linked identity the last passkey is the only way back in
+        if identities.is_empty() && credentials.len() <= 1 {
+            return Err(HttpError::bad_request("This is the only way to sign in to your account, set a password first".to_string()));
+        }
+    }
+
+    let deleted = app_state.db_client
+        .delete_webauthn_credential(credential_id, user.user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !deleted {
+        return Err(HttpError::not_found("Passkey not found".to_string()));
+    }
+
+    let response = Response {
+        status: "success",
+        message: "Passkey deleted".to_string(),
+    };
+
+    Ok(Json(response))
+}
@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+use axum::{http::header, response::IntoResponse, routing::get, Extension, Json, Router};
+
+use crate::AppState;
+
+/// How long verifiers may cache the key set; new keys must be deployed at least this long
+/// before they start signing.
+const JWKS_MAXAGE_SECONDS: u32 = 300;
+
+pub fn well_known_handler() -> Router {
+    Router::new()
+        .route("/jwks.json", get(jwks))
+}
+
+pub async fn jwks(
+    Extension(app_state): Extension<Arc<AppState>>,
+) -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, format!("public, max-age={}", JWKS_MAXAGE_SECONDS))],
+        Json(app_state.jwt_keys.jwks()),
+    )
+}
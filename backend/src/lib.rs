@@ -0,0 +1,31 @@
+pub mod config;
+pub mod models;
+pub mod dtos;
+pub mod error;
+pub mod db;
+pub mod utils;
+pub mod middleware;
+pub mod handler;
+pub mod router;
+pub mod storage;
+pub mod mail;
+
+use std::sync::Arc;
+
+use config::Config;
+use db::DBClient;
+use mail::Mailer;
+use storage::BlobStore;
+use utils::{events::EventHub, jwt_keys::JwtKeys, key_cache::KeyCache, oidc::OidcClient};
+
+#[derive(Debug, Clone)]
+pub struct AppState {
+    pub env: Config,
+    pub db_client: DBClient,
+    pub blob_store: Arc<dyn BlobStore>,
+    pub key_cache: KeyCache,
+    pub mailer: Arc<dyn Mailer>,
+    pub oidc: OidcClient,
+    pub jwt_keys: JwtKeys,
+    pub events: EventHub,
+}
@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use tokio::fs;
+use uuid::Uuid;
+
+use super::{build_message, Email, MailError, MailResult, Mailer};
+
+/// Writes each message as an `.eml` file under a directory instead of sending it.
+#[derive(Debug)]
+pub struct FileMailer {
+    root: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(from: Mailbox, root: impl Into<PathBuf>) -> Self {
+        FileMailer { root: root.into(), from }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> MailResult<()> {
+        let message = build_message(&self.from, email)?;
+
+        fs::create_dir_all(&self.root).await
+            .map_err(|e| MailError(e.to_string()))?;
+
+        // Timestamped names keep the directory in the order messages were sent
+        let path = self.root.join(format!("{}-{}.eml", Utc::now().format("%Y%m%dT%H%M%S%.3f"), Uuid::new_v4()));
+
+        fs::write(path, message.formatted()).await
+            .map_err(|e| MailError(e.to_string()))
+    }
+}
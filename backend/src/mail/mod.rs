@@ -0,0 +1,115 @@
+mod file;
+mod smtp;
+mod stdout;
+
+use std::{fmt, sync::Arc};
+
+use async_trait::async_trait;
+use lettre::{message::{header::ContentType, Mailbox}, Message};
+
+use crate::config::MailConfig;
+
+pub use file::FileMailer;
+pub use smtp::SmtpMailer;
+pub use stdout::StdoutMailer;
+
+pub type MailResult<T> = Result<T, MailError>;
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MailError: {}", self.0)
+    }
+}
+
+impl std::error::Error for MailError {}
+
+/// A plain-text email to a single recipient.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers transactional email such as verification and password reset links.
+#[async_trait]
+pub trait Mailer: Send + Sync + fmt::Debug {
+    async fn send(&self, email: Email) -> MailResult<()>;
+}
+
+pub fn create_mailer(config: &MailConfig, from: &str) -> Arc<dyn Mailer> {
+    let from: Mailbox = from.parse()
+        .unwrap_or_else(|e| panic!("MAIL_FROM must be a valid address: {}", e));
+
+    match config {
+        MailConfig::Smtp { host, port, username, password, tls } => {
+            Arc::new(SmtpMailer::new(from, host, *port, username.as_deref(), password.as_deref(), *tls))
+        }
+        MailConfig::File { root } => Arc::new(FileMailer::new(from, root)),
+        MailConfig::Stdout => Arc::new(StdoutMailer::new(from)),
+    }
+}
+
+/// Builds the RFC 5322 message every mailer sends or writes out.
+fn build_message(from: &Mailbox, email: Email) -> MailResult<Message> {
+    let to: Mailbox = email.to.parse()
+        .map_err(|e| MailError(format!("Invalid recipient {}: {}", email.to, e)))?;
+
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body)
+        .map_err(|e| MailError(e.to_string()))
+}
+
+/// Sends without holding up the request; failures are only logged. Responses then take
+/// the same time whether or not an email went out.
+pub fn send_in_background(mailer: &Arc<dyn Mailer>, email: Email) {
+    let mailer = mailer.clone();
+
+    tokio::spawn(async move {
+        let to = email.to.clone();
+
+        if let Err(err) = mailer.send(email).await {
+            tracing::error!("Failed to send email to {}: {}", to, err);
+        }
+    });
+}
+
+pub fn verification_email(to: &str, name: &str, link: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Verify your Aerofy email address".to_string(),
+        body: format!(
+            "Hi {},\n\nPlease confirm this is your email address by opening the link below:\n\n{}\n\nThe link expires in 24 hours. If you did not create an Aerofy account, you can ignore this email.\n",
+            name, link
+        ),
+    }
+}
+
+pub fn password_reset_email(to: &str, name: &str, link: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your Aerofy password".to_string(),
+        body: format!(
+            "Hi {},\n\nSomeone asked to reset the password of your Aerofy account. To choose a new one, open the link below:\n\n{}\n\nThe link expires in 30 minutes and works once. If you did not ask for this, you can ignore this email.\n",
+            name, link
+        ),
+    }
+}
+
+pub fn organization_invitation_email(to: &str, name: &str, organization_name: &str, invited_by: &str, link: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: format!("You are invited to join {} on Aerofy", organization_name),
+        body: format!(
+            "Hi {},\n\n{} invited you to join the organization {} on Aerofy. You can accept or decline the invitation here:\n\n{}\n\nThe invitation expires in 7 days. Nothing changes for your account unless you accept it.\n",
+            name, invited_by, organization_name, link
+        ),
+    }
+}
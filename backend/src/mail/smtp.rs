@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use lettre::{message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+use crate::config::SmtpTls;
+
+use super::{build_message, Email, MailError, MailResult, Mailer};
+
+/// Sends through an SMTP relay, or a local stand-in such as MailHog.
+#[derive(Debug)]
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        from: Mailbox,
+        host: &str,
+        port: u16,
+        username: Option<&str>,
+        password: Option<&str>,
+        tls: SmtpTls,
+    ) -> Self {
+        let builder = match tls {
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)),
+        };
+
+        let mut builder = builder
+            .unwrap_or_else(|e| panic!("Invalid SMTP host {}: {}", host, e))
+            .port(port);
+
+        if let (Some(username), Some(password)) = (username, password) {
+            builder = builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+        }
+
+        SmtpMailer {
+            transport: builder.build(),
+            from,
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> MailResult<()> {
+        let message = build_message(&self.from, email)?;
+
+        self.transport.send(message).await
+            .map(|_| ())
+            .map_err(|e| MailError(e.to_string()))
+    }
+}
@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+
+use super::{build_message, Email, MailResult, Mailer};
+
+/// Prints each message to standard output; the default for local development.
+#[derive(Debug)]
+pub struct StdoutMailer {
+    from: Mailbox,
+}
+
+impl StdoutMailer {
+    pub fn new(from: Mailbox) -> Self {
+        StdoutMailer { from }
+    }
+}
+
+#[async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, email: Email) -> MailResult<()> {
+        let message = build_message(&self.from, email)?;
+
+        println!("📧 Outgoing email:\n{}", String::from_utf8_lossy(&message.formatted()));
+
+        Ok(())
+    }
+}
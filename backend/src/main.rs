@@ -1,33 +1,13 @@
-mod config;
-mod models;
-mod dtos;
-mod error;
-mod db;
-mod utils;
-mod middleware;
-mod handler;
-mod router;
-
-
-use std::sync::Arc;
-
-use axum::http::{header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE}, HeaderValue, Method};
-use config::Config;
-use db::{DBClient, UserExt};
+use std::{env, net::SocketAddr, sync::Arc};
+
+use axum::http::{header::{ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, LOCATION, RANGE}, HeaderName, HeaderValue, Method};
+use backend::{config::Config, db::{DBClient, UserExt}, mail::create_mailer, models, router::create_router, storage::{create_blob_store, delete_blobs, migrate::migrate_blobs}, utils::{events::{publish_expiry_warnings, EventHub}, jwt_keys::{generate_key, JwtKeys}, key_cache::KeyCache, keys::rewrap_legacy_keys, oidc::OidcClient}, AppState};
 use dotenv::dotenv;
-use router::create_router;
 use sqlx::postgres::PgPoolOptions;
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::filter::LevelFilter;
 use tokio_cron_scheduler::{JobScheduler, Job};
 
-
-#[derive(Debug, Clone)]
-pub struct AppState {
-    pub env: Config,
-    pub db_client: DBClient,
-}
-
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -37,6 +17,40 @@ async fn main() {
     dotenv().ok();
     let config = Config::init();
 
+    // `backend generate-jwt-key [eddsa|rs256]` adds a signing key to JWT_KEYS_DIR for a rotation
+    if env::args().nth(1).as_deref() == Some("generate-jwt-key") {
+        let algorithm = match env::args().nth(2).as_deref() {
+            None | Some("eddsa") => jsonwebtoken::Algorithm::EdDSA,
+            Some("rs256") => jsonwebtoken::Algorithm::RS256,
+            Some(other) => {
+                println!("🔥 Unknown key algorithm {}, use eddsa or rs256", other);
+                std::process::exit(1);
+            }
+        };
+
+        match generate_key(&config.jwt_keys_dir, algorithm) {
+            Ok(kid) => println!("✅Generated JWT key {}. Keep JWT_SIGNING_KID on the current key until this one is deployed everywhere, then switch it to {}", kid, kid),
+            Err(err) => {
+                println!("🔥 Failed to generate a JWT key: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let jwt_keys = match JwtKeys::load(
+        &config.jwt_keys_dir,
+        config.jwt_signing_kid.as_deref(),
+        config.jwt_secret.as_deref(),
+        config.jwt_generate_key,
+    ) {
+        Ok(jwt_keys) => jwt_keys,
+        Err(err) => {
+            println!("🔥 Failed to load JWT keys: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     let pool = match PgPoolOptions::new()
         .max_connections(10)
         .connect(&config.database_url)
@@ -51,29 +65,122 @@ async fn main() {
             }
         };
 
+    let db_client = DBClient::new(pool.clone());
+    let blob_store = create_blob_store(&config.storage);
+
+    // `backend migrate-blobs` moves file content still stored in Postgres into the blob store
+    if env::args().nth(1).as_deref() == Some("migrate-blobs") {
+        match migrate_blobs(&blob_store, &db_client).await {
+            Ok(count) => println!("✅Migrated {} files to the blob store.", count),
+            Err(err) => {
+                println!("🔥 Failed to migrate files to the blob store: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // `backend set-role <email> <user|auditor|admin>` bootstraps the first admin
+    if env::args().nth(1).as_deref() == Some("set-role") {
+        let (Some(email), Some(role)) = (env::args().nth(2), env::args().nth(3)) else {
+            println!("🔥 Usage: backend set-role <email> <user|auditor|admin>");
+            std::process::exit(1);
+        };
+
+        let role = match models::Role::try_from(role.as_str()) {
+            Ok(role) => role,
+            Err(_) => {
+                println!("🔥 Unsupported role: {}", role);
+                std::process::exit(1);
+            }
+        };
+
+        let user = match db_client.get_user(None, None, Some(&email)).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                println!("🔥 No user with email {}", email);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                println!("🔥 Failed to look up the user: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+        match db_client.update_user_role(user.id, role).await {
+            Ok(_) => println!("✅{} is now {}.", email, role.as_str()),
+            Err(err) => {
+                println!("🔥 Failed to change the role: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let cors = CorsLayer::new()
         .allow_origin(config.client_url.parse::<HeaderValue>().unwrap())
-        .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE])
+        .allow_headers([
+            AUTHORIZATION,
+            ACCEPT,
+            CONTENT_TYPE,
+            RANGE,
+            IF_RANGE,
+            IF_NONE_MATCH,
+            HeaderName::from_static("tus-resumable"),
+            HeaderName::from_static("upload-length"),
+            HeaderName::from_static("upload-offset"),
+            HeaderName::from_static("upload-metadata"),
+        ])
+        .expose_headers([
+            LOCATION,
+            ETAG,
+            ACCEPT_RANGES,
+            CONTENT_RANGE,
+            CONTENT_DISPOSITION,
+            HeaderName::from_static("x-encrypted-aes-key"),
+            HeaderName::from_static("x-iv"),
+            HeaderName::from_static("x-cipher"),
+            HeaderName::from_static("x-key-wrap"),
+            HeaderName::from_static("x-segment-size"),
+            HeaderName::from_static("tus-resumable"),
+            HeaderName::from_static("tus-version"),
+            HeaderName::from_static("tus-extension"),
+            HeaderName::from_static("tus-max-size"),
+            HeaderName::from_static("upload-length"),
+            HeaderName::from_static("upload-offset"),
+            HeaderName::from_static("upload-expires"),
+        ])
         .allow_credentials(true)
-        .allow_methods([Method::GET, Method::POST, Method::PUT]);
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::HEAD, Method::DELETE]);
 
-    let db_client = DBClient::new(pool);
     let app_state = AppState {
         env: config.clone(),
         db_client: db_client.clone(),
+        blob_store: blob_store.clone(),
+        key_cache: KeyCache::new(),
+        mailer: create_mailer(&config.mail, &config.mail_from),
+        oidc: OidcClient::new(),
+        jwt_keys,
+        events: EventHub::new(),
     };
 
+    // Every instance hears every event, and passes on those for the clients connected to it
+    app_state.events.listen(pool);
+
     let sched = JobScheduler::new().await.unwrap();
 
     let job = Job::new_async("0 0 * * * *", {
        move |_, _| {
         let db_client = db_client.clone();
+        let blob_store = blob_store.clone();
         Box::pin(async move {
             println!("Running scheduled task to delete expired files...");
-            if let Err(err) = db_client.delete_expired_files().await {
-                eprintln!("Error deleting expired files: {:?}", err);
-            } else {
-                println!("Successfully deleted expired files.");
+            match db_client.delete_expired_files().await {
+                Ok(storage_keys) => {
+                    delete_blobs(&blob_store, storage_keys).await;
+                    println!("Successfully deleted expired files.");
+                }
+                Err(err) => eprintln!("Error deleting expired files: {:?}", err),
             }
         })
        } 
@@ -81,19 +188,88 @@ async fn main() {
 
     sched.add(job).await.unwrap();
 
+    let rewrap_job = Job::new_async("0 30 * * * *", {
+       let db_client = app_state.db_client.clone();
+       move |_, _| {
+        let db_client = db_client.clone();
+        Box::pin(async move {
+            println!("Running scheduled task to re-wrap legacy file keys...");
+            match rewrap_legacy_keys(&db_client).await {
+                Ok(count) => println!("Re-wrapped {} legacy file keys with RSA-OAEP.", count),
+                Err(err) => eprintln!("Error re-wrapping legacy file keys: {:?}", err),
+            }
+        })
+       }
+    }).unwrap();
+
+    sched.add(rewrap_job).await.unwrap();
+
+    let tus_cleanup_job = Job::new_async("0 15 * * * *", {
+       let db_client = app_state.db_client.clone();
+       let blob_store = app_state.blob_store.clone();
+       let max_age_hours = config.tus_upload_expiry_hours;
+       move |_, _| {
+        let db_client = db_client.clone();
+        let blob_store = blob_store.clone();
+        Box::pin(async move {
+            println!("Running scheduled task to delete stale resumable uploads...");
+            match db_client.delete_stale_tus_uploads(max_age_hours).await {
+                Ok(storage_keys) => {
+                    println!("Deleted {} staged parts of stale resumable uploads.", storage_keys.len());
+                    delete_blobs(&blob_store, storage_keys).await;
+                }
+                Err(err) => eprintln!("Error deleting stale resumable uploads: {:?}", err),
+            }
+        })
+       }
+    }).unwrap();
+
+    sched.add(tus_cleanup_job).await.unwrap();
+
+    let session_cleanup_job = Job::new_async("0 45 * * * *", {
+       let db_client = app_state.db_client.clone();
+       move |_, _| {
+        let db_client = db_client.clone();
+        Box::pin(async move {
+            println!("Running scheduled task to delete expired sessions...");
+            match db_client.delete_expired_sessions().await {
+                Ok(count) => println!("Deleted {} expired or revoked sessions.", count),
+                Err(err) => eprintln!("Error deleting expired sessions: {:?}", err),
+            }
+        })
+       }
+    }).unwrap();
+
+    sched.add(session_cleanup_job).await.unwrap();
+
+    let expiry_warning_job = Job::new_async("0 5 * * * *", {
+       let db_client = app_state.db_client.clone();
+       let within_hours = config.expiry_warning_hours;
+       move |_, _| {
+        let db_client = db_client.clone();
+        Box::pin(async move {
+            println!("Running scheduled task to warn about expiring shares...");
+            match publish_expiry_warnings(&db_client, within_hours).await {
+                Ok(count) => println!("Warned the recipients of {} expiring shares.", count),
+                Err(err) => eprintln!("Error warning about expiring shares: {:?}", err),
+            }
+        })
+       }
+    }).unwrap();
+
+    sched.add(expiry_warning_job).await.unwrap();
+
     tokio::spawn(async move {
         sched.start().await.unwrap();
     });
 
     let app = create_router(Arc::new(app_state.clone())).layer(cors.clone());
         
-    println!(
-        "{}",
-        format!("🚀 Server is running on http://localhost:{}", config.port)
-    );
+    println!("🚀 Server is running on http://localhost:{}", config.port);
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", &config.port))
     .await.unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    // Peer addresses are recorded against sessions
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
\ No newline at end of file
@@ -1,15 +1,57 @@
 use std::sync::Arc;
 
-use axum::{extract::Request, http::header, middleware::Next, response::IntoResponse, Extension};
+use axum::{extract::{Request, State}, http::{header, HeaderMap, StatusCode}, middleware::Next, response::IntoResponse, Extension};
 use serde::{Deserialize, Serialize};
 use axum_extra::extract::cookie::CookieJar;
 
-use crate::{db::UserExt, error::{ErrorMessage, HttpError}, models::User, utils::token, AppState};
+use crate::{db::UserExt, error::{ErrorMessage, HttpError}, models::{Role, Scope, User}, utils::token, AppState};
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JWTAuthMiddeware {
     pub user: User,
+    // The login session behind the request; None when a personal access token was used
+    pub session_id: Option<uuid::Uuid>,
+    // Scopes of the personal access token; None for login sessions, which may do everything
+    pub scopes: Option<Vec<Scope>>,
+    pub role: Role,
+}
+
+impl JWTAuthMiddeware {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.contains(&scope))
+    }
+}
+
+/// The access token from the `token` cookie, or failing that the bearer token.
+pub fn request_token(cookie_jar: &CookieJar, headers: &HeaderMap) -> Option<String> {
+    cookie_jar
+        .get("token")
+        .map(|cookie| cookie.value().to_string())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|auth_header| auth_header.to_str().ok())
+                .and_then(|auth_value| {
+                    auth_value.strip_prefix("Bearer ").map(|token| token.to_owned())
+                })
+        })
+}
+
+/// Decodes an access token and makes sure neither it nor its session has been revoked.
+pub async fn verify_access_token(app_state: &Arc<AppState>, token: String) -> Result<token::TokenClaims, HttpError> {
+    let claims = token::decode_token(token, &app_state.jwt_keys)?;
+
+    let active = app_state.db_client
+        .is_token_active(claims.jti, claims.sid)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !active {
+        return Err(HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()));
+    }
+
+    Ok(claims)
 }
 
 pub async fn auth(
@@ -18,34 +60,31 @@ pub async fn auth(
     mut req: Request,
     next: Next,
 ) -> Result<impl IntoResponse, HttpError> {
-    let cookies = cookie_jar
-            .get("token")
-            .map(|cookie| cookie.value().to_string())
-            .or_else(|| {
-                req.headers()
-                    .get(header::AUTHORIZATION)
-                    .and_then(|auth_header| auth_header.to_str().ok())
-                    .and_then(|auth_value| {
-                        if auth_value.starts_with("Bearer ") {
-                            Some(auth_value[7..].to_owned())
-                        }else {
-                            None
-                        }
-                    })  
-            });
-    let token = cookies.ok_or_else(|| {
+    let token = request_token(&cookie_jar, req.headers()).ok_or_else(|| {
         HttpError::unauthorized(ErrorMessage::TokenNotProvided.to_string())
     })?;
 
-    let token_details = 
-        match token::decode_token(token, app_state.env.jwt_secret.as_bytes()) {
-            Ok(token_details) => token_details,
-            Err(_) => {
-                return Err(HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()));
-            }
-        };
-    
-    let user_id = uuid::Uuid::parse_str(&token_details.to_string()).unwrap();
+    let (user_id, session_id, scopes) = if token.starts_with(token::PERSONAL_ACCESS_TOKEN_PREFIX) {
+        let grant = app_state.db_client
+            .use_personal_access_token(&token::hash_personal_access_token(&token))
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?
+            .ok_or_else(|| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+        let scopes = grant.scopes
+            .iter()
+            .map(|scope| Scope::try_from(scope.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        (grant.user_id, None, Some(scopes))
+    } else {
+        let token_details = verify_access_token(&app_state, token).await?;
+
+        let user_id = uuid::Uuid::parse_str(&token_details.sub)
+            .map_err(|_| HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))?;
+
+        (user_id, Some(token_details.sid), None)
+    };
 
     let user = app_state.db_client.get_user(Some(user_id), None, None)
         .await
@@ -55,9 +94,78 @@ pub async fn auth(
         HttpError::unauthorized(ErrorMessage::UserNoLongerExist.to_string())
     })?;
 
+    // Disabling revokes the sessions, but personal access tokens are only stopped here
+    if user.disabled_at.is_some() {
+        return Err(HttpError::new(ErrorMessage::AccountDisabled.to_string(), StatusCode::FORBIDDEN));
+    }
+
+    let role = Role::try_from(user.role.as_str())?;
+
     req.extensions_mut().insert(JWTAuthMiddeware {
         user: user.clone(),
+        session_id,
+        scopes,
+        role,
     });
 
     Ok(next.run(req).await)
-}
\ No newline at end of file
+}
+
+/// Keeps accounts with an unverified email away from files, so nobody can register an
+/// address they do not own and open what is sent to it. Runs inside `auth`.
+pub async fn require_verified_email(
+    Extension(app_state): Extension<Arc<AppState>>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, HttpError> {
+    if app_state.env.require_email_verification && user.user.email_verified_at.is_none() {
+        return Err(HttpError::new(ErrorMessage::EmailNotVerified.to_string(), StatusCode::FORBIDDEN));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Lets personal access tokens through only if they carry `scope`; login sessions always pass.
+/// Layered per route group in `router::create_router`, inside `auth`.
+pub async fn require_scope(
+    State(scope): State<Scope>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, HttpError> {
+    if !user.has_scope(scope) {
+        return Err(HttpError::new(ErrorMessage::MissingScope(scope.as_str()).to_string(), StatusCode::FORBIDDEN));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Keeps personal access tokens away from account management, such as passwords, sessions,
+/// two-factor settings and the tokens themselves. Runs inside `auth`.
+pub async fn require_session(
+    Extension(user): Extension<JWTAuthMiddeware>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, HttpError> {
+    if user.session_id.is_none() {
+        return Err(HttpError::new(ErrorMessage::SessionRequired.to_string(), StatusCode::FORBIDDEN));
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Lets through accounts whose role is at least `role`. Layered per route group in
+/// `router::create_router`, inside `auth`.
+pub async fn require_role(
+    State(role): State<Role>,
+    Extension(user): Extension<JWTAuthMiddeware>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, HttpError> {
+    if user.role < role {
+        return Err(HttpError::new(ErrorMessage::PermissionDenied.to_string(), StatusCode::FORBIDDEN));
+    }
+
+    Ok(next.run(req).await)
+}
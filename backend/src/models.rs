@@ -1,30 +1,80 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::{error::HttpError, utils::{cipher::{FileCipher, KeyWrap, LinkKey}, keys::PasswordWrappedKey}};
+
 #[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow, sqlx::Type)]
 pub struct User {
     pub id: uuid::Uuid,
     pub name: String,
     pub email: String,
-    pub password: String,
+    // None for accounts created through single sign-on
+    pub password: Option<String>,
     pub public_key: Option<String>,
     pub private_key: Option<String>, 
+    pub encrypted_private_key: Option<Vec<u8>>,
+    pub private_key_salt: Option<Vec<u8>>,
+    pub private_key_nonce: Option<Vec<u8>>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    // One of `Role`, stored in `users.role`
+    pub role: String,
+    pub disabled_at: Option<DateTime<Utc>>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+impl User {
+    pub fn wrapped_private_key(&self) -> Option<PasswordWrappedKey> {
+        match (&self.encrypted_private_key, &self.private_key_salt, &self.private_key_nonce) {
+            (Some(ciphertext), Some(salt), Some(nonce)) => Some(PasswordWrappedKey {
+                ciphertext: ciphertext.clone(),
+                salt: salt.clone(),
+                nonce: nonce.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow, sqlx::Type)]
 pub struct File {
     pub id: uuid::Uuid,
     pub user_id: Option<uuid::Uuid>,
     pub file_name: String,
     pub file_size: i64,
-    pub encrypted_aes_key: Vec<u8>,
-    pub encrypted_file: Vec<u8>,
     pub iv: Vec<u8>,
+    pub cipher: String,
+    pub client_encrypted: bool,
+    pub chunk_count: Option<i32>,
+    pub storage_key: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
 }
 
+// A file whose ciphertext has been written to the blob store but has no row yet
+pub struct NewFile {
+    pub user_id: uuid::Uuid,
+    pub file_name: String,
+    pub file_size: i64,
+    pub storage_key: String,
+    pub iv: Vec<u8>,
+    pub cipher: FileCipher,
+    pub client_encrypted: bool,
+    pub chunk_count: Option<i32>,
+}
+
+// The file's AES key as wrapped for one recipient
+#[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow)]
+pub struct FileKey {
+    pub encrypted_aes_key: Vec<u8>,
+    pub key_wrap: String,
+}
+
+pub struct NewFileKey {
+    pub recipient_user_id: uuid::Uuid,
+    pub encrypted_aes_key: Vec<u8>,
+    pub key_wrap: KeyWrap,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow, sqlx::Type)]
 pub struct SharedLink {
     pub id: uuid::Uuid,  // Primary key should never be NULL
@@ -33,16 +83,572 @@ pub struct SharedLink {
     pub password: String,
     pub expiration_date: Option<DateTime<Utc>>,  // Already correct
     pub created_at: Option<DateTime<Utc>>,       // Already correct
-    pub is_retrieved: Option<bool>,  // Make this optional too
+    pub status: String,
+    pub failed_attempts: i32,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub locked_at: Option<DateTime<Utc>>,
+}
+
+/// Lifecycle of a share, stored in `shared_links.status`. Pending, accepted and downloaded
+/// shares are open; the others are final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Revoked,
+    Expired,
+    Downloaded,
+}
+
+impl ShareStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareStatus::Pending => "pending",
+            ShareStatus::Accepted => "accepted",
+            ShareStatus::Declined => "declined",
+            ShareStatus::Revoked => "revoked",
+            ShareStatus::Expired => "expired",
+            ShareStatus::Downloaded => "downloaded",
+        }
+    }
+}
+
+impl TryFrom<&str> for ShareStatus {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "pending" => Ok(ShareStatus::Pending),
+            "accepted" => Ok(ShareStatus::Accepted),
+            "declined" => Ok(ShareStatus::Declined),
+            "revoked" => Ok(ShareStatus::Revoked),
+            "expired" => Ok(ShareStatus::Expired),
+            "downloaded" => Ok(ShareStatus::Downloaded),
+            other => Err(HttpError::server_error(format!("Unsupported share status: {}", other))),
+        }
+    }
+}
+
+/// What an account may do beyond its own files, stored in `users.role`. Each role includes
+/// the ones before it: auditors see usage across accounts, admins can also act on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Auditor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Auditor => "auditor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl TryFrom<&str> for Role {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "user" => Ok(Role::User),
+            "auditor" => Ok(Role::Auditor),
+            "admin" => Ok(Role::Admin),
+            other => Err(HttpError::server_error(format!("Unsupported role: {}", other))),
+        }
+    }
+}
+
+/// An account's place in its organization, stored in `organization_members.role`. Owners
+/// manage the members, groups and policy; members can send files to the groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Owner,
+    Member,
+}
+
+impl OrgRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Owner => "owner",
+            OrgRole::Member => "member",
+        }
+    }
+}
+
+impl TryFrom<&str> for OrgRole {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "owner" => Ok(OrgRole::Owner),
+            "member" => Ok(OrgRole::Member),
+            other => Err(HttpError::server_error(format!("Unsupported organization role: {}", other))),
+        }
+    }
+}
+
+/// What an audit event records, stored in `audit_events.action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Login,
+    PasswordChanged,
+    PasswordReset,
+    KeysGenerated,
+    FileUploaded,
+    ShareAccepted,
+    ShareDeclined,
+    ShareDownloaded,
+    RoleChanged,
+    UserDisabled,
+    UserEnabled,
+    SharesExpired,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "auth.login",
+            AuditAction::PasswordChanged => "user.password_changed",
+            AuditAction::PasswordReset => "user.password_reset",
+            AuditAction::KeysGenerated => "user.keys_generated",
+            AuditAction::FileUploaded => "file.uploaded",
+            AuditAction::ShareAccepted => "share.accepted",
+            AuditAction::ShareDeclined => "share.declined",
+            AuditAction::ShareDownloaded => "share.downloaded",
+            AuditAction::RoleChanged => "admin.role_changed",
+            AuditAction::UserDisabled => "admin.user_disabled",
+            AuditAction::UserEnabled => "admin.user_enabled",
+            AuditAction::SharesExpired => "admin.shares_expired",
+        }
+    }
+}
+
+impl TryFrom<&str> for AuditAction {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "auth.login" => Ok(AuditAction::Login),
+            "user.password_changed" => Ok(AuditAction::PasswordChanged),
+            "user.password_reset" => Ok(AuditAction::PasswordReset),
+            "user.keys_generated" => Ok(AuditAction::KeysGenerated),
+            "file.uploaded" => Ok(AuditAction::FileUploaded),
+            "share.accepted" => Ok(AuditAction::ShareAccepted),
+            "share.declined" => Ok(AuditAction::ShareDeclined),
+            "share.downloaded" => Ok(AuditAction::ShareDownloaded),
+            "admin.role_changed" => Ok(AuditAction::RoleChanged),
+            "admin.user_disabled" => Ok(AuditAction::UserDisabled),
+            "admin.user_enabled" => Ok(AuditAction::UserEnabled),
+            "admin.shares_expired" => Ok(AuditAction::SharesExpired),
+            other => Err(HttpError::bad_request(format!("Unknown audit action: {}", other))),
+        }
+    }
+}
+
+/// What a personal access token may be used for. Login sessions may do everything; tokens
+/// only reach the routes `router::create_router` grants to one of their scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "files:send")]
+    FilesSend,
+    #[serde(rename = "files:receive")]
+    FilesReceive,
+    #[serde(rename = "users:read")]
+    UsersRead,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::FilesSend => "files:send",
+            Scope::FilesReceive => "files:receive",
+            Scope::UsersRead => "users:read",
+        }
+    }
+}
+
+impl TryFrom<&str> for Scope {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "files:send" => Ok(Scope::FilesSend),
+            "files:receive" => Ok(Scope::FilesReceive),
+            "users:read" => Ok(Scope::UsersRead),
+            other => Err(HttpError::bad_request(format!("Unknown scope: {}", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PersonalAccessToken {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub token_prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// The owner and scopes of a personal access token presented with a request
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TokenGrant {
+    pub user_id: uuid::Uuid,
+    pub scopes: Vec<String>,
+}
+
+// An account at an OpenID Connect provider that signs in as a user
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserIdentity {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub provider: String,
+    pub email: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+// What an authorization request left for its callback to check
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OidcAuthRequest {
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub user_id: Option<uuid::Uuid>,
+}
+
+// An account as administrators see it: its usage, never its files
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserOverview {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub file_count: i64,
+    pub stored_bytes: i64,
+}
+
+// A share as administrators see it, without the file name or anything that opens it
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ShareOverview {
+    pub id: uuid::Uuid,
+    pub file_id: uuid::Uuid,
+    pub file_size: i64,
+    pub recipient_email: Option<String>,
+    pub link_key: Option<String>,
+    pub status: String,
+    pub expiration_date: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// Totals across all accounts, for the admin dashboard
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct StorageStats {
+    pub users: i64,
+    pub disabled_users: i64,
+    pub files: i64,
+    pub stored_bytes: i64,
+    pub open_shares: i64,
+    pub link_shares: i64,
+    pub pending_uploads: i64,
+    pub pending_upload_bytes: i64,
+}
+
+// An organization as one of its members sees it, with their role in it
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Organization {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub allowed_domains: Vec<String>,
+    pub max_expiry_days: Option<i32>,
+    pub role: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl Organization {
+    pub fn role(&self) -> Result<OrgRole, HttpError> {
+        OrgRole::try_from(self.role.as_str())
+    }
+
+    pub fn share_policy(&self) -> SharePolicy {
+        SharePolicy {
+            allowed_domains: self.allowed_domains.clone(),
+            max_expiry_days: self.max_expiry_days,
+        }
+    }
+}
+
+/// Limits an organization puts on the files its members share, checked when a share is
+/// created or its expiration changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SharePolicy {
+    // Lowercase recipient email domains; empty allows any
+    pub allowed_domains: Vec<String>,
+    pub max_expiry_days: Option<i32>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrganizationMember {
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// An invitation to join an organization, with enough of both sides for the owners and the invitee
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OrganizationInvitation {
+    pub id: uuid::Uuid,
+    pub organization_id: uuid::Uuid,
+    pub organization_name: String,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub invited_by_email: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Group {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub member_count: i64,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct GroupMember {
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub email: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+// An entry of the audit log, with the hash that chains it to the one before
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub user_id: Option<uuid::Uuid>,
+    pub action: String,
+    pub succeeded: bool,
+    pub target_id: Option<uuid::Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: serde_json::Value,
+    pub occurred_at: DateTime<Utc>,
+    pub prev_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+// An audit event about to be appended; its time and hashes are set as it is written
+pub struct NewAuditEvent {
+    pub user_id: Option<uuid::Uuid>,
+    pub action: AuditAction,
+    pub succeeded: bool,
+    pub target_id: Option<uuid::Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: serde_json::Value,
+}
+
+// Which audit events to list; None matches anything
+pub struct AuditFilter {
+    pub user_id: Option<uuid::Uuid>,
+    pub action: Option<AuditAction>,
+    pub succeeded: Option<bool>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+// What the sender and recipient of a share are told about it in real time
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ShareNotice {
+    pub shared_id: uuid::Uuid,
+    pub file_id: uuid::Uuid,
+    pub file_name: String,
+    pub sender_id: uuid::Uuid,
+    pub sender_email: String,
+    pub recipient_user_id: Option<uuid::Uuid>,
+    pub expiration_date: Option<DateTime<Utc>>,
+}
+
+// A passkey registered to an account
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct WebauthnCredential {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub algorithm: i32,
+    pub sign_count: i64,
+    pub transports: Vec<String>,
+    pub backup_eligible: bool,
+    pub backed_up: bool,
+    pub name: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Which WebAuthn ceremony a challenge was issued for, stored in `webauthn_challenges.purpose`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CeremonyPurpose {
+    // Registering the first passkey of a new, passkey-only account
+    Signup,
+    // Adding a passkey to a signed-in account
+    Register,
+    Login,
+}
+
+impl CeremonyPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CeremonyPurpose::Signup => "signup",
+            CeremonyPurpose::Register => "register",
+            CeremonyPurpose::Login => "login",
+        }
+    }
+}
+
+// What a WebAuthn challenge left for the response to it
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct WebauthnChallenge {
+    pub user_id: Option<uuid::Uuid>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+// A shared link without a recipient account, opened with the share password alone
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LinkShare {
+    pub id: uuid::Uuid,
+    pub file_id: Option<uuid::Uuid>,
+    pub password: String,
+    pub failed_attempts: i32,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub locked_at: Option<DateTime<Utc>>,
+    pub link_key: String,
+    pub key_check: Option<Vec<u8>>,
+    pub encrypted_aes_key: Option<Vec<u8>>,
+    pub key_salt: Option<Vec<u8>>,
+    pub key_nonce: Option<Vec<u8>>,
+}
+
+impl LinkShare {
+    pub fn wrapped_key(&self) -> Option<PasswordWrappedKey> {
+        match (&self.encrypted_aes_key, &self.key_salt, &self.key_nonce) {
+            (Some(ciphertext), Some(salt), Some(nonce)) => Some(PasswordWrappedKey {
+                ciphertext: ciphertext.clone(),
+                salt: salt.clone(),
+                nonce: nonce.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// A share as seen by the owner of its file, for revoking it or changing its terms
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct OwnedShare {
+    pub id: uuid::Uuid,
+    pub password: String,
+    pub link_key: Option<String>,
+    pub encrypted_aes_key: Option<Vec<u8>>,
+    pub key_salt: Option<Vec<u8>>,
+    pub key_nonce: Option<Vec<u8>>,
+}
+
+impl OwnedShare {
+    pub fn wrapped_key(&self) -> Option<PasswordWrappedKey> {
+        match (&self.encrypted_aes_key, &self.key_salt, &self.key_nonce) {
+            (Some(ciphertext), Some(salt), Some(nonce)) => Some(PasswordWrappedKey {
+                ciphertext: ciphertext.clone(),
+                salt: salt.clone(),
+                nonce: nonce.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct NewLinkShare {
+    pub id: uuid::Uuid,
+    pub link_key: LinkKey,
+    pub key_check: Option<Vec<u8>>,
+    pub wrapped_key: Option<PasswordWrappedKey>,
 }
 
 #[derive(sqlx::FromRow)]
 pub struct SentFileDetails {
     pub file_id: uuid::Uuid,
+    pub shared_id: uuid::Uuid,
     pub file_name: String,
-    pub recipient_email: String,
+    pub recipient_email: Option<String>,
     pub expiration_date: Option<DateTime<Utc>>,
-    pub created_at: Option<DateTime<Utc>>
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub status: String,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub declined_at: Option<DateTime<Utc>>,
+    pub downloaded_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub locked_at: Option<DateTime<Utc>>,
+}
+
+// A login, kept alive by its rotating refresh token
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Session {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
+}
+
+// A user's TOTP authenticator; enabled_at is None until enrolment is confirmed
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserTotp {
+    pub secret: Vec<u8>,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub last_used_step: Option<i64>,
+    pub failed_attempts: i32,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+}
+
+impl UserTotp {
+    pub fn attempts(&self) -> PasswordAttempts {
+        PasswordAttempts {
+            failed_attempts: self.failed_attempts,
+            last_attempt_at: self.last_attempt_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RecoveryCode {
+    pub id: uuid::Uuid,
+    pub code_hash: String,
+}
+
+// Password attempts counted against a share or a recipient
+#[derive(Debug, Clone, Default, sqlx::FromRow)]
+pub struct PasswordAttempts {
+    pub failed_attempts: i32,
+    pub last_attempt_at: Option<DateTime<Utc>>,
 }
 
 #[derive(sqlx::FromRow)]
@@ -52,5 +658,39 @@ pub struct ReceiveFileDetails {
     pub file_name: String,
     pub sender_email: String,
     pub expiration_date: Option<DateTime<Utc>>,
-    pub created_at: Option<DateTime<Utc>>
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub status: String,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct LegacyWrappedKey {
+    pub file_id: uuid::Uuid,
+    pub recipient_user_id: uuid::Uuid,
+    pub encrypted_aes_key: Vec<u8>,
+    pub private_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow)]
+pub struct TusUpload {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub file_name: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub recipient_user_ids: Vec<uuid::Uuid>,
+    pub password: String,
+    pub expiration_date: DateTime<Utc>,
+    pub max_downloads: Option<i32>,
+    pub staging_key: Vec<u8>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow)]
+pub struct TusUploadPart {
+    pub part_offset: i64,
+    pub part_size: i64,
+    pub storage_key: String,
+    pub iv: Vec<u8>,
 }
@@ -1,33 +1,83 @@
 use std::sync::Arc;
 
-use axum::{middleware, Extension, Router, Json, response::IntoResponse, routing::get};
+use axum::{extract::DefaultBodyLimit, middleware, Extension, Router, Json, response::IntoResponse, routing::get};
 use tower_http::trace::TraceLayer;
 
-use crate::{handler::{auth::auth_handler, file::file_handle, file_query::get_file_list_handler, user::users_handler}, middleware::auth, AppState};
+use crate::{handler::{admin::{admin_handler, admin_read_handler}, audit::{audit_handler, audit_verify_handler}, auth::auth_handler, events::events_handler, file::{file_receive_handler, file_send_handler}, file_query::{get_received_list_handler, get_sent_list_handler}, organization::organization_handler, share::share_handler, user::{users_handler, users_read_handler}, webauthn::webauthn_credentials_handler, well_known::well_known_handler}, middleware::{auth, require_role, require_scope, require_session, require_verified_email}, models::{Role, Scope}, AppState};
 
 pub fn create_router(app_state: Arc<AppState>) -> Router {
+    // Personal access tokens reach a route group only with its scope; the rest need a login
+    let send_scope = || middleware::from_fn_with_state(Scope::FilesSend, require_scope);
+    let receive_scope = || middleware::from_fn_with_state(Scope::FilesReceive, require_scope);
+
     let api_route = Router::new()
         .nest("/auth", auth_handler())
+        .nest(
+            "/auth/webauthn/credentials",
+            webauthn_credentials_handler()
+                .layer(middleware::from_fn(require_session))
+                .layer(middleware::from_fn(auth))
+        )
         .nest(
             "/users", 
-            users_handler()
+            users_read_handler()
+                .layer(middleware::from_fn_with_state(Scope::UsersRead, require_scope))
+                .merge(users_handler().layer(middleware::from_fn(require_session)))
                 .layer(middleware::from_fn(auth))
         )
         .nest(
             "/file",
-            file_handle()
+            file_send_handler()
+            .layer(send_scope())
+            .merge(file_receive_handler().layer(receive_scope()))
+            .layer(DefaultBodyLimit::max(app_state.env.max_upload_bytes))
+            .layer(middleware::from_fn(require_verified_email))
             .layer(middleware::from_fn(auth)) 
         )
         .nest(
             "/list",
-            get_file_list_handler()
+            get_sent_list_handler()
+            .layer(send_scope())
+            .merge(get_received_list_handler().layer(receive_scope()))
+            .layer(middleware::from_fn(require_verified_email))
             .layer(middleware::from_fn(auth)) 
         )
+        .nest("/share", share_handler())
+        .nest(
+            "/org",
+            organization_handler()
+                .layer(middleware::from_fn(require_session))
+                .layer(middleware::from_fn(require_verified_email))
+                .layer(middleware::from_fn(auth))
+        )
+        .nest(
+            "/admin",
+            admin_read_handler()
+                .layer(middleware::from_fn_with_state(Role::Auditor, require_role))
+                .merge(admin_handler().layer(middleware::from_fn_with_state(Role::Admin, require_role)))
+                .layer(middleware::from_fn(require_session))
+                .layer(middleware::from_fn(auth))
+        )
+        .nest(
+            "/events",
+            events_handler()
+                .layer(middleware::from_fn(require_session))
+                .layer(middleware::from_fn(require_verified_email))
+                .layer(middleware::from_fn(auth))
+        )
+        .nest(
+            "/audit",
+            audit_handler()
+                .merge(audit_verify_handler().layer(middleware::from_fn_with_state(Role::Auditor, require_role)))
+                .layer(middleware::from_fn(require_session))
+                .layer(middleware::from_fn(auth))
+        )
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(app_state));
+        .layer(Extension(app_state.clone()));
 
     Router::new()
         .nest("/api", api_route)
+        .nest("/.well-known", well_known_handler().layer(Extension(app_state)))
         .route("/", get(root_handler))
 }
 
@@ -0,0 +1,96 @@
+use std::{io::SeekFrom, path::PathBuf};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::TryStreamExt;
+use tokio::{fs, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+use tokio_util::io::ReaderStream;
+
+use super::{BlobStore, BlobStream, BlobWriter, StorageError, StorageResult};
+
+/// Stores blobs as files under a root directory, one file per key.
+#[derive(Debug)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsBlobStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> StorageResult<PathBuf> {
+        // Keys are generated by the server, but never let one escape the root
+        if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(StorageError(format!("Invalid storage key: {}", key)));
+        }
+
+        Ok(self.root.join(key))
+    }
+}
+
+struct FsBlobWriter {
+    file: fs::File,
+    partial_path: PathBuf,
+    final_path: PathBuf,
+}
+
+#[async_trait]
+impl BlobWriter for FsBlobWriter {
+    async fn write(&mut self, data: Bytes) -> StorageResult<()> {
+        self.file.write_all(&data).await.map_err(io_error)
+    }
+
+    async fn finish(mut self: Box<Self>) -> StorageResult<()> {
+        self.file.flush().await.map_err(io_error)?;
+        self.file.sync_all().await.map_err(io_error)?;
+        fs::rename(&self.partial_path, &self.final_path).await.map_err(io_error)
+    }
+
+    async fn abort(self: Box<Self>) -> StorageResult<()> {
+        drop(self.file);
+        fs::remove_file(&self.partial_path).await.map_err(io_error)
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn writer(&self, key: &str) -> StorageResult<Box<dyn BlobWriter>> {
+        let final_path = self.path(key)?;
+
+        if let Some(parent) = final_path.parent() {
+            fs::create_dir_all(parent).await.map_err(io_error)?;
+        }
+
+        // Written under a temporary name so readers never see a half-written blob
+        let partial_path = final_path.with_extension("partial");
+        let file = fs::File::create(&partial_path).await.map_err(io_error)?;
+
+        Ok(Box::new(FsBlobWriter { file, partial_path, final_path }))
+    }
+
+    async fn get(&self, key: &str) -> StorageResult<BlobStream> {
+        let file = fs::File::open(self.path(key)?).await.map_err(io_error)?;
+
+        Ok(Box::pin(ReaderStream::new(file).map_err(io_error)))
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobStream> {
+        let mut file = fs::File::open(self.path(key)?).await.map_err(io_error)?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(io_error)?;
+
+        Ok(Box::pin(ReaderStream::new(file.take(length)).map_err(io_error)))
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        match fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> StorageError {
+    StorageError(e.to_string())
+}
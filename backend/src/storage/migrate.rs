@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+
+use crate::{db::{DBClient, UserExt}, models::File};
+
+use super::{new_blob_key, open_file_content, BlobStore, StorageError, StorageResult};
+
+const BATCH_SIZE: i64 = 100;
+
+/// Copies file content still held in `files.encrypted_file` or `file_chunks` into the blob
+/// store, then clears it from Postgres. Safe to re-run: only rows without a storage key are read.
+pub async fn migrate_blobs(blob_store: &Arc<dyn BlobStore>, db_client: &DBClient) -> StorageResult<usize> {
+    let mut migrated = 0;
+
+    loop {
+        let files = db_client
+            .get_unmigrated_files(BATCH_SIZE)
+            .await
+            .map_err(|e| StorageError(e.to_string()))?;
+
+        if files.is_empty() {
+            break;
+        }
+
+        let mut batch_migrated = 0;
+
+        for file in files {
+            match migrate_file(blob_store, db_client, &file).await {
+                Ok(()) => batch_migrated += 1,
+                Err(e) => tracing::error!("Failed to migrate file {}: {}", file.id, e),
+            }
+        }
+
+        // A batch where every row failed would otherwise be fetched again forever
+        if batch_migrated == 0 {
+            tracing::error!("Stopping blob migration, the remaining files could not be migrated");
+            break;
+        }
+
+        migrated += batch_migrated;
+        tracing::info!("Migrated {} files to the blob store", migrated);
+    }
+
+    Ok(migrated)
+}
+
+async fn migrate_file(blob_store: &Arc<dyn BlobStore>, db_client: &DBClient, file: &File) -> StorageResult<()> {
+    let mut content = open_file_content(blob_store, db_client, file).await?;
+
+    let storage_key = new_blob_key();
+    let mut writer = blob_store.writer(&storage_key).await?;
+
+    while let Some(bytes) = content.next().await {
+        let written = match bytes {
+            Ok(bytes) => writer.write(bytes).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = written {
+            writer.abort().await?;
+            return Err(e);
+        }
+    }
+
+    writer.finish().await?;
+
+    if let Err(e) = db_client.set_file_storage_key(file.id, storage_key.clone()).await {
+        blob_store.delete(&storage_key).await?;
+        return Err(StorageError(e.to_string()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+mod fs;
+mod s3;
+pub mod migrate;
+
+use std::{fmt, pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::{config::StorageConfig, db::{DBClient, UserExt}, models::File, utils::encrypt::ENCRYPTED_SEGMENT_SIZE};
+
+pub use fs::FsBlobStore;
+pub use s3::S3BlobStore;
+
+pub type BlobStream = Pin<Box<dyn Stream<Item = StorageResult<Bytes>> + Send>>;
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StorageError: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Incremental upload of a single blob. Nothing is visible under the key until `finish`.
+#[async_trait]
+pub trait BlobWriter: Send {
+    async fn write(&mut self, data: Bytes) -> StorageResult<()>;
+
+    async fn finish(self: Box<Self>) -> StorageResult<()>;
+
+    async fn abort(self: Box<Self>) -> StorageResult<()>;
+}
+
+/// Storage for encrypted file content; `files.storage_key` points into it.
+#[async_trait]
+pub trait BlobStore: Send + Sync + fmt::Debug {
+    async fn writer(&self, key: &str) -> StorageResult<Box<dyn BlobWriter>>;
+
+    async fn get(&self, key: &str) -> StorageResult<BlobStream>;
+
+    /// Reads `length` bytes starting at `offset`; `length` must not be zero.
+    async fn get_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobStream>;
+
+    /// Deletes the blob; deleting a key that does not exist is not an error.
+    async fn delete(&self, key: &str) -> StorageResult<()>;
+}
+
+pub fn create_blob_store(config: &StorageConfig) -> Arc<dyn BlobStore> {
+    match config {
+        StorageConfig::Filesystem { root } => Arc::new(FsBlobStore::new(root)),
+        StorageConfig::S3 { bucket, region, endpoint, access_key, secret_key } => {
+            Arc::new(S3BlobStore::new(bucket, region, endpoint.as_deref(), access_key, secret_key))
+        }
+    }
+}
+
+/// Generates the storage key for a newly uploaded file.
+pub fn new_blob_key() -> String {
+    format!("files/{}", Uuid::new_v4())
+}
+
+/// Opens a file's ciphertext wherever it currently lives: the blob store, or the legacy
+/// `files.encrypted_file` / `file_chunks` columns for rows `migrate-blobs` has not moved yet.
+pub async fn open_file_content(
+    blob_store: &Arc<dyn BlobStore>,
+    db_client: &DBClient,
+    file: &File,
+) -> StorageResult<BlobStream> {
+    if let Some(storage_key) = &file.storage_key {
+        return blob_store.get(storage_key).await;
+    }
+
+    if let Some(chunk_count) = file.chunk_count {
+        let db_client = db_client.clone();
+        let file_id = file.id;
+
+        let chunks = stream::iter(0..chunk_count).then(move |index| {
+            let db_client = db_client.clone();
+
+            async move {
+                db_client
+                    .get_file_chunk(file_id, index)
+                    .await
+                    .map_err(|e| StorageError(e.to_string()))?
+                    .map(Bytes::from)
+                    .ok_or_else(|| StorageError(format!("Missing chunk {} of file {}", index, file_id)))
+            }
+        });
+
+        return Ok(Box::pin(chunks));
+    }
+
+    let content = db_client
+        .get_legacy_file_content(file.id)
+        .await
+        .map_err(|e| StorageError(e.to_string()))?
+        .ok_or_else(|| StorageError(format!("File {} has no stored content", file.id)))?;
+
+    Ok(Box::pin(stream::once(async move { Ok(Bytes::from(content)) })))
+}
+
+/// Opens `length` bytes of a file's ciphertext starting at `offset`, wherever it lives.
+pub async fn open_file_range(
+    blob_store: &Arc<dyn BlobStore>,
+    db_client: &DBClient,
+    file: &File,
+    offset: u64,
+    length: u64,
+) -> StorageResult<BlobStream> {
+    if let Some(storage_key) = &file.storage_key {
+        return blob_store.get_range(storage_key, offset, length).await;
+    }
+
+    // Legacy segmented rows hold exactly one segment per chunk, so only the covering chunks are read
+    if let Some(chunk_count) = file.chunk_count {
+        let segment_size = ENCRYPTED_SEGMENT_SIZE as u64;
+        let first_chunk = (offset / segment_size) as i32;
+        let last_chunk = (((offset + length - 1) / segment_size) as i32).min(chunk_count - 1);
+
+        let db_client = db_client.clone();
+        let file_id = file.id;
+
+        let chunks = stream::iter(first_chunk..=last_chunk).then(move |index| {
+            let db_client = db_client.clone();
+
+            async move {
+                db_client
+                    .get_file_chunk(file_id, index)
+                    .await
+                    .map_err(|e| StorageError(e.to_string()))?
+                    .map(Bytes::from)
+                    .ok_or_else(|| StorageError(format!("Missing chunk {} of file {}", index, file_id)))
+            }
+        });
+
+        let mut skip = (offset % segment_size) as usize;
+        let mut remaining = length as usize;
+
+        let sliced = chunks.map(move |chunk| {
+            let mut chunk = chunk?;
+            let start = skip.min(chunk.len());
+            skip -= start;
+            chunk = chunk.slice(start..);
+            let take = remaining.min(chunk.len());
+            remaining -= take;
+            Ok(chunk.slice(..take))
+        });
+
+        return Ok(Box::pin(sliced));
+    }
+
+    let content = db_client
+        .get_legacy_file_content(file.id)
+        .await
+        .map_err(|e| StorageError(e.to_string()))?
+        .ok_or_else(|| StorageError(format!("File {} has no stored content", file.id)))?;
+
+    let start = (offset as usize).min(content.len());
+    let end = (start + length as usize).min(content.len());
+    let content = Bytes::from(content).slice(start..end);
+
+    Ok(Box::pin(stream::once(async move { Ok(content) })))
+}
+
+/// Best-effort removal of blobs whose rows are already gone; failures are only logged.
+pub async fn delete_blobs(blob_store: &Arc<dyn BlobStore>, storage_keys: Vec<String>) {
+    for storage_key in storage_keys {
+        if let Err(err) = blob_store.delete(&storage_key).await {
+            tracing::error!("Error deleting blob {}: {}", storage_key, err);
+        }
+    }
+}
+
+/// Reads a whole blob stream into memory; only used for legacy single-shot ciphers.
+pub async fn collect_blob(mut blob: BlobStream) -> StorageResult<Vec<u8>> {
+    let mut data = Vec::new();
+
+    while let Some(bytes) = blob.next().await {
+        data.extend_from_slice(&bytes?);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,238 @@
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::{BehaviorVersion, Credentials, Region},
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use axum::body::Bytes;
+use futures_util::stream;
+
+use super::{BlobStore, BlobStream, BlobWriter, StorageError, StorageResult};
+
+/// Multipart part size; S3 requires every part but the last to be at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Stores blobs in an S3-compatible bucket (AWS S3, MinIO, ...).
+#[derive(Debug)]
+pub struct S3BlobStore {
+    client: Client,
+    bucket: String,
+}
+
+impl S3BlobStore {
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        access_key: &str,
+        secret_key: &str,
+    ) -> Self {
+        let credentials = Credentials::new(access_key, secret_key, None, None, "aerofy");
+
+        let mut builder = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(region.to_string()))
+            .credentials_provider(credentials);
+
+        // Custom endpoints (MinIO and friends) generally only support path-style addressing
+        if let Some(endpoint) = endpoint {
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        S3BlobStore {
+            client: Client::from_conf(builder.build()),
+            bucket: bucket.to_string(),
+        }
+    }
+
+    async fn get_object(&self, key: &str, range: Option<String>) -> StorageResult<BlobStream> {
+        let object = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_range(range)
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        let body = stream::unfold(object.body, |mut body| async move {
+            body.try_next()
+                .await
+                .map_err(|e| StorageError(e.to_string()))
+                .transpose()
+                .map(|chunk| (chunk, body))
+        });
+
+        Ok(Box::pin(body))
+    }
+}
+
+/// Buffers writes into parts and only starts a multipart upload once a blob outgrows one part.
+struct S3BlobWriter {
+    client: Client,
+    bucket: String,
+    key: String,
+    buffer: Vec<u8>,
+    upload_id: Option<String>,
+    parts: Vec<CompletedPart>,
+}
+
+impl S3BlobWriter {
+    async fn upload_part(&mut self, data: Vec<u8>) -> StorageResult<()> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let upload = self.client
+                    .create_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .send()
+                    .await
+                    .map_err(s3_error)?;
+
+                let upload_id = upload.upload_id()
+                    .ok_or_else(|| StorageError("S3 did not return an upload id".to_string()))?
+                    .to_string();
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+
+        let part_number = self.parts.len() as i32 + 1;
+
+        let part = self.client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        self.parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .build()
+        );
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobWriter for S3BlobWriter {
+    async fn write(&mut self, data: Bytes) -> StorageResult<()> {
+        self.buffer.extend_from_slice(&data);
+
+        while self.buffer.len() >= PART_SIZE {
+            let rest = self.buffer.split_off(PART_SIZE);
+            let part = std::mem::replace(&mut self.buffer, rest);
+            self.upload_part(part).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> StorageResult<()> {
+        if self.upload_id.is_none() {
+            let data = std::mem::take(&mut self.buffer);
+
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .body(ByteStream::from(data))
+                .send()
+                .await
+                .map_err(s3_error)?;
+
+            return Ok(());
+        }
+
+        if !self.buffer.is_empty() {
+            let data = std::mem::take(&mut self.buffer);
+            self.upload_part(data).await?;
+        }
+
+        let completed = self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .set_upload_id(self.upload_id.clone())
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(std::mem::take(&mut self.parts)))
+                    .build()
+            )
+            .send()
+            .await;
+
+        // Otherwise the uploaded parts are kept (and billed) until a lifecycle rule removes them
+        if let Err(e) = completed {
+            let _ = self.abort().await;
+            return Err(s3_error(e));
+        }
+
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) -> StorageResult<()> {
+        if let Some(upload_id) = &self.upload_id {
+            self.client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(upload_id)
+                .send()
+                .await
+                .map_err(s3_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn writer(&self, key: &str) -> StorageResult<Box<dyn BlobWriter>> {
+        Ok(Box::new(S3BlobWriter {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            buffer: Vec::new(),
+            upload_id: None,
+            parts: Vec::new(),
+        }))
+    }
+
+    async fn get(&self, key: &str) -> StorageResult<BlobStream> {
+        self.get_object(key, None).await
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobStream> {
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+
+        self.get_object(key, Some(range)).await
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        // DeleteObject already succeeds for keys that do not exist
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+}
+
+fn s3_error<E: std::error::Error>(e: E) -> StorageError {
+    StorageError(aws_sdk_s3::error::DisplayErrorContext(e).to_string())
+}
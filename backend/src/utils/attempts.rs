@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use crate::{db::UserExt, error::HttpError, models::PasswordAttempts, utils::password, AppState};
+
+/// Longest anyone is asked to wait between two password attempts.
+const MAX_BACKOFF_SECONDS: i64 = 15 * 60;
+
+/// A share password about to be checked, with the attempts already counted against the share.
+pub struct ShareAttempt<'a> {
+    pub shared_id: Uuid,
+    pub password_hash: &'a str,
+    pub attempts: PasswordAttempts,
+    pub locked_at: Option<DateTime<Utc>>,
+}
+
+/// No wait before the first attempt, then `base_seconds` doubling with every further one.
+fn backoff_seconds(failed_attempts: i32, base_seconds: i64) -> i64 {
+    if failed_attempts <= 0 {
+        return 0;
+    }
+
+    let exponent = (failed_attempts - 1).min(20) as u32;
+    base_seconds.saturating_mul(1 << exponent).min(MAX_BACKOFF_SECONDS)
+}
+
+pub fn check_backoff(attempts: &PasswordAttempts, base_seconds: i64) -> Result<(), HttpError> {
+    let Some(last_attempt_at) = attempts.last_attempt_at else {
+        return Ok(());
+    };
+
+    let retry_at = last_attempt_at + Duration::seconds(backoff_seconds(attempts.failed_attempts, base_seconds));
+    let wait = (retry_at - Utc::now()).num_milliseconds();
+
+    if wait > 0 {
+        return Err(too_many_attempts((wait as u64).div_ceil(1000) as i64));
+    }
+
+    Ok(())
+}
+
+pub fn too_many_attempts(wait_seconds: i64) -> HttpError {
+    HttpError::new(
+        format!("Too many attempts, try again in {} seconds", wait_seconds),
+        StatusCode::TOO_MANY_REQUESTS,
+    )
+}
+
+fn share_locked() -> HttpError {
+    HttpError::new(
+        "This share is locked after too many wrong passwords, ask the sender to unlock it",
+        StatusCode::LOCKED,
+    )
+}
+
+/// Checks a share password without letting callers guess freely. Each attempt is counted
+/// against the share, and the recipient when there is one, before Argon2 runs, so attempts
+/// inside the backoff window are turned away without hashing anything. A correct password
+/// resets both counters; running out of attempts locks the share until its sender unlocks it.
+pub async fn verify_share_password(
+    app_state: &Arc<AppState>,
+    share: ShareAttempt<'_>,
+    user_id: Option<Uuid>,
+    password: &str,
+) -> Result<(), HttpError> {
+    if share.locked_at.is_some() {
+        return Err(share_locked());
+    }
+
+    let base_seconds = app_state.env.password_backoff_seconds;
+
+    check_backoff(&share.attempts, base_seconds)?;
+
+    let user_attempts = match user_id {
+        Some(user_id) => {
+            let attempts = app_state.db_client
+                .get_user_password_attempts(user_id)
+                .await
+                .map_err(|e| HttpError::server_error(e.to_string()))?
+                .unwrap_or_default();
+
+            check_backoff(&attempts, base_seconds)?;
+            Some((user_id, attempts))
+        }
+        None => None,
+    };
+
+    // Claiming fails when a concurrent attempt got in first, which has just restarted the backoff
+    let claimed = app_state.db_client
+        .claim_share_attempt(share.shared_id, share.attempts.failed_attempts)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !claimed {
+        return Err(too_many_attempts(base_seconds));
+    }
+
+    if let Some((user_id, attempts)) = &user_attempts {
+        let claimed = app_state.db_client
+            .claim_user_attempt(*user_id, attempts.failed_attempts)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if !claimed {
+            return Err(too_many_attempts(base_seconds));
+        }
+    }
+
+    let is_valid = password::compare(password, share.password_hash)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if is_valid {
+        app_state.db_client
+            .reset_password_attempts(share.shared_id, user_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        return Ok(());
+    }
+
+    if share.attempts.failed_attempts + 1 >= app_state.env.share_password_max_attempts {
+        app_state.db_client
+            .lock_share(share.shared_id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        return Err(share_locked());
+    }
+
+    Err(HttpError::unauthorized("Invalid password".to_string()))
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{db::UserExt, models::{AuditAction, AuditEvent, NewAuditEvent}, utils::session::ClientInfo, AppState};
+
+/// What the first event of the chain points back to.
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+/// An event to record, before the request's client details are added.
+pub struct AuditEntry {
+    pub user_id: Option<Uuid>,
+    pub action: AuditAction,
+    pub succeeded: bool,
+    pub target_id: Option<Uuid>,
+    pub details: Value,
+}
+
+impl AuditEntry {
+    pub fn succeeded(action: AuditAction, user_id: Option<Uuid>) -> Self {
+        AuditEntry { user_id, action, succeeded: true, target_id: None, details: Value::Object(Default::default()) }
+    }
+
+    pub fn failed(action: AuditAction, user_id: Option<Uuid>) -> Self {
+        AuditEntry { succeeded: false, ..AuditEntry::succeeded(action, user_id) }
+    }
+
+    pub fn target(self, target_id: Uuid) -> Self {
+        AuditEntry { target_id: Some(target_id), ..self }
+    }
+
+    pub fn details(self, details: Value) -> Self {
+        AuditEntry { details, ..self }
+    }
+}
+
+/// Appends an event to the audit log. Failing to write it is logged rather than failing the
+/// request it describes, which has already happened by the time it is recorded.
+pub async fn record(app_state: &Arc<AppState>, client: Option<&ClientInfo>, entry: AuditEntry) {
+    let event = NewAuditEvent {
+        user_id: entry.user_id,
+        action: entry.action,
+        succeeded: entry.succeeded,
+        target_id: entry.target_id,
+        ip_address: client.and_then(|client| client.ip_address.clone()),
+        user_agent: client.and_then(|client| client.user_agent.clone()),
+        details: entry.details,
+    };
+
+    if let Err(e) = app_state.db_client.append_audit_event(event).await {
+        tracing::error!("Failed to record audit event {}: {}", entry.action.as_str(), e);
+    }
+}
+
+/// SHA-256 over the previous hash and every field of the event, each length-prefixed so
+/// that no two different events encode the same way.
+pub fn chain_hash(prev_hash: &[u8], occurred_at: DateTime<Utc>, event: &NewAuditEvent) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+
+    let fields = [
+        Some(prev_hash.to_vec()),
+        Some(occurred_at.to_rfc3339_opts(SecondsFormat::Micros, true).into_bytes()),
+        event.user_id.map(|id| id.to_string().into_bytes()),
+        Some(event.action.as_str().as_bytes().to_vec()),
+        Some(vec![event.succeeded as u8]),
+        event.target_id.map(|id| id.to_string().into_bytes()),
+        event.ip_address.as_ref().map(|ip| ip.as_bytes().to_vec()),
+        event.user_agent.as_ref().map(|ua| ua.as_bytes().to_vec()),
+        Some(canonical_json(&event.details).into_bytes()),
+    ];
+
+    for field in fields {
+        match field {
+            Some(bytes) => {
+                hasher.update((bytes.len() as u32).to_be_bytes());
+                hasher.update(&bytes);
+            }
+            None => hasher.update(u32::MAX.to_be_bytes()),
+        }
+    }
+
+    hasher.finalize().to_vec()
+}
+
+/// Whether a stored event still hashes to what it was written with.
+pub fn event_hash_matches(event: &AuditEvent) -> bool {
+    let Ok(action) = AuditAction::try_from(event.action.as_str()) else {
+        return false;
+    };
+
+    let written = NewAuditEvent {
+        user_id: event.user_id,
+        action,
+        succeeded: event.succeeded,
+        target_id: event.target_id,
+        ip_address: event.ip_address.clone(),
+        user_agent: event.user_agent.clone(),
+        details: event.details.clone(),
+    };
+
+    chain_hash(&event.prev_hash, event.occurred_at, &written) == event.hash
+}
+
+// JSONB does not keep key order, so objects are hashed with their keys sorted
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            let fields: Vec<String> = keys.into_iter()
+                .map(|key| format!("{}:{}", Value::String(key.clone()), canonical_json(&map[key])))
+                .collect();
+
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,96 @@
+use crate::error::HttpError;
+
+/// Content cipher recorded in `files.cipher` for every stored file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum FileCipher {
+    /// Legacy AES-256-CBC with PKCS7 padding, kept so older rows still decrypt.
+    Aes256Cbc,
+    /// AES-256-GCM with a 96-bit nonce stored in `files.iv`.
+    Aes256Gcm,
+    /// Segmented AES-256-GCM stored in `file_chunks`, see `utils::encrypt::StreamEncryptor`.
+    Aes256GcmStream,
+}
+
+impl FileCipher {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileCipher::Aes256Cbc => "aes-256-cbc",
+            FileCipher::Aes256Gcm => "aes-256-gcm",
+            FileCipher::Aes256GcmStream => "aes-256-gcm-stream",
+        }
+    }
+}
+
+impl TryFrom<&str> for FileCipher {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "aes-256-cbc" => Ok(FileCipher::Aes256Cbc),
+            "aes-256-gcm" => Ok(FileCipher::Aes256Gcm),
+            "aes-256-gcm-stream" => Ok(FileCipher::Aes256GcmStream),
+            other => Err(HttpError::server_error(format!("Unsupported file cipher: {}", other))),
+        }
+    }
+}
+
+/// RSA padding used to wrap a file's AES key, recorded in `file_keys.key_wrap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyWrap {
+    /// Legacy PKCS#1 v1.5 padding, only unwrapped until the re-wrap job upgrades the row.
+    RsaPkcs1v15,
+    /// RSA-OAEP with SHA-256, used for every new upload.
+    RsaOaepSha256,
+}
+
+impl KeyWrap {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyWrap::RsaPkcs1v15 => "rsa-pkcs1v15",
+            KeyWrap::RsaOaepSha256 => "rsa-oaep-sha256",
+        }
+    }
+}
+
+impl TryFrom<&str> for KeyWrap {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "rsa-pkcs1v15" => Ok(KeyWrap::RsaPkcs1v15),
+            "rsa-oaep-sha256" => Ok(KeyWrap::RsaOaepSha256),
+            other => Err(HttpError::server_error(format!("Unsupported key wrap: {}", other))),
+        }
+    }
+}
+
+/// Where the file key of a link share comes from, recorded in `shared_links.link_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKey {
+    /// Carried in the link's URL fragment; the server only keeps a hash to check it against.
+    Fragment,
+    /// Wrapped under a key derived from the share password.
+    Password,
+}
+
+impl LinkKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkKey::Fragment => "fragment",
+            LinkKey::Password => "password",
+        }
+    }
+}
+
+impl TryFrom<&str> for LinkKey {
+    type Error = HttpError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "fragment" => Ok(LinkKey::Fragment),
+            "password" => Ok(LinkKey::Password),
+            other => Err(HttpError::server_error(format!("Unsupported link key: {}", other))),
+        }
+    }
+}
@@ -1,36 +1,148 @@
-use aes::Aes256;
-use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
 use rand::Rng;
-use rsa::{Pkcs1v15Encrypt, RsaPublicKey};
 
 use crate::error::HttpError;
 
-pub async fn encrypt_file(
-    file_data: Vec<u8>,
-    user_public_key: &RsaPublicKey
-) -> Result<(Vec<u8>,Vec<u8>,Vec<u8>), HttpError> {
+/// Plaintext bytes per encrypted segment; every segment except the last is exactly this size.
+pub const SEGMENT_SIZE: usize = 256 * 1024;
 
+/// Stored size of a full segment: its ciphertext followed by the 16 byte GCM tag.
+pub const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE + 16;
+
+/// Number of segments `StreamEncryptor` produces for a plaintext of this size; an empty
+/// plaintext still gets one (empty) final segment.
+pub fn segment_count(plaintext_size: u64) -> u64 {
+    plaintext_size.div_ceil(SEGMENT_SIZE as u64).max(1)
+}
+
+/// Random per-file nonce prefix, stored in `files.iv` for segmented files.
+pub const NONCE_PREFIX_SIZE: usize = 7;
+
+/// Builds the nonce for one segment: the file's random prefix, a big-endian segment
+/// counter and a final-segment flag, so segments cannot be reordered or truncated.
+pub fn segment_nonce(nonce_prefix: &[u8], index: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(nonce_prefix);
+    nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Generates a fresh AES-256 key and segment nonce prefix for a new file.
+pub fn generate_file_key() -> ([u8; 32], [u8; NONCE_PREFIX_SIZE]) {
     let mut aes_key = [0u8; 32];
-    let mut iv = [0u8; 16];
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
     rand::thread_rng().fill(&mut aes_key);
-    rand::thread_rng().fill(&mut iv);
-
-    let cipher = Cbc::<Aes256, Pkcs7>::new_from_slices(&aes_key, &iv)
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
-
-    let mut buffer = file_data.clone();
-    let encrypted_data = cipher.encrypt_vec(&mut buffer);
-
-    let encrypted_aes_key = user_public_key.encrypt(
-        &mut rand::thread_rng(), 
-        Pkcs1v15Encrypt, 
-        &aes_key
-    )
-    .map_err(|e| HttpError::server_error(e.to_string()))?;
-
-    Ok((
-        encrypted_aes_key,
-        encrypted_data,
-        iv.to_vec(),
-    ))
-}
\ No newline at end of file
+    rand::thread_rng().fill(&mut nonce_prefix);
+    (aes_key, nonce_prefix)
+}
+
+/// Encrypts a plaintext stream into fixed-size AES-256-GCM segments while holding at most
+/// one segment of plaintext in memory.
+pub struct StreamEncryptor {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    index: u32,
+    buffer: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    pub fn new(aes_key: &[u8; 32], nonce_prefix: [u8; NONCE_PREFIX_SIZE]) -> Self {
+        StreamEncryptor {
+            cipher: Aes256Gcm::new(aes_key.into()),
+            nonce_prefix,
+            index: 0,
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+        }
+    }
+
+    /// Feeds more plaintext and returns every segment that is now complete. A full segment
+    /// is only emitted once more data arrives, since the final one has to be flagged as such.
+    pub fn update(&mut self, mut data: &[u8]) -> Result<Vec<Vec<u8>>, HttpError> {
+        let mut segments = Vec::new();
+
+        while !data.is_empty() {
+            if self.buffer.len() == SEGMENT_SIZE {
+                let segment = std::mem::take(&mut self.buffer);
+                segments.push(self.seal(&segment, false)?);
+                self.buffer.reserve(SEGMENT_SIZE);
+            }
+
+            let take = (SEGMENT_SIZE - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+        }
+
+        Ok(segments)
+    }
+
+    /// Seals whatever is left as the final segment. Empty files produce one empty segment.
+    pub fn finish(mut self) -> Result<Vec<u8>, HttpError> {
+        let segment = std::mem::take(&mut self.buffer);
+        self.seal(&segment, true)
+    }
+
+    fn seal(&mut self, plaintext: &[u8], last: bool) -> Result<Vec<u8>, HttpError> {
+        let nonce = segment_nonce(&self.nonce_prefix, self.index, last);
+        self.index = self.index.checked_add(1)
+            .ok_or_else(|| HttpError::bad_request("File is too large".to_string()))?;
+
+        self.cipher.encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| HttpError::server_error("Failed to encrypt file".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(plaintext: &[u8], write_size: usize) -> Vec<Vec<u8>> {
+        let mut encryptor = StreamEncryptor::new(&[7u8; 32], [1u8; NONCE_PREFIX_SIZE]);
+        let mut segments = Vec::new();
+
+        for piece in plaintext.chunks(write_size.max(1)) {
+            segments.extend(encryptor.update(piece).unwrap());
+        }
+
+        segments.push(encryptor.finish().unwrap());
+        segments
+    }
+
+    #[test]
+    fn segments_are_full_except_the_last() {
+        let plaintext = vec![3u8; 2 * SEGMENT_SIZE + 10];
+        let segments = encrypt(&plaintext, 1000);
+
+        assert_eq!(segments.len() as u64, segment_count(plaintext.len() as u64));
+        assert_eq!(segments[0].len(), ENCRYPTED_SEGMENT_SIZE);
+        assert_eq!(segments[1].len(), ENCRYPTED_SEGMENT_SIZE);
+        assert_eq!(segments[2].len(), 10 + 16);
+    }
+
+    #[test]
+    fn a_plaintext_of_exactly_one_segment_is_one_final_segment() {
+        let segments = encrypt(&vec![3u8; SEGMENT_SIZE], SEGMENT_SIZE);
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segment_count(SEGMENT_SIZE as u64), 1);
+        assert_eq!(segments[0].len(), ENCRYPTED_SEGMENT_SIZE);
+    }
+
+    #[test]
+    fn an_empty_plaintext_is_one_empty_segment() {
+        let segments = encrypt(&[], 1);
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segment_count(0), 1);
+        assert_eq!(segments[0].len(), 16);
+    }
+
+    #[test]
+    fn nonces_differ_by_index_and_final_flag() {
+        let prefix = [9u8; NONCE_PREFIX_SIZE];
+
+        assert_ne!(segment_nonce(&prefix, 0, false), segment_nonce(&prefix, 1, false));
+        assert_ne!(segment_nonce(&prefix, 0, false), segment_nonce(&prefix, 0, true));
+        assert_eq!(&segment_nonce(&prefix, 0x01020304, true)[NONCE_PREFIX_SIZE..], &[1, 2, 3, 4, 1]);
+    }
+}
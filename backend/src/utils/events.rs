@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{postgres::PgListener, Pool, Postgres};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{db::{DBClient, UserExt}, models::ShareNotice};
+
+/// The Postgres channel every instance publishes to and listens on.
+pub const EVENTS_CHANNEL: &str = "aerofy_events";
+
+/// Events buffered for each connected client before it is told it missed some.
+const EVENT_BUFFER: usize = 256;
+
+/// How long to wait before listening again after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    #[serde(rename = "share.created")]
+    ShareCreated,
+    #[serde(rename = "share.accepted")]
+    ShareAccepted,
+    #[serde(rename = "share.declined")]
+    ShareDeclined,
+    #[serde(rename = "share.revoked")]
+    ShareRevoked,
+    #[serde(rename = "share.expiry_warning")]
+    ShareExpiryWarning,
+    // Only sent by an instance to its own clients, when they fell behind
+    #[serde(rename = "events.missed")]
+    EventsMissed,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::ShareCreated => "share.created",
+            EventKind::ShareAccepted => "share.accepted",
+            EventKind::ShareDeclined => "share.declined",
+            EventKind::ShareRevoked => "share.revoked",
+            EventKind::ShareExpiryWarning => "share.expiry_warning",
+            EventKind::EventsMissed => "events.missed",
+        }
+    }
+}
+
+/// A notification for one account, as it travels through Postgres and out to the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEvent {
+    pub user_id: Uuid,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub data: Value,
+}
+
+impl UserEvent {
+    pub fn share_created(notice: &ShareNotice, recipient_user_id: Uuid) -> Self {
+        UserEvent {
+            user_id: recipient_user_id,
+            kind: EventKind::ShareCreated,
+            data: serde_json::json!({
+                "shared_id": notice.shared_id,
+                "file_id": notice.file_id,
+                "file_name": notice.file_name,
+                "sender_email": notice.sender_email,
+                "expiration_date": notice.expiration_date,
+            }),
+        }
+    }
+
+    pub fn share_accepted(notice: &ShareNotice, recipient_email: &str) -> Self {
+        UserEvent {
+            user_id: notice.sender_id,
+            kind: EventKind::ShareAccepted,
+            data: serde_json::json!({
+                "shared_id": notice.shared_id,
+                "file_id": notice.file_id,
+                "file_name": notice.file_name,
+                "recipient_email": recipient_email,
+            }),
+        }
+    }
+
+    pub fn share_declined(notice: &ShareNotice, recipient_email: &str) -> Self {
+        UserEvent {
+            user_id: notice.sender_id,
+            kind: EventKind::ShareDeclined,
+            data: serde_json::json!({
+                "shared_id": notice.shared_id,
+                "file_id": notice.file_id,
+                "file_name": notice.file_name,
+                "recipient_email": recipient_email,
+            }),
+        }
+    }
+
+    pub fn share_revoked(notice: &ShareNotice, recipient_user_id: Uuid) -> Self {
+        UserEvent {
+            user_id: recipient_user_id,
+            kind: EventKind::ShareRevoked,
+            data: serde_json::json!({
+                "shared_id": notice.shared_id,
+                "file_name": notice.file_name,
+                "sender_email": notice.sender_email,
+            }),
+        }
+    }
+
+    pub fn share_expiry_warning(notice: &ShareNotice, recipient_user_id: Uuid) -> Self {
+        UserEvent {
+            user_id: recipient_user_id,
+            kind: EventKind::ShareExpiryWarning,
+            data: serde_json::json!({
+                "shared_id": notice.shared_id,
+                "file_id": notice.file_id,
+                "file_name": notice.file_name,
+                "sender_email": notice.sender_email,
+                "expiration_date": notice.expiration_date,
+            }),
+        }
+    }
+
+    // Carries nothing, as it is the cue to reload what the client shows
+    pub fn events_missed(user_id: Uuid) -> Self {
+        UserEvent {
+            user_id,
+            kind: EventKind::EventsMissed,
+            data: serde_json::json!({}),
+        }
+    }
+}
+
+/// Hands the events this instance hears on `EVENTS_CHANNEL` to its connected clients.
+#[derive(Debug, Clone)]
+pub struct EventHub {
+    sender: broadcast::Sender<UserEvent>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventHub { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UserEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Listens on its own connection for as long as the server runs, reconnecting when it drops.
+    pub fn listen(&self, pool: Pool<Postgres>) {
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = forward_notifications(&pool, &sender).await {
+                    tracing::error!("Lost the {} listener, reconnecting: {}", EVENTS_CHANNEL, e);
+                }
+
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+}
+
+async fn forward_notifications(pool: &Pool<Postgres>, sender: &broadcast::Sender<UserEvent>) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(EVENTS_CHANNEL).await?;
+
+    loop {
+        let notification = listener.recv().await?;
+
+        match serde_json::from_str::<UserEvent>(notification.payload()) {
+            // Nobody being connected to this instance is not an error
+            Ok(event) => { let _ = sender.send(event); }
+            Err(e) => tracing::error!("Ignoring malformed event {}: {}", notification.payload(), e),
+        }
+    }
+}
+
+/// Publishes an event to every instance. Like the audit log, a failure is logged rather than
+/// failing the request, which has already happened.
+pub async fn publish(db_client: &DBClient, event: UserEvent) {
+    let payload = match serde_json::to_string(&event) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::error!("Failed to encode {} event: {}", event.kind.as_str(), e);
+            return;
+        }
+    };
+
+    if let Err(e) = db_client.notify_event(EVENTS_CHANNEL, &payload).await {
+        tracing::error!("Failed to publish {} event: {}", event.kind.as_str(), e);
+    }
+}
+
+/// Tells every recipient of a new file about it.
+pub async fn publish_share_created(db_client: &DBClient, file_id: Uuid) {
+    let notices = match db_client.get_file_share_notices(file_id).await {
+        Ok(notices) => notices,
+        Err(e) => {
+            tracing::error!("Failed to load the shares of file {}: {}", file_id, e);
+            return;
+        }
+    };
+
+    for notice in notices {
+        if let Some(recipient_user_id) = notice.recipient_user_id {
+            publish(db_client, UserEvent::share_created(&notice, recipient_user_id)).await;
+        }
+    }
+}
+
+/// Tells the sender that a recipient accepted their file.
+pub async fn publish_share_accepted(db_client: &DBClient, shared_id: Uuid, recipient_email: &str) {
+    match db_client.get_share_notice(shared_id).await {
+        Ok(Some(notice)) => publish(db_client, UserEvent::share_accepted(&notice, recipient_email)).await,
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to load share {}: {}", shared_id, e),
+    }
+}
+
+/// Tells the sender that a recipient turned their file down.
+pub async fn publish_share_declined(db_client: &DBClient, shared_id: Uuid, recipient_email: &str) {
+    match db_client.get_share_notice(shared_id).await {
+        Ok(Some(notice)) => publish(db_client, UserEvent::share_declined(&notice, recipient_email)).await,
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to load share {}: {}", shared_id, e),
+    }
+}
+
+/// Tells the recipient that a share was taken back; link shares have nobody to tell.
+pub async fn publish_share_revoked(db_client: &DBClient, shared_id: Uuid) {
+    match db_client.get_share_notice(shared_id).await {
+        Ok(Some(notice)) => {
+            if let Some(recipient_user_id) = notice.recipient_user_id {
+                publish(db_client, UserEvent::share_revoked(&notice, recipient_user_id)).await;
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to load share {}: {}", shared_id, e),
+    }
+}
+
+/// Warns recipients whose shares are about to expire. Run by the scheduler on every instance;
+/// claiming the shares first means each warning goes out once.
+pub async fn publish_expiry_warnings(db_client: &DBClient, within_hours: i64) -> Result<usize, sqlx::Error> {
+    let notices = db_client.take_expiring_share_notices(within_hours).await?;
+
+    for notice in &notices {
+        if let Some(recipient_user_id) = notice.recipient_user_id {
+            publish(db_client, UserEvent::share_expiry_warning(notice, recipient_user_id)).await;
+        }
+    }
+
+    Ok(notices.len())
+}
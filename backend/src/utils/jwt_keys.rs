@@ -0,0 +1,262 @@
+use std::{collections::HashMap, fmt, fs, io::Write, os::unix::fs::OpenOptionsExt, path::Path, sync::Arc};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::{rngs::OsRng, Rng};
+use ring::{rand::SystemRandom, signature::{Ed25519KeyPair, KeyPair}};
+use rsa::{pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey}, pkcs8::{der::pem, DecodePrivateKey, DecodePublicKey, EncodePrivateKey, LineEnding}, traits::PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{ErrorMessage, HttpError};
+
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo, followed by the 32-byte public key.
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+const RSA_KEY_BITS: usize = 2048;
+
+struct VerificationKey {
+    kid: String,
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+    jwk: serde_json::Value,
+}
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+}
+
+struct ParsedKey {
+    algorithm: Algorithm,
+    encoding_key: Option<EncodingKey>,
+    decoding_key: DecodingKey,
+    jwk: serde_json::Value,
+}
+
+/// The keys access and single-purpose tokens are signed and verified with. Every `<kid>.pem`
+/// in `JWT_KEYS_DIR` verifies tokens and is published at `/.well-known/jwks.json`, so keys can
+/// be rotated without logging anyone out; the private key `JWT_SIGNING_KID` signs new tokens.
+#[derive(Clone)]
+pub struct JwtKeys {
+    signing_key: Arc<SigningKey>,
+    verification_keys: Arc<Vec<VerificationKey>>,
+    // The old shared HS256 secret, still accepted for tokens without a `kid` until they expire
+    legacy_secret: Option<Arc<DecodingKey>>,
+}
+
+impl JwtKeys {
+    /// Loads the key directory; with several private keys, `signing_kid` must be set. An empty
+    /// directory is an error unless `generate_if_empty`, which gives it a new Ed25519 key. That key
+    /// only exists on this instance, so it is meant for local development.
+    pub fn load(
+        keys_dir: &str,
+        signing_kid: Option<&str>,
+        legacy_secret: Option<&str>,
+        generate_if_empty: bool,
+    ) -> Result<JwtKeys, String> {
+        fs::create_dir_all(keys_dir).map_err(|e| format!("{}: {}", keys_dir, e))?;
+
+        let mut paths = fs::read_dir(keys_dir)
+            .map_err(|e| format!("{}: {}", keys_dir, e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "pem"))
+            .collect::<Vec<_>>();
+
+        if paths.is_empty() && signing_kid.is_none() {
+            // Every instance generating its own key would reject the others' tokens, and lose its own on restart
+            if !generate_if_empty {
+                return Err(format!(
+                    "No keys in {}, run `backend generate-jwt-key` and deploy the key to every instance, or set JWT_GENERATE_KEY=true for local development",
+                    keys_dir
+                ));
+            }
+
+            let kid = generate_key(keys_dir, Algorithm::EdDSA)?;
+            paths.push(Path::new(keys_dir).join(format!("{}.pem", kid)));
+        }
+
+        paths.sort();
+
+        let mut encoding_keys = HashMap::new();
+        let mut verification_keys = Vec::new();
+
+        for path in paths {
+            let kid = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string();
+            let pem_text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let key = parse_key(&kid, &pem_text).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            if let Some(encoding_key) = key.encoding_key {
+                encoding_keys.insert(kid.clone(), (key.algorithm, encoding_key));
+            }
+
+            verification_keys.push(VerificationKey {
+                kid,
+                algorithm: key.algorithm,
+                decoding_key: key.decoding_key,
+                jwk: key.jwk,
+            });
+        }
+
+        let signing_kid = match signing_kid {
+            Some(kid) => kid.to_string(),
+            None if encoding_keys.len() == 1 => encoding_keys.keys().next().cloned().unwrap_or_default(),
+            None => {
+                let mut kids = encoding_keys.keys().cloned().collect::<Vec<_>>();
+                kids.sort();
+                return Err(format!("Private keys {} found in {}, set JWT_SIGNING_KID to the one that signs", kids.join(", "), keys_dir));
+            }
+        };
+
+        let (algorithm, encoding_key) = encoding_keys
+            .remove(&signing_kid)
+            .ok_or_else(|| format!("No private key {}.pem in {}", signing_kid, keys_dir))?;
+
+        Ok(JwtKeys {
+            signing_key: Arc::new(SigningKey { kid: signing_kid, algorithm, encoding_key }),
+            verification_keys: Arc::new(verification_keys),
+            legacy_secret: legacy_secret.map(|secret| Arc::new(DecodingKey::from_secret(secret.as_bytes()))),
+        })
+    }
+
+    /// Signs `claims` with the current signing key, naming it in the `kid` header.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.signing_key.algorithm);
+        header.kid = Some(self.signing_key.kid.clone());
+
+        encode(&header, claims, &self.signing_key.encoding_key)
+    }
+
+    /// Verifies a token with the key its `kid` names, only ever with that key's algorithm.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, HttpError> {
+        let header = decode_header(token).map_err(|_| invalid_token())?;
+
+        let (decoding_key, algorithm) = match header.kid.as_deref() {
+            Some(kid) => self.verification_keys
+                .iter()
+                .find(|key| key.kid == kid)
+                .map(|key| (&key.decoding_key, key.algorithm))
+                .ok_or_else(invalid_token)?,
+            None => self.legacy_secret
+                .as_deref()
+                .map(|decoding_key| (decoding_key, Algorithm::HS256))
+                .ok_or_else(invalid_token)?,
+        };
+
+        decode::<T>(token, decoding_key, &Validation::new(algorithm))
+            .map(|token| token.claims)
+            .map_err(|_| invalid_token())
+    }
+
+    /// The public verification keys, as a JWK Set.
+    pub fn jwks(&self) -> serde_json::Value {
+        serde_json::json!({
+            "keys": self.verification_keys.iter().map(|key| key.jwk.clone()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl fmt::Debug for JwtKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kids = self.verification_keys.iter().map(|key| key.kid.as_str()).collect::<Vec<_>>();
+        write!(f, "JwtKeys {{ signing: {}, verifying: {:?} }}", self.signing_key.kid, kids)
+    }
+}
+
+/// Writes a new private key to `<keys_dir>/<kid>.pem`, readable by the owner only, and
+/// returns its kid.
+pub fn generate_key(keys_dir: &str, algorithm: Algorithm) -> Result<String, String> {
+    let pem_text = match algorithm {
+        Algorithm::EdDSA => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|e| e.to_string())?;
+            pem::encode_string("PRIVATE KEY", LineEnding::LF, pkcs8.as_ref()).map_err(|e| e.to_string())?
+        }
+        Algorithm::RS256 => {
+            let private_key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS).map_err(|e| e.to_string())?;
+            private_key.to_pkcs8_pem(LineEnding::LF).map_err(|e| e.to_string())?.to_string()
+        }
+        other => return Err(format!("Unsupported signing algorithm {:?}", other)),
+    };
+
+    let kid = format!("{}-{:08x}", Utc::now().format("%Y%m%d"), rand::thread_rng().r#gen::<u32>());
+    let path = Path::new(keys_dir).join(format!("{}.pem", kid));
+
+    fs::create_dir_all(keys_dir).map_err(|e| format!("{}: {}", keys_dir, e))?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(pem_text.as_bytes()))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    Ok(kid)
+}
+
+// Private keys sign and verify; public keys, such as those of retired signing keys, only verify
+fn parse_key(kid: &str, pem_text: &str) -> Result<ParsedKey, String> {
+    let (label, der) = pem::decode_vec(pem_text.as_bytes()).map_err(|e| e.to_string())?;
+
+    match label {
+        "PRIVATE KEY" => match RsaPrivateKey::from_pkcs8_der(&der) {
+            Ok(private_key) => rsa_key(kid, Some(pem_text), &RsaPublicKey::from(&private_key)),
+            Err(_) => {
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+                    .map_err(|_| "Private key is neither RSA nor Ed25519".to_string())?;
+                ed25519_key(kid, Some(pem_text), key_pair.public_key().as_ref())
+            }
+        },
+        "RSA PRIVATE KEY" => {
+            let private_key = RsaPrivateKey::from_pkcs1_der(&der).map_err(|e| e.to_string())?;
+            rsa_key(kid, Some(pem_text), &RsaPublicKey::from(&private_key))
+        }
+        "PUBLIC KEY" => match RsaPublicKey::from_public_key_der(&der) {
+            Ok(public_key) => rsa_key(kid, None, &public_key),
+            Err(_) if der.len() == 44 && der.starts_with(&ED25519_SPKI_PREFIX) => ed25519_key(kid, None, &der[12..]),
+            Err(_) => Err("Public key is neither RSA nor Ed25519".to_string()),
+        },
+        "RSA PUBLIC KEY" => {
+            let public_key = RsaPublicKey::from_pkcs1_der(&der).map_err(|e| e.to_string())?;
+            rsa_key(kid, None, &public_key)
+        }
+        other => Err(format!("Unsupported PEM block {}", other)),
+    }
+}
+
+fn rsa_key(kid: &str, private_pem: Option<&str>, public_key: &RsaPublicKey) -> Result<ParsedKey, String> {
+    let n = URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be());
+    let e = URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be());
+
+    let encoding_key = private_pem
+        .map(|pem_text| EncodingKey::from_rsa_pem(pem_text.as_bytes()))
+        .transpose()
+        .map_err(|e| e.to_string())?;
+
+    Ok(ParsedKey {
+        algorithm: Algorithm::RS256,
+        encoding_key,
+        decoding_key: DecodingKey::from_rsa_components(&n, &e).map_err(|e| e.to_string())?,
+        jwk: serde_json::json!({ "kty": "RSA", "use": "sig", "alg": "RS256", "kid": kid, "n": n, "e": e }),
+    })
+}
+
+fn ed25519_key(kid: &str, private_pem: Option<&str>, public_key: &[u8]) -> Result<ParsedKey, String> {
+    let x = URL_SAFE_NO_PAD.encode(public_key);
+
+    let encoding_key = private_pem
+        .map(|pem_text| EncodingKey::from_ed_pem(pem_text.as_bytes()))
+        .transpose()
+        .map_err(|e| e.to_string())?;
+
+    Ok(ParsedKey {
+        algorithm: Algorithm::EdDSA,
+        encoding_key,
+        decoding_key: DecodingKey::from_ed_components(&x).map_err(|e| e.to_string())?,
+        jwk: serde_json::json!({ "kty": "OKP", "crv": "Ed25519", "use": "sig", "alg": "EdDSA", "kid": kid, "x": x }),
+    })
+}
+
+fn invalid_token() -> HttpError {
+    HttpError::unauthorized(ErrorMessage::InvalidToken.to_string())
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, fmt, sync::{Arc, RwLock}};
+
+use chrono::{DateTime, Duration, Utc};
+use rsa::RsaPrivateKey;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+struct UnlockedKey {
+    private_key: RsaPrivateKey,
+    expires_at: DateTime<Utc>,
+}
+
+struct HeldKey {
+    user_id: Uuid,
+    key: UnlockedKey,
+}
+
+/// Private keys unlocked at login, held in memory only for the lifetime of the session.
+#[derive(Clone, Default)]
+pub struct KeyCache {
+    keys: Arc<RwLock<HashMap<Uuid, UnlockedKey>>>,
+    // Unlocked by the first step of a login and waiting on its second factor, by token hash
+    held: Arc<RwLock<HashMap<Vec<u8>, HeldKey>>>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        KeyCache::default()
+    }
+
+    pub fn insert(&self, user_id: Uuid, private_key: RsaPrivateKey, ttl_minutes: i64) {
+        let now = Utc::now();
+        let mut keys = self.keys.write().unwrap();
+
+        keys.retain(|_, key| key.expires_at > now);
+
+        // Another session may already hold the key for longer
+        let expires_at = keys.get(&user_id)
+            .map(|key| key.expires_at)
+            .unwrap_or(now)
+            .max(now + Duration::minutes(ttl_minutes));
+
+        keys.insert(user_id, UnlockedKey {
+            private_key,
+            expires_at,
+        });
+    }
+
+    pub fn get(&self, user_id: Uuid) -> Option<RsaPrivateKey> {
+        let keys = self.keys.read().unwrap();
+
+        keys.get(&user_id)
+            .filter(|key| key.expires_at > Utc::now())
+            .map(|key| key.private_key.clone())
+    }
+
+    /// Keeps an unlocked key for another `ttl_minutes`, as its session is refreshed.
+    pub fn extend(&self, user_id: Uuid, ttl_minutes: i64) {
+        if let Some(key) = self.keys.write().unwrap().get_mut(&user_id) {
+            key.expires_at = key.expires_at.max(Utc::now() + Duration::minutes(ttl_minutes));
+        }
+    }
+
+    pub fn remove(&self, user_id: Uuid) {
+        self.keys.write().unwrap().remove(&user_id);
+        self.held.write().unwrap().retain(|_, held| held.user_id != user_id);
+    }
+
+    /// Keeps a key unlocked by a password out of the cache until the second factor of the
+    /// login is checked; only `release` with the same MFA token makes it usable.
+    pub fn hold(&self, mfa_token: &str, user_id: Uuid, private_key: RsaPrivateKey, ttl_minutes: i64) {
+        let now = Utc::now();
+        let mut held = self.held.write().unwrap();
+
+        held.retain(|_, held| held.key.expires_at > now);
+
+        held.insert(token_hash(mfa_token), HeldKey {
+            user_id,
+            key: UnlockedKey {
+                private_key,
+                expires_at: now + Duration::minutes(ttl_minutes),
+            },
+        });
+    }
+
+    /// Moves the key held for `mfa_token` into the cache for `ttl_minutes`, once the second
+    /// factor has been checked.
+    pub fn release(&self, mfa_token: &str, user_id: Uuid, ttl_minutes: i64) {
+        let held = self.held.write().unwrap().remove(&token_hash(mfa_token));
+
+        if let Some(held) = held
+            && held.user_id == user_id
+            && held.key.expires_at > Utc::now()
+        {
+            self.insert(user_id, held.key.private_key, ttl_minutes);
+        }
+    }
+}
+
+impl fmt::Debug for KeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.keys.read().map(|keys| keys.len()).unwrap_or(0);
+        let held = self.held.read().map(|held| held.len()).unwrap_or(0);
+        write!(f, "KeyCache {{ unlocked: {}, held: {} }}", count, held)
+    }
+}
+
+// MFA tokens are bearer credentials, so the cache does not keep them as they are
+fn token_hash(mfa_token: &str) -> Vec<u8> {
+    Sha256::digest(mfa_token.as_bytes()).to_vec()
+}
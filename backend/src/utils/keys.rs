@@ -1,42 +1,389 @@
 use std::sync::Arc;
 
-use axum::{http::StatusCode, response::IntoResponse};
-use rand::rngs::OsRng;
-use rsa::{pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey}, RsaPrivateKey, RsaPublicKey};
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, Rng};
+use rsa::{pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPrivateKey, EncodeRsaPublicKey}, pkcs8::DecodePublicKey, traits::PublicKeyParts, Oaep, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use sha2::Sha256;
+use uuid::Uuid;
 
-use crate::{db::UserExt, error::HttpError, models::User, AppState};
+use crate::{db::{DBClient, UserExt}, error::HttpError, models::{AuditAction, LegacyWrappedKey, User}, utils::{audit::{self, AuditEntry}, cipher::KeyWrap, password}, AppState};
+
+/// Number of legacy rows the re-wrap job reads at a time.
+const REWRAP_BATCH_SIZE: i64 = 100;
+
+/// Smallest RSA modulus accepted for client-registered public keys.
+const MIN_RSA_KEY_BITS: usize = 2048;
+
+/// A secret encrypted with AES-256-GCM under an Argon2id key derived from a password.
+pub struct PasswordWrappedKey {
+    pub ciphertext: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
 
 pub async fn generate_key(
     app_state: &Arc<AppState>,
     user: &User,
-) -> Result<impl IntoResponse, HttpError> {
+    password: &str,
+) -> Result<RsaPrivateKey, HttpError> {
+    let (private_key, public_key_pem, wrapped_private_key) = new_key_pair(user.id, password)?;
+
+    app_state.db_client
+        .save_generated_keys(
+            user.id,
+            public_key_pem,
+            wrapped_private_key,
+        )
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let entry = AuditEntry::succeeded(AuditAction::KeysGenerated, Some(user.id))
+        .details(serde_json::json!({ "source": "server" }));
+    audit::record(app_state, None, entry).await;
+
+    Ok(private_key)
+}
 
+/// A new RSA key pair: the private key, the public key as PEM and the private key wrapped under
+/// `password`. Only the wrapped private key ever reaches the database.
+pub fn new_key_pair(user_id: Uuid, password: &str) -> Result<(RsaPrivateKey, String, PasswordWrappedKey), HttpError> {
     let mut rng = OsRng;
 
     let private_key = RsaPrivateKey::new(&mut rng, 2048)
-    .map_err(|e| {
-        HttpError::server_error(e.to_string())
-    })?;
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
 
     let public_key = RsaPublicKey::from(&private_key);
 
-    let private_key_pem = private_key.to_pkcs1_pem(rsa::pkcs1::LineEnding::LF)
-    .map_err(|e| HttpError::server_error(e.to_string()))?;
-
     let public_key_pem = public_key.to_pkcs1_pem(rsa::pkcs1::LineEnding::LF)
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
-    // Save both keys in the database
+    let wrapped_private_key = wrap_private_key(user_id, &private_key, password)?;
+
+    Ok((private_key, public_key_pem, wrapped_private_key))
+}
+
+/// Stands in for the password of accounts that have none, such as those provisioned through
+/// single sign-on. Their private key is wrapped under a secret derived from `KEY_WRAP_SECRET`,
+/// which keeps it encrypted at rest but lets the server unlock it without the user.
+pub fn server_key_passphrase(app_state: &Arc<AppState>, user_id: Uuid) -> Result<String, HttpError> {
+    let secret = app_state.env.key_wrap_secret
+        .as_ref()
+        .ok_or_else(|| HttpError::server_error("KEY_WRAP_SECRET is not set".to_string()))?;
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(user_id.as_bytes());
+
+    Ok(URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+}
+
+/// Encrypts the private key with AES-256-GCM under an Argon2id key derived from `password`.
+/// The user id is bound as associated data so wrapped keys cannot be swapped between rows.
+pub fn wrap_private_key(
+    user_id: Uuid,
+    private_key: &RsaPrivateKey,
+    password: &str,
+) -> Result<PasswordWrappedKey, HttpError> {
+    let der = private_key.to_pkcs1_der()
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    wrap_with_password(der.as_bytes(), password, user_id.as_bytes())
+        .map_err(|_| HttpError::server_error("Failed to wrap private key".to_string()))
+}
+
+pub fn unwrap_private_key(
+    user_id: Uuid,
+    wrapped: &PasswordWrappedKey,
+    password: &str,
+) -> Result<RsaPrivateKey, HttpError> {
+    let der = unwrap_with_password(wrapped, password, user_id.as_bytes())
+        .map_err(|_| HttpError::server_error("Unable to unlock private key".to_string()))?;
+
+    RsaPrivateKey::from_pkcs1_der(&der)
+        .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+/// Wraps a link share's file key under its share password, bound to the link id.
+pub fn wrap_link_key(
+    link_id: Uuid,
+    aes_key: &[u8],
+    password: &str,
+) -> Result<PasswordWrappedKey, HttpError> {
+    wrap_with_password(aes_key, password, link_id.as_bytes())
+        .map_err(|_| HttpError::server_error("Failed to wrap file key".to_string()))
+}
+
+pub fn unwrap_link_key(
+    link_id: Uuid,
+    wrapped: &PasswordWrappedKey,
+    password: &str,
+) -> Result<Vec<u8>, HttpError> {
+    unwrap_with_password(wrapped, password, link_id.as_bytes())
+        .map_err(|_| HttpError::server_error("Unable to decrypt file".to_string()))
+}
+
+fn wrap_with_password(secret: &[u8], password: &str, aad: &[u8]) -> Result<PasswordWrappedKey, HttpError> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill(&mut salt);
+    rand::thread_rng().fill(&mut nonce);
+
+    let kek = password::derive_key(password, &salt)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let ciphertext = Aes256Gcm::new(&kek.into())
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret, aad })
+        .map_err(|_| HttpError::server_error("Encryption failed".to_string()))?;
+
+    Ok(PasswordWrappedKey {
+        ciphertext,
+        salt: salt.to_vec(),
+        nonce: nonce.to_vec(),
+    })
+}
+
+fn unwrap_with_password(wrapped: &PasswordWrappedKey, password: &str, aad: &[u8]) -> Result<Vec<u8>, HttpError> {
+    if wrapped.nonce.len() != 12 {
+        return Err(HttpError::server_error("Stored key is malformed".to_string()));
+    }
+
+    let kek = password::derive_key(password, &wrapped.salt)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Aes256Gcm::new(&kek.into())
+        .decrypt(Nonce::from_slice(&wrapped.nonce), Payload { msg: &wrapped.ciphertext, aad })
+        .map_err(|_| HttpError::server_error("Decryption failed".to_string()))
+}
+
+/// Unlocks the user's private key with their login password. Legacy plaintext keys are
+/// wrapped on the way through and the plaintext copy is cleared from the database.
+pub async fn unlock_private_key(
+    db_client: &DBClient,
+    user: &User,
+    password: &str,
+) -> Result<Option<RsaPrivateKey>, HttpError> {
+    if let Some(wrapped) = user.wrapped_private_key() {
+        return unwrap_private_key(user.id, &wrapped, password).map(Some);
+    }
+
+    let Some(private_key_pem) = &user.private_key else {
+        return Ok(None);
+    };
+
+    let private_key = RsaPrivateKey::from_pkcs1_pem(private_key_pem)
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let wrapped = wrap_private_key(user.id, &private_key, password)?;
+
+    db_client
+        .save_wrapped_private_key(user.id, wrapped)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(Some(private_key))
+}
+
+/// Unlocks the key of an account without a password into the key cache when it signs in some
+/// other way. Accounts with a password only unlock their key when logging in with it.
+pub async fn unlock_passwordless_key(app_state: &Arc<AppState>, user: &User) -> Result<(), HttpError> {
+    if user.password.is_some() {
+        return Ok(());
+    }
+
+    let passphrase = server_key_passphrase(app_state, user.id)?;
+    if let Ok(Some(private_key)) = unlock_private_key(&app_state.db_client, user, &passphrase).await {
+        app_state.key_cache.insert(user.id, private_key, app_state.env.jwt_maxage);
+    }
+
+    Ok(())
+}
+
+/// What became of a user's key pair when their password was reset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyReset {
+    /// The private key was still unlocked and is now wrapped under the new password
+    Rewrapped,
+    /// The private key could not be recovered, so a new key pair replaced it
+    Regenerated,
+    /// The server holds no private key for this user
+    Unchanged,
+}
+
+/// Keeps a user's key pair usable after a password reset. The old password is gone, so the
+/// private key can only be re-wrapped while it is unlocked in memory, still stored in the clear
+/// or, for an account without a password, wrapped under the server passphrase; otherwise a new
+/// pair is generated and files sent under the old one cannot be opened.
+pub async fn reset_private_key(
+    app_state: &Arc<AppState>,
+    user: &User,
+    new_password: &str,
+) -> Result<KeyReset, HttpError> {
+    if user.wrapped_private_key().is_none() && user.private_key.is_none() {
+        return Ok(KeyReset::Unchanged);
+    }
+
+    let private_key = match app_state.key_cache.get(user.id) {
+        Some(private_key) => Some(private_key),
+        None => user.private_key
+            .as_deref()
+            .and_then(|private_key_pem| RsaPrivateKey::from_pkcs1_pem(private_key_pem).ok())
+            .or_else(|| unwrap_passwordless_key(app_state, user)),
+    };
+
+    let Some(private_key) = private_key else {
+        generate_key(app_state, user, new_password).await?;
+        return Ok(KeyReset::Regenerated);
+    };
+
+    let wrapped = wrap_private_key(user.id, &private_key, new_password)?;
+
     app_state.db_client
-        .save_user_keys(
-            user.id,
-            public_key_pem.clone(),
-            private_key_pem.to_string(),
-        )
+        .save_wrapped_private_key(user.id, wrapped)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(KeyReset::Rewrapped)
+}
+
+// Accounts without a password keep their key wrapped under the server-held passphrase
+fn unwrap_passwordless_key(app_state: &Arc<AppState>, user: &User) -> Option<RsaPrivateKey> {
+    if user.password.is_some() {
+        return None;
+    }
+
+    let passphrase = server_key_passphrase(app_state, user.id).ok()?;
+    unwrap_private_key(user.id, &user.wrapped_private_key()?, &passphrase).ok()
+}
+
+/// Parses a client-supplied RSA public key in either PKCS#1 (`RSA PUBLIC KEY`) or
+/// SPKI (`PUBLIC KEY`, as exported by WebCrypto) PEM form.
+pub fn parse_public_key(public_key_pem: &str) -> Result<RsaPublicKey, HttpError> {
+    let public_key = RsaPublicKey::from_pkcs1_pem(public_key_pem)
+        .or_else(|_| RsaPublicKey::from_public_key_pem(public_key_pem))
+        .map_err(|_| HttpError::bad_request("Public key must be an RSA key in PEM format".to_string()))?;
+
+    if public_key.size() * 8 < MIN_RSA_KEY_BITS {
+        return Err(HttpError::bad_request(format!("Public key must be at least {} bits", MIN_RSA_KEY_BITS)));
+    }
+
+    Ok(public_key)
+}
+
+pub fn wrap_aes_key(
+    aes_key: &[u8],
+    public_key: &RsaPublicKey,
+    key_wrap: KeyWrap,
+) -> Result<Vec<u8>, HttpError> {
+    let mut rng = rand::thread_rng();
+
+    let wrapped = match key_wrap {
+        KeyWrap::RsaOaepSha256 => public_key.encrypt(&mut rng, Oaep::new::<Sha256>(), aes_key),
+        KeyWrap::RsaPkcs1v15 => public_key.encrypt(&mut rng, Pkcs1v15Encrypt, aes_key),
+    };
+
+    wrapped.map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+pub fn unwrap_aes_key(
+    encrypted_aes_key: &[u8],
+    private_key: &RsaPrivateKey,
+    key_wrap: KeyWrap,
+) -> Result<Vec<u8>, HttpError> {
+    let unwrapped = match key_wrap {
+        KeyWrap::RsaOaepSha256 => private_key.decrypt(Oaep::new::<Sha256>(), encrypted_aes_key),
+        KeyWrap::RsaPkcs1v15 => private_key.decrypt(Pkcs1v15Encrypt, encrypted_aes_key),
+    };
+
+    // Keep the error opaque so the response cannot act as a padding oracle
+    unwrapped.map_err(|_| HttpError::server_error("Unable to decrypt file".to_string()))
+}
+
+/// Re-wraps AES keys still stored with PKCS#1 v1.5 padding using RSA-OAEP. Only recipients
+/// whose legacy private key is still stored in the clear can be handled here; everyone else
+/// is upgraded by `rewrap_user_legacy_keys` when they next log in.
+pub async fn rewrap_legacy_keys(db_client: &DBClient) -> Result<usize, HttpError> {
+    let mut upgraded = 0;
+
+    // Pages by key rather than re-reading the first batch: rows that cannot be re-wrapped stay
+    // legacy, and would otherwise fill every batch once there are enough of them
+    let mut after = (Uuid::nil(), Uuid::nil());
+
+    loop {
+        let legacy_keys = db_client
+            .get_legacy_wrapped_keys(after, REWRAP_BATCH_SIZE)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        let Some(last) = legacy_keys.last() else {
+            break;
+        };
+        after = (last.file_id, last.recipient_user_id);
+
+        for legacy in &legacy_keys {
+            let Some(private_key_pem) = &legacy.private_key else {
+                continue;
+            };
+
+            let private_key = match RsaPrivateKey::from_pkcs1_pem(private_key_pem) {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::error!("Skipping key re-wrap for file {}: {}", legacy.file_id, e);
+                    continue;
+                }
+            };
+
+            if rewrap_file_key(db_client, legacy, &private_key).await? {
+                upgraded += 1;
+            }
+        }
+    }
+
+    Ok(upgraded)
+}
+
+/// Re-wraps every legacy AES key addressed to `user_id` with their freshly unlocked private key.
+pub async fn rewrap_user_legacy_keys(
+    db_client: &DBClient,
+    user_id: Uuid,
+    private_key: &RsaPrivateKey,
+) -> Result<usize, HttpError> {
+    let legacy_keys = db_client
+        .get_user_legacy_wrapped_keys(user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let mut upgraded = 0;
+
+    for legacy in legacy_keys {
+        if rewrap_file_key(db_client, &legacy, private_key).await? {
+            upgraded += 1;
+        }
+    }
+
+    Ok(upgraded)
+}
+
+async fn rewrap_file_key(
+    db_client: &DBClient,
+    legacy: &LegacyWrappedKey,
+    private_key: &RsaPrivateKey,
+) -> Result<bool, HttpError> {
+    let aes_key = match unwrap_aes_key(&legacy.encrypted_aes_key, private_key, KeyWrap::RsaPkcs1v15) {
+        Ok(key) => key,
+        Err(_) => {
+            tracing::error!("Skipping key re-wrap for file {}: unwrap failed", legacy.file_id);
+            return Ok(false);
+        }
+    };
+
+    let public_key = RsaPublicKey::from(private_key);
+    let encrypted_aes_key = wrap_aes_key(&aes_key, &public_key, KeyWrap::RsaOaepSha256)?;
+
+    db_client
+        .update_file_key_wrap(legacy.file_id, legacy.recipient_user_id, encrypted_aes_key, KeyWrap::RsaOaepSha256)
         .await
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
-    // Remove filesystem code - we're not storing keys on disk anymore
-    
-    Ok((StatusCode::OK, "true"))
-}
\ No newline at end of file
+    Ok(true)
+}
@@ -1,5 +1,15 @@
 pub mod password;
 pub mod token;
 pub mod keys;
+pub mod key_cache;
+pub mod cipher;
 pub mod encrypt;
-pub mod decrypt;
\ No newline at end of file
+pub mod decrypt;
+pub mod range;
+pub mod attempts;pub mod session;
+pub mod totp;
+pub mod oidc;
+pub mod jwt_keys;
+pub mod webauthn;
+pub mod audit;
+pub mod events;
@@ -0,0 +1,259 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}, time::Duration};
+
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::{Jwk, JwkSet}, Algorithm, DecodingKey, Validation};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{config::OidcProviderConfig, error::HttpError};
+
+/// How long a request to an identity provider may take.
+const PROVIDER_TIMEOUT_SECONDS: u64 = 10;
+
+/// The parts of a provider's discovery document the login flow uses.
+#[derive(Debug, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims of an ID token whose signature, issuer, audience, expiry and nonce were checked.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    // A boolean, though some providers send it as a string
+    email_verified: Option<serde_json::Value>,
+    pub name: Option<String>,
+}
+
+impl IdTokenClaims {
+    pub fn email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        }
+    }
+}
+
+/// Talks to the configured OpenID Connect providers, caching their discovery documents and
+/// signing keys by provider name.
+#[derive(Debug, Clone)]
+pub struct OidcClient {
+    http: reqwest::Client,
+    metadata: Arc<RwLock<HashMap<String, Arc<ProviderMetadata>>>>,
+    jwks: Arc<RwLock<HashMap<String, Arc<JwkSet>>>>,
+}
+
+impl Default for OidcClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OidcClient {
+    pub fn new() -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(PROVIDER_TIMEOUT_SECONDS))
+            .build()
+            .expect("Failed to build the HTTP client");
+
+        OidcClient {
+            http,
+            metadata: Arc::default(),
+            jwks: Arc::default(),
+        }
+    }
+
+    async fn metadata(&self, provider: &OidcProviderConfig) -> Result<Arc<ProviderMetadata>, HttpError> {
+        if let Some(metadata) = self.metadata.read().unwrap().get(&provider.name) {
+            return Ok(metadata.clone());
+        }
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_end_matches('/'));
+        let metadata: ProviderMetadata = self.get_json(&discovery_url).await?;
+
+        if metadata.issuer != provider.issuer {
+            return Err(provider_error(format!("discovery document is for issuer {}", metadata.issuer)));
+        }
+
+        let metadata = Arc::new(metadata);
+        self.metadata.write().unwrap().insert(provider.name.clone(), metadata.clone());
+
+        Ok(metadata)
+    }
+
+    /// Where to send the browser to sign in, for the authorization-code flow with PKCE (S256).
+    pub async fn authorization_url(
+        &self,
+        provider: &OidcProviderConfig,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, HttpError> {
+        let metadata = self.metadata(provider).await?;
+
+        let mut url = Url::parse(&metadata.authorization_endpoint).map_err(provider_error)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &provider.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.into())
+    }
+
+    /// Redeems an authorization code and returns the verified claims of the ID token it buys.
+    pub async fn exchange_code(
+        &self,
+        provider: &OidcProviderConfig,
+        redirect_uri: &str,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, HttpError> {
+        let metadata = self.metadata(provider).await?;
+
+        let mut request = self.http
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", &provider.client_id),
+                ("code_verifier", code_verifier),
+            ]);
+
+        // Confidential clients authenticate with client_secret_basic; public clients rely on PKCE
+        if let Some(client_secret) = &provider.client_secret {
+            request = request.basic_auth(form_encode(&provider.client_id), Some(form_encode(client_secret)));
+        }
+
+        let response = request.send().await.map_err(provider_error)?;
+
+        if !response.status().is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(provider_error(format!("token request failed: {}", body)));
+        }
+
+        let tokens: TokenResponse = response.json().await.map_err(provider_error)?;
+
+        self.verify_id_token(provider, &metadata, &tokens.id_token, nonce).await
+    }
+
+    async fn verify_id_token(
+        &self,
+        provider: &OidcProviderConfig,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, HttpError> {
+        let header = decode_header(id_token).map_err(|_| invalid_id_token())?;
+
+        // A symmetric key would have to be the client secret, which this client never verifies with
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(invalid_id_token());
+        }
+
+        let jwk = self.signing_key(provider, metadata, header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid_id_token())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|_| invalid_id_token())?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid_id_token());
+        }
+
+        Ok(claims)
+    }
+
+    async fn signing_key(
+        &self,
+        provider: &OidcProviderConfig,
+        metadata: &ProviderMetadata,
+        kid: Option<&str>,
+    ) -> Result<Jwk, HttpError> {
+        let cached = self.jwks.read().unwrap().get(&provider.name).cloned();
+
+        if let Some(jwk) = cached.as_deref().and_then(|jwks| find_key(jwks, kid)) {
+            return Ok(jwk.clone());
+        }
+
+        // Unknown keys are looked up again in case the provider has rotated them
+        let jwks: Arc<JwkSet> = Arc::new(self.get_json(&metadata.jwks_uri).await?);
+        self.jwks.write().unwrap().insert(provider.name.clone(), jwks.clone());
+
+        find_key(&jwks, kid).cloned().ok_or_else(invalid_id_token)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, HttpError> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)
+    }
+}
+
+fn find_key<'a>(jwks: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        // Without a key id the choice is only unambiguous if the provider publishes one key
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+}
+
+/// A random value for `state`, `nonce` or a PKCE code verifier.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_state(state: &str) -> Vec<u8> {
+    Sha256::digest(state.as_bytes()).to_vec()
+}
+
+// RFC 6749 §2.3.1: credentials are form-encoded before they go into the Basic header
+fn form_encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+fn provider_error(error: impl ToString) -> HttpError {
+    HttpError::new(format!("Identity provider error: {}", error.to_string()), StatusCode::BAD_GATEWAY)
+}
+
+fn invalid_id_token() -> HttpError {
+    HttpError::unauthorized("The identity provider returned an invalid ID token".to_string())
+}
@@ -47,7 +47,25 @@ pub fn compare(password: &str, hashed_password: &str) -> Result<bool, ErrorMessa
 
     let password_matched = Argon2::default()
         .verify_password(password.as_bytes(), &parsed_hash)
-        .map_or(false, |_| true);
+        .is_ok();
 
     Ok(password_matched)
-}
\ No newline at end of file
+}
+
+/// Derives a 256-bit key-encryption key from the user's password with Argon2id.
+pub fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], ErrorMessage> {
+    if password.is_empty() {
+        return Err(ErrorMessage::EmptyPassword);
+    }
+
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return Err(ErrorMessage::ExceededMaxPasswordLength(MAX_PASSWORD_LENGTH));
+    }
+
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|_| ErrorMessage::HashingError)?;
+
+    Ok(key)
+}
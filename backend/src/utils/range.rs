@@ -0,0 +1,219 @@
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{future, Stream, StreamExt};
+
+/// An inclusive byte range of a representation, as sent in `Content-Range`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+// Both ends are included, so a range is never empty
+#[allow(clippy::len_without_is_empty)]
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    Full,
+    Partial(ByteRange),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against a representation of `size` bytes. Only a single
+/// `bytes` range is supported; anything else is served in full, which RFC 9110 allows.
+pub fn parse_range(range: &str, size: u64) -> RangeRequest {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        // bytes=-N: the last N bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(suffix) if suffix > 0 && size > 0 => {
+                Some(ByteRange { start: size.saturating_sub(suffix), end: size - 1 })
+            }
+            Ok(_) => None,
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+
+            let end = match end {
+                "" => size.saturating_sub(1),
+                end => match end.parse::<u64>() {
+                    Ok(end) => end.min(size.saturating_sub(1)),
+                    Err(_) => return RangeRequest::Full,
+                },
+            };
+
+            (start < size && start <= end).then_some(ByteRange { start, end })
+        }
+    };
+
+    match range {
+        Some(range) => RangeRequest::Partial(range),
+        None => RangeRequest::Unsatisfiable,
+    }
+}
+
+/// Weak comparison of an entity tag against an `If-None-Match` list.
+pub fn none_match(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// `If-Range` holds either a strong entity tag or an HTTP date; the range is only honoured
+/// if the representation is unchanged.
+pub fn if_range_matches(if_range: &str, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+
+    match (DateTime::parse_from_rfc2822(if_range), last_modified) {
+        (Ok(date), Some(last_modified)) => date.timestamp() == last_modified.timestamp(),
+        _ => false,
+    }
+}
+
+/// Formats a timestamp as an HTTP date (IMF-fixdate).
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Drops the first `skip` bytes of a stream and ends it after `take` more.
+pub fn slice_stream<S, E>(stream: S, skip: u64, take: u64) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    stream.scan((skip, take), |(skip, take), chunk| {
+        if *take == 0 {
+            return future::ready(None);
+        }
+
+        let chunk = chunk.map(|mut chunk| {
+            let start = (*skip).min(chunk.len() as u64);
+            *skip -= start;
+            chunk = chunk.slice(start as usize..);
+
+            let end = (*take).min(chunk.len() as u64);
+            *take -= end;
+            chunk.slice(..end as usize)
+        });
+
+        future::ready(Some(chunk))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use futures_util::stream;
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), partial(0, 99));
+        assert_eq!(parse_range("bytes=100-", 1000), partial(100, 999));
+        assert_eq!(parse_range("bytes=900-5000", 1000), partial(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), partial(900, 999));
+    }
+
+    #[test]
+    fn a_suffix_longer_than_the_file_is_the_whole_file() {
+        assert_eq!(parse_range("bytes=-5000", 1000), partial(0, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn a_start_at_or_past_the_end_is_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1001", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=20-10", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn multiple_or_malformed_ranges_are_served_in_full() {
+        for header in ["bytes=0-1,5-9", "items=0-9", "bytes=5", "bytes=a-9", "bytes=0-z", "bytes=-z", "bytes=--5", ""] {
+            assert_eq!(parse_range(header, 1000), RangeRequest::Full, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = "\"abc\"";
+
+        assert!(none_match("\"abc\"", etag));
+        assert!(none_match("W/\"abc\"", etag));
+        assert!(none_match("\"xyz\", W/\"abc\"", etag));
+        assert!(none_match("*", etag));
+        assert!(!none_match("\"xyz\"", etag));
+    }
+
+    #[test]
+    fn if_range_needs_the_strong_entity_tag() {
+        let etag = "\"abc\"";
+
+        assert!(if_range_matches("\"abc\"", etag, None));
+        assert!(!if_range_matches("W/\"abc\"", etag, None));
+        assert!(!if_range_matches("\"xyz\"", etag, None));
+    }
+
+    #[test]
+    fn if_range_needs_the_exact_date() {
+        let created_at = Utc.with_ymd_and_hms(2026, 3, 1, 12, 30, 0).unwrap();
+
+        assert!(if_range_matches(&http_date(created_at), "\"abc\"", Some(created_at)));
+        assert!(!if_range_matches(&http_date(created_at - Duration::seconds(1)), "\"abc\"", Some(created_at)));
+        assert!(!if_range_matches(&http_date(created_at + Duration::days(1)), "\"abc\"", Some(created_at)));
+        assert!(!if_range_matches(&http_date(created_at), "\"abc\"", None));
+        assert!(!if_range_matches("yesterday", "\"abc\"", Some(created_at)));
+    }
+
+    async fn slice(chunks: &[&[u8]], skip: u64, take: u64) -> Vec<u8> {
+        let chunks = chunks.iter().map(|chunk| Ok::<_, ()>(Bytes::copy_from_slice(chunk)));
+        let sliced: Vec<_> = slice_stream(stream::iter(chunks), skip, take).collect().await;
+
+        sliced.into_iter().flat_map(|chunk| chunk.unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn slices_inside_and_across_chunks() {
+        // Chunks the size of decrypted segments, with the range starting partway into one
+        let chunks: &[&[u8]] = &[b"0123456789", b"abcdefghij", b"ABCDE"];
+
+        assert_eq!(slice(chunks, 3, 4).await, b"3456");
+        assert_eq!(slice(chunks, 7, 6).await, b"789abc");
+        assert_eq!(slice(chunks, 10, 10).await, b"abcdefghij");
+        assert_eq!(slice(chunks, 12, 100).await, b"cdefghijABCDE");
+        assert_eq!(slice(chunks, 0, 25).await, b"0123456789abcdefghijABCDE");
+        assert!(slice(chunks, 5, 0).await.is_empty());
+    }
+}
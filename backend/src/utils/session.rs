@@ -0,0 +1,162 @@
+use std::{net::{IpAddr, SocketAddr}, sync::Arc};
+
+use axum::{async_trait, extract::{ConnectInfo, FromRequestParts}, http::{header, request::Parts, HeaderMap, StatusCode}};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::{config::TrustedProxy, db::UserExt, error::{ErrorMessage, HttpError}, utils::token, AppState};
+
+/// Device details recorded against a session when it is started or refreshed.
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts.headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(512).collect());
+
+        let peer_ip = parts.extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        let trusted_proxies = parts.extensions
+            .get::<Arc<AppState>>()
+            .map(|app_state| app_state.env.trusted_proxies.as_slice())
+            .unwrap_or_default();
+
+        // Anyone can send X-Forwarded-For, so it only counts when a trusted proxy passed it on
+        let ip_address = match peer_ip {
+            Some(peer_ip) if is_trusted(trusted_proxies, peer_ip) => {
+                Some(forwarded_client(&parts.headers, trusted_proxies).unwrap_or(peer_ip))
+            }
+            peer_ip => peer_ip,
+        };
+
+        Ok(ClientInfo {
+            user_agent,
+            ip_address: ip_address.map(|ip| ip.to_string()),
+        })
+    }
+}
+
+fn is_trusted(trusted_proxies: &[TrustedProxy], ip: IpAddr) -> bool {
+    trusted_proxies.iter().any(|proxy| proxy.contains(ip))
+}
+
+/// Each proxy appends the address it received the request from, so the client is the last hop
+/// that was not added by a trusted proxy. Earlier hops could have been made up by the client.
+fn forwarded_client(headers: &HeaderMap, trusted_proxies: &[TrustedProxy]) -> Option<IpAddr> {
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+
+    let mut client = None;
+
+    for hop in hops.into_iter().rev() {
+        // An address that cannot be parsed cannot be trusted either, so the walk ends here
+        let hop = hop?;
+        client = Some(hop);
+
+        if !is_trusted(trusted_proxies, hop) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// The tokens handed out when a session starts or is refreshed.
+pub struct SessionTokens {
+    pub user_id: Uuid,
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+fn access_token(app_state: &Arc<AppState>, user_id: Uuid, session_id: Uuid) -> Result<String, HttpError> {
+    token::create_token(
+        &user_id.to_string(),
+        session_id,
+        &app_state.jwt_keys,
+        app_state.env.jwt_maxage,
+    )
+    .map_err(|e| HttpError::server_error(e.to_string()))
+}
+
+/// Starts a session for a user who has just proven who they are, however they signed in.
+/// Disabled accounts get no new sessions.
+pub async fn start_session(
+    app_state: &Arc<AppState>,
+    user_id: Uuid,
+    client: &ClientInfo,
+) -> Result<SessionTokens, HttpError> {
+    let disabled = app_state.db_client
+        .get_user(Some(user_id), None, None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .is_some_and(|user| user.disabled_at.is_some());
+
+    if disabled {
+        return Err(HttpError::new(ErrorMessage::AccountDisabled.to_string(), StatusCode::FORBIDDEN));
+    }
+
+    let (refresh_token, refresh_token_hash) = token::generate_refresh_token();
+    let expires_at = Utc::now() + Duration::days(app_state.env.refresh_token_maxage_days);
+
+    let session = app_state.db_client
+        .create_session(user_id, refresh_token_hash, client.user_agent.clone(), client.ip_address.clone(), expires_at)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(SessionTokens {
+        user_id,
+        access_token: access_token(app_state, user_id, session.id)?,
+        refresh_token,
+    })
+}
+
+/// Exchanges a refresh token for a new access token and a new refresh token. Each refresh
+/// token works once; presenting one that was already exchanged means it has leaked, so the
+/// whole session is revoked.
+pub async fn refresh_session(
+    app_state: &Arc<AppState>,
+    refresh_token: &str,
+    client: &ClientInfo,
+) -> Result<SessionTokens, HttpError> {
+    let refresh_token_hash = token::hash_refresh_token(refresh_token);
+    let (new_refresh_token, new_refresh_token_hash) = token::generate_refresh_token();
+
+    let session = app_state.db_client
+        .rotate_refresh_token(refresh_token_hash.clone(), new_refresh_token_hash, client.user_agent.clone(), client.ip_address.clone())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let Some(session) = session else {
+        let reused = app_state.db_client
+            .revoke_reused_session(refresh_token_hash)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if reused {
+            tracing::warn!("Revoked a session after its refresh token was reused");
+        }
+
+        return Err(HttpError::unauthorized(ErrorMessage::InvalidRefreshToken.to_string()));
+    };
+
+    Ok(SessionTokens {
+        user_id: session.user_id,
+        access_token: access_token(app_state, session.user_id, session.id)?,
+        refresh_token: new_refresh_token,
+    })
+}
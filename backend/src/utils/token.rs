@@ -1,20 +1,26 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 
-use crate::error::{ErrorMessage, HttpError};
+use crate::{error::{ErrorMessage, HttpError}, utils::jwt_keys::JwtKeys};
 
 #[derive(Debug, Serialize, Deserialize,)]
 pub struct TokenClaims {
     pub sub: String,
+    pub sid: Uuid,
+    pub jti: Uuid,
     pub iat: usize,
     pub exp: usize,
 }
 
 pub fn create_token (
     user_id: &str,
-    secret: &[u8],
-    expires_in_seconds: i64,
+    session_id: Uuid,
+    keys: &JwtKeys,
+    expires_in_minutes: i64,
 ) -> Result<String, jsonwebtoken::errors::Error> {
     if user_id.is_empty() {
         return Err(jsonwebtoken::errors::ErrorKind::InvalidSubject.into());
@@ -22,32 +28,127 @@ pub fn create_token (
 
     let now = Utc::now();
     let iat = now.timestamp() as usize;
-    let exp = (now + Duration::minutes(expires_in_seconds)).timestamp() as usize;
+    let exp = (now + Duration::minutes(expires_in_minutes)).timestamp() as usize;
     let claims = TokenClaims {
         sub: user_id.to_string(),
+        sid: session_id,
+        jti: Uuid::new_v4(),
         iat,
         exp,
     };
 
-    encode(
-        &Header::default(), 
-        &claims, 
-        &EncodingKey::from_secret(secret),
-    )
+    keys.encode(&claims)
 }
 
 pub fn decode_token<T: Into<String>>(
     token: T,
-    secret: &[u8],
-) -> Result<String, HttpError> {
-    let decode = decode::<TokenClaims>(
-        &token.into(), 
-        &DecodingKey::from_secret(secret), 
-        &Validation::new(jsonwebtoken::Algorithm::HS256),
-    );
-
-    match decode {
-        Ok(token) => Ok(token.claims.sub),
-        Err(_) => Err(HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))
+    keys: &JwtKeys,
+) -> Result<TokenClaims, HttpError> {
+    keys.decode::<TokenClaims>(&token.into())
+}
+
+/// What a single-purpose token was issued for; it is accepted for nothing else, and never
+/// as an access token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenPurpose {
+    /// The password step of a login passed and a second factor is still required
+    Mfa,
+    EmailVerification,
+    PasswordReset,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::Mfa => "mfa",
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::PasswordReset => "password_reset",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize,)]
+pub struct PurposeTokenClaims {
+    pub sub: String,
+    pub purpose: String,
+    // Fingerprint of state the token is only valid against, such as the password a reset replaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bnd: Option<String>,
+    pub iat: usize,
+    pub exp: usize,
+}
+
+pub fn create_purpose_token(
+    user_id: &str,
+    purpose: TokenPurpose,
+    binding: Option<&str>,
+    keys: &JwtKeys,
+    expires_in_minutes: i64,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now();
+    let claims = PurposeTokenClaims {
+        sub: user_id.to_string(),
+        purpose: purpose.as_str().to_string(),
+        bnd: binding.map(token_binding),
+        iat: now.timestamp() as usize,
+        exp: (now + Duration::minutes(expires_in_minutes)).timestamp() as usize,
+    };
+
+    keys.encode(&claims)
+}
+
+impl PurposeTokenClaims {
+    /// Whether the state the token was bound to at issue is still `value`.
+    pub fn is_bound_to(&self, value: &str) -> bool {
+        self.bnd.as_deref() == Some(token_binding(value).as_str())
     }
-}
\ No newline at end of file
+}
+
+/// Decodes a single-purpose token, checking it was issued for `purpose`.
+pub fn decode_purpose_token(
+    token: &str,
+    purpose: TokenPurpose,
+    keys: &JwtKeys,
+) -> Result<PurposeTokenClaims, HttpError> {
+    match keys.decode::<PurposeTokenClaims>(token) {
+        Ok(claims) if claims.purpose == purpose.as_str() => Ok(claims),
+        _ => Err(HttpError::unauthorized(ErrorMessage::InvalidToken.to_string()))
+    }
+}
+
+fn token_binding(value: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(value.as_bytes()))
+}
+
+/// A new opaque refresh token, returned with the hash that is stored in its place.
+pub fn generate_refresh_token() -> (String, Vec<u8>) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+
+    let refresh_token = URL_SAFE_NO_PAD.encode(bytes);
+    let hash = hash_refresh_token(&refresh_token);
+
+    (refresh_token, hash)
+}
+
+pub fn hash_refresh_token(refresh_token: &str) -> Vec<u8> {
+    Sha256::digest(refresh_token.as_bytes()).to_vec()
+}
+
+/// Prefix of personal access tokens, so `auth` can tell them apart from JWTs at a glance.
+pub const PERSONAL_ACCESS_TOKEN_PREFIX: &str = "aerofy_pat_";
+
+/// A new personal access token, returned with the hash that is stored in its place.
+pub fn generate_personal_access_token() -> (String, Vec<u8>) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+
+    let token = format!("{}{}", PERSONAL_ACCESS_TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+    let hash = hash_personal_access_token(&token);
+
+    (token, hash)
+}
+
+pub fn hash_personal_access_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
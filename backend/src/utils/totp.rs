@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use uuid::Uuid;
+
+use crate::{db::UserExt, error::HttpError, utils::{attempts::{check_backoff, too_many_attempts}, password}, AppState};
+
+/// Name shown next to the account in authenticator apps.
+const ISSUER: &str = "Aerofy";
+
+const SECRET_BYTES: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+
+/// Codes one step either side of now are accepted to allow for clock drift.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    rand::thread_rng().fill(&mut secret[..]);
+    secret
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// The `otpauth://` URI authenticator apps scan from a QR code.
+pub fn otpauth_uri(email: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(ISSUER),
+        percent_encode(email),
+        encode_secret(secret),
+        percent_encode(ISSUER),
+        DIGITS,
+        STEP_SECONDS,
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// RFC 4226 HOTP value for one counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Checks an RFC 6238 code and returns the time step it belongs to. Steps at or before
+/// `last_used_step` are refused so a code cannot be replayed.
+pub fn verify_code(secret: &[u8], code: &str, last_used_step: Option<i64>) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let code: u32 = code.parse().ok()?;
+    let current_step = Utc::now().timestamp() / STEP_SECONDS;
+
+    (current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| hotp(secret, *step as u64) == code)
+}
+
+/// Fresh recovery codes, returned with the hashes that are stored in their place.
+pub fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>), HttpError> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let raw: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .map(|c| (c as char).to_ascii_lowercase())
+            .collect();
+        let code = format!("{}-{}", &raw[..5], &raw[5..]);
+
+        hashes.push(password::hash(&code).map_err(|e| HttpError::server_error(e.to_string()))?);
+        codes.push(code);
+    }
+
+    Ok((codes, hashes))
+}
+
+fn invalid_code() -> HttpError {
+    HttpError::unauthorized("Invalid authentication code".to_string())
+}
+
+/// Checks a second factor: a TOTP code or an unused recovery code. Attempts are counted
+/// and backed off like share passwords, so codes cannot be guessed within their window.
+pub async fn verify_second_factor(
+    app_state: &Arc<AppState>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<(), HttpError> {
+    let totp = app_state.db_client
+        .get_user_totp(user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .filter(|totp| totp.enabled_at.is_some())
+        .ok_or_else(|| HttpError::bad_request("Two-factor authentication is not enabled".to_string()))?;
+
+    let base_seconds = app_state.env.password_backoff_seconds;
+
+    check_backoff(&totp.attempts(), base_seconds)?;
+
+    let claimed = app_state.db_client
+        .claim_totp_attempt(user_id, totp.failed_attempts)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !claimed {
+        return Err(too_many_attempts(base_seconds));
+    }
+
+    let code = code.trim();
+
+    if let Some(step) = verify_code(&totp.secret, code, totp.last_used_step) {
+        // A concurrent login may have used the same code first
+        let recorded = app_state.db_client
+            .record_totp_success(user_id, Some(step))
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        return if recorded { Ok(()) } else { Err(invalid_code()) };
+    }
+
+    let recovery_codes = app_state.db_client
+        .get_unused_recovery_codes(user_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let recovery_code = code.to_ascii_lowercase();
+
+    for stored in recovery_codes {
+        let matched = password::compare(&recovery_code, &stored.code_hash)
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if !matched {
+            continue;
+        }
+
+        let used = app_state.db_client
+            .use_recovery_code(stored.id)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        if !used {
+            break;
+        }
+
+        app_state.db_client
+            .record_totp_success(user_id, None)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        return Ok(());
+    }
+
+    Err(invalid_code())
+}
@@ -0,0 +1,314 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use rand::Rng;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, error::HttpError, models::WebauthnCredential};
+
+/// How long the browser has to answer a WebAuthn challenge.
+pub const CHALLENGE_MAXAGE_MINUTES: i64 = 5;
+
+// COSE algorithm identifiers, in the order new credentials should prefer them
+const COSE_ALG_EDDSA: i32 = -8;
+const COSE_ALG_ES256: i32 = -7;
+const COSE_ALG_RS256: i32 = -257;
+
+const SUPPORTED_ALGORITHMS: [i32; 3] = [COSE_ALG_EDDSA, COSE_ALG_ES256, COSE_ALG_RS256];
+
+// Flags of the authenticator data
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_BACKUP_ELIGIBLE: u8 = 0x08;
+const FLAG_BACKED_UP: u8 = 0x10;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// RP ID hash, flags and signature counter
+const AUTHENTICATOR_DATA_MIN_LEN: usize = 37;
+
+const AAGUID_LEN: usize = 16;
+
+/// A new credential from a registration whose client data and authenticator data were checked.
+pub struct VerifiedCredential {
+    pub credential_id: Vec<u8>,
+    // The COSE_Key exactly as the authenticator encoded it
+    pub public_key: Vec<u8>,
+    pub algorithm: i32,
+    pub sign_count: i64,
+    pub backup_eligible: bool,
+    pub backed_up: bool,
+}
+
+/// What a verified assertion changes about the credential it was made with.
+pub struct VerifiedAssertion {
+    pub sign_count: i64,
+    pub backed_up: bool,
+}
+
+#[derive(Deserialize)]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    #[serde(rename = "crossOrigin", default)]
+    cross_origin: bool,
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    // Attested credential data and extensions, if any
+    rest: &'a [u8],
+}
+
+enum PublicKey {
+    Ed25519(Vec<u8>),
+    // Uncompressed P-256 point
+    P256(Vec<u8>),
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+}
+
+/// A random challenge for a ceremony, base64url-encoded as it appears in the client data.
+pub fn random_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_challenge(challenge: &str) -> Vec<u8> {
+    Sha256::digest(challenge.as_bytes()).to_vec()
+}
+
+/// Options for `navigator.credentials.create()`, in the JSON form browsers parse with
+/// `PublicKeyCredential.parseCreationOptionsFromJSON`. Passkeys have to be discoverable and
+/// verify the user, since they stand in for both the username and the password.
+pub fn creation_options(
+    config: &Config,
+    challenge: &str,
+    user_handle: &[u8],
+    user_name: &str,
+    display_name: &str,
+    exclude_credentials: &[WebauthnCredential],
+) -> serde_json::Value {
+    serde_json::json!({
+        "publicKey": {
+            "rp": { "id": config.webauthn_rp_id, "name": config.webauthn_rp_name },
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(user_handle),
+                "name": user_name,
+                "displayName": display_name,
+            },
+            "challenge": challenge,
+            "pubKeyCredParams": SUPPORTED_ALGORITHMS
+                .iter()
+                .map(|algorithm| serde_json::json!({ "type": "public-key", "alg": algorithm }))
+                .collect::<Vec<_>>(),
+            "timeout": CHALLENGE_MAXAGE_MINUTES * 60 * 1000,
+            "attestation": "none",
+            "authenticatorSelection": {
+                "residentKey": "required",
+                "requireResidentKey": true,
+                "userVerification": "required",
+            },
+            "excludeCredentials": credential_descriptors(exclude_credentials),
+        }
+    })
+}
+
+/// Options for `navigator.credentials.get()`. Without `allow_credentials` the browser offers
+/// every passkey it has for this site.
+pub fn request_options(config: &Config, challenge: &str, allow_credentials: &[WebauthnCredential]) -> serde_json::Value {
+    serde_json::json!({
+        "publicKey": {
+            "rpId": config.webauthn_rp_id,
+            "challenge": challenge,
+            "timeout": CHALLENGE_MAXAGE_MINUTES * 60 * 1000,
+            "userVerification": "required",
+            "allowCredentials": credential_descriptors(allow_credentials),
+        }
+    })
+}
+
+fn credential_descriptors(credentials: &[WebauthnCredential]) -> Vec<serde_json::Value> {
+    credentials
+        .iter()
+        .map(|credential| serde_json::json!({
+            "type": "public-key",
+            "id": URL_SAFE_NO_PAD.encode(&credential.credential_id),
+            "transports": credential.transports,
+        }))
+        .collect()
+}
+
+/// Checks the client data of a `webauthn.create` or `webauthn.get` ceremony came from one of
+/// `WEBAUTHN_ORIGINS`, and returns the challenge it answers.
+pub fn client_data_challenge(config: &Config, client_data_json: &[u8], ceremony: &str) -> Result<String, HttpError> {
+    let client_data: CollectedClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| invalid_response("client data is not valid JSON"))?;
+
+    if client_data.kind != ceremony {
+        return Err(invalid_response("client data is for another ceremony"));
+    }
+
+    if client_data.cross_origin || !config.webauthn_origins.contains(&client_data.origin) {
+        return Err(invalid_response("client data is from another origin"));
+    }
+
+    Ok(client_data.challenge)
+}
+
+/// Verifies the attestation object of a registration and extracts the new credential. The
+/// attestation statement itself is not checked, as the options ask for none: any authenticator
+/// that verifies its user may be registered.
+pub fn verify_registration(config: &Config, attestation_object: &[u8]) -> Result<VerifiedCredential, HttpError> {
+    let attestation: Value = ciborium::from_reader(attestation_object)
+        .map_err(|_| invalid_response("attestation object is not valid CBOR"))?;
+
+    let auth_data = attestation
+        .as_map()
+        .and_then(|entries| entries.iter().find(|(key, _)| key.as_text() == Some("authData")))
+        .and_then(|(_, value)| value.as_bytes())
+        .ok_or_else(|| invalid_response("attestation object has no authenticator data"))?;
+
+    let auth_data = parse_authenticator_data(config, auth_data)?;
+
+    if auth_data.flags & FLAG_ATTESTED_CREDENTIAL == 0 {
+        return Err(invalid_response("authenticator data has no credential"));
+    }
+
+    let rest = auth_data.rest
+        .get(AAGUID_LEN..)
+        .filter(|rest| rest.len() >= 2)
+        .ok_or_else(|| invalid_response("attested credential data is truncated"))?;
+
+    let credential_id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    let credential_id = rest
+        .get(2..2 + credential_id_len)
+        .ok_or_else(|| invalid_response("attested credential data is truncated"))?;
+
+    // The COSE_Key may be followed by extensions, so only the bytes it was decoded from are kept
+    let key_bytes = &rest[2 + credential_id_len..];
+    let mut reader = key_bytes;
+    let cose_key: Value = ciborium::from_reader(&mut reader)
+        .map_err(|_| invalid_response("credential public key is not valid CBOR"))?;
+    let public_key = key_bytes[..key_bytes.len() - reader.len()].to_vec();
+
+    let (algorithm, _) = parse_cose_key(&cose_key)?;
+
+    Ok(VerifiedCredential {
+        credential_id: credential_id.to_vec(),
+        public_key,
+        algorithm,
+        sign_count: auth_data.sign_count as i64,
+        backup_eligible: auth_data.flags & FLAG_BACKUP_ELIGIBLE != 0,
+        backed_up: auth_data.flags & FLAG_BACKED_UP != 0,
+    })
+}
+
+/// Verifies an assertion made with `credential`: its authenticator data, its signature over
+/// that data and the client data, and that the signature counter moved forward.
+pub fn verify_assertion(
+    config: &Config,
+    credential: &WebauthnCredential,
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<VerifiedAssertion, HttpError> {
+    let auth_data = parse_authenticator_data(config, authenticator_data)?;
+
+    let cose_key: Value = ciborium::from_reader(credential.public_key.as_slice())
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+    let (algorithm, public_key) = parse_cose_key(&cose_key)?;
+
+    if algorithm != credential.algorithm {
+        return Err(HttpError::server_error("Stored passkey does not match its algorithm".to_string()));
+    }
+
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data_json));
+
+    let verified = match &public_key {
+        PublicKey::Ed25519(x) => UnparsedPublicKey::new(&signature::ED25519, x).verify(&message, signature),
+        PublicKey::P256(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point).verify(&message, signature),
+        PublicKey::Rsa { n, e } => RsaPublicKeyComponents { n, e }.verify(&signature::RSA_PKCS1_2048_8192_SHA256, &message, signature),
+    };
+
+    if verified.is_err() {
+        return Err(HttpError::unauthorized("Passkey signature is invalid".to_string()));
+    }
+
+    // Authenticators without a counter always send 0; otherwise a counter that did not grow
+    // means the credential was copied to another authenticator
+    let sign_count = auth_data.sign_count as i64;
+    if (sign_count != 0 || credential.sign_count != 0) && sign_count <= credential.sign_count {
+        return Err(HttpError::unauthorized("Passkey signature counter went backwards, the authenticator may be cloned".to_string()));
+    }
+
+    Ok(VerifiedAssertion {
+        sign_count,
+        backed_up: auth_data.flags & FLAG_BACKED_UP != 0,
+    })
+}
+
+fn parse_authenticator_data<'a>(config: &Config, data: &'a [u8]) -> Result<AuthenticatorData<'a>, HttpError> {
+    if data.len() < AUTHENTICATOR_DATA_MIN_LEN {
+        return Err(invalid_response("authenticator data is truncated"));
+    }
+
+    if data[..32] != Sha256::digest(config.webauthn_rp_id.as_bytes())[..] {
+        return Err(invalid_response("credential is for another site"));
+    }
+
+    let flags = data[32];
+
+    if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+        return Err(invalid_response("the authenticator did not verify the user"));
+    }
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[AUTHENTICATOR_DATA_MIN_LEN..],
+    })
+}
+
+// RFC 9053: OKP keys (kty 1) on Ed25519 (crv 6), EC2 keys (kty 2) on P-256 (crv 1), RSA keys (kty 3)
+fn parse_cose_key(key: &Value) -> Result<(i32, PublicKey), HttpError> {
+    let entries = key.as_map().ok_or_else(|| invalid_response("credential public key is not a COSE key"))?;
+
+    let get = |label: i128| entries
+        .iter()
+        .find(|(key, _)| key.as_integer().is_some_and(|key| i128::from(key) == label))
+        .map(|(_, value)| value);
+    let int = |label: i128| get(label).and_then(Value::as_integer).map(i128::from);
+    let bytes = |label: i128| get(label).and_then(Value::as_bytes).cloned();
+
+    let algorithm = int(3)
+        .and_then(|algorithm| i32::try_from(algorithm).ok())
+        .ok_or_else(|| invalid_response("credential public key has no algorithm"))?;
+
+    let public_key = match (algorithm, int(1), int(-1)) {
+        (COSE_ALG_EDDSA, Some(1), Some(6)) => bytes(-2)
+            .filter(|x| x.len() == 32)
+            .map(PublicKey::Ed25519),
+        (COSE_ALG_ES256, Some(2), Some(1)) => bytes(-2)
+            .zip(bytes(-3))
+            .filter(|(x, y)| x.len() == 32 && y.len() == 32)
+            .map(|(x, y)| PublicKey::P256([&[0x04], x.as_slice(), y.as_slice()].concat())),
+        (COSE_ALG_RS256, Some(3), _) => bytes(-1)
+            .zip(bytes(-2))
+            .map(|(n, e)| PublicKey::Rsa { n, e }),
+        _ => return Err(invalid_response("credential uses an unsupported algorithm")),
+    };
+
+    public_key
+        .map(|public_key| (algorithm, public_key))
+        .ok_or_else(|| invalid_response("credential public key is malformed"))
+}
+
+fn invalid_response(reason: &str) -> HttpError {
+    HttpError::bad_request(format!("Invalid passkey response: {}", reason))
+}
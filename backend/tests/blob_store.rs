@@ -0,0 +1,124 @@
+//! The contract every `BlobStore` backend has to keep. The filesystem store is always
+//! checked; an S3-compatible store is checked when `S3_TEST_ENDPOINT` points at one, with
+//! `S3_TEST_BUCKET` (an existing bucket, `aerofy` by default), `S3_TEST_REGION`,
+//! `S3_TEST_ACCESS_KEY` and `S3_TEST_SECRET_KEY`.
+
+use std::{env, sync::Arc};
+
+use axum::body::Bytes;
+use backend::storage::{collect_blob, new_blob_key, BlobStore, FsBlobStore, S3BlobStore};
+
+/// Larger than an S3 multipart part, so the writer has to upload more than one.
+const BLOB_SIZE: usize = 9 * 1024 * 1024 + 123;
+
+/// Written in uneven pieces, so nothing lines up with the store's own buffering.
+const WRITE_SIZE: usize = 1024 * 1024 + 7;
+
+fn test_content() -> Vec<u8> {
+    (0..BLOB_SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+async fn write_blob(store: &Arc<dyn BlobStore>, key: &str, content: &[u8]) {
+    let mut writer = store.writer(key).await.expect("open writer");
+
+    for piece in content.chunks(WRITE_SIZE) {
+        writer.write(Bytes::copy_from_slice(piece)).await.expect("write");
+    }
+
+    writer.finish().await.expect("finish");
+}
+
+async fn read_blob(store: &Arc<dyn BlobStore>, key: &str) -> Option<Vec<u8>> {
+    match store.get(key).await {
+        Ok(blob) => collect_blob(blob).await.ok(),
+        Err(_) => None,
+    }
+}
+
+async fn read_range(store: &Arc<dyn BlobStore>, key: &str, offset: u64, length: u64) -> Vec<u8> {
+    let blob = store.get_range(key, offset, length).await.expect("get_range");
+    collect_blob(blob).await.expect("read range")
+}
+
+async fn check_contract(store: Arc<dyn BlobStore>) {
+    let content = test_content();
+    let key = new_blob_key();
+
+    // Nothing is visible under the key until the writer finishes
+    let mut writer = store.writer(&key).await.expect("open writer");
+    writer.write(Bytes::copy_from_slice(&content[..WRITE_SIZE])).await.expect("write");
+    assert_eq!(read_blob(&store, &key).await, None, "blob visible before finish");
+
+    for piece in content[WRITE_SIZE..].chunks(WRITE_SIZE) {
+        writer.write(Bytes::copy_from_slice(piece)).await.expect("write");
+    }
+    writer.finish().await.expect("finish");
+
+    assert!(read_blob(&store, &key).await == Some(content.clone()), "blob differs from what was written");
+
+    // Ranges are exact, wherever they fall
+    let ranges = [
+        (0, 1),
+        (0, 4096),
+        (WRITE_SIZE as u64 - 3, 10),
+        (5 * 1024 * 1024, 3 * 1024 * 1024 + 17),
+        (BLOB_SIZE as u64 - 100, 100),
+        (BLOB_SIZE as u64 - 1, 1),
+    ];
+
+    for (offset, length) in ranges {
+        let expected = &content[offset as usize..(offset + length) as usize];
+        assert!(read_range(&store, &key, offset, length).await == expected, "range {}+{} differs", offset, length);
+    }
+
+    // Deleting removes the blob, and deleting it again is not an error
+    store.delete(&key).await.expect("delete");
+    assert_eq!(read_blob(&store, &key).await, None, "blob still readable after delete");
+    store.delete(&key).await.expect("delete a missing blob");
+
+    // An aborted upload leaves nothing behind
+    let aborted_key = new_blob_key();
+    let mut writer = store.writer(&aborted_key).await.expect("open writer");
+    writer.write(Bytes::copy_from_slice(&content[..WRITE_SIZE])).await.expect("write");
+    writer.abort().await.expect("abort");
+    assert_eq!(read_blob(&store, &aborted_key).await, None, "aborted blob is visible");
+
+    // A blob that never existed cannot be read
+    assert!(store.get(&new_blob_key()).await.is_err(), "missing blob was readable");
+
+    // Small blobs fit in a single write
+    let small_key = new_blob_key();
+    write_blob(&store, &small_key, b"hello").await;
+    assert_eq!(read_blob(&store, &small_key).await.as_deref(), Some(&b"hello"[..]));
+    assert_eq!(read_range(&store, &small_key, 1, 3).await, b"ell");
+    store.delete(&small_key).await.expect("delete");
+}
+
+#[tokio::test]
+async fn fs_blob_store_keeps_the_contract() {
+    let root = env::temp_dir().join(format!("aerofy-blob-test-{}", uuid::Uuid::new_v4()));
+
+    check_contract(Arc::new(FsBlobStore::new(&root))).await;
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn s3_blob_store_keeps_the_contract() {
+    let Ok(endpoint) = env::var("S3_TEST_ENDPOINT") else {
+        eprintln!("S3_TEST_ENDPOINT is not set, skipping the S3 blob store");
+        return;
+    };
+
+    let var = |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+
+    let store = S3BlobStore::new(
+        &var("S3_TEST_BUCKET", "aerofy"),
+        &var("S3_TEST_REGION", "us-east-1"),
+        Some(&endpoint),
+        &var("S3_TEST_ACCESS_KEY", "minioadmin"),
+        &var("S3_TEST_SECRET_KEY", "minioadmin"),
+    );
+
+    check_contract(Arc::new(store)).await;
+}
@@ -0,0 +1,247 @@
+//! A server for the integration tests: the real router and database, with mail, blobs and JWT
+//! keys kept in a temporary directory. `DATABASE_URL` has to point at a migrated database.
+
+#![allow(dead_code)]
+
+use std::{env, path::PathBuf, sync::Arc};
+
+use axum::{body::{Body, Bytes}, http::{header, HeaderMap, Method, Request, StatusCode}, Router};
+use hmac::{Hmac, Mac};
+use backend::{config::{Config, MailConfig, StorageConfig}, db::DBClient, mail::create_mailer, router::create_router, storage::create_blob_store, utils::{events::EventHub, jwt_keys::JwtKeys, key_cache::KeyCache, oidc::OidcClient}, AppState};
+use serde_json::Value;
+use sha1::Sha1;
+use sqlx::postgres::PgPoolOptions;
+use tower::ServiceExt;
+
+pub const CLIENT_URL: &str = "http://localhost:3000";
+
+pub struct TestApp {
+    pub app_state: Arc<AppState>,
+    pub router: Router,
+    pub root: PathBuf,
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Value,
+    // The raw body, for responses that are not JSON
+    pub bytes: Bytes,
+}
+
+impl TestResponse {
+    /// The values of every `Set-Cookie`, as `name=value` without the attributes.
+    pub fn cookies(&self) -> Vec<String> {
+        self.headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.split(';').next())
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn message(&self) -> &str {
+        self.body["message"].as_str().unwrap_or_default()
+    }
+}
+
+pub fn test_config(root: &std::path::Path) -> Config {
+    let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+
+    Config {
+        database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+        jwt_secret: None,
+        jwt_keys_dir: path("keys"),
+        jwt_signing_kid: None,
+        jwt_generate_key: true,
+        jwt_maxage: 60,
+        refresh_token_maxage_days: 30,
+        port: 8080,
+        client_url: CLIENT_URL.to_string(),
+        public_url: "http://localhost:8080".to_string(),
+        max_upload_bytes: 16 * 1024 * 1024,
+        tus_upload_expiry_hours: 24,
+        expiry_warning_hours: 24,
+        share_password_max_attempts: 5,
+        password_backoff_seconds: 0,
+        require_email_verification: false,
+        mail_from: "Aerofy <no-reply@localhost>".to_string(),
+        mail: MailConfig::File { root: path("mail") },
+        storage: StorageConfig::Filesystem { root: path("storage") },
+        oidc_providers: Vec::new(),
+        key_wrap_secret: Some("integration-test-key-wrap-secret".to_string()),
+        webauthn_rp_id: "localhost".to_string(),
+        webauthn_rp_name: "Aerofy".to_string(),
+        webauthn_origins: vec![CLIENT_URL.to_string()],
+        trusted_proxies: Vec::new(),
+    }
+}
+
+impl TestApp {
+    pub async fn new() -> TestApp {
+        TestApp::with_config(|_| {}).await
+    }
+
+    /// Starts from `test_config` and lets the test change what it is about.
+    pub async fn with_config(configure: impl FnOnce(&mut Config)) -> TestApp {
+        let root = env::temp_dir().join(format!("aerofy-test-{}", uuid::Uuid::new_v4()));
+        let mut config = test_config(&root);
+        configure(&mut config);
+
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&config.database_url)
+            .await
+            .expect("connect to DATABASE_URL");
+
+        let jwt_keys = JwtKeys::load(
+            &config.jwt_keys_dir,
+            config.jwt_signing_kid.as_deref(),
+            config.jwt_secret.as_deref(),
+            config.jwt_generate_key,
+        )
+        .expect("load JWT keys");
+
+        let app_state = Arc::new(AppState {
+            db_client: DBClient::new(pool),
+            blob_store: create_blob_store(&config.storage),
+            key_cache: KeyCache::new(),
+            mailer: create_mailer(&config.mail, &config.mail_from),
+            oidc: OidcClient::new(),
+            jwt_keys,
+            events: EventHub::new(),
+            env: config,
+        });
+
+        TestApp {
+            router: create_router(app_state.clone()),
+            app_state,
+            root,
+        }
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> TestResponse {
+        let response = self.router.clone().oneshot(request).await.expect("router is infallible");
+        let status = response.status();
+        let headers = response.headers().clone();
+
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.expect("read body");
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+
+        TestResponse { status, headers, body, bytes }
+    }
+
+    /// A JSON request, signed in with `token` when there is one.
+    pub async fn call(&self, method: Method, path: &str, body: Option<Value>, token: Option<&str>) -> TestResponse {
+        let mut request = Request::builder().method(method).uri(path);
+
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+
+        self.send(request.unwrap()).await
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> TestResponse {
+        self.call(Method::POST, path, Some(body), None).await
+    }
+
+    pub async fn get(&self, path: &str, token: &str) -> TestResponse {
+        self.call(Method::GET, path, None, Some(token)).await
+    }
+
+    /// Registers an account with a password, returning the access token it is signed in with.
+    pub async fn register(&self, email: &str, password: &str) -> String {
+        let response = self.post("/api/auth/register", serde_json::json!({
+            "name": "Test User",
+            "email": email,
+            "password": password,
+            "passwordConfirm": password,
+        })).await;
+        assert_eq!(response.status, StatusCode::OK, "register: {}", response.body);
+
+        response.body["token"].as_str().expect("access token").to_string()
+    }
+
+    pub async fn login(&self, email: &str, password: &str) -> String {
+        let response = self.post("/api/auth/login", serde_json::json!({ "email": email, "password": password })).await;
+        assert_eq!(response.status, StatusCode::OK, "login: {}", response.body);
+
+        response.body["token"].as_str().expect("access token").to_string()
+    }
+
+    /// The messages the file mailer wrote to `email`, oldest first. Mail is sent in the
+    /// background, so this waits until there are at least `count` of them.
+    pub async fn mail_to(&self, email: &str, count: usize) -> Vec<String> {
+        for _ in 0..50 {
+            let messages = self.read_mail(email);
+            if messages.len() >= count {
+                return messages;
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        panic!("expected {} messages to {}", count, email);
+    }
+
+    fn read_mail(&self, email: &str) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(self.root.join("mail")) else {
+            return Vec::new();
+        };
+
+        // The file names start with the time they were written
+        let mut paths: Vec<PathBuf> = entries.filter_map(Result::ok).map(|entry| entry.path()).collect();
+        paths.sort();
+
+        paths
+            .into_iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .filter(|message| message.lines().any(|line| line.starts_with("To:") && line.contains(email)))
+            .collect()
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// An address nobody else in the database has, so tests can run side by side.
+pub fn unique_email(prefix: &str) -> String {
+    format!("{}-{}@example.com", prefix, uuid::Uuid::new_v4().simple())
+}
+
+/// The `token` query parameter of the first link in a message. Bodies are quoted-printable,
+/// so soft line breaks are joined and `=3D` turned back into `=` first.
+pub fn link_token(message: &str) -> String {
+    let body = message.replace("=\r\n", "").replace("=\n", "").replace("=3D", "=");
+
+    body.split_whitespace()
+        .find_map(|word| word.split_once("?token=").map(|(_, token)| token.to_string()))
+        .expect("a link with a token")
+}
+
+/// The current code of an authenticator app set up with `secret`.
+pub fn totp_code(secret: &str) -> String {
+    let secret = data_encoding::BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+    let step = (chrono::Utc::now().timestamp() / 30) as u64;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(&secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+
+    format!("{:06}", binary % 1_000_000)
+}
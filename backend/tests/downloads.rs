@@ -0,0 +1,102 @@
+//! Download limits on shares, for whole files and byte ranges alike.
+
+mod common;
+
+use axum::{body::Body, http::{header, Method, Request, StatusCode}};
+use common::{unique_email, TestApp, TestResponse};
+use serde_json::json;
+
+const SHARE_PASSWORD: &str = "share-password";
+
+fn content() -> Vec<u8> {
+    (0..200_000).map(|i| (i % 251) as u8).collect()
+}
+
+/// Uploads `content` for `recipient_email` with a download limit, returning the share's id once
+/// the recipient accepted it.
+async fn share_with_limit(app: &TestApp, sender: &str, recipient: &str, recipient_email: &str, max_downloads: i32) -> String {
+    let boundary = "aerofy-test-boundary";
+    let mut body = Vec::new();
+
+    let fields = [
+        ("recipient_email", recipient_email.to_string()),
+        ("password", SHARE_PASSWORD.to_string()),
+        ("max_downloads", max_downloads.to_string()),
+        ("expiration_date", "2099-01-01T00:00:00Z".to_string()),
+    ];
+
+    for (name, value) in fields {
+        body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", boundary, name, value).as_bytes());
+    }
+
+    body.extend_from_slice(format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"fileUpload\"; filename=\"data.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        boundary
+    ).as_bytes());
+    body.extend_from_slice(&content());
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let request = Request::post("/api/file/upload")
+        .header(header::AUTHORIZATION, format!("Bearer {}", sender))
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+        .body(Body::from(body))
+        .unwrap();
+
+    let response = app.send(request).await;
+    assert_eq!(response.status, StatusCode::OK, "upload: {}", response.body);
+
+    let pending = app.get("/api/list/pendingreceive", recipient).await;
+    let shared_id = pending.body["files"][0]["shared_id"].as_str().expect("a pending share").to_string();
+
+    let response = app.call(Method::POST, "/api/file/accept", Some(json!({
+        "shared_id": shared_id,
+        "password": SHARE_PASSWORD,
+    })), Some(recipient)).await;
+    assert_eq!(response.status, StatusCode::OK, "accept: {}", response.body);
+
+    shared_id
+}
+
+async fn download(app: &TestApp, recipient: &str, shared_id: &str, range: Option<&str>) -> TestResponse {
+    let mut request = Request::get(format!("/api/file/retrieve/{}", shared_id))
+        .header(header::AUTHORIZATION, format!("Bearer {}", recipient));
+
+    if let Some(range) = range {
+        request = request.header(header::RANGE, range);
+    }
+
+    app.send(request.body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test]
+async fn ranges_count_against_the_download_limit() {
+    let app = TestApp::new().await;
+    let sender = app.register(&unique_email("sender"), "sender-password").await;
+    let recipient_email = unique_email("recipient");
+    let recipient = app.register(&recipient_email, "recipient-password").await;
+
+    let shared_id = share_with_limit(&app, &sender, &recipient, &recipient_email, 2).await;
+    let content = content();
+
+    let response = download(&app, &recipient, &shared_id, None).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    assert!(response.bytes[..] == content[..], "the download differs from the upload");
+
+    // A range that skips the first byte is the last download the share allows
+    let response = download(&app, &recipient, &shared_id, Some("bytes=1-")).await;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT, "{}", response.body);
+    assert!(response.bytes[..] == content[1..], "the range differs from the upload");
+
+    // Used up, the share serves no more bytes, whichever one a range starts at
+    for range in [Some("bytes=1-"), Some("bytes=100000-"), None] {
+        let response = download(&app, &recipient, &shared_id, range).await;
+
+        assert!(
+            matches!(response.status, StatusCode::GONE | StatusCode::BAD_REQUEST),
+            "{:?} was served after the limit: {}",
+            range,
+            response.status,
+        );
+        assert!(response.bytes.is_empty() || response.body.is_object(), "{:?} sent file content", range);
+    }
+}
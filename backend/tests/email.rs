@@ -0,0 +1,131 @@
+//! Email verification and password reset, following the links the file mailer writes out.
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{link_token, unique_email, TestApp};
+use serde_json::json;
+
+const PASSWORD: &str = "first-password";
+const NEW_PASSWORD: &str = "second-password";
+
+async fn reset_password(app: &TestApp, token: &str, new_password: &str) -> common::TestResponse {
+    app.post("/api/auth/reset-password", json!({
+        "token": token,
+        "new_password": new_password,
+        "new_password_confirm": new_password,
+    })).await
+}
+
+/// Asks for a reset link and returns the token in the newest message to `email`.
+async fn request_reset(app: &TestApp, email: &str, messages_before: usize) -> String {
+    let response = app.post("/api/auth/forgot-password", json!({ "email": email })).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+    let messages = app.mail_to(email, messages_before + 1).await;
+    let message = messages.last().unwrap();
+    assert!(message.contains("Subject: Reset your Aerofy password"), "{}", message);
+
+    link_token(message)
+}
+
+#[tokio::test]
+async fn the_emailed_link_verifies_the_address() {
+    let app = TestApp::with_config(|config| config.require_email_verification = true).await;
+    let email = unique_email("verify");
+    let token = app.register(&email, PASSWORD).await;
+
+    // Files stay closed until the address is proven
+    let files = app.get("/api/list/receive", &token).await;
+    assert_eq!(files.status, StatusCode::FORBIDDEN, "{}", files.body);
+
+    let messages = app.mail_to(&email, 1).await;
+    assert!(messages[0].contains("Subject: Verify your Aerofy email address"), "{}", messages[0]);
+
+    let response = app.post("/api/auth/verify-email", json!({ "token": link_token(&messages[0]) })).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+    let me = app.get("/api/users/me", &token).await;
+    assert_eq!(me.body["data"]["user"]["email_verified"], true);
+
+    let files = app.get("/api/list/receive", &token).await;
+    assert_eq!(files.status, StatusCode::OK, "{}", files.body);
+}
+
+#[tokio::test]
+async fn a_reset_token_cannot_verify_an_email_or_the_other_way_round() {
+    let app = TestApp::new().await;
+    let email = unique_email("purpose");
+    app.register(&email, PASSWORD).await;
+
+    let verification_token = link_token(&app.mail_to(&email, 1).await[0]);
+    let reset_token = request_reset(&app, &email, 1).await;
+
+    let response = app.post("/api/auth/verify-email", json!({ "token": reset_token })).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+
+    let response = reset_password(&app, &verification_token, NEW_PASSWORD).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+}
+
+#[tokio::test]
+async fn the_emailed_link_resets_the_password_once() {
+    let app = TestApp::new().await;
+    let email = unique_email("reset");
+    let old_session = app.register(&email, PASSWORD).await;
+
+    let token = request_reset(&app, &email, 1).await;
+
+    let response = reset_password(&app, &token, NEW_PASSWORD).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+    // The new password signs in, the old one and the sessions started with it do not
+    app.login(&email, NEW_PASSWORD).await;
+
+    let response = app.post("/api/auth/login", json!({ "email": email, "password": PASSWORD })).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", response.body);
+
+    let me = app.get("/api/users/me", &old_session).await;
+    assert_eq!(me.status, StatusCode::UNAUTHORIZED, "{}", me.body);
+
+    // The token was bound to the hash it replaced, so it is dead now
+    let response = reset_password(&app, &token, "third-password").await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+
+    app.login(&email, NEW_PASSWORD).await;
+}
+
+#[tokio::test]
+async fn changing_the_password_invalidates_an_unused_reset_link() {
+    let app = TestApp::new().await;
+    let email = unique_email("reset-change");
+    let session = app.register(&email, PASSWORD).await;
+
+    let token = request_reset(&app, &email, 1).await;
+
+    let response = app.call(Method::PUT, "/api/users/password", Some(json!({
+        "old_password": PASSWORD,
+        "new_password": NEW_PASSWORD,
+        "new_password_confirm": NEW_PASSWORD,
+    })), Some(&session)).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+    let response = reset_password(&app, &token, "third-password").await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+
+    app.login(&email, NEW_PASSWORD).await;
+}
+
+#[tokio::test]
+async fn forgot_password_does_not_reveal_who_has_an_account() {
+    let app = TestApp::new().await;
+    let email = unique_email("known");
+    app.register(&email, PASSWORD).await;
+
+    let known = app.post("/api/auth/forgot-password", json!({ "email": email })).await;
+    let unknown = app.post("/api/auth/forgot-password", json!({ "email": unique_email("unknown") })).await;
+
+    assert_eq!(known.status, StatusCode::OK);
+    assert_eq!(known.status, unknown.status);
+    assert_eq!(known.body, unknown.body);
+}
@@ -0,0 +1,50 @@
+//! Two-step password logins, and when the private key they unlock becomes usable.
+
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::{totp_code, unique_email, TestApp};
+use serde_json::json;
+
+const PASSWORD: &str = "mfa-password";
+
+/// Turns on two-factor authentication, returning the account's id and a recovery code.
+async fn enable_totp(app: &TestApp, session: &str) -> (uuid::Uuid, String) {
+    let setup = app.call(Method::POST, "/api/users/totp/setup", None, Some(session)).await;
+    assert_eq!(setup.status, StatusCode::OK, "{}", setup.body);
+
+    let code = totp_code(setup.body["secret"].as_str().unwrap());
+    let confirmed = app.call(Method::POST, "/api/users/totp/confirm", Some(json!({ "code": code })), Some(session)).await;
+    assert_eq!(confirmed.status, StatusCode::OK, "{}", confirmed.body);
+
+    let me = app.get("/api/users/me", session).await;
+    let user_id = me.body["data"]["user"]["id"].as_str().unwrap().parse().unwrap();
+
+    (user_id, confirmed.body["recovery_codes"][0].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn the_private_key_waits_for_the_second_factor() {
+    let app = TestApp::new().await;
+    let email = unique_email("mfa");
+    let session = app.register(&email, PASSWORD).await;
+    let (user_id, recovery_code) = enable_totp(&app, &session).await;
+
+    // As if the server had restarted since registering
+    app.app_state.key_cache.remove(user_id);
+
+    let response = app.post("/api/auth/login", json!({ "email": email, "password": PASSWORD })).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    assert_eq!(response.body["status"], "mfa_required");
+    let mfa_token = response.body["mfa_token"].as_str().unwrap().to_string();
+
+    assert!(app.app_state.key_cache.get(user_id).is_none(), "the password alone unlocked the key");
+
+    let response = app.post("/api/auth/login/mfa", json!({ "mfa_token": mfa_token, "code": "not-a-code" })).await;
+    assert!(response.status.is_client_error(), "{}", response.body);
+    assert!(app.app_state.key_cache.get(user_id).is_none(), "a wrong code unlocked the key");
+
+    let response = app.post("/api/auth/login/mfa", json!({ "mfa_token": mfa_token, "code": recovery_code })).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    assert!(app.app_state.key_cache.get(user_id).is_some(), "the second factor did not unlock the key");
+}
@@ -0,0 +1,424 @@
+//! Single sign-on against a mock identity provider that runs inside the test. It serves
+//! discovery, its keys and the token endpoint over HTTP like a real provider; the browser's
+//! visit to the authorization endpoint is played in memory.
+
+mod common;
+
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use axum::{body::Body, extract::State, http::{header, HeaderMap, Method, Request, StatusCode}, routing::{get, post}, Form, Json, Router};
+use backend::{config::OidcProviderConfig, db::UserExt};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use common::{totp_code, unique_email, TestApp, TestResponse, CLIENT_URL};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use ring::{rand::SystemRandom, signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING}};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+const PROVIDER: &str = "mock";
+const CLIENT_ID: &str = "aerofy";
+const CLIENT_SECRET: &str = "s3cret";
+const KID: &str = "mock-key";
+
+/// How the provider answers the next token request.
+#[derive(Default)]
+struct Behaviour {
+    // Puts another nonce in the ID token than the one the client sent
+    wrong_nonce: bool,
+    // Signs the ID token with the client secret, as HS256
+    sign_with_secret: bool,
+}
+
+struct PendingCode {
+    redirect_uri: String,
+    nonce: String,
+    code_challenge: String,
+    claims: Value,
+}
+
+struct IdpState {
+    issuer: String,
+    signing_key: EncodingKey,
+    jwks: Value,
+    pending: HashMap<String, PendingCode>,
+    behaviour: Behaviour,
+    // Token requests whose code verifier matched the challenge of their code
+    pkce_verified: usize,
+}
+
+#[derive(Clone)]
+struct MockIdp {
+    state: Arc<Mutex<IdpState>>,
+}
+
+impl MockIdp {
+    async fn start() -> MockIdp {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        // ES256, so no RSA key has to be generated for every test
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let point = key_pair.public_key().as_ref();
+
+        let jwks = json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": KID,
+                "use": "sig",
+                "alg": "ES256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]
+        });
+
+        let idp = MockIdp {
+            state: Arc::new(Mutex::new(IdpState {
+                issuer,
+                signing_key: EncodingKey::from_ec_der(pkcs8.as_ref()),
+                jwks,
+                pending: HashMap::new(),
+                behaviour: Behaviour::default(),
+                pkce_verified: 0,
+            })),
+        };
+
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks_document))
+            .route("/token", post(token))
+            .with_state(idp.clone());
+
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        idp
+    }
+
+    fn issuer(&self) -> String {
+        self.state.lock().unwrap().issuer.clone()
+    }
+
+    fn provider(&self) -> OidcProviderConfig {
+        OidcProviderConfig {
+            name: PROVIDER.to_string(),
+            display_name: "Mock".to_string(),
+            issuer: self.issuer(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            scopes: "openid email profile".to_string(),
+        }
+    }
+
+    fn behave(&self, behaviour: Behaviour) {
+        self.state.lock().unwrap().behaviour = behaviour;
+    }
+
+    fn pkce_verified(&self) -> usize {
+        self.state.lock().unwrap().pkce_verified
+    }
+
+    /// The user signs in as `claims` at the authorization endpoint, which answers with a code
+    /// and the state it was given.
+    fn authorize(&self, authorization_url: &str, claims: Value) -> (String, String) {
+        let url = url::Url::parse(authorization_url).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+        assert_eq!(query["response_type"], "code");
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert!(query["scope"].split(' ').any(|scope| scope == "openid"));
+
+        let code = uuid::Uuid::new_v4().to_string();
+        self.state.lock().unwrap().pending.insert(code.clone(), PendingCode {
+            redirect_uri: query["redirect_uri"].clone(),
+            nonce: query["nonce"].clone(),
+            code_challenge: query["code_challenge"].clone(),
+            claims,
+        });
+
+        (code, query["state"].clone())
+    }
+}
+
+async fn discovery(State(idp): State<MockIdp>) -> Json<Value> {
+    let issuer = idp.issuer();
+
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    }))
+}
+
+async fn jwks_document(State(idp): State<MockIdp>) -> Json<Value> {
+    Json(idp.state.lock().unwrap().jwks.clone())
+}
+
+async fn token(
+    State(idp): State<MockIdp>,
+    headers: HeaderMap,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let refuse = |status: StatusCode, error: &str| (status, Json(json!({ "error": error })));
+
+    let client_auth = format!("Basic {}", STANDARD.encode(format!("{}:{}", CLIENT_ID, CLIENT_SECRET)));
+    if headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()) != Some(client_auth.as_str()) {
+        return Err(refuse(StatusCode::UNAUTHORIZED, "invalid_client"));
+    }
+
+    let mut state = idp.state.lock().unwrap();
+
+    let pending = form.get("code")
+        .and_then(|code| state.pending.remove(code))
+        .ok_or_else(|| refuse(StatusCode::BAD_REQUEST, "invalid_grant"))?;
+
+    if form.get("redirect_uri") != Some(&pending.redirect_uri) {
+        return Err(refuse(StatusCode::BAD_REQUEST, "invalid_grant"));
+    }
+
+    let code_verifier = form.get("code_verifier").ok_or_else(|| refuse(StatusCode::BAD_REQUEST, "invalid_request"))?;
+    if URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) != pending.code_challenge {
+        return Err(refuse(StatusCode::BAD_REQUEST, "invalid_grant"));
+    }
+    state.pkce_verified += 1;
+
+    let now = chrono::Utc::now().timestamp();
+    let mut claims = pending.claims;
+    claims["iss"] = json!(state.issuer);
+    claims["aud"] = json!(CLIENT_ID);
+    claims["iat"] = json!(now);
+    claims["exp"] = json!(now + 300);
+    claims["nonce"] = json!(if state.behaviour.wrong_nonce { "another-nonce".to_string() } else { pending.nonce });
+
+    let id_token = if state.behaviour.sign_with_secret {
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()))
+    } else {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(KID.to_string());
+        encode(&header, &claims, &state.signing_key)
+    }
+    .unwrap();
+
+    Ok(Json(json!({ "access_token": "unused", "token_type": "Bearer", "id_token": id_token })))
+}
+
+async fn app_with(idp: &MockIdp) -> TestApp {
+    let provider = idp.provider();
+    TestApp::with_config(|config| config.oidc_providers = vec![provider]).await
+}
+
+fn identity(email: &str) -> Value {
+    json!({
+        "sub": uuid::Uuid::new_v4().to_string(),
+        "email": email,
+        "email_verified": true,
+        "name": "Single Sign-On User",
+    })
+}
+
+fn cookie(response: &TestResponse, name: &str) -> Option<String> {
+    response.cookies()
+        .into_iter()
+        .find_map(|cookie| cookie.strip_prefix(&format!("{}=", name)).map(str::to_string))
+}
+
+fn location(response: &TestResponse) -> String {
+    response.headers[header::LOCATION].to_str().unwrap().to_string()
+}
+
+/// Opens the login route and returns where it sends the browser, and the state cookie.
+async fn start_login(app: &TestApp) -> (String, String) {
+    let response = app.send(Request::get(format!("/api/auth/oidc/{}/login", PROVIDER)).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+
+    (location(&response), cookie(&response, "oidc_state").expect("state cookie"))
+}
+
+async fn callback(app: &TestApp, code: &str, state: &str, state_cookie: Option<&str>) -> TestResponse {
+    let mut request = Request::get(format!("/api/auth/oidc/{}/callback?code={}&state={}", PROVIDER, code, state));
+
+    if let Some(state_cookie) = state_cookie {
+        request = request.header(header::COOKIE, format!("oidc_state={}", state_cookie));
+    }
+
+    app.send(request.body(Body::empty()).unwrap()).await
+}
+
+/// The whole round trip through the provider, signing in as `claims`.
+async fn sign_in(app: &TestApp, idp: &MockIdp, claims: Value) -> TestResponse {
+    let (authorization_url, state_cookie) = start_login(app).await;
+    let (code, state) = idp.authorize(&authorization_url, claims);
+
+    callback(app, &code, &state, Some(&state_cookie)).await
+}
+
+#[tokio::test]
+async fn the_first_sign_in_provisions_an_account_and_later_ones_reuse_it() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+    let email = unique_email("sso");
+    let claims = identity(&email);
+
+    let response = sign_in(&app, &idp, claims.clone()).await;
+    assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+    assert_eq!(location(&response), CLIENT_URL);
+    assert_eq!(cookie(&response, "oidc_state").as_deref(), Some(""), "the state cookie is not cleared");
+
+    let token = cookie(&response, "token").expect("session cookie");
+    let me = app.get("/api/users/me", &token).await;
+    assert_eq!(me.body["data"]["user"]["email"], email.as_str());
+    assert_eq!(me.body["data"]["user"]["email_verified"], true);
+    assert!(me.body["data"]["user"]["public_key"].is_string(), "the account has no key pair");
+
+    let user = app.app_state.db_client.get_user(None, None, Some(&email)).await.unwrap().unwrap();
+    assert!(user.password.is_none());
+
+    let identities = app.get("/api/users/identities", &token).await;
+    assert_eq!(identities.body["identities"].as_array().unwrap().len(), 1);
+
+    // The same identity signs in to the same account, even once its email changed
+    let mut claims = claims;
+    claims["email"] = json!(unique_email("sso-renamed"));
+    let response = sign_in(&app, &idp, claims).await;
+    let me = app.get("/api/users/me", &cookie(&response, "token").expect("session cookie")).await;
+    assert_eq!(me.body["data"]["user"]["id"], user.id.to_string());
+}
+
+#[tokio::test]
+async fn the_code_is_redeemed_with_the_pkce_verifier() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+
+    let (authorization_url, state_cookie) = start_login(&app).await;
+
+    // Only the challenge travels through the browser, never the verifier
+    assert!(authorization_url.contains("code_challenge="));
+    assert!(!authorization_url.contains("code_verifier"));
+
+    let (code, state) = idp.authorize(&authorization_url, identity(&unique_email("pkce")));
+    let response = callback(&app, &code, &state, Some(&state_cookie)).await;
+
+    assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+    assert_eq!(idp.pkce_verified(), 1);
+}
+
+#[tokio::test]
+async fn the_callback_needs_the_state_cookie_of_the_browser_that_started() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+
+    let (authorization_url, state_cookie) = start_login(&app).await;
+    let (code, state) = idp.authorize(&authorization_url, identity(&unique_email("state")));
+
+    for wrong_cookie in [None, Some("another-browser")] {
+        let response = callback(&app, &code, &state, wrong_cookie).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", response.body);
+        assert!(response.message().contains("another browser"), "{}", response.body);
+    }
+
+    let response = callback(&app, &code, &state, Some(&state_cookie)).await;
+    assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+
+    // Each state is good for one callback
+    let replayed = callback(&app, &code, &state, Some(&state_cookie)).await;
+    assert_eq!(replayed.status, StatusCode::BAD_REQUEST, "{}", replayed.body);
+    assert!(replayed.message().contains("expired"), "{}", replayed.body);
+}
+
+#[tokio::test]
+async fn an_id_token_for_another_nonce_is_refused() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+    let email = unique_email("nonce");
+
+    idp.behave(Behaviour { wrong_nonce: true, ..Behaviour::default() });
+    let response = sign_in(&app, &idp, identity(&email)).await;
+
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+    assert!(app.app_state.db_client.get_user(None, None, Some(&email)).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn an_id_token_signed_with_the_client_secret_is_refused() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+    let email = unique_email("hs256");
+
+    idp.behave(Behaviour { sign_with_secret: true, ..Behaviour::default() });
+    let response = sign_in(&app, &idp, identity(&email)).await;
+
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{}", response.body);
+    assert!(app.app_state.db_client.get_user(None, None, Some(&email)).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn an_identity_is_not_linked_to_an_existing_account_by_its_email() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+    let email = unique_email("taken");
+    let session = app.register(&email, "account-password").await;
+    let claims = identity(&email);
+
+    let response = sign_in(&app, &idp, claims.clone()).await;
+    assert_eq!(response.status, StatusCode::CONFLICT, "{}", response.body);
+    assert!(cookie(&response, "token").is_none());
+
+    let identities = app.get("/api/users/identities", &session).await;
+    assert_eq!(identities.body["identities"].as_array().unwrap().len(), 0);
+
+    // Signed in, the owner of the account can link the identity themselves
+    let response = app.call(Method::POST, "/api/users/identities", Some(json!({ "provider": PROVIDER })), Some(&session)).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+
+    let state_cookie = cookie(&response, "oidc_state").expect("state cookie");
+    let (code, state) = idp.authorize(response.body["authorization_url"].as_str().unwrap(), claims.clone());
+    let response = callback(&app, &code, &state, Some(&state_cookie)).await;
+    assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+    assert_eq!(location(&response), format!("{}/profile", CLIENT_URL));
+
+    // From then on the identity signs in to that account
+    let response = sign_in(&app, &idp, claims).await;
+    let me = app.get("/api/users/me", &cookie(&response, "token").expect("session cookie")).await;
+    assert_eq!(me.body["data"]["user"]["email"], email.as_str());
+}
+
+#[tokio::test]
+async fn single_sign_on_asks_for_the_second_factor_of_the_account() {
+    let idp = MockIdp::start().await;
+    let app = app_with(&idp).await;
+    let claims = identity(&unique_email("sso-totp"));
+
+    let response = sign_in(&app, &idp, claims.clone()).await;
+    let session = cookie(&response, "token").expect("session cookie");
+
+    let setup = app.call(Method::POST, "/api/users/totp/setup", None, Some(&session)).await;
+    assert_eq!(setup.status, StatusCode::OK, "{}", setup.body);
+
+    let code = totp_code(setup.body["secret"].as_str().unwrap());
+    let confirmed = app.call(Method::POST, "/api/users/totp/confirm", Some(json!({ "code": code })), Some(&session)).await;
+    assert_eq!(confirmed.status, StatusCode::OK, "{}", confirmed.body);
+    let recovery_code = confirmed.body["recovery_codes"][0].as_str().unwrap().to_string();
+
+    let me = app.get("/api/users/me", &session).await;
+    let user_id: uuid::Uuid = me.body["data"]["user"]["id"].as_str().unwrap().parse().unwrap();
+    app.app_state.key_cache.remove(user_id);
+
+    // The provider vouches for the first factor only, so no session is started and no key unlocked yet
+    let response = sign_in(&app, &idp, claims).await;
+    assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+    assert!(cookie(&response, "token").is_none(), "single sign-on skipped the second factor");
+    assert!(app.app_state.key_cache.get(user_id).is_none(), "single sign-on unlocked the key before the second factor");
+
+    let location = location(&response);
+    let mfa_token = location
+        .strip_prefix(&format!("{}/login/mfa#mfa_token=", CLIENT_URL))
+        .unwrap_or_else(|| panic!("not sent to the second factor: {}", location));
+
+    let response = app.post("/api/auth/login/mfa", json!({ "mfa_token": mfa_token, "code": recovery_code })).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    assert!(response.body["token"].is_string());
+    assert!(app.app_state.key_cache.get(user_id).is_some(), "the second factor did not unlock the key");
+}
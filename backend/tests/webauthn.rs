@@ -0,0 +1,299 @@
+//! Passkey sign-up and login against a software authenticator that signs with ES256, the
+//! algorithm platform authenticators use most.
+
+mod common;
+
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use backend::db::UserExt;
+use ciborium::Value as Cbor;
+use common::{unique_email, TestApp, CLIENT_URL};
+use ring::{rand::SystemRandom, signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING}};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+// User present, user verified, backup eligible and backed up
+const FLAGS: u8 = 0x01 | 0x04 | 0x08 | 0x10;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+struct Authenticator {
+    key: EcdsaKeyPair,
+    credential_id: Vec<u8>,
+    user_handle: Vec<u8>,
+    rng: SystemRandom,
+}
+
+impl Authenticator {
+    fn new() -> Authenticator {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+
+        Authenticator {
+            key,
+            credential_id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+            user_handle: Vec::new(),
+            rng,
+        }
+    }
+
+    fn authenticator_data(flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut data = Sha256::digest(b"localhost").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        // The public key is an uncompressed point: 0x04, then x and y
+        let point = self.key.public_key().as_ref();
+        let int = |value: i64| Cbor::Integer(value.into());
+
+        let key = Cbor::Map(vec![
+            (int(1), int(2)),
+            (int(3), int(-7)),
+            (int(-1), int(1)),
+            (int(-2), Cbor::Bytes(point[1..33].to_vec())),
+            (int(-3), Cbor::Bytes(point[33..].to_vec())),
+        ]);
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&key, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// Answers `navigator.credentials.create()` as a browser at `origin` would.
+    fn create(&mut self, options: &Value, origin: &str) -> Value {
+        let options = &options["options"]["publicKey"];
+        self.user_handle = URL_SAFE_NO_PAD.decode(options["user"]["id"].as_str().unwrap()).unwrap();
+
+        let mut auth_data = Self::authenticator_data(FLAGS | FLAG_ATTESTED_CREDENTIAL, 0);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&self.cose_key());
+
+        let attestation = Cbor::Map(vec![
+            (Cbor::Text("fmt".into()), Cbor::Text("none".into())),
+            (Cbor::Text("attStmt".into()), Cbor::Map(Vec::new())),
+            (Cbor::Text("authData".into()), Cbor::Bytes(auth_data)),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        let client_data = json!({
+            "type": "webauthn.create",
+            "challenge": options["challenge"],
+            "origin": origin,
+            "crossOrigin": false,
+        });
+
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data.to_string()),
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation_object),
+                "transports": ["internal"],
+            },
+        })
+    }
+
+    /// Answers `navigator.credentials.get()` with the signature counter at `sign_count`.
+    fn get(&self, options: &Value, sign_count: u32, user_handle: &[u8]) -> Value {
+        let auth_data = Self::authenticator_data(FLAGS, sign_count);
+
+        let client_data = json!({
+            "type": "webauthn.get",
+            "challenge": options["options"]["publicKey"]["challenge"],
+            "origin": CLIENT_URL,
+        })
+        .to_string();
+
+        let mut message = auth_data.clone();
+        message.extend_from_slice(&Sha256::digest(client_data.as_bytes()));
+        let signature = self.key.sign(&self.rng, &message).unwrap();
+
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+                "userHandle": URL_SAFE_NO_PAD.encode(user_handle),
+            },
+        })
+    }
+}
+
+async fn signup_options(app: &TestApp, email: &str) -> Value {
+    let response = app.post("/api/auth/webauthn/signup/options", json!({ "name": "Passkey User", "email": email })).await;
+    assert_eq!(response.status, StatusCode::OK, "signup options: {}", response.body);
+
+    response.body
+}
+
+async fn login_options(app: &TestApp) -> Value {
+    let response = app.post("/api/auth/webauthn/login/options", json!({})).await;
+    assert_eq!(response.status, StatusCode::OK, "login options: {}", response.body);
+
+    response.body
+}
+
+/// Creates a passkey-only account, returning its authenticator and access token.
+async fn signup(app: &TestApp, email: &str) -> (Authenticator, String) {
+    let mut authenticator = Authenticator::new();
+    let options = signup_options(app, email).await;
+    let credential = authenticator.create(&options, CLIENT_URL);
+
+    let response = app.post("/api/auth/webauthn/signup", json!({ "credential": credential })).await;
+    assert_eq!(response.status, StatusCode::OK, "signup: {}", response.body);
+
+    (authenticator, response.body["token"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn passkey_only_signup_creates_an_account_that_can_sign_in() {
+    let app = TestApp::new().await;
+    let email = unique_email("passkey");
+
+    let (authenticator, token) = signup(&app, &email).await;
+
+    let me = app.get("/api/users/me", &token).await;
+    assert_eq!(me.status, StatusCode::OK);
+    assert_eq!(me.body["data"]["user"]["email"], email.as_str());
+    assert!(me.body["data"]["user"]["public_key"].is_string(), "the account has no key pair");
+
+    let user = app.app_state.db_client.get_user(None, None, Some(&email)).await.unwrap().unwrap();
+    assert!(user.password.is_none());
+    assert_eq!(authenticator.user_handle, user.id.as_bytes());
+
+    let passkeys = app.get("/api/auth/webauthn/credentials", &token).await;
+    assert_eq!(passkeys.body["passkeys"].as_array().unwrap().len(), 1);
+
+    let options = login_options(&app).await;
+    let response = app.post("/api/auth/webauthn/login", json!({
+        "credential": authenticator.get(&options, 1, &authenticator.user_handle),
+    })).await;
+    assert_eq!(response.status, StatusCode::OK, "login: {}", response.body);
+
+    let me = app.get("/api/users/me", response.body["token"].as_str().unwrap()).await;
+    assert_eq!(me.body["data"]["user"]["email"], email.as_str());
+}
+
+#[tokio::test]
+async fn signups_racing_with_one_passkey_leave_no_account_without_it() {
+    let app = TestApp::new().await;
+    let mut authenticator = Authenticator::new();
+
+    let emails = [unique_email("passkey-race"), unique_email("passkey-race")];
+    let mut credentials = Vec::new();
+    for email in &emails {
+        let options = signup_options(&app, email).await;
+        credentials.push(authenticator.create(&options, CLIENT_URL));
+    }
+
+    // Both can pass the check for a registered passkey; the loser then fails on the unique
+    // credential after its account row was written, which has to roll back with it
+    let (first, second) = tokio::join!(
+        app.post("/api/auth/webauthn/signup", json!({ "credential": credentials[0] })),
+        app.post("/api/auth/webauthn/signup", json!({ "credential": credentials[1] })),
+    );
+
+    let statuses = [first.status, second.status];
+    assert!(statuses.contains(&StatusCode::OK), "{} / {}", first.body, second.body);
+    assert!(statuses.contains(&StatusCode::CONFLICT), "{} / {}", first.body, second.body);
+
+    let loser = if first.status == StatusCode::OK { &emails[1] } else { &emails[0] };
+    let user = app.app_state.db_client.get_user(None, None, Some(loser)).await.unwrap();
+    assert!(user.is_none(), "a failed signup left an account without a passkey");
+
+    // And the email is still free to sign up with
+    signup(&app, loser).await;
+}
+
+#[tokio::test]
+async fn signup_from_another_origin_is_refused() {
+    let app = TestApp::new().await;
+    let email = unique_email("passkey");
+
+    let mut authenticator = Authenticator::new();
+    let options = signup_options(&app, &email).await;
+    let response = app.post("/api/auth/webauthn/signup", json!({
+        "credential": authenticator.create(&options, "https://evil.example.com"),
+    })).await;
+
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert!(response.message().contains("another origin"), "{}", response.body);
+    assert!(app.app_state.db_client.get_user(None, None, Some(&email)).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn a_challenge_is_answered_only_once() {
+    let app = TestApp::new().await;
+    let email = unique_email("passkey");
+
+    let mut authenticator = Authenticator::new();
+    let options = signup_options(&app, &email).await;
+    let credential = authenticator.create(&options, CLIENT_URL);
+
+    let response = app.post("/api/auth/webauthn/signup", json!({ "credential": credential })).await;
+    assert_eq!(response.status, StatusCode::OK, "signup: {}", response.body);
+
+    let replayed = app.post("/api/auth/webauthn/signup", json!({ "credential": credential })).await;
+    assert_eq!(replayed.status, StatusCode::BAD_REQUEST);
+    assert!(replayed.message().contains("expired"), "{}", replayed.body);
+
+    // The same goes for a login: a captured assertion cannot be sent again
+    let options = login_options(&app).await;
+    let assertion = authenticator.get(&options, 1, &authenticator.user_handle);
+
+    let response = app.post("/api/auth/webauthn/login", json!({ "credential": assertion })).await;
+    assert_eq!(response.status, StatusCode::OK, "login: {}", response.body);
+
+    let replayed = app.post("/api/auth/webauthn/login", json!({ "credential": assertion })).await;
+    assert_eq!(replayed.status, StatusCode::BAD_REQUEST);
+    assert!(replayed.message().contains("expired"), "{}", replayed.body);
+}
+
+#[tokio::test]
+async fn a_signature_counter_that_does_not_grow_is_refused() {
+    let app = TestApp::new().await;
+    let (authenticator, _) = signup(&app, &unique_email("passkey")).await;
+
+    let options = login_options(&app).await;
+    let response = app.post("/api/auth/webauthn/login", json!({
+        "credential": authenticator.get(&options, 5, &authenticator.user_handle),
+    })).await;
+    assert_eq!(response.status, StatusCode::OK, "login: {}", response.body);
+
+    for sign_count in [5, 3] {
+        let options = login_options(&app).await;
+        let response = app.post("/api/auth/webauthn/login", json!({
+            "credential": authenticator.get(&options, sign_count, &authenticator.user_handle),
+        })).await;
+
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED, "counter {}: {}", sign_count, response.body);
+        assert!(response.message().contains("cloned"), "{}", response.body);
+    }
+
+    let options = login_options(&app).await;
+    let response = app.post("/api/auth/webauthn/login", json!({
+        "credential": authenticator.get(&options, 6, &authenticator.user_handle),
+    })).await;
+    assert_eq!(response.status, StatusCode::OK, "login: {}", response.body);
+}
+
+#[tokio::test]
+async fn an_assertion_for_another_user_handle_is_refused() {
+    let app = TestApp::new().await;
+    let (authenticator, _) = signup(&app, &unique_email("passkey")).await;
+    let (other, _) = signup(&app, &unique_email("passkey-other")).await;
+
+    let options = login_options(&app).await;
+    let response = app.post("/api/auth/webauthn/login", json!({
+        "credential": authenticator.get(&options, 1, &other.user_handle),
+    })).await;
+
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert!(response.message().contains("another account"), "{}", response.body);
+}